@@ -1,8 +1,241 @@
+use std::{env, fmt::Write as _, fs, path::PathBuf};
+
+use toml::Table;
+
+// every pin the firmware needs, see board.toml.
+const PINS: [&str; 11] = [
+    "step",
+    "dir",
+    "endstop",
+    "green_led",
+    "red_led",
+    "home_button",
+    "raise_button",
+    "lower_button",
+    "bottom_button",
+    "uart_tx",
+    "uart_rx",
+];
+// pins for optional hardware, each set turning on the cfg of the same name when all of its pins
+// are given.
+const OPTIONAL_PINS: [(&str, &[&str]); 6] = [
+    ("bottom_endstop", &["bottom_endstop"]),
+    ("encoder", &["encoder_sda", "encoder_scl"]),
+    ("diag", &["diag"]),
+    ("obstruction", &["obstruction"]),
+    ("tof", &["tof_sda", "tof_scl"]),
+    ("enable", &["enable"]),
+];
+// optional hardware that can't be fitted together, like two I2C devices for the one I2C
+// peripheral.
+const CONFLICTS: [(&str, &str); 1] = [("encoder", "tof")];
+const MOTION: [&str; 9] = [
+    "travel_limit",
+    "max_vel",
+    "max_accel",
+    "start_vel",
+    "jog_vel",
+    "microsteps",
+    "run_current",
+    "homing_current",
+    "hold_current",
+];
+// the hardware's timings and thresholds, each handed to the firmware as the build variable of the
+// same name in capitals, which its module reads and checks. See `[tuning]` in board.toml.
+const TUNING: [&str; 14] = [
+    "step_pulse_ns",
+    "dir_setup_ns",
+    "min_step_interval_ns",
+    "step_cpu_budget_percent",
+    "tmc_baud",
+    "tmc_write_retries",
+    "stall_threshold",
+    "soft_limit",
+    "motor_fullsteps_per_rev",
+    "encoder_drift_limit",
+    "obstruction_mm",
+    "obstruction_back_off",
+    "hot_board_c",
+    "low_battery_mv",
+];
+// how the encoder is used, if one is fitted, each generating `ENCODER_` and its name in capitals.
+const ENCODER: [&str; 2] = ["rehome", "reversed"];
+const FEATURES: [&str; 8] = [
+    "expert_mode",
+    "test_rig",
+    "esp_now",
+    "vactual_mode",
+    "vactual_jogs",
+    "rmt_steps",
+    "ble",
+    "stall_recovery",
+];
+
 fn main() {
     linker_be_nice();
-    println!("cargo:rustc-link-arg=-Tdefmt.x");
+    generate_board();
+    // only for the firmware, the library's tests link for the host.
+    println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
-    println!("cargo:rustc-link-arg=-Tlinkall.x");
+    println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
+}
+
+/// Turns the board file into `$OUT_DIR/board.rs`, for `src/board.rs` to include.
+fn generate_board() {
+    let path = env::var("CRABROLL_BOARD").unwrap_or_else(|_| "board.toml".into());
+    println!("cargo:rerun-if-env-changed=CRABROLL_BOARD");
+    println!("cargo:rerun-if-changed={path}");
+    let board: Table = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("can't read {path}: {e}"))
+        .parse()
+        .unwrap_or_else(|e| panic!("{path} isn't valid TOML: {e}"));
+    let section = |name: &str| {
+        board
+            .get(name)
+            .and_then(|s| s.as_table())
+            .unwrap_or_else(|| panic!("{path} has no [{name}] section"))
+    };
+    let (pins, motion, features) = (section("pins"), section("motion"), section("features"));
+    let int = |section: &Table, name: &str, key: &str| {
+        section
+            .get(key)
+            .and_then(|v| v.as_integer())
+            .unwrap_or_else(|| panic!("{path} needs a whole number for {name}.{key}"))
+    };
+    let flag = |section: &Table, name: &str, key: &str| {
+        section
+            .get(key)
+            .map(|v| {
+                v.as_bool()
+                    .unwrap_or_else(|| panic!("{path} needs true or false for {name}.{key}"))
+            })
+            .unwrap_or(false)
+    };
+
+    let mut out = String::from("// Generated by build.rs from the board file, don't edit.\n");
+    // peripherals can only be taken by field, so the pins are picked with a macro.
+    out.push_str("macro_rules! pin {\n");
+    for pin in PINS {
+        let gpio = int(pins, "pins", pin);
+        writeln!(out, "    ($p:expr, {pin}) => {{ $p.GPIO{gpio} }};").unwrap();
+    }
+    // optional pins get a cfg as well as their arm, so code that uses them can be left out.
+    let mut fitted = Vec::new();
+    for (cfg, cfg_pins) in OPTIONAL_PINS {
+        println!("cargo::rustc-check-cfg=cfg({cfg})");
+        let given = cfg_pins.iter().filter(|p| pins.contains_key(**p)).count();
+        if given == 0 {
+            continue;
+        } else if given < cfg_pins.len() {
+            panic!("{path} needs all of {cfg_pins:?} for the {cfg}, or none of them");
+        }
+        for pin in cfg_pins {
+            let gpio = int(pins, "pins", pin);
+            writeln!(out, "    ($p:expr, {pin}) => {{ $p.GPIO{gpio} }};").unwrap();
+        }
+        println!("cargo:rustc-cfg={cfg}");
+        fitted.push(cfg);
+    }
+    for (a, b) in CONFLICTS {
+        if fitted.contains(&a) && fitted.contains(&b) {
+            panic!("{path} can't have both the {a} and the {b}");
+        }
+    }
+    out.push_str("}\n");
+    // the buttons that wake the board, which power.rs has to steal back by type.
+    for (pin, name) in [
+        ("home_button", "HomeButton"),
+        ("raise_button", "RaiseButton"),
+        ("lower_button", "LowerButton"),
+    ] {
+        let gpio = int(pins, "pins", pin);
+        writeln!(
+            out,
+            "pub(crate) type {name} = esp_hal::peripherals::GPIO{gpio}<'static>;"
+        )
+        .unwrap();
+    }
+    for key in MOTION {
+        // the old environment variable still wins, so existing build scripts keep working.
+        let value = match (key, env::var("MICROSTEPS")) {
+            ("microsteps", Ok(m)) => m
+                .parse()
+                .unwrap_or_else(|_| panic!("MICROSTEPS must be a whole number")),
+            _ => int(motion, "motion", key),
+        };
+        let ty = if key.ends_with("current") {
+            "u8"
+        } else {
+            "u32"
+        };
+        writeln!(
+            out,
+            "pub(crate) const {}: {ty} = {value};",
+            key.to_uppercase()
+        )
+        .unwrap();
+    }
+    println!("cargo:rerun-if-env-changed=MICROSTEPS");
+    // on unless turned off.
+    let intpol = match env_flag("NO_INTPOL") {
+        Some(off) => !off,
+        None => !motion.contains_key("intpol") || flag(motion, "motion", "intpol"),
+    };
+    println!("cargo:rerun-if-env-changed=NO_INTPOL");
+    writeln!(out, "pub(crate) const INTPOL: bool = {intpol};").unwrap();
+    let spreadcycle = flag(motion, "motion", "spreadcycle");
+    writeln!(out, "pub(crate) const SPREADCYCLE: bool = {spreadcycle};").unwrap();
+    // optional, as is everything in it, so older board files still build.
+    if let Some(tuning) = board.get("tuning") {
+        let tuning = tuning
+            .as_table()
+            .unwrap_or_else(|| panic!("{path}'s tuning isn't a section"));
+        if let Some(key) = tuning.keys().find(|k| !TUNING.contains(&k.as_str())) {
+            panic!("{path} has no tuning.{key}, it can set any of {TUNING:?}");
+        }
+        for key in TUNING {
+            let name = key.to_uppercase();
+            // the environment variable still wins, like the others.
+            if env::var_os(&name).is_none() && tuning.contains_key(key) {
+                let value = int(tuning, "tuning", key);
+                println!("cargo:rustc-env={name}={value}");
+            }
+        }
+    }
+    for key in TUNING {
+        println!("cargo:rerun-if-env-changed={}", key.to_uppercase());
+    }
+    // optional too, for older board files and boards without an encoder.
+    let encoder = board.get("encoder").map(|e| {
+        e.as_table()
+            .unwrap_or_else(|| panic!("{path}'s encoder isn't a section"))
+    });
+    for key in ENCODER {
+        let name = format!("ENCODER_{}", key.to_uppercase());
+        println!("cargo:rerun-if-env-changed={name}");
+        let on = env_flag(&name)
+            .unwrap_or_else(|| encoder.is_some_and(|e| flag(e, "encoder", key)));
+        writeln!(out, "pub(crate) const {name}: bool = {on};").unwrap();
+    }
+    for key in FEATURES {
+        let name = key.to_uppercase();
+        println!("cargo:rerun-if-env-changed={name}");
+        let on = env_flag(&name).unwrap_or_else(|| flag(features, "features", key));
+        writeln!(out, "pub(crate) const {name}: bool = {on};").unwrap();
+    }
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("board.rs");
+    fs::write(out_path, out).unwrap();
+}
+
+/// Whether an environment variable turns something on or off, None if it isn't set.
+fn env_flag(name: &str) -> Option<bool> {
+    let value = env::var_os(name)?;
+    match value.to_str().map(str::to_ascii_lowercase).as_deref() {
+        Some("1" | "true") => Some(true),
+        Some("" | "0" | "false") => Some(false),
+        _ => panic!("{name} must be 1 or true to turn it on, or 0, false or empty to turn it off"),
+    }
 }
 
 fn linker_be_nice() {
@@ -53,7 +286,7 @@ fn linker_be_nice() {
     }
 
     println!(
-        "cargo:rustc-link-arg=--error-handling-script={}",
+        "cargo:rustc-link-arg-bins=--error-handling-script={}",
         std::env::current_exe().unwrap().display()
     );
 }
@@ -0,0 +1,157 @@
+use core::cell::Cell;
+
+use defmt::{Format, error, info};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use serde::Deserialize;
+
+use crate::{
+    AXES, Command, ErrorSeverity,
+    config_store::{LOCKOUT_KEY, fetch_board_item, store_board_item},
+    state::{confirm, raise_error},
+};
+
+// Set COMMAND_HOLD_SECS when building to change how long a move from one source keeps lower
+// priority ones from moving the blind, see `Source`.
+const HOLD: Duration = match option_env!("COMMAND_HOLD_SECS") {
+    Some(secs) => match u64::from_str_radix(secs, 10) {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => panic!("COMMAND_HOLD_SECS must be a whole number"),
+    },
+    None => Duration::from_secs(60),
+};
+
+// Loaded from flash by main, and changed on the `lockout` topic.
+static LOCKOUT: Mutex<CriticalSectionRawMutex, Cell<Lockout>> =
+    Mutex::new(Cell::new(Lockout::NONE));
+// The source of each axis's last move, and when it stops holding off the others.
+static HOLDERS: [Mutex<CriticalSectionRawMutex, Cell<Option<(Source, Instant)>>>; AXES] =
+    [const { Mutex::new(Cell::new(None)) }; AXES];
+
+/// Where a command came from, lowest priority first. A move holds the blind against lower
+/// priority sources for [`HOLD`], so the schedule doesn't undo someone at the window.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub(crate) enum Source {
+    /// The schedule and the solar automation.
+    Automation,
+    /// MQTT, the web UI, the ESPHome API and BLE.
+    Network,
+    /// Paired ESP-NOW remotes.
+    Remote,
+    Button,
+    /// Crabroll itself and the console, never refused.
+    Board,
+}
+
+/// Which sources are ignored altogether, as given on the `lockout` topic. Anything left out is
+/// let through, so `{"buttons": true}` is a child lock.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Format, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Lockout {
+    buttons: bool,
+    remotes: bool,
+}
+
+impl Lockout {
+    const NONE: Self = Self {
+        buttons: false,
+        remotes: false,
+    };
+
+    /// Parses the lockout as JSON, like `{"buttons": true, "remotes": false}`.
+    pub(crate) fn parse(json: &[u8]) -> Option<Self> {
+        serde_json_core::from_slice(json).ok().map(|(l, _)| l)
+    }
+
+    pub(crate) fn to_bits(self) -> u8 {
+        u8::from(self.buttons) | (u8::from(self.remotes) << 1)
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Self {
+            buttons: bits & 1 != 0,
+            remotes: bits & 2 != 0,
+        }
+    }
+
+    fn locks_out(&self, source: Source) -> bool {
+        match source {
+            Source::Button => self.buttons,
+            Source::Remote => self.remotes,
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn lockout() -> Lockout {
+    LOCKOUT.lock(|l| l.get())
+}
+
+/// Loads the lockout. For main only, after setting up the storage.
+pub(crate) async fn load_lockout() {
+    match fetch_board_item::<u8>(LOCKOUT_KEY).await {
+        Ok(Some(bits)) => LOCKOUT.lock(|l| l.set(Lockout::from_bits(bits))),
+        Ok(None) => (),
+        Err(_) => {
+            error!("Error getting item in flash");
+            raise_error(ErrorSeverity::Soft);
+        }
+    }
+}
+
+/// Changes the lockout and saves it, if it is any different.
+pub(crate) async fn set_lockout(lockout: Lockout) {
+    if LOCKOUT.lock(|l| l.replace(lockout)) == lockout {
+        return;
+    }
+    info!("New lockout: {}", lockout);
+    match store_board_item(LOCKOUT_KEY, &lockout.to_bits()).await {
+        Ok(()) => confirm(),
+        Err(_) => {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Hard);
+        }
+    }
+}
+
+/// Whether a command from a source should go through to an axis. Stops always do, short of a
+/// lockout, and a move that does takes the hold on the axis.
+pub(crate) fn admit(axis: usize, source: Source, command: &Command) -> bool {
+    if lockout().locks_out(source) {
+        info!("Ignoring {} command, it is locked out", source);
+        raise_error(ErrorSeverity::Soft);
+        return false;
+    }
+    let moves = matches!(
+        command,
+        Command::MoveToPos(_)
+            | Command::MoveToPosAt(..)
+            | Command::MoveToStep(_)
+            | Command::StartJog(_)
+            | Command::Tilt(_)
+            | Command::Home
+            | Command::Calibrate
+    );
+    if !moves {
+        return true;
+    }
+    HOLDERS[axis].lock(|h| {
+        let now = Instant::now();
+        match h.get() {
+            Some((holder, until)) if holder > source && now < until => {
+                info!(
+                    "Ignoring {} move on axis {}, {} has it for {}s",
+                    source,
+                    axis,
+                    holder,
+                    (until - now).as_secs()
+                );
+                false
+            }
+            _ => {
+                h.set(Some((source, now + HOLD)));
+                true
+            }
+        }
+    })
+}
@@ -0,0 +1,84 @@
+use defmt::{Format, info};
+
+use crate::{Command, Permille};
+
+// Outdoor brightness above which the sun is considered to be shining on the window.
+const SOLAR_LUX_THRESHOLD: u32 = 20_000;
+// Brightness has to drop this far below the threshold before it counts as no longer sunny,
+// so a passing cloud doesn't flap the blind.
+const SOLAR_LUX_HYSTERESIS: u32 = 5_000;
+
+/// The mode of the HVAC system, as reported by Home Assistant's climate integration.
+#[derive(Format, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HvacMode {
+    Off,
+    /// Close the blind when the sun is out, to keep the heat out.
+    Cool,
+    /// Open the blind when the sun is out, to let the heat in.
+    Heat,
+}
+
+impl HvacMode {
+    /// Parses the HVAC mode names used by Home Assistant. Modes that are neither heating nor
+    /// cooling leave the blind alone.
+    pub(crate) fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "cool" => Some(Self::Cool),
+            "heat" => Some(Self::Heat),
+            "off" | "auto" | "heat_cool" | "dry" | "fan_only" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// A lux based rule that closes the blind against the sun when cooling, and opens it to the sun
+/// when heating. Only acts when the sun comes out or the mode changes, so manual moves stick.
+pub(crate) struct SolarAutomation {
+    hvac_mode: HvacMode,
+    sunny: bool,
+}
+
+impl SolarAutomation {
+    pub(crate) const fn new() -> Self {
+        Self {
+            hvac_mode: HvacMode::Off,
+            sunny: false,
+        }
+    }
+
+    pub(crate) fn on_lux(&mut self, lux: u32) -> Option<Command> {
+        let was_sunny = self.sunny;
+        if lux > SOLAR_LUX_THRESHOLD {
+            self.sunny = true;
+        } else if lux < SOLAR_LUX_THRESHOLD - SOLAR_LUX_HYSTERESIS {
+            self.sunny = false;
+        }
+        if self.sunny && !was_sunny {
+            self.action()
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn on_hvac_mode(&mut self, mode: HvacMode) -> Option<Command> {
+        let changed = mode != self.hvac_mode;
+        self.hvac_mode = mode;
+        if changed && self.sunny {
+            self.action()
+        } else {
+            None
+        }
+    }
+
+    fn action(&self) -> Option<Command> {
+        let command = match self.hvac_mode {
+            HvacMode::Off => None,
+            HvacMode::Cool => Some(Command::MoveToPos(Permille::BOTTOM)),
+            HvacMode::Heat => Some(Command::MoveToPos(Permille::HOME)),
+        };
+        if command.is_some() {
+            info!("Sun is out with HVAC in {} mode", self.hvac_mode);
+        }
+        command
+    }
+}
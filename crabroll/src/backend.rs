@@ -0,0 +1,661 @@
+use core::{cmp::max, iter::FusedIterator};
+
+use defmt::{Format, error, warn};
+use embassy_time::{Duration, Instant, TICK_HZ, Timer};
+use esp_hal::{
+    Async,
+    delay::Delay,
+    gpio::{Level, Output},
+    rmt::{Channel, PulseCode, Tx, TxChannelConfig},
+};
+use heapless::{String, Vec, format};
+use iter_step_gen::Direction;
+
+use crate::{
+    ErrorSeverity, TMC_BUS, TmcRequest, driver_addr,
+    settings::settings,
+    state::{publish_jitter, raise_error},
+    tmc_request,
+    tmc2209::Mscuract,
+};
+
+// Set `step_pulse_ns` in the board file (or STEP_PULSE_NS when building) to change how long STEP is
+// held high for each step.
+const STEP_PULSE_NS: u32 = match option_env!("STEP_PULSE_NS") {
+    Some(ns) => match u32::from_str_radix(ns, 10) {
+        Ok(ns) => ns,
+        _ => panic!("STEP_PULSE_NS must be a whole number"),
+    },
+    None => 200,
+};
+// Set `dir_setup_ns` in the board file (or DIR_SETUP_NS when building) to change how long DIR is
+// left to settle before the first step.
+const DIR_SETUP_NS: u32 = match option_env!("DIR_SETUP_NS") {
+    Some(ns) => match u32::from_str_radix(ns, 10) {
+        Ok(ns) => ns,
+        _ => panic!("DIR_SETUP_NS must be a whole number"),
+    },
+    None => 1000,
+};
+// Set `min_step_interval_ns` in the board file (or MIN_STEP_INTERVAL_NS when building) to change
+// how close together steps can ever be, whatever the plan asks for.
+const MIN_STEP_INTERVAL_NS: u32 = match option_env!("MIN_STEP_INTERVAL_NS") {
+    Some(ns) => match u32::from_str_radix(ns, 10) {
+        Ok(ns) => ns,
+        _ => panic!("MIN_STEP_INTERVAL_NS must be a whole number"),
+    },
+    None => 1000,
+};
+pub(crate) const STEP_TIMING: StepTiming =
+    StepTiming::new(STEP_PULSE_NS, DIR_SETUP_NS, MIN_STEP_INTERVAL_NS);
+// The TMC2209 datasheet's minimums: STEP high and low for a clock cycle and 20ns each, and DIR
+// settled 20ns before a STEP edge.
+const TMC_MIN_STEP_NS: u32 = 1_000_000_000u64.div_ceil(TMC_CLOCK_HZ) as u32 + 20;
+const TMC_MIN_DIR_SETUP_NS: u32 = 20;
+// How late a step can be before we decide we can't keep up and slow down.
+const MAX_STEP_LATENESS: Duration = Duration::from_micros(200);
+// Set `step_cpu_budget_percent` in the board file (or STEP_CPU_BUDGET_PERCENT when building) to
+// change how much of the CPU the step executor can take. It runs above everything else, so whatever
+// it takes, MQTT and the wifi go without. Plans are slowed down to stay inside it.
+const STEP_CPU_BUDGET_PERCENT: u32 = match option_env!("STEP_CPU_BUDGET_PERCENT") {
+    Some(percent) => match u32::from_str_radix(percent, 10) {
+        Ok(percent) if percent > 0 && percent <= 100 => percent,
+        _ => panic!("STEP_CPU_BUDGET_PERCENT must be from 1 to 100"),
+    },
+    None => 50,
+};
+// How many steps to average the CPU use over before checking it against the budget.
+const BUDGET_WINDOW: u32 = 64;
+// In VACTUAL mode the steps are only counted, so they are walked in batches this far apart rather
+// than waking for every one.
+const VACTUAL_BATCH_INTERVAL: Duration = Duration::from_millis(2);
+// How often to update the velocity in VACTUAL mode. Each update is a UART round trip.
+const VACTUAL_UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+// The TMC2209's internal clock, VACTUAL is in microsteps per 2^24 clock cycles.
+const TMC_CLOCK_HZ: u64 = 12_000_000;
+
+// The RMT is clocked at 80MHz, divided down to count in microseconds.
+pub(crate) const RMT_CLOCK_DIVIDER: u8 = 80;
+// The longest either half of an RMT pulse code can be, in RMT ticks.
+const RMT_MAX_TICKS: u16 = 0x7fff;
+const RMT_MAX_WAIT: Duration = Duration::from_micros(RMT_MAX_TICKS as u64);
+// `STEP_TIMING`'s pulse, in RMT ticks.
+const RMT_PULSE_TICKS: u16 = STEP_TIMING.pulse_ns.div_ceil(1000) as u16;
+// An ESP32-C3 RMT channel holds 48 pulse codes, and async sends have to fit, end marker and all.
+const RMT_BATCH_STEPS: usize = 47;
+// How much motion to plan into each batch. The plan runs this far ahead of the motor at most, so
+// this is also how late a `stop_fn` can be to notice something.
+const RMT_BATCH_TIME: Duration = Duration::from_millis(4);
+
+const CHOPCONF: u8 = 0x6c;
+const VACTUAL: u8 = 0x22;
+// MSCNT counts 1024 to the driver's microstep table, 256 to a fullstep.
+pub(crate) const MSCNT_CYCLE: u32 = 1024;
+
+/// How STEP and DIR are timed, checked against the TMC2209 datasheet when building. Everything is
+/// in nanoseconds.
+#[derive(Clone, Copy)]
+pub(crate) struct StepTiming {
+    /// How long STEP is held high. Waited out without yielding, as a round trip through the
+    /// executor costs far more than that.
+    pub(crate) pulse_ns: u32,
+    /// How long DIR settles before the first step, and is held for after the last.
+    pub(crate) dir_setup_ns: u32,
+    /// The shortest gap between the start of one step and the next, plans are slowed to it.
+    pub(crate) min_interval_ns: u32,
+}
+
+impl StepTiming {
+    /// Fails the build if any of them are too short for the driver to see.
+    const fn new(pulse_ns: u32, dir_setup_ns: u32, min_interval_ns: u32) -> Self {
+        assert!(
+            pulse_ns >= TMC_MIN_STEP_NS,
+            "STEP_PULSE_NS is too short for the TMC2209"
+        );
+        assert!(
+            dir_setup_ns >= TMC_MIN_DIR_SETUP_NS,
+            "DIR_SETUP_NS is too short for the TMC2209"
+        );
+        assert!(
+            min_interval_ns >= pulse_ns + TMC_MIN_STEP_NS,
+            "MIN_STEP_INTERVAL_NS leaves STEP low for too short for the TMC2209"
+        );
+        Self {
+            pulse_ns,
+            dir_setup_ns,
+            min_interval_ns,
+        }
+    }
+
+    fn min_interval(&self) -> Duration {
+        Duration::from_nanos(self.min_interval_ns.into())
+    }
+
+    /// Waits out the DIR setup time, without yielding.
+    fn settle_dir(&self) {
+        Delay::new().delay_nanos(self.dir_setup_ns);
+    }
+
+    /// Pulses STEP.
+    fn pulse(&self, step_pin: &mut Output<'_>) {
+        step_pin.set_high();
+        Delay::new().delay_nanos(self.pulse_ns);
+        step_pin.set_low();
+    }
+}
+
+/// Something that can turn a step plan into motion.
+pub(crate) trait MotionBackend {
+    /// Moves through a plan in one direction, stopping early if `stop_fn` returns true after a
+    /// step. `stop_fn` is also handed the plan, so it can be adjusted between steps, but never
+    /// reversed. Returns once the motor has stopped.
+    async fn execute_plan<P: FusedIterator<Item = Duration>>(
+        &mut self,
+        dir: Direction,
+        plan: P,
+        stop_fn: impl FnMut(&mut P) -> bool,
+    );
+    /// Stops any motion straight away.
+    async fn stop(&mut self);
+    /// Keeps the motor energised, holding its position.
+    async fn hold(&mut self);
+    /// Powers the motor down, so it can be moved by hand.
+    async fn de_energize(&mut self);
+}
+
+/// A driver that is stepped one step at a time, for [`StepDir`] to pace. Backends that hand
+/// whole batches of steps to hardware, like [`RmtStepDir`], or that don't step at all, like
+/// [`Vactual`], are a [`MotionBackend`] of their own instead.
+pub(crate) trait StepDriver {
+    /// Takes one step, in the direction last set.
+    fn step(&mut self);
+    /// Sets the direction of the next steps, returning once it is safe to step.
+    fn set_direction(&mut self, dir: Direction);
+    /// Energises the motor, holding its position.
+    async fn enable(&mut self);
+    /// Powers the motor down, so it can be moved by hand.
+    async fn disable(&mut self);
+}
+
+/// The driver's STEP and DIR pins, timed by [`STEP_TIMING`], with the output stage switched on and
+/// off over UART.
+pub(crate) struct GpioStepDriver {
+    step_pin: Output<'static>,
+    dir_pin: Output<'static>,
+    axis: usize,
+}
+
+impl GpioStepDriver {
+    pub(crate) fn new(step_pin: Output<'static>, dir_pin: Output<'static>, axis: usize) -> Self {
+        Self {
+            step_pin,
+            dir_pin,
+            axis,
+        }
+    }
+}
+
+impl StepDriver for GpioStepDriver {
+    fn step(&mut self) {
+        STEP_TIMING.pulse(&mut self.step_pin);
+    }
+
+    fn set_direction(&mut self, dir: Direction) {
+        self.dir_pin.set_level(dir_level(self.axis, dir));
+        STEP_TIMING.settle_dir();
+    }
+
+    async fn enable(&mut self) {
+        set_chopconf(self.axis, true).await;
+    }
+
+    async fn disable(&mut self) {
+        set_chopconf(self.axis, false).await;
+    }
+}
+
+/// Steps a [`StepDriver`] through a plan from the CPU, one step at a time.
+pub(crate) struct StepDir<D> {
+    driver: D,
+    axis: usize,
+}
+
+impl<D: StepDriver> StepDir<D> {
+    pub(crate) fn new(driver: D, axis: usize) -> Self {
+        Self { driver, axis }
+    }
+}
+
+impl<D: StepDriver> MotionBackend for StepDir<D> {
+    /// DIR is set up [`STEP_TIMING`]'s setup time before the first step, and is held until after
+    /// the last, as every step waits out its delay before we return.
+    /// If a step deadline is missed by more than [`MAX_STEP_LATENESS`] (for example, because a
+    /// flash erase held up the executor), the rest of the plan is slowed down to the rate we
+    /// actually managed, rather than trying to catch up and losing steps. Anything less is made up
+    /// on the next step, see [`Pacer::wait`].
+    async fn execute_plan<P: FusedIterator<Item = Duration>>(
+        &mut self,
+        dir: Direction,
+        mut plan: P,
+        mut stop_fn: impl FnMut(&mut P) -> bool,
+    ) {
+        self.driver.set_direction(dir);
+        let mut pacer = Pacer::new();
+        while let Some(delay) = plan.next() {
+            let step_time = Instant::now();
+            self.driver.step();
+            pacer.wait(step_time, delay).await;
+            // checked after the step, as the plan has already counted it.
+            if stop_fn(&mut plan) {
+                break;
+            }
+        }
+        pacer.report();
+        publish_jitter(self.axis, pacer.jitter);
+    }
+
+    /// Without pulses the motor stops by itself.
+    async fn stop(&mut self) {}
+
+    async fn hold(&mut self) {
+        self.driver.enable().await;
+    }
+
+    async fn de_energize(&mut self) {
+        self.driver.disable().await;
+    }
+}
+
+/// Where an axis's driver is in its microstep table (MSCNT), in 256ths of a fullstep counting up
+/// as the motor turns in `dir`. It wraps every [`MSCNT_CYCLE`], 4 fullsteps.
+pub(crate) async fn microstep_count(axis: usize, dir: Direction) -> Option<u32> {
+    let driver = TMC_BUS.get().await.handle(driver_addr(axis));
+    let mscnt = match driver.read_mscnt().await {
+        Ok(mscnt) => u32::from(mscnt),
+        Err(e) => {
+            error!("Error reading MSCNT: {}", e);
+            return None;
+        }
+    };
+    // MSCNT goes up the way DIR low turns the motor, like a positive VACTUAL.
+    Some(if dir_level(axis, dir) == Level::High {
+        (MSCNT_CYCLE - mscnt) % MSCNT_CYCLE
+    } else {
+        mscnt
+    })
+}
+
+/// The current the driver is giving each of an axis's motor's coils.
+pub(crate) async fn coil_currents(axis: usize) -> Option<Mscuract> {
+    let driver = TMC_BUS.get().await.handle(driver_addr(axis));
+    driver
+        .read_mscuract()
+        .await
+        .inspect_err(|e| error!("Error reading MSCURACT: {}", e))
+        .ok()
+}
+
+/// The level of DIR that turns an axis's motor in `dir`.
+fn dir_level(axis: usize, dir: Direction) -> Level {
+    let home_level = settings(axis).dir_to_home();
+    match dir {
+        Direction::ToHome => home_level,
+        Direction::AwayFromHome => !home_level,
+    }
+}
+
+/// Spaces out steps, slowing the rest of the plan down if we fall behind it, or if stepping is
+/// taking more than [`STEP_CPU_BUDGET_PERCENT`] of the CPU.
+struct Pacer {
+    min_delay: Duration,
+    // when the step being waited on was due, None when the next one is taken as it comes.
+    due: Option<Instant>,
+    jitter: Jitter,
+    // when we last woke from waiting on a step, everything since is our own work.
+    woke: Instant,
+    busy: Duration,
+    window_delay: Duration,
+    window_steps: u32,
+    missed_deadlines: u32,
+    worst_lateness: Duration,
+    over_budget: bool,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Self {
+            min_delay: STEP_TIMING.min_interval(),
+            due: None,
+            jitter: Jitter::NONE,
+            woke: Instant::now(),
+            busy: Duration::from_ticks(0),
+            window_delay: Duration::from_ticks(0),
+            window_steps: 0,
+            missed_deadlines: 0,
+            worst_lateness: Duration::from_ticks(0),
+            over_budget: false,
+        }
+    }
+
+    /// Waits until `delay` after a step was due, or longer if we are slowed down. A step taken at
+    /// `step_time` a little after it was due, from waking late or the work around the pulse, has
+    /// its wait cut short by as much, so the error doesn't add up over the move.
+    async fn wait(&mut self, step_time: Instant, delay: Duration) {
+        let delay = max(delay, self.min_delay);
+        self.busy += Instant::now().saturating_duration_since(self.woke);
+        self.window_delay += delay;
+        self.window_steps += 1;
+        if self.window_steps == BUDGET_WINDOW {
+            // stretch the steps until the work fits in the budget.
+            if self.busy * 100 > self.window_delay * STEP_CPU_BUDGET_PERCENT {
+                let needed = self.busy * 100 / (STEP_CPU_BUDGET_PERCENT * BUDGET_WINDOW);
+                self.min_delay = max(self.min_delay, needed);
+                self.over_budget = true;
+            }
+            self.busy = Duration::from_ticks(0);
+            self.window_delay = Duration::from_ticks(0);
+            self.window_steps = 0;
+        }
+        let due = match self.due {
+            Some(due) => {
+                self.jitter.add(step_time.saturating_duration_since(due));
+                due
+            }
+            None => step_time,
+        };
+        // never closer than the driver can take.
+        let deadline = max(
+            due.saturating_add(delay),
+            step_time.saturating_add(STEP_TIMING.min_interval()),
+        );
+        Timer::at(deadline).await;
+        self.woke = Instant::now();
+        let lateness = self.woke.saturating_duration_since(deadline);
+        if lateness > MAX_STEP_LATENESS {
+            self.missed_deadlines += 1;
+            self.worst_lateness = max(self.worst_lateness, lateness);
+            self.min_delay = max(self.min_delay, delay + lateness);
+            // far enough behind to start again from here, rather than rush to catch up.
+            self.due = None;
+        } else {
+            self.due = Some(deadline);
+        }
+    }
+
+    fn report(&self) {
+        if self.missed_deadlines > 0 {
+            warn!(
+                "missed {} step deadlines, worst by {}us, slowed to {}us per step",
+                self.missed_deadlines,
+                self.worst_lateness.as_micros(),
+                self.min_delay.as_micros()
+            );
+        }
+        if self.over_budget {
+            warn!(
+                "stepping went over the {}% CPU budget, slowed to {}us per step",
+                STEP_CPU_BUDGET_PERCENT,
+                self.min_delay.as_micros()
+            );
+        }
+    }
+}
+
+/// How far steps were taken from when they were due over a move, made up for on the step after,
+/// see [`Pacer::wait`]. Steps after a missed deadline are timed afresh, so they don't count.
+#[derive(Clone, Copy, Format)]
+pub(crate) struct Jitter {
+    steps: u32,
+    total: Duration,
+    worst: Duration,
+}
+
+impl Jitter {
+    const NONE: Self = Self {
+        steps: 0,
+        total: Duration::from_ticks(0),
+        worst: Duration::from_ticks(0),
+    };
+
+    fn add(&mut self, jitter: Duration) {
+        self.steps += 1;
+        self.total += jitter;
+        self.worst = max(self.worst, jitter);
+    }
+
+    pub(crate) fn json(&self) -> String<64> {
+        let mean = self.total.as_micros() / u64::from(self.steps.max(1));
+        format!(
+            64;
+            "{{\"steps\":{},\"mean_us\":{},\"worst_us\":{}}}",
+            self.steps,
+            mean,
+            self.worst.as_micros(),
+        )
+        .unwrap()
+    }
+}
+
+/// Has the driver generate the steps itself, by writing the velocity to VACTUAL over UART.
+/// The plan is still walked step by step, so position tracking and `stop_fn` work as they do
+/// for [`StepDir`], but the driver only hears about it every [`VACTUAL_UPDATE_INTERVAL`].
+/// As nothing has to happen at each step, they are walked in batches every
+/// [`VACTUAL_BATCH_INTERVAL`], leaving the CPU alone in between however fast the motor goes.
+pub(crate) struct Vactual {
+    axis: usize,
+    steps: u32,
+}
+
+impl Vactual {
+    pub(crate) fn new(axis: usize) -> Self {
+        Self { axis, steps: 0 }
+    }
+
+    /// How many steps of the last plan were walked. The driver's own count can be a little off
+    /// from this, see [`microstep_count`].
+    pub(crate) fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    async fn set_velocity(&self, velocity: i32) {
+        let request = TmcRequest {
+            addr: Some(driver_addr(self.axis)),
+            register: VACTUAL,
+            // two's complement, as the register is signed.
+            value: Some(velocity as u32),
+        };
+        if tmc_request(request).await.is_none() {
+            error!("Error setting VACTUAL");
+        }
+    }
+}
+
+impl MotionBackend for Vactual {
+    async fn execute_plan<P: FusedIterator<Item = Duration>>(
+        &mut self,
+        dir: Direction,
+        mut plan: P,
+        mut stop_fn: impl FnMut(&mut P) -> bool,
+    ) {
+        // VACTUAL's sign picks the direction, positive turning the way DIR low would.
+        let sign = if dir_level(self.axis, dir) == Level::High {
+            -1
+        } else {
+            1
+        };
+        let mut step_time = Instant::now();
+        let mut next_update = step_time;
+        self.steps = 0;
+        while let Some(delay) = plan.next() {
+            self.steps += 1;
+            if Instant::now() >= next_update {
+                let steps_per_sec = TICK_HZ / delay.as_ticks().max(1);
+                let velocity = (steps_per_sec << 24) / TMC_CLOCK_HZ;
+                self.set_velocity(sign * velocity as i32).await;
+                next_update += VACTUAL_UPDATE_INTERVAL;
+            }
+            step_time += delay;
+            // only sleep once the batch has caught up with the clock.
+            if step_time > Instant::now() {
+                Timer::at(max(step_time, Instant::now() + VACTUAL_BATCH_INTERVAL)).await;
+            }
+            if stop_fn(&mut plan) {
+                break;
+            }
+        }
+        self.stop().await;
+    }
+
+    async fn stop(&mut self) {
+        self.set_velocity(0).await;
+    }
+
+    async fn hold(&mut self) {
+        self.stop().await;
+        set_chopconf(self.axis, true).await;
+    }
+
+    async fn de_energize(&mut self) {
+        self.stop().await;
+        set_chopconf(self.axis, false).await;
+    }
+}
+
+/// What drives an axis's STEP pin.
+pub(crate) enum StepOutput {
+    Pin(Output<'static>),
+    Rmt(Channel<'static, Async, Tx>),
+}
+
+/// The RMT channel config for [`StepOutput::Rmt`], STEP idling low.
+pub(crate) fn rmt_config() -> TxChannelConfig {
+    TxChannelConfig::default()
+        .with_clk_divider(RMT_CLOCK_DIVIDER)
+        .with_idle_output(true)
+        .with_idle_output_level(Level::Low)
+}
+
+/// Pulses the driver's STEP pin from the RMT peripheral, leaving the timing to hardware.
+/// The plan is walked a batch of steps at a time, each batch being at most [`RMT_BATCH_STEPS`]
+/// steps or [`RMT_BATCH_TIME`] of motion, and handed to the RMT while the CPU waits. `stop_fn` is
+/// called for each step as it is planned, so it can be up to a batch ahead of the motor.
+/// Each step waits out its delay before its pulse rather than after, so a batch ends on a step,
+/// and the time taken to plan the next batch comes out of its first delay.
+pub(crate) struct RmtStepDir {
+    channel: Channel<'static, Async, Tx>,
+    dir_pin: Output<'static>,
+    axis: usize,
+}
+
+impl RmtStepDir {
+    pub(crate) fn new(
+        channel: Channel<'static, Async, Tx>,
+        dir_pin: Output<'static>,
+        axis: usize,
+    ) -> Self {
+        Self {
+            channel,
+            dir_pin,
+            axis,
+        }
+    }
+}
+
+impl MotionBackend for RmtStepDir {
+    async fn execute_plan<P: FusedIterator<Item = Duration>>(
+        &mut self,
+        dir: Direction,
+        mut plan: P,
+        mut stop_fn: impl FnMut(&mut P) -> bool,
+    ) {
+        self.dir_pin.set_level(dir_level(self.axis, dir));
+        STEP_TIMING.settle_dir();
+        let mut codes: Vec<PulseCode, { RMT_BATCH_STEPS + 1 }> = Vec::new();
+        // when the last batch finished, on its last step.
+        let mut sent = Instant::now();
+        // a step too slow to wait out inside a batch, left to start the next one.
+        let mut carried = None;
+        let mut stopping = false;
+        loop {
+            let first = match carried.take() {
+                Some(delay) => Some(delay),
+                None if stopping => None,
+                None => {
+                    let delay = plan.next();
+                    stopping = delay.is_some() && stop_fn(&mut plan);
+                    delay
+                }
+            };
+            let Some(first) = first else { break };
+            // anything too long for the RMT is waited out here instead.
+            let step_time = sent + first;
+            if step_time.saturating_duration_since(Instant::now()) > RMT_MAX_WAIT {
+                Timer::at(step_time - RMT_MAX_WAIT).await;
+            }
+            codes.clear();
+            // can't overflow, the batch has only just started.
+            codes
+                .push(step_code(
+                    step_time.saturating_duration_since(Instant::now()),
+                ))
+                .unwrap();
+            let mut queued = first;
+            while !stopping && codes.len() < RMT_BATCH_STEPS && queued < RMT_BATCH_TIME {
+                let Some(delay) = plan.next() else { break };
+                stopping = stop_fn(&mut plan);
+                if delay > RMT_MAX_WAIT {
+                    carried = Some(delay);
+                    break;
+                }
+                codes.push(step_code(delay)).unwrap();
+                queued += delay;
+            }
+            codes.push(PulseCode::end_marker()).unwrap();
+            if self.channel.transmit(&codes).await.is_err() {
+                // the plan has counted steps that were never made.
+                error!("Error sending steps to the RMT");
+                raise_error(ErrorSeverity::Soft);
+                break;
+            }
+            sent = Instant::now();
+        }
+    }
+
+    /// Without pulses the motor stops by itself.
+    async fn stop(&mut self) {}
+
+    async fn hold(&mut self) {
+        set_chopconf(self.axis, true).await;
+    }
+
+    async fn de_energize(&mut self) {
+        set_chopconf(self.axis, false).await;
+    }
+}
+
+/// One step for the RMT, low until `wait` is up, then the pulse. Never closer to the last step
+/// than [`STEP_TIMING`] allows.
+fn step_code(wait: Duration) -> PulseCode {
+    let low = max(wait, STEP_TIMING.min_interval())
+        .as_micros()
+        .saturating_sub(RMT_PULSE_TICKS.into())
+        .clamp(1, RMT_MAX_TICKS.into()) as u16;
+    PulseCode::new(Level::Low, low, Level::High, RMT_PULSE_TICKS)
+}
+
+/// Turns the driver's output stage on or off, with TOFF.
+async fn set_chopconf(axis: usize, energized: bool) {
+    let chopconf = if energized {
+        settings(axis).chopconf()
+    } else {
+        settings(axis).chopconf().with_toff(0)
+    };
+    let request = TmcRequest {
+        addr: Some(driver_addr(axis)),
+        register: CHOPCONF,
+        value: Some(chopconf.into_bits()),
+    };
+    if tmc_request(request).await.is_none() {
+        error!("Error setting CHOPCONF");
+    }
+}
@@ -0,0 +1,313 @@
+use core::net::Ipv4Addr;
+
+use bt_hci::controller::ExternalController;
+use defmt::{error, info, warn};
+use embassy_futures::{
+    join::join,
+    select::{Either, select},
+};
+use embassy_time::Timer;
+use esp_hal::system::software_reset;
+use esp_radio::ble::controller::BleConnector;
+use heapless::String;
+use trouble_host::prelude::*;
+
+use crate::{
+    Command, Connectivity, ErrorSeverity, Permille, Position,
+    arbiter::Source,
+    config_store::store_credentials,
+    connectivity,
+    mdns::HOSTNAME,
+    mqtt::{invert_percent, invert_permille},
+    provisioning::{
+        Credentials, TOPIC_PREFIX_LEN, default_host_id, default_topic_prefix, valid_topic_prefix,
+    },
+    state::{PositionReceiver, confirm, raise_error, send_command, watch_pos},
+};
+
+// one phone at a time, with the signalling and attribute channels.
+const CONNECTIONS: usize = 1;
+const L2CAP_CHANNELS: usize = 2;
+// what fits in an advertisement after the flags.
+const ADVERTISED_NAME_LEN: usize = 26;
+// what the command characteristic takes.
+const OPEN: u8 = 1;
+const CLOSE: u8 = 2;
+const STOP: u8 = 3;
+const HOME: u8 = 4;
+
+#[gatt_server]
+struct Server {
+    control: ControlService,
+    provisioning: ProvisioningService,
+}
+
+/// Drives the first axis, like the buttons.
+#[gatt_service(uuid = "6c9e0001-5b1f-4c1a-9f3e-6372616272c0")]
+struct ControlService {
+    /// A user facing percentage, notified after every move, or written to move there.
+    #[characteristic(uuid = "6c9e0002-5b1f-4c1a-9f3e-6372616272c0", read, write, notify)]
+    position: u8,
+    /// One of `OPEN`, `CLOSE`, `STOP` or `HOME`.
+    #[characteristic(uuid = "6c9e0003-5b1f-4c1a-9f3e-6372616272c0", write)]
+    command: u8,
+}
+
+/// The wifi and MQTT details, as the portal's form has them, written one by one as UTF-8 and
+/// then saved by writing 1 to `save`.
+#[gatt_service(uuid = "6c9e0101-5b1f-4c1a-9f3e-6372616272c0")]
+struct ProvisioningService {
+    #[characteristic(uuid = "6c9e0102-5b1f-4c1a-9f3e-6372616272c0", write)]
+    ssid: [u8; 32],
+    #[characteristic(uuid = "6c9e0103-5b1f-4c1a-9f3e-6372616272c0", write)]
+    password: [u8; 64],
+    #[characteristic(uuid = "6c9e0104-5b1f-4c1a-9f3e-6372616272c0", write)]
+    broker: [u8; 15],
+    #[characteristic(uuid = "6c9e0105-5b1f-4c1a-9f3e-6372616272c0", write)]
+    mqtt_username: [u8; 64],
+    #[characteristic(uuid = "6c9e0106-5b1f-4c1a-9f3e-6372616272c0", write)]
+    mqtt_password: [u8; 64],
+    /// Left unwritten for the built in ones.
+    #[characteristic(uuid = "6c9e0108-5b1f-4c1a-9f3e-6372616272c0", write)]
+    host_id: [u8; 32],
+    #[characteristic(uuid = "6c9e0109-5b1f-4c1a-9f3e-6372616272c0", write)]
+    topic_prefix: [u8; TOPIC_PREFIX_LEN],
+    #[characteristic(uuid = "6c9e0107-5b1f-4c1a-9f3e-6372616272c0", write)]
+    save: u8,
+}
+
+/// The provisioning details written so far on a connection.
+#[derive(Default)]
+struct Provisioning {
+    ssid: String<32>,
+    password: String<64>,
+    broker: String<15>,
+    mqtt_username: String<64>,
+    mqtt_password: String<64>,
+    host_id: String<32>,
+    topic_prefix: String<TOPIC_PREFIX_LEN>,
+}
+
+impl Provisioning {
+    fn credentials(&self) -> Option<Credentials> {
+        Some(Credentials {
+            ssid: Some(self.ssid.clone()).filter(|s| !s.is_empty())?,
+            password: self.password.clone(),
+            broker_ip: self.broker.parse::<Ipv4Addr>().ok()?,
+            mqtt_username: self.mqtt_username.clone(),
+            mqtt_password: self.mqtt_password.clone(),
+            host_id: Some(self.host_id.clone())
+                .filter(|h| !h.is_empty())
+                .unwrap_or_else(default_host_id),
+            topic_prefix: Some(
+                Some(self.topic_prefix.clone())
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or_else(default_topic_prefix),
+            )
+            .filter(|p| valid_topic_prefix(p))?,
+            stored: true,
+        })
+    }
+}
+
+/// Advertises a GATT service for setting up the wifi and driving the blind from a phone, in
+/// provisioning mode or when the wifi is down as much as any other time. Spawn before the wifi
+/// for both to share the radio.
+#[embassy_executor::task]
+pub(crate) async fn ble_task(connector: BleConnector<'static>, mac: [u8; 6]) {
+    let controller: ExternalController<_, 20> = ExternalController::new(connector);
+    let mut resources: HostResources<DefaultPacketPool, CONNECTIONS, L2CAP_CHANNELS> =
+        HostResources::new();
+    // a static random address needs its top two bits set.
+    let mut address = mac;
+    address[5] |= 0xc0;
+    let stack =
+        trouble_host::new(controller, &mut resources).set_random_address(Address::random(address));
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+    let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: HOSTNAME,
+        appearance: &appearance::UNKNOWN,
+    }))
+    .unwrap();
+    let mut positions = watch_pos(0);
+    let run = async {
+        loop {
+            if let Err(e) = runner.run().await {
+                error!("Error running the BLE stack: {:?}", e);
+            }
+        }
+    };
+    let serve = async {
+        loop {
+            match advertise(&mut peripheral, &server).await {
+                Ok(conn) => {
+                    info!("BLE client connected");
+                    serve_connection(&server, &conn, &mut positions).await;
+                }
+                Err(e) => {
+                    error!("Error advertising over BLE: {:?}", e);
+                    Timer::after_secs(1).await;
+                }
+            }
+        }
+    };
+    join(run, serve).await;
+}
+
+async fn advertise<'values, 'server, C: Controller>(
+    peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
+    server: &'server Server<'values>,
+) -> Result<GattConnection<'values, 'server, DefaultPacketPool>, BleHostError<C::Error>> {
+    let name = HOSTNAME.as_bytes();
+    let name = if name.len() <= ADVERTISED_NAME_LEN {
+        AdStructure::CompleteLocalName(name)
+    } else {
+        AdStructure::ShortenedLocalName(&name[..ADVERTISED_NAME_LEN])
+    };
+    let mut data = [0; 31];
+    let len = AdStructure::encode_slice(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            name,
+        ],
+        &mut data,
+    )?;
+    let advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &data[..len],
+                scan_data: &[],
+            },
+        )
+        .await?;
+    Ok(advertiser.accept().await?.with_attribute_server(server)?)
+}
+
+async fn serve_connection(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    positions: &mut PositionReceiver,
+) {
+    let mut provisioning = Provisioning::default();
+    loop {
+        match select(conn.next(), positions.changed()).await {
+            Either::First(GattConnectionEvent::Disconnected { .. }) => {
+                info!("BLE client disconnected");
+                return;
+            }
+            Either::First(GattConnectionEvent::Gatt { event }) => {
+                let save = match &event {
+                    GattEvent::Write(write) => {
+                        handle_write(server, &mut provisioning, write.handle(), write.data())
+                    }
+                    _ => false,
+                };
+                match event.accept() {
+                    Ok(reply) => reply.send().await,
+                    Err(e) => warn!("Error answering BLE request: {:?}", e),
+                }
+                if save {
+                    save_credentials(&provisioning).await;
+                }
+            }
+            Either::First(_) => (),
+            Either::Second(pos) => {
+                if let Err(e) = server.control.position.notify(conn, &percent(&pos)).await {
+                    warn!("Error notifying BLE client: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+fn percent(pos: &Position) -> u8 {
+    invert_percent(0, pos.percent).try_into().unwrap_or(0)
+}
+
+/// Takes a write, true if it asked to save the credentials.
+fn handle_write(
+    server: &Server<'_>,
+    provisioning: &mut Provisioning,
+    handle: u16,
+    data: &[u8],
+) -> bool {
+    let (control, p) = (&server.control, &server.provisioning);
+    let command = |command| {
+        send_command(0, Source::Network, command);
+        true
+    };
+    let taken = match handle {
+        h if h == control.position.handle => {
+            // a percent past 100 is just the bottom.
+            let percent = data.first().map_or(0, |p| (*p).min(100)) as i8;
+            command(Command::MoveToPos(invert_permille(
+                0,
+                Permille::from_percent(percent),
+            )))
+        }
+        h if h == control.command.handle => match data.first().copied() {
+            Some(OPEN) => command(Command::MoveToPos(Permille::HOME)),
+            Some(CLOSE) => command(Command::MoveToPos(Permille::BOTTOM)),
+            Some(STOP) => command(Command::Stop),
+            Some(HOME) => command(Command::Home),
+            _ => false,
+        },
+        h if h == p.ssid.handle => set_text(&mut provisioning.ssid, data),
+        h if h == p.password.handle => set_text(&mut provisioning.password, data),
+        h if h == p.broker.handle => set_text(&mut provisioning.broker, data),
+        h if h == p.mqtt_username.handle => set_text(&mut provisioning.mqtt_username, data),
+        h if h == p.mqtt_password.handle => set_text(&mut provisioning.mqtt_password, data),
+        h if h == p.host_id.handle => set_text(&mut provisioning.host_id, data),
+        h if h == p.topic_prefix.handle => set_text(&mut provisioning.topic_prefix, data),
+        h if h == p.save.handle => return data.first() == Some(&1),
+        _ => true,
+    };
+    if !taken {
+        warn!("Ignoring BLE write that doesn't make sense");
+        raise_error(ErrorSeverity::Soft);
+    }
+    false
+}
+
+/// Copies written text into a field, false if it isn't UTF-8 or doesn't fit.
+fn set_text<const N: usize>(field: &mut String<N>, data: &[u8]) -> bool {
+    match core::str::from_utf8(data)
+        .ok()
+        .and_then(|s| String::try_from(s).ok())
+    {
+        Some(text) => {
+            *field = text;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stores the credentials and reboots onto them. Only while the wifi is down, so that nobody in
+/// range can take over a working blind.
+async fn save_credentials(provisioning: &Provisioning) {
+    if connectivity() != Connectivity::Offline {
+        warn!("Not taking credentials over BLE while connected");
+        raise_error(ErrorSeverity::Soft);
+        return;
+    }
+    let Some(credentials) = provisioning.credentials() else {
+        warn!("BLE credentials are missing the SSID or the broker, or have a bad topic prefix");
+        raise_error(ErrorSeverity::Soft);
+        return;
+    };
+    info!("Provisioned over BLE for {}", credentials.ssid.as_str());
+    if store_credentials(&credentials).await.is_err() {
+        error!("Error storing item in flash");
+        raise_error(ErrorSeverity::Hard);
+        return;
+    }
+    confirm();
+    Timer::after_secs(1).await;
+    software_reset();
+}
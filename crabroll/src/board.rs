@@ -0,0 +1,4 @@
+// What build.rs read from the board file (`board.toml`, or wherever `CRABROLL_BOARD` points): the
+// pins, the default motion settings and the feature toggles. Pick a pin from the peripherals with
+// `pin!(peripherals, step)`.
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
@@ -0,0 +1,99 @@
+use defmt::Format;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Timer};
+use esp_hal::gpio::{Input, Level};
+
+// how long a press has to be stable for.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+const LONG_PRESS: Duration = Duration::from_secs(1);
+// how soon a second press has to come to make a double press.
+const DOUBLE_PRESS: Duration = Duration::from_millis(300);
+// Events barely ever pile up, people only have so many fingers.
+const EVENT_QUEUE: usize = 8;
+
+// Sent by the button tasks, received only by whoever handles the buttons.
+static EVENTS: Channel<CriticalSectionRawMutex, (ButtonId, ButtonEvent), EVENT_QUEUE> =
+    Channel::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum ButtonId {
+    Home,
+    Raise,
+    Lower,
+    Bottom,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum ButtonEvent {
+    /// As soon as the button goes down, for press and hold.
+    Pressed,
+    /// However the press ended, for press and hold.
+    Released,
+    /// Released before a long press. With double presses on, only once no second press follows.
+    Short,
+    /// Held down for a second, sent while the button is still held.
+    Long,
+    /// Pressed twice in quick succession, instead of two short presses.
+    Double,
+}
+
+/// Waits for a button to settle at a level.
+async fn debounced(button: &mut Input<'static>, level: Level) {
+    loop {
+        match level {
+            Level::Low => button.wait_for_low().await,
+            Level::High => button.wait_for_high().await,
+        }
+        Timer::after(DEBOUNCE).await;
+        if button.level() == level {
+            return;
+        }
+    }
+}
+
+/// Turns a pulled up, active low button into events, see [`next_event`]. Double presses are only
+/// looked for if asked for, as otherwise they hold up every short press.
+#[embassy_executor::task(pool_size = 4)]
+pub(crate) async fn button_task(mut button: Input<'static>, id: ButtonId, double_press: bool) {
+    let send = |event| async move { EVENTS.send((id, event)).await };
+    loop {
+        debounced(&mut button, Level::Low).await;
+        send(ButtonEvent::Pressed).await;
+        if let Either::Second(_) = select(
+            debounced(&mut button, Level::High),
+            Timer::after(LONG_PRESS),
+        )
+        .await
+        {
+            send(ButtonEvent::Long).await;
+            debounced(&mut button, Level::High).await;
+            send(ButtonEvent::Released).await;
+            continue;
+        }
+        send(ButtonEvent::Released).await;
+        if !double_press {
+            send(ButtonEvent::Short).await;
+            continue;
+        }
+        match select(
+            debounced(&mut button, Level::Low),
+            Timer::after(DOUBLE_PRESS),
+        )
+        .await
+        {
+            Either::First(_) => {
+                send(ButtonEvent::Pressed).await;
+                debounced(&mut button, Level::High).await;
+                send(ButtonEvent::Released).await;
+                send(ButtonEvent::Double).await;
+            }
+            Either::Second(_) => send(ButtonEvent::Short).await,
+        }
+    }
+}
+
+/// The next thing to happen to any of the buttons.
+pub(crate) async fn next_event() -> (ButtonId, ButtonEvent) {
+    EVENTS.receive().await
+}
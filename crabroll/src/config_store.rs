@@ -0,0 +1,620 @@
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use bitfield_struct::bitfield;
+use defmt::warn;
+use embassy_embedded_hal::adapter::BlockingAsync;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage_async::nor_flash::ErrorType;
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, FlashRegion, PARTITION_TABLE_MAX_LEN, PartitionType,
+};
+use esp_storage::FlashStorage;
+use heapless::Vec;
+use iter_step_gen::MoveState;
+use sequential_storage::{
+    Error,
+    cache::NoCache,
+    map::{MapConfig, MapStorage, Value},
+};
+use static_cell::StaticCell;
+
+use crate::{
+    AXES, ErrorSeverity, INVERT_PERCENT, MICROSTEPS, arbiter, board, espnow,
+    network::{NetworkConfig, network_config},
+    presets,
+    provisioning::{CREDENTIALS, Credentials, default_host_id, default_topic_prefix},
+    quiet, schedule,
+    settings::{Settings, settings},
+    state::raise_error,
+    stats,
+    strings::is,
+    watchdog,
+};
+
+// storage consts, each axis gets its own block of keys. The first axis keeps the keys from before
+// there were multiple axes.
+const KEYS_PER_AXIS: u8 = 16;
+const TRAVEL_LIMIT_KEY: u8 = 0;
+// only present after a clean shutdown, or while the blind is in use too, see
+// `PositionPersistence`.
+const POSITION_KEY: u8 = 1;
+const INVERT_PERCENT_KEY: u8 = 2;
+// the settings, each stored as a u32.
+const MAX_VEL_KEY: u8 = 3;
+const MAX_ACCEL_KEY: u8 = 4;
+const START_VEL_KEY: u8 = 5;
+const MICROSTEPS_KEY: u8 = 6;
+const RUN_CURRENT_KEY: u8 = 7;
+const INVERT_DIR_KEY: u8 = 8;
+const HOMING_CURRENT_KEY: u8 = 9;
+const POSITION_TOLERANCE_KEY: u8 = 10;
+// the daily moves, see `schedule::Schedule::to_bytes`.
+pub(crate) const SCHEDULE_KEY: u8 = 11;
+// the lifetime counters, see `stats::Stats::to_bytes`.
+pub(crate) const STATS_KEY: u8 = 12;
+// a setting like the others, after the schedule and stats because it came later.
+const BACKLASH_KEY: u8 = 13;
+const INTPOL_KEY: u8 = 14;
+// the hold current, SpreadCycle and tilt, packed into one as it is the last key an axis has, see
+// `DriverConfig`.
+const DRIVER_CONFIG_KEY: u8 = 15;
+// keys for the whole board, in a block of their own above every axis's.
+const BOARD_KEYS_START: u8 = u8::MAX - KEYS_PER_AXIS + 1;
+// the paired ESP-NOW remotes, one key each.
+pub(crate) const ESPNOW_PEER_KEY: u8 = 0;
+// set to come up in provisioning mode on the next boot.
+const PROVISION_KEY: u8 = 4;
+// the provisioned credentials, the strings stored as they are and the broker IP as a u32.
+const WIFI_SSID_KEY: u8 = 5;
+const WIFI_PASSWORD_KEY: u8 = 6;
+const BROKER_IP_KEY: u8 = 7;
+const MQTT_USERNAME_KEY: u8 = 8;
+const MQTT_PASSWORD_KEY: u8 = 9;
+// the last crash, see `watchdog::Crash::to_bytes`.
+pub(crate) const CRASH_KEY: u8 = 10;
+// static addresses, see `network::NetworkConfig::to_bytes`.
+pub(crate) const NETWORK_KEY: u8 = 11;
+// the named positions, see `presets::Presets::to_bytes`.
+pub(crate) const PRESETS_KEY: u8 = 12;
+// the move each axis is in the middle of, one key each, see `MoveState::to_bytes`.
+const MOVE_KEY: u8 = 13;
+const _: () = assert!(AXES <= 3, "there are only keys left for 3 axes' moves");
+// and a second block for the whole board, under the first once it had filled up.
+const MORE_BOARD_KEYS_START: u8 = BOARD_KEYS_START - KEYS_PER_AXIS;
+const _: () = assert!(
+    AXES * KEYS_PER_AXIS as usize <= MORE_BOARD_KEYS_START as usize,
+    "the axes' keys run into the board's"
+);
+// the nightly window, see `quiet::QuietHours::to_bytes`. The first key of the second block.
+pub(crate) const QUIET_HOURS_KEY: u8 = KEYS_PER_AXIS;
+// the rest of the provisioned credentials, MQTT's client ID and topic prefix, stored as they are.
+const HOST_ID_KEY: u8 = KEYS_PER_AXIS + 1;
+const TOPIC_PREFIX_KEY: u8 = KEYS_PER_AXIS + 2;
+// the sources locked out, see `arbiter::Lockout::to_bits`.
+pub(crate) const LOCKOUT_KEY: u8 = KEYS_PER_AXIS + 3;
+// set once the credentials in use have connected to the wifi, cleared when new ones are stored.
+const WIFI_CONNECTED_KEY: u8 = KEYS_PER_AXIS + 4;
+
+// Set POSITION_PERSISTENCE when building to also save the position while the blind is in use, so
+// a power cut doesn't mean homing again: `sleep` (the default), `idle`, or a percent of the travel,
+// like `10`. See `PositionPersistence`.
+const POSITION_PERSISTENCE: PositionPersistence = match option_env!("POSITION_PERSISTENCE") {
+    None => PositionPersistence::BeforeSleep,
+    Some(policy) if is(policy, "sleep") => PositionPersistence::BeforeSleep,
+    Some(policy) if is(policy, "idle") => PositionPersistence::OnIdle,
+    Some(percent) => match u32::from_str_radix(percent, 10) {
+        Ok(percent) if percent > 0 && percent <= 100 => PositionPersistence::OnChange(percent),
+        _ => panic!("POSITION_PERSISTENCE must be sleep, idle or a percent from 1 to 100"),
+    },
+};
+// what SAVED_POSITIONS and POSITIONS hold when there's no position.
+const NO_POSITION: u32 = u32::MAX;
+
+type Partition = BlockingAsync<FlashRegion<'static, FlashStorage<'static>>>;
+pub(crate) type StorageError = Error<<Partition as ErrorType>::Error>;
+
+/// The settings map, shared by all axes, and a buffer to go through it with. Everything in flash
+/// goes through here, taking turns on the lock, so no two tasks ever write at once.
+static STORAGE: Mutex<
+    CriticalSectionRawMutex,
+    Option<(MapStorage<u8, Partition, NoCache>, [u8; 4096])>,
+> = Mutex::new(None);
+// every axis's position as in flash, and as it is now, so a position is only written once it has
+// changed.
+static SAVED_POSITIONS: [AtomicU32; AXES] = [const { AtomicU32::new(NO_POSITION) }; AXES];
+static POSITIONS: [AtomicU32; AXES] = [const { AtomicU32::new(NO_POSITION) }; AXES];
+// every axis's travel limit, so a flash recovery can rewrite all of them.
+static TRAVEL_LIMITS: [AtomicU32; AXES] =
+    [const { AtomicU32::new(board::TRAVEL_LIMIT * MICROSTEPS) }; AXES];
+
+/// Sets up the settings map, must be called before spawning anything that uses flash.
+pub(crate) async fn init_storage(flash: FlashStorage<'static>) {
+    static FLASH: StaticCell<FlashStorage<'static>> = StaticCell::new();
+    static PT_MEM: StaticCell<[u8; PARTITION_TABLE_MAX_LEN]> = StaticCell::new();
+    let flash = FLASH.init(flash);
+    let pt_mem = PT_MEM.init([0u8; PARTITION_TABLE_MAX_LEN]);
+    let pt = partitions::read_partition_table(flash, pt_mem).unwrap();
+    let nvs = pt
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+        .unwrap()
+        .unwrap();
+    let partition = nvs.as_embedded_storage(flash);
+    let map = MapStorage::<u8, _, _>::new(
+        BlockingAsync::new(partition),
+        MapConfig::new(0x0000..0x6000),
+        NoCache::new(),
+    );
+    *STORAGE.lock().await = Some((map, [0u8; 4096]));
+}
+
+fn key(axis: usize, base: u8) -> u8 {
+    axis as u8 * KEYS_PER_AXIS + base
+}
+
+fn board_key(base: u8) -> u8 {
+    if base < KEYS_PER_AXIS {
+        BOARD_KEYS_START + base
+    } else {
+        MORE_BOARD_KEYS_START + base - KEYS_PER_AXIS
+    }
+}
+
+pub(crate) async fn fetch_item<V: for<'d> Value<'d>>(
+    axis: usize,
+    key_base: u8,
+) -> Result<Option<V>, StorageError> {
+    fetch_key(key(axis, key_base)).await
+}
+
+/// Fetches something that belongs to the whole board rather than an axis.
+pub(crate) async fn fetch_board_item<V: for<'d> Value<'d>>(
+    key_base: u8,
+) -> Result<Option<V>, StorageError> {
+    fetch_key(board_key(key_base)).await
+}
+
+async fn fetch_key<V: for<'d> Value<'d>>(key: u8) -> Result<Option<V>, StorageError> {
+    let mut storage = STORAGE.lock().await;
+    let (map, buffer) = storage.as_mut().expect("storage not initialised");
+    map.fetch_item(buffer, &key).await
+}
+
+async fn remove_item(axis: usize, key_base: u8) -> Result<(), StorageError> {
+    remove_key(key(axis, key_base)).await
+}
+
+async fn remove_key(key: u8) -> Result<(), StorageError> {
+    let mut storage = STORAGE.lock().await;
+    let (map, buffer) = storage.as_mut().expect("storage not initialised");
+    map.remove_item(buffer, &key).await
+}
+
+pub(crate) async fn store_item<V: for<'d> Value<'d>>(
+    axis: usize,
+    key_base: u8,
+    item: &V,
+) -> Result<(), StorageError> {
+    store_key(key(axis, key_base), item).await
+}
+
+/// Stores something that belongs to the whole board rather than an axis.
+pub(crate) async fn store_board_item<V: for<'d> Value<'d>>(
+    key_base: u8,
+    item: &V,
+) -> Result<(), StorageError> {
+    store_key(board_key(key_base), item).await
+}
+
+/// Stores an item, recovering the map and trying again if it is full or corrupted.
+async fn store_key<V: for<'d> Value<'d>>(key: u8, item: &V) -> Result<(), StorageError> {
+    let mut storage = STORAGE.lock().await;
+    let (map, buffer) = storage.as_mut().expect("storage not initialised");
+    match map.store_item(buffer, &key, item).await {
+        Err(Error::FullStorage | Error::Corrupted { .. }) => {
+            recover(map, buffer).await?;
+            map.store_item(buffer, &key, item).await
+        }
+        result => result,
+    }
+}
+
+/// Wipes the map and rewrites what we hold in RAM, for when reading it fails.
+pub(crate) async fn recover_storage() -> Result<(), StorageError> {
+    let mut storage = STORAGE.lock().await;
+    let (map, buffer) = storage.as_mut().expect("storage not initialised");
+    recover(map, buffer).await
+}
+
+/// The stored travel limit, in microsteps. Zero if it needs setting again.
+pub(crate) async fn travel_limit(axis: usize) -> Result<Option<u32>, StorageError> {
+    fetch_item(axis, TRAVEL_LIMIT_KEY).await
+}
+
+/// Stores the travel limit, and keeps it for rewriting if the flash needs recovering.
+pub(crate) async fn store_travel_limit(axis: usize, limit: u32) -> Result<(), StorageError> {
+    keep_travel_limit(axis, limit);
+    store_item(axis, TRAVEL_LIMIT_KEY, &limit).await
+}
+
+/// Keeps the travel limit a recovery rewrites, without storing it now.
+pub(crate) fn keep_travel_limit(axis: usize, limit: u32) {
+    TRAVEL_LIMITS[axis].store(limit, Ordering::Relaxed);
+}
+
+/// When an axis's position is written to flash. Every write wears the flash a little, so it isn't
+/// written on every move, and every policy writes it on shutdown, which comes before sleeping.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PositionPersistence {
+    /// Only on shutdown, so anything else the power goes out without means homing again.
+    BeforeSleep,
+    /// Once the axis has sat still for a minute after moving.
+    OnIdle,
+    /// Whenever the axis is this many percent of its travel from where it was last written.
+    OnChange(u32),
+}
+
+/// Where the axis was when its position was saved, if it was. Only a shutdown's position is
+/// trusted once the axis moves again, so under `BeforeSleep` it is removed as it is restored.
+pub(crate) async fn take_position(axis: usize) -> Result<Option<u32>, StorageError> {
+    let pos = fetch_item::<u32>(axis, POSITION_KEY).await?;
+    POSITIONS[axis].store(pos.unwrap_or(NO_POSITION), Ordering::Relaxed);
+    if pos.is_some() && POSITION_PERSISTENCE == PositionPersistence::BeforeSleep {
+        remove_item(axis, POSITION_KEY).await?;
+    } else {
+        SAVED_POSITIONS[axis].store(pos.unwrap_or(NO_POSITION), Ordering::Relaxed);
+    }
+    Ok(pos)
+}
+
+/// For the step executor after every command, writing the position if it has moved far enough
+/// for the policy. A position that has been lost is removed, so it can't be restored.
+pub(crate) async fn position_moved(
+    axis: usize,
+    pos: Option<u32>,
+    travel_limit: u32,
+) -> Result<(), StorageError> {
+    let pos = pos.unwrap_or(NO_POSITION);
+    POSITIONS[axis].store(pos, Ordering::Relaxed);
+    let saved = SAVED_POSITIONS[axis].load(Ordering::Relaxed);
+    if pos == NO_POSITION {
+        if saved != NO_POSITION {
+            remove_item(axis, POSITION_KEY).await?;
+            SAVED_POSITIONS[axis].store(NO_POSITION, Ordering::Relaxed);
+        }
+        return Ok(());
+    }
+    match POSITION_PERSISTENCE {
+        PositionPersistence::OnChange(percent)
+            if saved == NO_POSITION
+                || u64::from(pos.abs_diff(saved)) * 100
+                    >= u64::from(percent) * u64::from(travel_limit) =>
+        {
+            flush_position(axis).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// For the step executor whenever the axis has been idle for a while.
+pub(crate) async fn position_idle(axis: usize) -> Result<(), StorageError> {
+    match POSITION_PERSISTENCE {
+        PositionPersistence::OnIdle => flush_position(axis).await,
+        _ => Ok(()),
+    }
+}
+
+/// Writes the position now whatever the policy, if it has changed since it was last written.
+pub(crate) async fn flush_position(axis: usize) -> Result<(), StorageError> {
+    let pos = POSITIONS[axis].load(Ordering::Relaxed);
+    if pos == NO_POSITION || pos == SAVED_POSITIONS[axis].load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    store_item(axis, POSITION_KEY, &pos).await?;
+    SAVED_POSITIONS[axis].store(pos, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Notes a move starting, so that if the power goes before [`finish_move`], the next boot knows
+/// the position can't be trusted and where the move was going.
+pub(crate) async fn start_move(axis: usize, state: &MoveState) -> Result<(), StorageError> {
+    // can't fail, it is exactly the right length.
+    let bytes = Vec::<u8, { MoveState::BYTES }>::from_slice(&state.to_bytes()).unwrap();
+    store_board_item(MOVE_KEY + axis as u8, &bytes).await
+}
+
+/// For after a move, however it ended.
+pub(crate) async fn finish_move(axis: usize) -> Result<(), StorageError> {
+    remove_key(board_key(MOVE_KEY + axis as u8)).await
+}
+
+/// The move the axis was in the middle of when it last went down, if it was, removing it.
+pub(crate) async fn take_move(axis: usize) -> Result<Option<MoveState>, StorageError> {
+    let key = MOVE_KEY + axis as u8;
+    let Some(bytes) = fetch_board_item::<Vec<u8, { MoveState::BYTES }>>(key).await? else {
+        return Ok(None);
+    };
+    finish_move(axis).await?;
+    Ok(MoveState::from_bytes(&bytes))
+}
+
+pub(crate) async fn invert_percent(axis: usize) -> Result<Option<bool>, StorageError> {
+    fetch_item(axis, INVERT_PERCENT_KEY).await
+}
+
+pub(crate) async fn store_invert_percent(axis: usize, invert: bool) -> Result<(), StorageError> {
+    store_item(axis, INVERT_PERCENT_KEY, &invert).await
+}
+
+/// The provisioned credentials, if there are any.
+pub(crate) async fn credentials() -> Result<Option<Credentials>, StorageError> {
+    let Some(ssid) = fetch_board_item(WIFI_SSID_KEY).await? else {
+        return Ok(None);
+    };
+    Ok(Some(Credentials {
+        ssid,
+        password: fetch_board_item(WIFI_PASSWORD_KEY)
+            .await?
+            .unwrap_or_default(),
+        broker_ip: Ipv4Addr::from_bits(fetch_board_item(BROKER_IP_KEY).await?.unwrap_or(0)),
+        mqtt_username: fetch_board_item(MQTT_USERNAME_KEY)
+            .await?
+            .unwrap_or_default(),
+        mqtt_password: fetch_board_item(MQTT_PASSWORD_KEY)
+            .await?
+            .unwrap_or_default(),
+        // credentials stored before these could be provisioned keep the built in ones.
+        host_id: fetch_board_item(HOST_ID_KEY)
+            .await?
+            .unwrap_or_else(default_host_id),
+        topic_prefix: fetch_board_item(TOPIC_PREFIX_KEY)
+            .await?
+            .unwrap_or_else(default_topic_prefix),
+        stored: true,
+    }))
+}
+
+pub(crate) async fn store_credentials(credentials: &Credentials) -> Result<(), StorageError> {
+    store_credentials_connected(false).await?;
+    store_board_item(WIFI_SSID_KEY, &credentials.ssid).await?;
+    store_board_item(WIFI_PASSWORD_KEY, &credentials.password).await?;
+    store_board_item(BROKER_IP_KEY, &credentials.broker_ip.to_bits()).await?;
+    store_board_item(MQTT_USERNAME_KEY, &credentials.mqtt_username).await?;
+    store_board_item(MQTT_PASSWORD_KEY, &credentials.mqtt_password).await?;
+    store_board_item(HOST_ID_KEY, &credentials.host_id).await?;
+    store_board_item(TOPIC_PREFIX_KEY, &credentials.topic_prefix).await
+}
+
+// what is stored under `WIFI_CONNECTED_KEY`, held for rewriting after a recovery.
+static CREDENTIALS_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the credentials in use have ever connected to the wifi.
+pub(crate) async fn credentials_connected() -> Result<bool, StorageError> {
+    let connected = fetch_board_item(WIFI_CONNECTED_KEY).await? == Some(true);
+    CREDENTIALS_CONNECTED.store(connected, Ordering::Relaxed);
+    Ok(connected)
+}
+
+pub(crate) async fn store_credentials_connected(connected: bool) -> Result<(), StorageError> {
+    CREDENTIALS_CONNECTED.store(connected, Ordering::Relaxed);
+    store_board_item(WIFI_CONNECTED_KEY, &connected).await
+}
+
+/// Whether the next boot should come up in provisioning mode.
+pub(crate) async fn provisioning_requested() -> Result<bool, StorageError> {
+    Ok(fetch_board_item(PROVISION_KEY).await? == Some(true))
+}
+
+pub(crate) async fn store_provisioning_request(requested: bool) -> Result<(), StorageError> {
+    store_board_item(PROVISION_KEY, &requested).await
+}
+
+/// The settings as stored in flash, by key.
+fn settings_items(settings: &Settings) -> [(u8, u32); 11] {
+    [
+        (MAX_VEL_KEY, settings.max_vel),
+        (MAX_ACCEL_KEY, settings.max_accel),
+        (START_VEL_KEY, settings.start_vel),
+        (MICROSTEPS_KEY, settings.microsteps),
+        (RUN_CURRENT_KEY, settings.run_current.into()),
+        (INVERT_DIR_KEY, settings.invert_dir.into()),
+        (HOMING_CURRENT_KEY, settings.homing_current.into()),
+        (POSITION_TOLERANCE_KEY, settings.position_tolerance),
+        (BACKLASH_KEY, settings.backlash),
+        (INTPOL_KEY, settings.intpol.into()),
+        (
+            DRIVER_CONFIG_KEY,
+            DriverConfig::new()
+                .with_hold_current(settings.hold_current)
+                .with_spreadcycle(settings.spreadcycle)
+                // can't truncate, validated to fit.
+                .with_tilt(settings.tilt as u16)
+                .with_idle_timeout(settings.idle_timeout)
+                .into_bits(),
+        ),
+    ]
+}
+
+/// How the driver options without a key of their own are stored.
+#[bitfield(u32)]
+struct DriverConfig {
+    #[bits(8)]
+    hold_current: u8,
+    spreadcycle: bool,
+    /// fullsteps.
+    #[bits(16)]
+    tilt: u16,
+    /// seconds.
+    #[bits(7)]
+    idle_timeout: u8,
+}
+
+/// Loads an axis's settings, using the defaults for any that were never changed, or if what is
+/// stored doesn't make sense.
+pub(crate) async fn load_settings(axis: usize) -> Result<Settings, StorageError> {
+    let mut items = settings_items(&Settings::DEFAULT);
+    for (key, value) in items.iter_mut() {
+        if let Some(stored) = fetch_item::<u32>(axis, *key).await? {
+            *value = stored;
+        }
+    }
+    let [
+        max_vel,
+        max_accel,
+        start_vel,
+        microsteps,
+        run_current,
+        invert_dir,
+        homing_current,
+        position_tolerance,
+        backlash,
+        intpol,
+        driver_config,
+    ] = items.map(|(_, v)| v);
+    let driver_config = DriverConfig::from_bits(driver_config);
+    let settings = Settings {
+        max_vel,
+        max_accel,
+        start_vel,
+        microsteps,
+        run_current: run_current.try_into().unwrap_or(u8::MAX),
+        homing_current: homing_current.try_into().unwrap_or(u8::MAX),
+        hold_current: driver_config.hold_current(),
+        spreadcycle: driver_config.spreadcycle(),
+        invert_dir: invert_dir != 0,
+        position_tolerance,
+        backlash,
+        intpol: intpol != 0,
+        tilt: driver_config.tilt().into(),
+        idle_timeout: driver_config.idle_timeout(),
+    };
+    match settings.validate() {
+        Ok(()) => Ok(settings),
+        Err(e) => {
+            warn!("Stored settings are invalid, using defaults: {}", e);
+            raise_error(ErrorSeverity::Soft);
+            Ok(Settings::DEFAULT)
+        }
+    }
+}
+
+pub(crate) async fn store_settings(axis: usize, settings: &Settings) -> Result<(), StorageError> {
+    for (key, value) in settings_items(settings) {
+        store_item(axis, key, &value).await?;
+    }
+    Ok(())
+}
+
+/// Wipes the map and rewrites the settings we hold in RAM, for when it is full or corrupted.
+async fn recover(
+    map: &mut MapStorage<u8, Partition, NoCache>,
+    buffer: &mut [u8],
+) -> Result<(), StorageError> {
+    warn!("Flash is full or corrupted, erasing and rewriting settings");
+    raise_error(ErrorSeverity::Soft);
+    map.erase_all().await?;
+    for axis in 0..AXES {
+        map.store_item(
+            buffer,
+            &key(axis, TRAVEL_LIMIT_KEY),
+            &TRAVEL_LIMITS[axis].load(Ordering::Relaxed),
+        )
+        .await?;
+        map.store_item(
+            buffer,
+            &key(axis, INVERT_PERCENT_KEY),
+            &INVERT_PERCENT[axis].load(Ordering::Relaxed),
+        )
+        .await?;
+        let pos = SAVED_POSITIONS[axis].load(Ordering::Relaxed);
+        if pos != NO_POSITION {
+            map.store_item(buffer, &key(axis, POSITION_KEY), &pos)
+                .await?;
+        }
+        for (key_base, value) in settings_items(&settings(axis)) {
+            map.store_item(buffer, &key(axis, key_base), &value).await?;
+        }
+        map.store_item(
+            buffer,
+            &key(axis, SCHEDULE_KEY),
+            &schedule::schedule(axis).to_bytes(),
+        )
+        .await?;
+        if let Some(stats) = stats::stats(axis) {
+            map.store_item(buffer, &key(axis, STATS_KEY), &stats.to_bytes())
+                .await?;
+        }
+    }
+    for (i, peer) in espnow::peers().iter().enumerate() {
+        if *peer != 0 {
+            map.store_item(buffer, &board_key(ESPNOW_PEER_KEY + i as u8), peer)
+                .await?;
+        }
+    }
+    if let Some(credentials) = CREDENTIALS.try_get().filter(|c| c.stored) {
+        map.store_item(buffer, &board_key(WIFI_SSID_KEY), &credentials.ssid)
+            .await?;
+        map.store_item(buffer, &board_key(WIFI_PASSWORD_KEY), &credentials.password)
+            .await?;
+        map.store_item(
+            buffer,
+            &board_key(BROKER_IP_KEY),
+            &credentials.broker_ip.to_bits(),
+        )
+        .await?;
+        map.store_item(
+            buffer,
+            &board_key(MQTT_USERNAME_KEY),
+            &credentials.mqtt_username,
+        )
+        .await?;
+        map.store_item(
+            buffer,
+            &board_key(MQTT_PASSWORD_KEY),
+            &credentials.mqtt_password,
+        )
+        .await?;
+        map.store_item(buffer, &board_key(HOST_ID_KEY), &credentials.host_id)
+            .await?;
+        map.store_item(
+            buffer,
+            &board_key(TOPIC_PREFIX_KEY),
+            &credentials.topic_prefix,
+        )
+        .await?;
+    }
+    if CREDENTIALS_CONNECTED.load(Ordering::Relaxed) {
+        map.store_item(buffer, &board_key(WIFI_CONNECTED_KEY), &true)
+            .await?;
+    }
+    if let Some(crash) = watchdog::crash_bytes() {
+        map.store_item(buffer, &board_key(CRASH_KEY), &crash)
+            .await?;
+    }
+    let network = network_config();
+    if network != NetworkConfig::DHCP {
+        map.store_item(buffer, &board_key(NETWORK_KEY), &network.to_bytes())
+            .await?;
+    }
+    map.store_item(
+        buffer,
+        &board_key(PRESETS_KEY),
+        &presets::presets().to_bytes(),
+    )
+    .await?;
+    map.store_item(
+        buffer,
+        &board_key(QUIET_HOURS_KEY),
+        &quiet::quiet_hours_bytes(),
+    )
+    .await?;
+    map.store_item(
+        buffer,
+        &board_key(LOCKOUT_KEY),
+        &arbiter::lockout().to_bits(),
+    )
+    .await?;
+    Ok(())
+}
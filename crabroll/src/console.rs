@@ -0,0 +1,421 @@
+use core::fmt::Write as _;
+
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use esp_hal::{Async, usb_serial_jtag::UsbSerialJtag};
+use heapless::String;
+use iter_step_gen::Direction;
+
+use crate::{
+    AXES, Command, TmcRequest,
+    arbiter::Source,
+    driver_addr,
+    settings::{Settings, SettingsUpdate, set_stallguard, settings, stallguard_result},
+    state::{last_pos, send_command},
+    tmc_request,
+};
+
+const IOIN: u8 = 0x06;
+// IOIN's top byte.
+const TMC2209_VERSION: u32 = 0x21;
+const TSTEP: u8 = 0x12;
+const TMC_CLOCK_HZ: u32 = 12_000_000;
+// The lowest SG_RESULT (out of 510) a current has to keep to count as reliable. The motor stalls
+// as it nears 0, this leaves room for a stiff blind on a cold day.
+const SG_MARGIN: u32 = 100;
+// Below this the motor barely holds, there is no point testing any lower.
+const MIN_TUNED_CURRENT: u8 = 1;
+// how long a test move can take before we give up on it.
+const TEST_MOVE_TIMEOUT: Duration = Duration::from_secs(30);
+const SG_SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+// how long to wait for the motor task to pick up a settings change.
+const SETTINGS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A line based terminal on the USB serial port.
+struct Console {
+    usb: UsbSerialJtag<'static, Async>,
+    last_byte: u8,
+}
+
+impl Console {
+    // Nothing to be done about errors here, the console is the only place to report them.
+    async fn print(&mut self, text: &str) {
+        self.usb.write_all(text.as_bytes()).await.ok();
+        self.usb.flush().await.ok();
+    }
+
+    /// Reads a line, echoing it back, and dropping anything past what fits.
+    async fn read_line(&mut self) -> String<32> {
+        let mut line = String::new();
+        loop {
+            let mut byte = [0];
+            if self.usb.read(&mut byte).await.is_err() {
+                continue;
+            }
+            let [byte] = byte;
+            let last_byte = core::mem::replace(&mut self.last_byte, byte);
+            match byte {
+                // the second half of a CRLF.
+                b'\n' if last_byte == b'\r' => (),
+                b'\r' | b'\n' => {
+                    self.print("\r\n").await;
+                    return line;
+                }
+                byte if byte.is_ascii() && !byte.is_ascii_control() => {
+                    if line.push(byte as char).is_ok() {
+                        self.usb.write_all(&[byte]).await.ok();
+                        self.usb.flush().await.ok();
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    async fn ask(&mut self, question: &str) -> bool {
+        loop {
+            self.print(question).await;
+            self.print(" [y/n] ").await;
+            match self.read_line().await.trim() {
+                "y" | "Y" => return true,
+                "n" | "N" => return false,
+                _ => (),
+            }
+        }
+    }
+
+    async fn wait_for_enter(&mut self, prompt: &str) {
+        self.print(prompt).await;
+        self.read_line().await;
+    }
+}
+
+/// Walks an installer through setting up a new blind. Connect a serial terminal to the USB port
+/// and press enter to start.
+#[embassy_executor::task]
+pub(crate) async fn console_task(usb: UsbSerialJtag<'static, Async>) {
+    let mut console = Console { usb, last_byte: 0 };
+    loop {
+        // nothing is printed until someone is there, writes wait for the host to read them.
+        console.read_line().await;
+        for axis in 0..AXES {
+            let mut heading: String<64> = String::new();
+            // can't overflow, there are only a few axes.
+            write!(heading, "\r\ncrabroll setup, axis {}\r\n", axis).unwrap();
+            console.print(&heading).await;
+            if !bring_up(&mut console, axis).await {
+                console
+                    .print("Setup stopped, press enter to start again.\r\n")
+                    .await;
+                break;
+            }
+        }
+    }
+}
+
+/// Runs through each step, saving the results as they are confirmed. Returns false if a step
+/// failed.
+async fn bring_up(console: &mut Console, axis: usize) -> bool {
+    let mut text: String<192> = String::new();
+
+    console.print("1. Checking the driver link... ").await;
+    let request = TmcRequest {
+        addr: Some(driver_addr(axis)),
+        register: IOIN,
+        value: None,
+    };
+    match tmc_request(request).await.map(|ioin| ioin >> 24) {
+        Some(TMC2209_VERSION) => console.print("TMC2209 found.\r\n").await,
+        _ => {
+            text.clear();
+            write!(
+                text,
+                "no TMC2209 answering at address {}, check the UART wiring and the MS1/MS2 \
+                straps.\r\n",
+                driver_addr(axis)
+            )
+            .unwrap();
+            console.print(&text).await;
+            return false;
+        }
+    }
+
+    loop {
+        text.clear();
+        write!(
+            text,
+            "2. Run current, 0 to 31 in 32nds of full scale (now {}), enter to keep: ",
+            settings(axis).run_current
+        )
+        .unwrap();
+        console.print(&text).await;
+        let line = console.read_line().await;
+        if line.is_empty() {
+            break;
+        }
+        match line.trim().parse::<u8>() {
+            Ok(current) if current <= 31 => {
+                let update = SettingsUpdate {
+                    run_current: Some(current),
+                    ..Default::default()
+                };
+                if configure(axis, update, |s| s.run_current == current).await {
+                    break;
+                }
+                console
+                    .print("The motor task didn't take the setting.\r\n")
+                    .await;
+                return false;
+            }
+            _ => console.print("That isn't a number from 0 to 31.\r\n").await,
+        }
+    }
+
+    // homing needs the motor going the right way, so if it couldn't home at boot, watch which
+    // way it goes trying.
+    let homed = last_pos(axis).and_then(|p| p.steps).is_some();
+    let right_way = if homed {
+        console
+            .print("3. Spinning the motor away from home for a second.\r\n")
+            .await;
+        send_command(
+            axis,
+            Source::Board,
+            Command::StartJog(Direction::AwayFromHome),
+        );
+        Timer::after_secs(1).await;
+        send_command(axis, Source::Board, Command::StopJog);
+        console.ask("4. Did the blind move away from home?").await
+    } else {
+        console
+            .print("3. The blind hasn't homed yet, trying to home it now.\r\n")
+            .await;
+        send_command(axis, Source::Board, Command::Home);
+        let right_way = console.ask("4. Is the blind moving towards home?").await;
+        // homing can't be interrupted, so wait for it to reach home or give up.
+        console
+            .wait_for_enter("Press enter once it has stopped. ")
+            .await;
+        right_way
+    };
+    if !right_way {
+        if !console.ask("Did it move at all?").await {
+            console
+                .print("Check the motor wiring and the current, then start again.\r\n")
+                .await;
+            return false;
+        }
+        let invert_dir = !settings(axis).invert_dir;
+        let update = SettingsUpdate {
+            invert_dir: Some(invert_dir),
+            ..Default::default()
+        };
+        if !configure(axis, update, |s| s.invert_dir == invert_dir).await {
+            console
+                .print("The motor task didn't take the setting.\r\n")
+                .await;
+            return false;
+        }
+        console.print("Reversed the motor.\r\n").await;
+    }
+
+    console.print("5. Homing.\r\n").await;
+    send_command(axis, Source::Board, Command::Home);
+    console
+        .wait_for_enter("Press enter once the blind has stopped at home. ")
+        .await;
+    if last_pos(axis).and_then(|p| p.steps) != Some(0) {
+        console
+            .print("The blind didn't home, check the endstop and its wiring.\r\n")
+            .await;
+        return false;
+    }
+
+    console
+        .print(
+            "6. Jog the blind to the bottom: d to move down, u to move up, s to stop, and enter \
+            on its own once it is at the bottom.\r\n",
+        )
+        .await;
+    loop {
+        match console.read_line().await.trim() {
+            "d" => send_command(
+                axis,
+                Source::Board,
+                Command::StartJog(Direction::AwayFromHome),
+            ),
+            "u" => send_command(axis, Source::Board, Command::StartJog(Direction::ToHome)),
+            "s" => send_command(axis, Source::Board, Command::StopJog),
+            "" => break,
+            _ => (),
+        }
+    }
+    send_command(axis, Source::Board, Command::StopJog);
+    send_command(axis, Source::Board, Command::SetBottom);
+    // give the motor task a moment to report the new bottom.
+    Timer::after_millis(500).await;
+
+    // everything is stored in flash as it is set, so this is just checking it all took.
+    let Some(pos) = last_pos(axis).filter(|p| p.steps == Some(p.travel_limit)) else {
+        console
+            .print("7. The bottom wasn't saved, is the blind homed?\r\n")
+            .await;
+        return false;
+    };
+    text.clear();
+    write!(
+        text,
+        "7. Saved, the blind travels {} steps.\r\n",
+        pos.travel_limit
+    )
+    .unwrap();
+    console.print(&text).await;
+    console.print(&settings(axis).json()).await;
+    console.print("\r\n").await;
+
+    if console
+        .ask(
+            "8. Find the lowest reliable run current? The blind will move up and down a few times.",
+        )
+        .await
+    {
+        return tune_current(console, axis, pos.travel_limit).await;
+    }
+    true
+}
+
+/// Steps IRUN down, doing a test move at each current while watching StallGuard, then offers to
+/// save the lowest one that kept a safe margin, plus a step of headroom.
+async fn tune_current(console: &mut Console, axis: usize, travel_limit: u32) -> bool {
+    let mut text: String<96> = String::new();
+    let original = settings(axis).run_current;
+    // well clear of both ends, so neither endstop cuts a test move short.
+    let (low, high) = (travel_limit / 4, travel_limit * 3 / 4);
+    send_command(axis, Source::Board, Command::MoveToStep(low));
+    if !reached(axis, low).await {
+        console
+            .print("The blind didn't move, is it homed?\r\n")
+            .await;
+        return false;
+    }
+    set_stallguard(axis, true).await;
+
+    let mut lowest_reliable = None;
+    for current in (MIN_TUNED_CURRENT..=original).rev() {
+        let update = SettingsUpdate {
+            run_current: Some(current),
+            ..Default::default()
+        };
+        if !configure(axis, update, |s| s.run_current == current).await {
+            break;
+        }
+        let margin = match test_move(axis, high).await {
+            Some(up) => match test_move(axis, low).await {
+                Some(down) => up.min(down),
+                None => 0,
+            },
+            None => 0,
+        };
+        text.clear();
+        write!(text, "   run current {}: margin {}\r\n", current, margin).unwrap();
+        console.print(&text).await;
+        if margin < SG_MARGIN {
+            break;
+        }
+        lowest_reliable = Some(current);
+    }
+    set_stallguard(axis, false).await;
+
+    let recommended = match lowest_reliable {
+        Some(current) => (current + 1).min(original),
+        None => original,
+    };
+    text.clear();
+    write!(
+        text,
+        "Recommended run current: {} (was {}).\r\n",
+        recommended, original
+    )
+    .unwrap();
+    console.print(&text).await;
+    let current = if recommended != original && console.ask("Save it?").await {
+        recommended
+    } else {
+        original
+    };
+    let update = SettingsUpdate {
+        run_current: Some(current),
+        ..Default::default()
+    };
+    if !configure(axis, update, |s| s.run_current == current).await {
+        console
+            .print("The motor task didn't take the setting.\r\n")
+            .await;
+        return false;
+    }
+    true
+}
+
+/// Moves to `target`, returning the lowest SG_RESULT seen while at speed, or None if the move
+/// didn't finish or never got up to speed.
+async fn test_move(axis: usize, target: u32) -> Option<u32> {
+    // only half of cruising speed and up is looked at, StallGuard reads low while ramping.
+    // TSTEP counts clocks between 256th microsteps, whatever the microstepping.
+    let max_tstep = TMC_CLOCK_HZ / (settings(axis).max_vel / 2 * 256).max(1);
+    send_command(axis, Source::Board, Command::MoveToStep(target));
+    let deadline = Instant::now() + TEST_MOVE_TIMEOUT;
+    let mut lowest = u32::MAX;
+    while last_pos(axis).and_then(|p| p.steps) != Some(target) {
+        if Instant::now() > deadline {
+            return None;
+        }
+        if let (Some(tstep), Some(sg)) = (
+            read_register(axis, TSTEP).await,
+            stallguard_result(axis).await,
+        ) && tstep <= max_tstep
+        {
+            lowest = lowest.min(sg);
+        }
+        Timer::after(SG_SAMPLE_INTERVAL).await;
+    }
+    // a move that never got up to speed tells us nothing.
+    (lowest != u32::MAX).then_some(lowest)
+}
+
+/// Waits for the axis to report being at `target`.
+async fn reached(axis: usize, target: u32) -> bool {
+    let deadline = Instant::now() + TEST_MOVE_TIMEOUT;
+    while Instant::now() < deadline {
+        if last_pos(axis).and_then(|p| p.steps) == Some(target) {
+            return true;
+        }
+        Timer::after_millis(50).await;
+    }
+    false
+}
+
+async fn read_register(axis: usize, register: u8) -> Option<u32> {
+    tmc_request(TmcRequest {
+        addr: Some(driver_addr(axis)),
+        register,
+        value: None,
+    })
+    .await
+}
+
+/// Sends a settings change and waits for the motor task to apply it.
+async fn configure(
+    axis: usize,
+    update: SettingsUpdate,
+    applied: impl Fn(Settings) -> bool,
+) -> bool {
+    send_command(axis, Source::Board, Command::Configure(update));
+    let deadline = Instant::now() + SETTINGS_TIMEOUT;
+    while Instant::now() < deadline {
+        if applied(settings(axis)) {
+            return true;
+        }
+        Timer::after_millis(50).await;
+    }
+    false
+}
@@ -0,0 +1,113 @@
+use defmt::Format;
+
+// Set `low_battery_mv` in the board file (or LOW_BATTERY_MV when building) to slow the motor down
+// when a battery supply (reported on the battery_voltage topic) drops below it. Battery derating is
+// off without it.
+const LOW_BATTERY_MV: Option<u32> = match option_env!("LOW_BATTERY_MV") {
+    Some(mv) => Some(parse_threshold(mv)),
+    None => None,
+};
+// Set `hot_board_c` in the board file (or HOT_BOARD_C when building) to change the board
+// temperature we start slowing down at.
+const HOT_BOARD_C: u32 = match option_env!("HOT_BOARD_C") {
+    Some(c) => parse_threshold(c),
+    None => 70,
+};
+// How much further past the threshold we go down to the minimum.
+const CRITICAL_BATTERY_DROP_MV: u32 = 300;
+const CRITICAL_TEMP_RISE_C: u32 = 10;
+// How far back past a threshold a reading has to come to leave a level, so it doesn't flap.
+const BATTERY_HYSTERESIS_MV: u32 = 100;
+const TEMP_HYSTERESIS_C: u32 = 3;
+
+const fn parse_threshold(threshold: &str) -> u32 {
+    match u32::from_str_radix(threshold, 10) {
+        Ok(t) => t,
+        Err(_) => panic!("derating thresholds must be whole numbers"),
+    }
+}
+
+/// How much the motor is slowed down to spare a struggling supply or a hot board.
+#[derive(Format, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Derating {
+    None,
+    Reduced,
+    /// Just enough to keep the blind moving.
+    Minimal,
+}
+
+impl Derating {
+    /// The percentage of the configured speed and acceleration to run at.
+    pub(crate) fn percent(&self) -> u32 {
+        match self {
+            Self::None => 100,
+            Self::Reduced => 50,
+            Self::Minimal => 25,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Reduced => "reduced",
+            Self::Minimal => "minimal",
+        }
+    }
+}
+
+/// Picks a derating level from the supply voltage and the board temperature, whichever is worse.
+pub(crate) struct DeratingPolicy {
+    level: Derating,
+}
+
+impl DeratingPolicy {
+    pub(crate) const fn new() -> Self {
+        Self {
+            level: Derating::None,
+        }
+    }
+
+    /// Either reading can be None if we don't have it, in which case it doesn't count.
+    pub(crate) fn update(&mut self, battery_mv: Option<u32>, temp_c: Option<i32>) -> Derating {
+        let battery = match (battery_mv, LOW_BATTERY_MV) {
+            // flipped, so that higher readings are worse like the temperature.
+            (Some(mv), Some(low)) => self.level_for(
+                -(mv as i32),
+                -(low as i32),
+                -(low.saturating_sub(CRITICAL_BATTERY_DROP_MV) as i32),
+                BATTERY_HYSTERESIS_MV as i32,
+            ),
+            _ => Derating::None,
+        };
+        let temp = match temp_c {
+            Some(c) => self.level_for(
+                c,
+                HOT_BOARD_C as i32,
+                (HOT_BOARD_C + CRITICAL_TEMP_RISE_C) as i32,
+                TEMP_HYSTERESIS_C as i32,
+            ),
+            None => Derating::None,
+        };
+        self.level = battery.max(temp);
+        self.level
+    }
+
+    /// The level a reading calls for, higher readings being worse. Leaving a level we are at
+    /// takes coming `hysteresis` back past its threshold.
+    fn level_for(&self, reading: i32, reduced: i32, minimal: i32, hysteresis: i32) -> Derating {
+        let threshold = |threshold: i32, level| {
+            if self.level >= level {
+                threshold - hysteresis
+            } else {
+                threshold
+            }
+        };
+        if reading > threshold(minimal, Derating::Minimal) {
+            Derating::Minimal
+        } else if reading > threshold(reduced, Derating::Reduced) {
+            Derating::Reduced
+        } else {
+            Derating::None
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicI32, AtomicU32, Ordering},
+};
+
+use embassy_net::Stack;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, format};
+
+use crate::Connectivity;
+
+// Set DIAGNOSTICS_INTERVAL_SECS when building to publish the diagnostics more or less often.
+const INTERVAL: Duration = match option_env!("DIAGNOSTICS_INTERVAL_SECS") {
+    Some(secs) => match u64::from_str_radix(secs, 10) {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => panic!("DIAGNOSTICS_INTERVAL_SECS must be a whole number above 0"),
+    },
+    None => Duration::from_secs(5 * 60),
+};
+// what RSSI says until the wifi task has read it.
+const NO_RSSI: i32 = i32::MIN;
+
+// dBm, sampled by the wifi task while connected.
+static RSSI: AtomicI32 = AtomicI32::new(NO_RSSI);
+// counted by `set_connectivity()`.
+static WIFI_CONNECTS: AtomicU32 = AtomicU32::new(0);
+static MQTT_CONNECTS: AtomicU32 = AtomicU32::new(0);
+// sent by the diagnostics task, and by MQTT on connecting, only received by MQTT.
+static DIAGNOSTICS: Signal<CriticalSectionRawMutex, Diagnostics> = Signal::new();
+
+/// How the board and its connection are doing, for the `diagnostics` topic.
+pub(crate) struct Diagnostics {
+    rssi: Option<i32>,
+    address: Option<Ipv4Addr>,
+    uptime_secs: u64,
+    free_heap: usize,
+    wifi_reconnects: u32,
+    mqtt_reconnects: u32,
+}
+
+impl Diagnostics {
+    fn now(stack: Stack<'static>) -> Self {
+        let rssi = RSSI.load(Ordering::Relaxed);
+        // the first connection isn't a reconnect.
+        let reconnects = |connects: &AtomicU32| connects.load(Ordering::Relaxed).saturating_sub(1);
+        Self {
+            rssi: (rssi != NO_RSSI).then_some(rssi),
+            address: stack.config_v4().map(|c| c.address.address()),
+            uptime_secs: Instant::now().as_secs(),
+            free_heap: esp_alloc::HEAP.free(),
+            wifi_reconnects: reconnects(&WIFI_CONNECTS),
+            mqtt_reconnects: reconnects(&MQTT_CONNECTS),
+        }
+    }
+
+    pub(crate) fn json(&self) -> String<224> {
+        let rssi = self.rssi.map(|r| format!(12; "{}", r).unwrap());
+        let address = self.address.map(|a| format!(18; "\"{}\"", a).unwrap());
+        // can't overflow, every field has a bounded length.
+        format!(
+            224;
+            "{{\"rssi\":{},\"ip\":{},\"uptime_secs\":{},\"free_heap\":{},\
+            \"wifi_reconnects\":{},\"mqtt_reconnects\":{},\"version\":\"{}\"}}",
+            rssi.as_deref().unwrap_or("null"),
+            address.as_deref().unwrap_or("null"),
+            self.uptime_secs,
+            self.free_heap,
+            self.wifi_reconnects,
+            self.mqtt_reconnects,
+            env!("CARGO_PKG_VERSION"),
+        )
+        .unwrap()
+    }
+}
+
+/// For the wifi task, whenever it reads the signal strength.
+pub(crate) fn set_rssi(rssi: i32) {
+    RSSI.store(rssi, Ordering::Relaxed);
+}
+
+/// For `set_connectivity()` only, counting every time the wifi or MQTT comes up.
+pub(crate) fn connectivity_changed(old: Connectivity, new: Connectivity) {
+    match (old, new) {
+        (Connectivity::Offline, Connectivity::Wifi) => &WIFI_CONNECTS,
+        (_, Connectivity::Mqtt) => &MQTT_CONNECTS,
+        _ => return,
+    }
+    .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Publishes the diagnostics straight away, for MQTT on connecting.
+pub(crate) fn refresh_diagnostics(stack: Stack<'static>) {
+    DIAGNOSTICS.signal(Diagnostics::now(stack));
+}
+
+pub(crate) async fn next_diagnostics() -> Diagnostics {
+    DIAGNOSTICS.wait().await
+}
+
+/// Publishes the diagnostics every so often.
+#[embassy_executor::task]
+pub(crate) async fn diagnostics_task(stack: Stack<'static>) {
+    loop {
+        Timer::after(INTERVAL).await;
+        refresh_diagnostics(stack);
+    }
+}
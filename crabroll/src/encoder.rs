@@ -0,0 +1,110 @@
+use core::cell::Cell;
+
+use defmt::error;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Timer};
+use esp_hal::{Async, i2c::master::I2c};
+
+use crate::board;
+
+// The AS5600's fixed I2C address, and its unfiltered 12 bit angle register.
+const AS5600_ADDR: u8 = 0x36;
+const RAW_ANGLE: u8 = 0x0c;
+const COUNTS_PER_REV: i32 = 4096;
+// Often enough that the shaft can't turn half a revolution in between, even at full speed.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// Set `motor_fullsteps_per_rev` in the board file (or MOTOR_FULLSTEPS_PER_REV when building) for
+// motors that aren't 1.8 degrees a step.
+const FULLSTEPS_PER_REV: i32 = match option_env!("MOTOR_FULLSTEPS_PER_REV") {
+    Some(steps) => match i32::from_str_radix(steps, 10) {
+        Ok(steps) if steps > 0 => steps,
+        _ => panic!("MOTOR_FULLSTEPS_PER_REV must be a whole number above 0"),
+    },
+    None => 200,
+};
+// Set `encoder_drift_limit` in the board file (or ENCODER_DRIFT_LIMIT when building) to change how
+// many fullsteps the encoder can disagree by before we call them skipped.
+pub(crate) const DRIFT_LIMIT: u32 = match option_env!("ENCODER_DRIFT_LIMIT") {
+    Some(steps) => match u32::from_str_radix(steps, 10) {
+        Ok(steps) => steps,
+        _ => panic!("ENCODER_DRIFT_LIMIT must be a whole number"),
+    },
+    None => 8,
+};
+// Set `rehome` in the board file's encoder section to rehome before the next move after skipped
+// steps, rather than just reporting them.
+pub(crate) const REHOME_ON_DRIFT: bool = board::ENCODER_REHOME;
+// Set `reversed` there if the encoder counts down as the blind moves away from home.
+const REVERSED: bool = board::ENCODER_REVERSED;
+// The encoder is on the first axis's motor.
+const ENCODER_AXIS: usize = 0;
+
+// Counts the shaft has turned since the encoder was first read, None until it has been, or after
+// a failed read loses track.
+static COUNTS: Mutex<CriticalSectionRawMutex, Cell<Option<i32>>> = Mutex::new(Cell::new(None));
+// What COUNTS would be at step 0, None until the axis's position is known.
+static ZERO: Mutex<CriticalSectionRawMutex, Cell<Option<i32>>> = Mutex::new(Cell::new(None));
+
+/// Tells the encoder where the axis is, after homing or restoring a position. Skipped steps are
+/// measured from here. Does nothing for axes without an encoder.
+pub(crate) fn set_position(axis: usize, steps: u32, microsteps: u32) {
+    let Some(counts) = COUNTS.lock(|c| c.get()).filter(|_| axis == ENCODER_AXIS) else {
+        return;
+    };
+    let expected = i64::from(steps) * i64::from(COUNTS_PER_REV)
+        / (i64::from(FULLSTEPS_PER_REV) * i64::from(microsteps));
+    ZERO.lock(|z| z.set(Some(counts.wrapping_sub(expected as i32))));
+}
+
+/// How many fullsteps the encoder says the axis is from `steps`, None if it has no encoder, or it
+/// hasn't been told the position since it last lost track.
+pub(crate) fn drift(axis: usize, steps: u32, microsteps: u32) -> Option<u32> {
+    if axis != ENCODER_AXIS {
+        return None;
+    }
+    let counts = COUNTS
+        .lock(|c| c.get())?
+        .wrapping_sub(ZERO.lock(|z| z.get())?);
+    // in 256ths of a fullstep, so the rounding doesn't eat a small drift.
+    let measured =
+        i64::from(counts) * i64::from(FULLSTEPS_PER_REV) * 256 / i64::from(COUNTS_PER_REV);
+    let expected = i64::from(steps) * 256 / i64::from(microsteps);
+    u32::try_from(measured.abs_diff(expected) / 256).ok()
+}
+
+async fn read_angle(i2c: &mut I2c<'static, Async>) -> Result<i32, esp_hal::i2c::master::Error> {
+    let mut angle = [0; 2];
+    i2c.write_read_async(AS5600_ADDR, &[RAW_ANGLE], &mut angle)
+        .await?;
+    Ok(i32::from(u16::from_be_bytes(angle) & 0x0fff))
+}
+
+/// Follows the shaft around, turning the encoder's angle into a count that carries on past one
+/// revolution.
+#[embassy_executor::task]
+pub(crate) async fn encoder_task(mut i2c: I2c<'static, Async>) {
+    let mut last = None;
+    let mut counts: i32 = 0;
+    loop {
+        match read_angle(&mut i2c).await {
+            Ok(angle) => {
+                if let Some(last) = last {
+                    // whichever way round is shorter.
+                    let delta = (angle - last + COUNTS_PER_REV / 2).rem_euclid(COUNTS_PER_REV)
+                        - COUNTS_PER_REV / 2;
+                    counts = counts.wrapping_add(if REVERSED { -delta } else { delta });
+                }
+                last = Some(angle);
+                COUNTS.lock(|c| c.set(Some(counts)));
+            }
+            Err(e) => {
+                error!("Error reading encoder: {}", e);
+                // the shaft may have turned any amount since, so start over.
+                last = None;
+                COUNTS.lock(|c| c.set(None));
+                ZERO.lock(|z| z.set(None));
+            }
+        }
+        Timer::after(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,451 @@
+use defmt::{error, info, warn};
+use embassy_futures::select::{Either, select, select_array};
+use embassy_net::{
+    Stack,
+    tcp::{Error, TcpSocket},
+};
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use heapless::{String, Vec, format};
+
+use crate::{
+    AXES, Command, Permille, Position,
+    arbiter::Source,
+    mdns::HOSTNAME,
+    state::{PositionReceiver, last_pos, send_command, watch_pos},
+};
+
+pub(crate) const ESPHOME_PORT: u16 = 6053;
+// Home Assistant pings every 20 seconds or so, anything quiet for this long has gone.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(60);
+const API_VERSION_MAJOR: u32 = 1;
+const API_VERSION_MINOR: u32 = 10;
+// bigger than anything Home Assistant sends us, a frame that doesn't fit drops the connection.
+const FRAME_LEN: usize = 256;
+
+// the message types we use, from ESPHome's api.proto.
+const HELLO_REQUEST: u32 = 1;
+const HELLO_RESPONSE: u32 = 2;
+const CONNECT_REQUEST: u32 = 3;
+const CONNECT_RESPONSE: u32 = 4;
+const DISCONNECT_REQUEST: u32 = 5;
+const DISCONNECT_RESPONSE: u32 = 6;
+const PING_REQUEST: u32 = 7;
+const PING_RESPONSE: u32 = 8;
+const DEVICE_INFO_REQUEST: u32 = 9;
+const DEVICE_INFO_RESPONSE: u32 = 10;
+const LIST_ENTITIES_REQUEST: u32 = 11;
+const LIST_ENTITIES_COVER_RESPONSE: u32 = 13;
+const LIST_ENTITIES_DONE_RESPONSE: u32 = 19;
+const SUBSCRIBE_STATES_REQUEST: u32 = 20;
+const COVER_STATE_RESPONSE: u32 = 22;
+const COVER_COMMAND_REQUEST: u32 = 30;
+
+// protobuf wire types.
+const VARINT: u32 = 0;
+const FIXED64: u32 = 1;
+const LEN: u32 = 2;
+const FIXED32: u32 = 5;
+
+// CoverOperation.
+const IDLE: u32 = 0;
+const OPENING: u32 = 1;
+const CLOSING: u32 = 2;
+// LegacyCoverState and LegacyCoverCommand.
+const LEGACY_OPEN: u64 = 0;
+const LEGACY_CLOSE: u64 = 1;
+
+/// Serves ESPHome's native API on its usual port, so Home Assistant can drive every axis as a
+/// cover without an MQTT broker. One client at a time, unencrypted and without a password.
+#[embassy_executor::task]
+pub(crate) async fn esphome_task(stack: Stack<'static>, mac: [u8; 6]) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut positions: [PositionReceiver; AXES] = core::array::from_fn(watch_pos);
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(SOCKET_TIMEOUT));
+        if let Err(e) = socket.accept(ESPHOME_PORT).await {
+            error!("Error accepting ESPHome API connection: {:?}", e);
+            continue;
+        }
+        info!("ESPHome API client connected");
+        if let Err(e) = serve(&mut socket, mac, &mut positions).await {
+            error!("Error serving ESPHome API client: {:?}", e);
+        }
+        socket.close();
+        if let Err(e) = socket.flush().await {
+            error!("Error closing ESPHome API connection: {:?}", e);
+        }
+    }
+}
+
+/// What one client has asked for.
+struct Client {
+    subscribed: bool,
+    // where each axis was last reported to be, to tell which way it's going.
+    last: [Option<(Permille, u32)>; AXES],
+}
+
+async fn serve(
+    socket: &mut TcpSocket<'_>,
+    mac: [u8; 6],
+    positions: &mut [PositionReceiver; AXES],
+) -> Result<(), Error> {
+    let mut client = Client {
+        subscribed: false,
+        last: [None; AXES],
+    };
+    let mut buffer = [0; FRAME_LEN];
+    let mut filled = 0;
+    loop {
+        let changed = select_array(positions.each_mut().map(|p| p.changed()));
+        let event = select(socket.read(&mut buffer[filled..]), changed).await;
+        match event {
+            Either::First(read) => {
+                let read = read?;
+                if read == 0 {
+                    return Ok(());
+                }
+                filled += read;
+                // every whole frame that has arrived, keeping the start of the next.
+                loop {
+                    let (ty, payload, len) = match frame(&buffer[..filled]) {
+                        Frame::Whole { ty, payload, len } => (ty, payload, len),
+                        Frame::Partial if filled < FRAME_LEN => break,
+                        Frame::Partial => {
+                            warn!("ESPHome API frame too big");
+                            return Ok(());
+                        }
+                        Frame::Bad => {
+                            warn!("Not an ESPHome API frame, only plaintext is supported");
+                            return Ok(());
+                        }
+                    };
+                    if !handle(socket, &mut client, mac, ty, payload).await? {
+                        return Ok(());
+                    }
+                    buffer.copy_within(len..filled, 0);
+                    filled -= len;
+                }
+            }
+            Either::Second((pos, axis)) if client.subscribed => {
+                send_state(socket, &mut client, axis, &pos).await?;
+            }
+            Either::Second(_) => (),
+        }
+    }
+}
+
+/// Answers one message, false once the client is done.
+async fn handle(
+    socket: &mut TcpSocket<'_>,
+    client: &mut Client,
+    mac: [u8; 6],
+    ty: u32,
+    payload: &[u8],
+) -> Result<bool, Error> {
+    match ty {
+        HELLO_REQUEST => {
+            let mut m = Message::new();
+            m.uint(1, API_VERSION_MAJOR);
+            m.uint(2, API_VERSION_MINOR);
+            m.string(3, concat!("crabroll ", env!("CARGO_PKG_VERSION")));
+            m.string(4, HOSTNAME);
+            send(socket, HELLO_RESPONSE, m).await?;
+        }
+        // there's no password, so it is always right.
+        CONNECT_REQUEST => send(socket, CONNECT_RESPONSE, Message::new()).await?,
+        DISCONNECT_REQUEST => {
+            send(socket, DISCONNECT_RESPONSE, Message::new()).await?;
+            return Ok(false);
+        }
+        DISCONNECT_RESPONSE => return Ok(false),
+        PING_REQUEST => send(socket, PING_RESPONSE, Message::new()).await?,
+        DEVICE_INFO_REQUEST => {
+            let [a, b, c, d, e, f] = mac;
+            // can't overflow, it is always 17 characters.
+            let mac: String<17> = format!(
+                17;
+                "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+                a, b, c, d, e, f
+            )
+            .unwrap();
+            let mut m = Message::new();
+            m.string(2, HOSTNAME);
+            m.string(3, &mac);
+            m.string(4, env!("CARGO_PKG_VERSION"));
+            m.string(6, "crabroll");
+            m.string(8, "gabevenberg.crabroll");
+            m.string(9, env!("CARGO_PKG_VERSION"));
+            m.string(12, "crabroll");
+            send(socket, DEVICE_INFO_RESPONSE, m).await?;
+        }
+        LIST_ENTITIES_REQUEST => {
+            for axis in 0..AXES {
+                let (object_id, name, unique_id) = entity_names(axis);
+                let mut m = Message::new();
+                m.string(1, &object_id);
+                m.fixed32(2, key(axis));
+                m.string(3, &name);
+                m.string(4, &unique_id);
+                m.bool(6, true);
+                m.string(8, "shade");
+                m.bool(12, true);
+                send(socket, LIST_ENTITIES_COVER_RESPONSE, m).await?;
+            }
+            send(socket, LIST_ENTITIES_DONE_RESPONSE, Message::new()).await?;
+        }
+        SUBSCRIBE_STATES_REQUEST => {
+            client.subscribed = true;
+            for axis in 0..AXES {
+                if let Some(pos) = last_pos(axis) {
+                    send_state(socket, client, axis, &pos).await?;
+                }
+            }
+        }
+        COVER_COMMAND_REQUEST => match cover_command(payload) {
+            Some((axis, command)) => send_command(axis, Source::Network, command),
+            None => warn!("Ignoring ESPHome cover command"),
+        },
+        // logs, services, the time and the rest we don't have.
+        _ => (),
+    }
+    Ok(true)
+}
+
+/// The entity's object id, name and unique id. The first axis keeps the plain names.
+fn entity_names(axis: usize) -> (String<16>, String<16>, String<48>) {
+    // can't overflow, there are never more than a few axes.
+    let (object_id, name) = match axis {
+        0 => ("blind".try_into().unwrap(), "Blind".try_into().unwrap()),
+        _ => (
+            format!(16; "blind_{}", axis + 1).unwrap(),
+            format!(16; "Blind {}", axis + 1).unwrap(),
+        ),
+    };
+    let unique_id = format!(48; "{}cover{}", HOSTNAME, object_id).unwrap();
+    (object_id, name, unique_id)
+}
+
+fn key(axis: usize) -> u32 {
+    axis as u32 + 1
+}
+
+async fn send_state(
+    socket: &mut TcpSocket<'_>,
+    client: &mut Client,
+    axis: usize,
+    pos: &Position,
+) -> Result<(), Error> {
+    let operation = match client.last[axis] {
+        _ if pos.velocity == 0 => IDLE,
+        Some((last, _)) if pos.permille.get() > last.get() => CLOSING,
+        Some((last, _)) if pos.permille.get() < last.get() => OPENING,
+        Some((_, operation)) => operation,
+        None => IDLE,
+    };
+    client.last[axis] = Some((pos.permille, operation));
+    // ESPHome covers are 1 open and 0 closed, and home is open.
+    let position = 1.0 - f32::from(pos.permille.get()) / 1000.0;
+    let mut m = Message::new();
+    m.fixed32(1, key(axis));
+    m.uint(2, u32::from(pos.permille == Permille::BOTTOM));
+    m.fixed32(3, position.to_bits());
+    m.uint(5, operation);
+    send(socket, COVER_STATE_RESPONSE, m).await
+}
+
+/// The axis and what to do with it, None for anything we can't do.
+fn cover_command(payload: &[u8]) -> Option<(usize, Command)> {
+    let (mut key, mut legacy, mut position) = (None, None, None);
+    let mut stop = false;
+    for (field, value) in fields(payload) {
+        match (field, value) {
+            (1, Field::Fixed32(k)) => key = Some(k),
+            // unset fields are 0, an open or a close to 0.0.
+            (2, Field::Varint(1)) => legacy = Some(legacy.unwrap_or(LEGACY_OPEN)),
+            (3, Field::Varint(command)) => legacy = Some(command),
+            (4, Field::Varint(1)) => position = Some(position.unwrap_or(0.0)),
+            (5, Field::Fixed32(p)) => position = Some(f32::from_bits(p)),
+            (8, Field::Varint(s)) => stop = s != 0,
+            _ => (),
+        }
+    }
+    let axis = usize::try_from(key?.checked_sub(1)?)
+        .ok()
+        .filter(|&a| a < AXES)?;
+    let command = match (stop, position, legacy) {
+        (true, ..) => Command::Stop,
+        (false, Some(position), _) => {
+            let open = (position.clamp(0.0, 1.0) * 1000.0 + 0.5) as u16;
+            Command::MoveToPos(Permille::new(1000 - open)?)
+        }
+        (false, None, Some(LEGACY_OPEN)) => Command::MoveToPos(Permille::HOME),
+        (false, None, Some(LEGACY_CLOSE)) => Command::MoveToPos(Permille::BOTTOM),
+        (false, None, Some(_)) => Command::Stop,
+        (false, None, None) => return None,
+    };
+    Some((axis, command))
+}
+
+enum Frame<'a> {
+    Whole {
+        ty: u32,
+        payload: &'a [u8],
+        // of the whole frame, header and all.
+        len: usize,
+    },
+    Partial,
+    Bad,
+}
+
+/// Splits the first plaintext frame off the buffer: a zero, the payload's length and the
+/// message type as varints, then the payload.
+fn frame(buffer: &[u8]) -> Frame<'_> {
+    match buffer.first() {
+        None => return Frame::Partial,
+        Some(0) => (),
+        // the noise protocol's frames start with a 1.
+        Some(_) => return Frame::Bad,
+    }
+    let mut i = 1;
+    let (Some(len), Some(ty)) = (varint(buffer, &mut i), varint(buffer, &mut i)) else {
+        return Frame::Partial;
+    };
+    let (Ok(len), Ok(ty)) = (usize::try_from(len), u32::try_from(ty)) else {
+        return Frame::Bad;
+    };
+    // the length is the client's, and could overflow.
+    let Some(end) = i.checked_add(len) else {
+        return Frame::Bad;
+    };
+    match buffer.get(i..end) {
+        Some(payload) => Frame::Whole {
+            ty,
+            payload,
+            len: end,
+        },
+        None => Frame::Partial,
+    }
+}
+
+fn varint(buffer: &[u8], i: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *buffer.get(*i)?;
+        *i += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Varint(u64),
+    Fixed32(u32),
+}
+
+/// A message's numeric fields and their numbers, ending early at anything that doesn't parse.
+/// Nothing we take is a string or a 64 bit number, so those are skipped.
+fn fields(payload: &[u8]) -> impl Iterator<Item = (u32, Field)> {
+    let mut i = 0;
+    core::iter::from_fn(move || {
+        loop {
+            let tag = u32::try_from(varint(payload, &mut i)?).ok()?;
+            let field = match tag & 7 {
+                VARINT => Field::Varint(varint(payload, &mut i)?),
+                FIXED32 => {
+                    let bytes = payload.get(i..i.checked_add(4)?)?;
+                    i += 4;
+                    Field::Fixed32(u32::from_le_bytes(bytes.try_into().ok()?))
+                }
+                // skipped, with lengths from the client checked so they can't overflow.
+                FIXED64 => {
+                    i = i.checked_add(8)?;
+                    continue;
+                }
+                LEN => {
+                    i = i.checked_add(usize::try_from(varint(payload, &mut i)?).ok()?)?;
+                    continue;
+                }
+                _ => return None,
+            };
+            return Some((tag >> 3, field));
+        }
+    })
+}
+
+/// A protobuf message being written. Proto3 leaves out fields at their defaults, and so do we.
+struct Message {
+    bytes: Vec<u8, FRAME_LEN>,
+    // set once anything didn't fit.
+    overflowed: bool,
+}
+
+impl Message {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    fn raw(&mut self, bytes: &[u8]) {
+        self.overflowed |= self.bytes.extend_from_slice(bytes).is_err();
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.raw(&[value as u8 | 0x80]);
+            value >>= 7;
+        }
+        self.raw(&[value as u8]);
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u32) {
+        if value != 0 {
+            self.tag(field, VARINT);
+            self.varint(value.into());
+        }
+    }
+
+    fn bool(&mut self, field: u32, value: bool) {
+        self.uint(field, value.into());
+    }
+
+    fn fixed32(&mut self, field: u32, value: u32) {
+        if value != 0 {
+            self.tag(field, FIXED32);
+            self.raw(&value.to_le_bytes());
+        }
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        if !value.is_empty() {
+            self.tag(field, LEN);
+            self.varint(value.len() as u64);
+            self.raw(value.as_bytes());
+        }
+    }
+}
+
+async fn send(socket: &mut TcpSocket<'_>, ty: u32, message: Message) -> Result<(), Error> {
+    if message.overflowed {
+        warn!("ESPHome API message {} didn't fit", ty);
+        return Ok(());
+    }
+    let mut header = Message::new();
+    header.raw(&[0]);
+    header.varint(message.bytes.len() as u64);
+    header.varint(ty.into());
+    socket.write_all(&header.bytes).await?;
+    socket.write_all(&message.bytes).await
+}
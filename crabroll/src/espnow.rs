@@ -0,0 +1,140 @@
+use core::cell::Cell;
+
+use defmt::{error, info, warn};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant};
+use esp_radio::esp_now::{EspNow, EspNowWifiInterface, PeerInfo};
+
+use crate::{
+    AXES, Command, Permille,
+    arbiter::Source,
+    config_store::{ESPNOW_PEER_KEY, fetch_board_item, store_board_item},
+    state::{confirm, send_local_command},
+};
+
+// How many remotes can be paired at once.
+pub(crate) const MAX_PEERS: usize = 4;
+// Remotes can only pair for this long after boot, so power cycling the blind is the pairing
+// button.
+const PAIRING_WINDOW: Duration = Duration::from_secs(120);
+
+// Every message is this, an opcode and an axis, so stray ESP-NOW traffic is ignored.
+const MAGIC: [u8; 2] = *b"CR";
+const PAIR: u8 = 0x00;
+const OPEN: u8 = 0x01;
+const CLOSE: u8 = 0x02;
+const STOP: u8 = 0x03;
+// sent back to a remote once it has been paired.
+const PAIRED: u8 = 0x80;
+
+// The paired remotes' MAC addresses, 0 for an empty slot. Only touched by the ESP-NOW task, but
+// the settings recovery needs to be able to rewrite them.
+static PEERS: Mutex<CriticalSectionRawMutex, Cell<[u64; MAX_PEERS]>> =
+    Mutex::new(Cell::new([0; MAX_PEERS]));
+
+pub(crate) fn peers() -> [u64; MAX_PEERS] {
+    PEERS.lock(|p| p.get())
+}
+
+fn to_u64(mac: [u8; 6]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes[2..].copy_from_slice(&mac);
+    u64::from_be_bytes(bytes)
+}
+
+fn to_mac(peer: u64) -> [u8; 6] {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&peer.to_be_bytes()[2..]);
+    mac
+}
+
+fn add_peer(esp_now: &mut EspNow<'static>, mac: [u8; 6]) {
+    if esp_now.peer_exists(&mac) {
+        return;
+    }
+    if let Err(e) = esp_now.add_peer(PeerInfo {
+        interface: EspNowWifiInterface::Sta,
+        peer_address: mac,
+        lmk: None,
+        channel: None,
+        encrypt: false,
+    }) {
+        error!("Error adding ESP-NOW peer: {:?}", e);
+    }
+}
+
+/// Takes commands from paired remote button pads over ESP-NOW, so they work without the wifi or
+/// MQTT broker.
+#[embassy_executor::task]
+pub(crate) async fn espnow_task(mut esp_now: EspNow<'static>) {
+    let mut peers = [0; MAX_PEERS];
+    for (i, peer) in peers.iter_mut().enumerate() {
+        match fetch_board_item::<u64>(ESPNOW_PEER_KEY + i as u8).await {
+            Ok(stored) => *peer = stored.unwrap_or(0),
+            Err(_) => error!("Error getting item in flash"),
+        }
+    }
+    PEERS.lock(|p| p.set(peers));
+    for peer in peers.into_iter().filter(|p| *p != 0) {
+        add_peer(&mut esp_now, to_mac(peer));
+    }
+    info!(
+        "ESP-NOW started, {} remotes paired",
+        peers.iter().filter(|p| **p != 0).count()
+    );
+
+    loop {
+        let received = esp_now.receive_async().await;
+        let mac = received.info.src_address;
+        let (opcode, axis) = match received.data() {
+            [m0, m1, opcode, axis] if [*m0, *m1] == MAGIC => (*opcode, usize::from(*axis)),
+            _ => continue,
+        };
+        let paired = peers.contains(&to_u64(mac));
+        if opcode == PAIR {
+            // Instants count from boot.
+            if Instant::now() > Instant::from_ticks(0) + PAIRING_WINDOW {
+                warn!("Remote {:x} tried to pair outside the pairing window", mac);
+                continue;
+            }
+            if !paired {
+                let Some(slot) = peers.iter().position(|p| *p == 0) else {
+                    warn!(
+                        "Can't pair remote {:x}, already paired with {}",
+                        mac, MAX_PEERS
+                    );
+                    continue;
+                };
+                peers[slot] = to_u64(mac);
+                PEERS.lock(|p| p.set(peers));
+                if store_board_item(ESPNOW_PEER_KEY + slot as u8, &peers[slot])
+                    .await
+                    .is_err()
+                {
+                    error!("Error storing item in flash");
+                }
+                add_peer(&mut esp_now, mac);
+                info!("Paired remote {:x}", mac);
+                confirm();
+            }
+            // answered even if already paired, in case the remote missed it the first time.
+            let reply = [MAGIC[0], MAGIC[1], PAIRED, axis as u8];
+            if let Err(e) = esp_now.send_async(&mac, &reply).await {
+                error!("Error replying to ESP-NOW remote: {:?}", e);
+            }
+            continue;
+        }
+        if !paired || axis >= AXES {
+            continue;
+        }
+        // open is always home, whatever way round the percentages are.
+        let command = match opcode {
+            OPEN => Command::MoveToPos(Permille::HOME),
+            CLOSE => Command::MoveToPos(Permille::BOTTOM),
+            STOP => Command::Stop,
+            _ => continue,
+        };
+        info!("ESP-NOW command from {:x}", mac);
+        send_local_command(axis, Source::Remote, command);
+    }
+}
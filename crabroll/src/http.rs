@@ -0,0 +1,249 @@
+use core::fmt::Write as _;
+
+use defmt::{error, info};
+use embassy_net::{
+    Stack,
+    tcp::{Error, TcpSocket},
+};
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use heapless::String;
+
+use crate::{
+    AXES, Command, Permille,
+    arbiter::Source,
+    mqtt::invert_percent,
+    settings::{SettingsUpdate, settings},
+    state::{last_pos, send_command},
+    strings::STRINGS,
+};
+
+pub(crate) const HTTP_PORT: u16 = 80;
+// drop clients that stop talking, we can only serve one at a time.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request, borrowed from the buffer it was read into.
+pub(crate) struct Request<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) path: &'a str,
+    pub(crate) query: &'a str,
+    pub(crate) body: &'a [u8],
+}
+
+impl Request<'_> {
+    fn param(&self, key: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|p| p.split_once('='))
+            .find_map(|(k, v)| (k == key).then_some(v))
+    }
+}
+
+/// Serves a small control page, so the blind can still be driven and configured from a browser
+/// while the MQTT broker is down.
+#[embassy_executor::task]
+pub(crate) async fn http_task(stack: Stack<'static>) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+    let mut request_buffer = [0; 1024];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(SOCKET_TIMEOUT));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            error!("Error accepting http connection: {:?}", e);
+            continue;
+        }
+        if let Err(e) = serve(&mut socket, &mut request_buffer).await {
+            error!("Error serving http request: {:?}", e);
+        }
+        socket.close();
+        if let Err(e) = socket.flush().await {
+            error!("Error closing http connection: {:?}", e);
+        }
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<(), Error> {
+    let Some(request) = read_request(socket, buffer).await? else {
+        return respond(socket, "400 Bad Request", "").await;
+    };
+    info!("http {} {}", request.method, request.path);
+    match (request.method, request.path) {
+        ("GET", "/") => respond(socket, "200 OK", &page()).await,
+        ("POST", "/command") => {
+            let axis = request
+                .param("axis")
+                .and_then(|a| a.parse::<usize>().ok())
+                .filter(|a| *a < AXES);
+            // open is always home, whatever way round the percentages are.
+            let command = match request.param("cmd") {
+                Some("open") => Some(Command::MoveToPos(Permille::HOME)),
+                Some("close") => Some(Command::MoveToPos(Permille::BOTTOM)),
+                Some("home") => Some(Command::Home),
+                Some("stop") => Some(Command::Stop),
+                _ => None,
+            };
+            match axis.zip(command) {
+                Some((axis, command)) => {
+                    send_command(axis, Source::Network, command);
+                    redirect(socket).await
+                }
+                None => respond(socket, "400 Bad Request", "").await,
+            }
+        }
+        ("POST", "/config") => {
+            let axis = request
+                .param("axis")
+                .and_then(|a| a.parse::<usize>().ok())
+                .filter(|a| *a < AXES);
+            match axis.zip(SettingsUpdate::parse(request.body)) {
+                Some((axis, update)) => {
+                    send_command(axis, Source::Network, Command::Configure(update));
+                    respond(socket, "204 No Content", "").await
+                }
+                None => respond(socket, "400 Bad Request", "").await,
+            }
+        }
+        _ => respond(socket, "404 Not Found", "").await,
+    }
+}
+
+/// Reads the request line, headers and body, returning None if the request doesn't fit in the
+/// buffer or doesn't make sense.
+pub(crate) async fn read_request<'a>(
+    socket: &mut TcpSocket<'_>,
+    buffer: &'a mut [u8],
+) -> Result<Option<Request<'a>>, Error> {
+    let mut len = 0;
+    let (header_end, content_length) = loop {
+        if len == buffer.len() {
+            return Ok(None);
+        }
+        let read = socket.read(&mut buffer[len..]).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        len += read;
+        let Some(end) = buffer[..len].windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let Ok(header) = str::from_utf8(&buffer[..end]) else {
+            return Ok(None);
+        };
+        let content_length = header
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        // checked, the content length is the client's and could wrap the sum around.
+        let Some(total) = end
+            .checked_add(4)
+            .and_then(|h| h.checked_add(content_length))
+            .filter(|&total| total <= buffer.len())
+        else {
+            return Ok(None);
+        };
+        if len >= total {
+            break (end, content_length);
+        }
+    };
+    let (header, rest) = buffer.split_at(header_end + 4);
+    // checked above.
+    let header = str::from_utf8(&header[..header_end]).unwrap();
+    let mut request_line = header.lines().next().unwrap_or("").split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Ok(None);
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        body: &rest[..content_length],
+    }))
+}
+
+pub(crate) async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    body: &str,
+) -> Result<(), Error> {
+    let mut head: String<128> = String::new();
+    // can't overflow, the status lines are all short.
+    write!(
+        head,
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .unwrap();
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await
+}
+
+/// Sends the browser back to the page after a form is submitted.
+async fn redirect(socket: &mut TcpSocket<'_>) -> Result<(), Error> {
+    socket
+        .write_all(
+            b"HTTP/1.1 303 See Other\r\nLocation: /\r\nContent-Length: 0\r\n\
+            Connection: close\r\n\r\n",
+        )
+        .await
+}
+
+fn page() -> String<4096> {
+    let mut page = String::new();
+    // the page is well under the buffer size, even with every axis.
+    write!(
+        page,
+        "<!DOCTYPE html><html lang={}><head><meta charset=utf-8>\
+        <meta name=viewport content=\"width=device-width\">\
+        <title>crabroll</title></head><body><h1>crabroll</h1>",
+        STRINGS.code
+    )
+    .unwrap();
+    for axis in 0..AXES {
+        write!(page, "<h2>{} {}</h2><p>", STRINGS.axis, axis).unwrap();
+        match last_pos(axis) {
+            Some(pos) => write!(
+                page,
+                "{}% ({} {} {} {})",
+                invert_percent(axis, pos.percent),
+                pos.steps.unwrap_or(0),
+                STRINGS.of,
+                pos.travel_limit,
+                STRINGS.steps
+            ),
+            None => write!(page, "{}", STRINGS.position_unknown),
+        }
+        .unwrap();
+        write!(page, "</p>").unwrap();
+        for (cmd, label) in [
+            ("open", STRINGS.open),
+            ("close", STRINGS.close),
+            ("home", STRINGS.home),
+            ("stop", STRINGS.stop),
+        ] {
+            write!(
+                page,
+                "<form method=post action=\"/command?axis={}&amp;cmd={}\" \
+                style=\"display:inline\"><button>{}</button></form> ",
+                axis, cmd, label
+            )
+            .unwrap();
+        }
+        write!(
+            page,
+            "<p><textarea id=config{axis} cols=40 rows=6>{}</textarea><br>\
+            <button onclick=\"fetch('/config?axis={axis}',{{method:'POST',\
+            body:document.getElementById('config{axis}').value}})\
+            .then(()=>setTimeout(()=>location.reload(),500))\">{}</button></p>",
+            settings(axis).json(),
+            STRINGS.save_settings
+        )
+        .unwrap();
+    }
+    write!(page, "</body></html>").unwrap();
+    page
+}
@@ -0,0 +1,137 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crabroll::led::{Inputs, Pattern, pattern};
+use embassy_futures::select::{Either, Either3, select, select3};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use esp_hal::{gpio::Output, system::software_reset};
+
+use crate::{
+    ErrorSeverity, connectivity,
+    state::{next_confirm, next_error},
+};
+
+// how often to blink the red LED while offline.
+const OFFLINE_BLINK_INTERVAL: Duration = Duration::from_secs(5);
+// how long a confirmation or a soft error lights its LED for.
+const FLASH: Duration = Duration::from_secs(1);
+const SHORT_BLINK: Duration = Duration::from_millis(100);
+const BLINK_GAP: Duration = Duration::from_millis(200);
+// how many quick red blinks come before rebooting for a hard error.
+const HARD_ERROR_BLINKS: usize = 5;
+const BOOT_BLINK: Duration = Duration::from_millis(250);
+
+// Cleared once by main, when everything has been spawned.
+static BOOTING: AtomicBool = AtomicBool::new(true);
+// How many axes are moving, see `Moving`.
+static MOVING: AtomicUsize = AtomicUsize::new(0);
+// Signaled whenever anything `inputs()` looks at changes, received only by the indicator task.
+static STATUS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The board's status, with no error or confirmation to flash.
+fn inputs() -> Inputs {
+    Inputs {
+        hard_error: false,
+        soft_error: false,
+        confirm: false,
+        booting: BOOTING.load(Ordering::Relaxed),
+        moving: MOVING.load(Ordering::Relaxed) > 0,
+        connectivity: connectivity(),
+    }
+}
+
+/// For main only, once everything is up.
+pub(crate) fn booted() {
+    BOOTING.store(false, Ordering::Relaxed);
+    STATUS_CHANGED.signal(());
+}
+
+/// For `set_connectivity()` only.
+pub(crate) fn connectivity_changed() {
+    STATUS_CHANGED.signal(());
+}
+
+/// Shows the motor moving for as long as it is held.
+pub(crate) struct Moving;
+
+impl Moving {
+    pub(crate) fn new() -> Self {
+        MOVING.fetch_add(1, Ordering::Relaxed);
+        STATUS_CHANGED.signal(());
+        Self
+    }
+}
+
+impl Drop for Moving {
+    fn drop(&mut self) {
+        MOVING.fetch_sub(1, Ordering::Relaxed);
+        STATUS_CHANGED.signal(());
+    }
+}
+
+async fn blink(led: &mut Output<'static>, on: Duration, off: Duration) {
+    led.set_high();
+    Timer::after(on).await;
+    led.set_low();
+    Timer::after(off).await;
+}
+
+/// Shows one round of a pattern, or holds it until the status changes for the steady ones.
+/// Reboots after showing a hard error.
+async fn show(pattern: Pattern, green: &mut Output<'static>, red: &mut Output<'static>) {
+    let blinks = match pattern {
+        Pattern::HardError => {
+            for _ in 0..HARD_ERROR_BLINKS {
+                blink(red, SHORT_BLINK, SHORT_BLINK).await;
+            }
+            software_reset();
+        }
+        Pattern::SoftError => return blink(red, FLASH, BLINK_GAP).await,
+        Pattern::Confirm => return blink(green, FLASH, BLINK_GAP).await,
+        Pattern::Booting => loop {
+            blink(green, BOOT_BLINK, Duration::MIN).await;
+            blink(red, BOOT_BLINK, Duration::MIN).await;
+        },
+        Pattern::Moving => {
+            green.set_high();
+            return core::future::pending().await;
+        }
+        Pattern::WifiConnecting => 1,
+        Pattern::MqttConnecting => 2,
+        Pattern::MqttConnected => return core::future::pending().await,
+    };
+    for _ in 0..blinks {
+        blink(red, SHORT_BLINK, BLINK_GAP).await;
+    }
+    Timer::after(OFFLINE_BLINK_INTERVAL).await;
+}
+
+/// Owns both LEDs, showing the board's status and flashing them for confirmations and errors
+/// from the state module. Reboots after showing a hard error.
+#[embassy_executor::task]
+pub(crate) async fn indicator_task(mut green: Output<'static>, mut red: Output<'static>) {
+    loop {
+        let status = pattern(&inputs());
+        let event = select3(
+            next_error(),
+            next_confirm(),
+            select(STATUS_CHANGED.wait(), show(status, &mut green, &mut red)),
+        )
+        .await;
+        green.set_low();
+        red.set_low();
+        let flash = match event {
+            Either3::First(severity) => Inputs {
+                hard_error: severity == ErrorSeverity::Hard,
+                soft_error: severity == ErrorSeverity::Soft,
+                ..inputs()
+            },
+            Either3::Second(()) => Inputs {
+                confirm: true,
+                ..inputs()
+            },
+            Either3::Third(Either::First(()) | Either::Second(())) => continue,
+        };
+        show(pattern(&flash), &mut green, &mut red).await;
+    }
+}
@@ -0,0 +1,162 @@
+//! Which pattern the LEDs show, picked apart from driving them so the priorities can be tested.
+
+use defmt::Format;
+
+use crate::Connectivity;
+
+/// Everything that has a say in what the LEDs show.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Format)]
+pub struct Inputs {
+    /// A hard error has come in, the board reboots once it has been shown.
+    pub hard_error: bool,
+    pub soft_error: bool,
+    pub confirm: bool,
+    /// Set until everything has been spawned.
+    pub booting: bool,
+    /// Whether any axis is moving.
+    pub moving: bool,
+    pub connectivity: Connectivity,
+}
+
+/// What the LEDs show.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Format)]
+pub enum Pattern {
+    /// Quick red blinks, then a reboot.
+    HardError,
+    /// The red LED for a second.
+    SoftError,
+    /// The green LED for a second.
+    Confirm,
+    /// Both LEDs taking turns.
+    Booting,
+    /// The green LED, steady.
+    Moving,
+    /// A short red blink every 5 seconds.
+    WifiConnecting,
+    /// Two short red blinks every 5 seconds.
+    MqttConnecting,
+    /// Both dark.
+    MqttConnected,
+}
+
+/// The first pattern that applies: a hard error, then a soft error or a confirmation, then
+/// booting, moving, and finally how connected we are.
+#[must_use]
+pub fn pattern(inputs: &Inputs) -> Pattern {
+    if inputs.hard_error {
+        Pattern::HardError
+    } else if inputs.soft_error {
+        Pattern::SoftError
+    } else if inputs.confirm {
+        Pattern::Confirm
+    } else if inputs.booting {
+        Pattern::Booting
+    } else if inputs.moving {
+        Pattern::Moving
+    } else {
+        match inputs.connectivity {
+            Connectivity::Offline => Pattern::WifiConnecting,
+            Connectivity::Wifi => Pattern::MqttConnecting,
+            Connectivity::Mqtt => Pattern::MqttConnected,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IDLE: Inputs = Inputs {
+        hard_error: false,
+        soft_error: false,
+        confirm: false,
+        booting: false,
+        moving: false,
+        connectivity: Connectivity::Mqtt,
+    };
+
+    #[test]
+    fn test_idle_shows_connectivity() {
+        assert_eq!(pattern(&IDLE), Pattern::MqttConnected);
+        let wifi = Inputs {
+            connectivity: Connectivity::Wifi,
+            ..IDLE
+        };
+        assert_eq!(pattern(&wifi), Pattern::MqttConnecting);
+        let offline = Inputs {
+            connectivity: Connectivity::Offline,
+            ..IDLE
+        };
+        assert_eq!(pattern(&offline), Pattern::WifiConnecting);
+    }
+
+    #[test]
+    fn test_moving_overrides_connectivity() {
+        let moving = Inputs {
+            moving: true,
+            connectivity: Connectivity::Offline,
+            ..IDLE
+        };
+        assert_eq!(pattern(&moving), Pattern::Moving);
+    }
+
+    #[test]
+    fn test_booting_overrides_moving() {
+        let booting = Inputs {
+            booting: true,
+            moving: true,
+            ..IDLE
+        };
+        assert_eq!(pattern(&booting), Pattern::Booting);
+    }
+
+    #[test]
+    fn test_flashes_override_status() {
+        let busy = Inputs {
+            booting: true,
+            moving: true,
+            connectivity: Connectivity::Offline,
+            ..IDLE
+        };
+        for (inputs, expected) in [
+            (
+                Inputs {
+                    confirm: true,
+                    ..busy
+                },
+                Pattern::Confirm,
+            ),
+            (
+                Inputs {
+                    soft_error: true,
+                    ..busy
+                },
+                Pattern::SoftError,
+            ),
+            (
+                Inputs {
+                    hard_error: true,
+                    ..busy
+                },
+                Pattern::HardError,
+            ),
+        ] {
+            assert_eq!(pattern(&inputs), expected);
+        }
+    }
+
+    #[test]
+    fn test_errors_override_confirm() {
+        let soft = Inputs {
+            soft_error: true,
+            confirm: true,
+            ..IDLE
+        };
+        assert_eq!(pattern(&soft), Pattern::SoftError);
+        let hard = Inputs {
+            hard_error: true,
+            ..soft
+        };
+        assert_eq!(pattern(&hard), Pattern::HardError);
+    }
+}
@@ -0,0 +1,17 @@
+//! The parts of Crabroll that don't touch the hardware, kept out of the firmware binary so they
+//! can be tested on the host, with `cargo test --lib --target` and the host's target triple.
+#![cfg_attr(not(test), no_std)]
+#![warn(clippy::all)]
+
+use defmt::Format;
+
+pub mod led;
+
+/// How connected we are, for tasks that need to behave differently when offline.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Format)]
+pub enum Connectivity {
+    Offline,
+    /// Connected to wifi, but not to the MQTT broker.
+    Wifi,
+    Mqtt,
+}
@@ -8,38 +8,116 @@
 #![warn(clippy::all)]
 #![allow(clippy::unusual_byte_groupings)]
 
+// first, so its `pin!` macro is there for everything after it.
+#[macro_use]
+mod board;
+mod arbiter;
+mod automation;
+mod backend;
+mod ble;
+mod buttons;
+mod config_store;
+mod console;
+mod derating;
+mod diagnostics;
+// only spawned on boards with an encoder, everything else just finds it missing.
+#[cfg_attr(not(encoder), allow(dead_code))]
+mod encoder;
+#[cfg(feature = "esphome_api")]
+mod esphome;
+mod espnow;
+mod http;
+mod indicator;
+mod mdns;
 mod motor;
 mod mqtt;
+mod network;
+// the sensor tasks are only spawned on boards with one, everything else finds it clear.
+#[cfg_attr(not(all(obstruction, tof)), allow(dead_code))]
+mod obstruction;
+mod power;
+mod presets;
+mod provisioning;
+mod quiet;
+mod schedule;
+mod settings;
+mod state;
+mod stats;
+mod strings;
 mod tmc2209;
+mod watchdog;
 mod wifi;
 
-use defmt::{Format, info};
+use core::{
+    cell::Cell,
+    num::NonZeroU32,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use crabroll::Connectivity;
+use defmt::{Format, error, info, warn};
 use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_net::StackResources;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, rwlock::RwLock, signal::Signal};
-use embassy_time::{Duration, Instant, Timer};
+use embassy_futures::select::{Either, select};
+use embassy_net::{Ipv4Cidr, StackResources, StaticConfigV4};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    once_lock::OnceLock,
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer, WithTimeout};
+use embedded_io_async::ErrorType;
 use esp_alloc as _;
 use esp_hal::{
+    Async,
     clock::CpuClock,
     gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull},
     interrupt::{Priority, software::SoftwareInterruptControl},
-    system::software_reset,
+    rmt::Rmt,
+    time::Rate,
     timer::systimer::SystemTimer,
+    tsens::TemperatureSensor,
     uart::{Config, Uart},
+    usb_serial_jtag::UsbSerialJtag,
 };
-use esp_radio::Controller;
+use esp_radio::{Controller, ble::controller::BleConnector};
 use esp_rtos::embassy::InterruptExecutor;
 use esp_storage::FlashStorage;
 use iter_step_gen::Direction;
-use panic_rtt_target as _;
 use static_cell::StaticCell;
-use tmc2209::Tmc2209;
+use tmc2209::{DrvStatus, Tmc2209, Tmc2209Bus, Tmc2209Handle, UartError};
 
+#[cfg(feature = "esphome_api")]
+use crate::esphome::esphome_task;
 use crate::{
+    arbiter::Source,
+    backend::{StepOutput, rmt_config},
+    ble::ble_task,
+    board::{
+        BLE, ESP_NOW, EXPERT_MODE, INTPOL, RMT_STEPS, SPREADCYCLE, STALL_RECOVERY, TEST_RIG,
+        VACTUAL_JOGS, VACTUAL_MODE,
+    },
+    buttons::{ButtonEvent, ButtonId, button_task, next_event},
+    console::console_task,
+    derating::{Derating, DeratingPolicy},
+    diagnostics::diagnostics_task,
+    espnow::espnow_task,
+    http::http_task,
+    mdns::mdns_task,
     motor::motor_task,
     mqtt::mqtt_task,
-    wifi::{connection, net_task},
+    network::load_network_config,
+    power::{SLEEP_AFTER, sleep_task},
+    presets::recall_nth_preset,
+    provisioning::{
+        AP_ADDRESS, CREDENTIALS, dhcp_task, dns_task, load_credentials, portal_task,
+        take_provisioning_request,
+    },
+    schedule::schedule_task,
+    settings::{SettingsUpdate, driver_settings, settings},
+    state::{raise_error, send_local_command},
+    stats::stats_task,
+    wifi::{access_point, connection, net_task},
 };
 
 esp_bootloader_esp_idf::esp_app_desc!();
@@ -58,119 +136,397 @@ async fn main(spawner: Spawner) {
 
     info!("Embassy initialized!");
 
+    // every axis's motor task, and so every step, runs here, preempting wifi and MQTT. Commands
+    // reach it through the critical section guarded queues in `state`, which is all the handoff
+    // needs with one core.
     static EXECUTOR: StaticCell<InterruptExecutor<2>> = StaticCell::new();
     let step_executor = InterruptExecutor::new(sw_int.software_interrupt2);
     let step_executor = EXECUTOR.init(step_executor);
     let step_spawner = step_executor.start(Priority::Priority10);
 
-    let step_pin = Output::new(peripherals.GPIO7, Level::Low, OutputConfig::default());
-    let dir_pin = Output::new(peripherals.GPIO6, Level::Low, OutputConfig::default());
+    let step_output = if RMT_STEPS {
+        let rmt = Rmt::new(peripherals.RMT, Rate::from_mhz(80))
+            .unwrap()
+            .into_async();
+        let channel = rmt
+            .channel0
+            .configure_tx(pin!(peripherals, step), rmt_config())
+            .unwrap();
+        StepOutput::Rmt(channel)
+    } else {
+        StepOutput::Pin(Output::new(
+            pin!(peripherals, step),
+            Level::Low,
+            OutputConfig::default(),
+        ))
+    };
+    let dir_pin = Output::new(pin!(peripherals, dir), Level::Low, OutputConfig::default());
     let endstop_pin = Input::new(
-        peripherals.GPIO2,
+        pin!(peripherals, endstop),
         InputConfig::default().with_pull(Pull::Up),
     );
-    let green_led_pin = Output::new(peripherals.GPIO8, Level::Low, OutputConfig::default());
-    let red_led_pin = Output::new(peripherals.GPIO9, Level::Low, OutputConfig::default());
+    let green_led_pin = Output::new(
+        pin!(peripherals, green_led),
+        Level::Low,
+        OutputConfig::default(),
+    );
+    let red_led_pin = Output::new(
+        pin!(peripherals, red_led),
+        Level::Low,
+        OutputConfig::default(),
+    );
 
     let home_button = Input::new(
-        peripherals.GPIO5,
+        pin!(peripherals, home_button),
         InputConfig::default().with_pull(Pull::Up),
     );
     let raise_button = Input::new(
-        peripherals.GPIO4,
+        pin!(peripherals, raise_button),
         InputConfig::default().with_pull(Pull::Up),
     );
     let lower_button = Input::new(
-        peripherals.GPIO3,
+        pin!(peripherals, lower_button),
         InputConfig::default().with_pull(Pull::Up),
     );
     let bottom_button = Input::new(
-        peripherals.GPIO10,
+        pin!(peripherals, bottom_button),
         InputConfig::default().with_pull(Pull::Up),
     );
 
+    // The v1 board has no spare GPIO for a bottom endstop, give one in the board file if yours does.
+    #[cfg(bottom_endstop)]
+    let bottom_endstop_pin = Some(Input::new(
+        pin!(peripherals, bottom_endstop),
+        InputConfig::default().with_pull(Pull::Up),
+    ));
+    #[cfg(not(bottom_endstop))]
+    let bottom_endstop_pin = None;
+    // DIAG goes high when StallGuard sees a stall.
+    #[cfg(diag)]
+    let diag_pin = Some(Input::new(
+        pin!(peripherals, diag),
+        InputConfig::default().with_pull(Pull::Down),
+    ));
+    #[cfg(not(diag))]
+    let diag_pin = None;
+    // EN is active low, so the driver starts enabled.
+    #[cfg(enable)]
+    let enable_pin = Some(Output::new(
+        pin!(peripherals, enable),
+        Level::Low,
+        OutputConfig::default(),
+    ));
+    #[cfg(not(enable))]
+    let enable_pin = None;
+
     let flash = FlashStorage::new(peripherals.FLASH);
+    let temp_sensor = TemperatureSensor::new(peripherals.TSENS, Default::default()).unwrap();
+    let usb_serial = UsbSerialJtag::new(peripherals.USB_DEVICE).into_async();
 
     info!("IO initalized!");
 
-    let uart = Uart::new(
-        peripherals.UART0,
-        Config::default()
-            .with_baudrate(115_200)
-            .with_parity(esp_hal::uart::Parity::None),
-    )
-    .unwrap()
-    .with_tx(peripherals.GPIO21)
-    .with_rx(peripherals.GPIO20)
-    .into_async();
+    let uart = Uart::new(peripherals.UART0, tmc_uart_config(TMC_BAUD))
+        .unwrap()
+        .with_tx(pin!(peripherals, uart_tx))
+        .with_rx(pin!(peripherals, uart_rx))
+        .into_async();
     info!("UART initalized!");
 
-    let mut tmc2209 = Tmc2209::new(uart, [true, false, false, false])
-        .await
-        .unwrap();
+    if MICROSTEPS != REQUESTED_MICROSTEPS {
+        warn!(
+            "{} microsteps would be too fast to step at full speed, falling back to fullstep",
+            REQUESTED_MICROSTEPS
+        );
+    }
+    // the drivers pick up the baud rate from each request's sync byte, but a long or noisy wire
+    // may not carry the faster one.
+    let mut drivers = Tmc2209::scan(uart).await;
+    if !drivers.present().contains(&true) && TMC_BAUD != TMC_FALLBACK_BAUD {
+        warn!(
+            "No TMC2209 answered at {} baud, falling back to {}",
+            TMC_BAUD, TMC_FALLBACK_BAUD
+        );
+        let mut uart = drivers.release();
+        // can't fail, the rate is in range.
+        uart.apply_config(&tmc_uart_config(TMC_FALLBACK_BAUD))
+            .unwrap();
+        drivers = Tmc2209::scan(uart).await;
+    }
+    let bus = Tmc2209Bus::new(drivers);
+    let tmc2209 = TMC_BUS.get_or_init(|| bus);
+    let present = tmc2209.present();
+    info!("TMC2209 addresses present: {}", present);
+    if !present.contains(&true) {
+        error!("No TMC2209 found on UART");
+        raise_error(ErrorSeverity::Hard);
+    }
 
-    // setup general config
-    tmc2209.write_register(0, 0, 0b0111000001).await.unwrap();
+    for addr in (0..present.len() as u8).filter(|a| present[*a as usize]) {
+        let driver = tmc2209.handle(addr);
+        if let Err(e) = configure_driver(&driver).await {
+            error!("Error configuring TMC2209 {}: {}", addr, e);
+            raise_error(ErrorSeverity::Hard);
+            continue;
+        }
+        // The reset flag is always set after power on, so clear it before we start monitoring.
+        match driver.read_and_clear_gstat().await {
+            Ok(gstat) if gstat.drv_err() || gstat.uv_cp() => {
+                error!("TMC2209 {} reported a fault at init: {}", addr, gstat);
+                DRIVER_FAULT.store(true, Ordering::Relaxed);
+                raise_error(ErrorSeverity::Soft);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                error!("Error reading GSTAT: {}", e);
+                raise_error(ErrorSeverity::Hard);
+            }
+        }
+    }
 
-    // set microstepping to fullstep
-    tmc2209
-        .write_register(0, 0x6c, 0b0001_1000_000000000000000110010011)
-        .await
+    for (button, id, double_press) in [
+        // double presses of the jog buttons would jog.
+        (home_button, ButtonId::Home, true),
+        (raise_button, ButtonId::Raise, false),
+        (lower_button, ButtonId::Lower, false),
+        (bottom_button, ButtonId::Bottom, true),
+    ] {
+        spawner
+            .spawn(button_task(button, id, double_press))
+            .unwrap();
+    }
+    spawner.spawn(button_handler_task()).unwrap();
+    spawner
+        .spawn(indicator::indicator_task(green_led_pin, red_led_pin))
         .unwrap();
-
-    // TODO: figure out why I need this, else we stall here.
-    // Bug in the UART code/TMC?
-    Timer::after_millis(1).await;
-
-    // set current limiting
-    tmc2209
-        .write_register(0, 0x10, 0b0000_10000_00000)
-        .await
+    spawner.spawn(driver_monitor_task(tmc2209)).unwrap();
+    spawner.spawn(derating_task(temp_sensor)).unwrap();
+    spawner.spawn(console_task(usb_serial)).unwrap();
+    spawner.spawn(stats_task()).unwrap();
+    #[cfg(encoder)]
+    {
+        let i2c = esp_hal::i2c::master::I2c::new(peripherals.I2C0, Default::default())
+            .unwrap()
+            .with_sda(pin!(peripherals, encoder_sda))
+            .with_scl(pin!(peripherals, encoder_scl))
+            .into_async();
+        spawner.spawn(encoder::encoder_task(i2c)).unwrap();
+    }
+    #[cfg(obstruction)]
+    {
+        let pin = Input::new(
+            pin!(peripherals, obstruction),
+            InputConfig::default().with_pull(Pull::Up),
+        );
+        spawner.spawn(obstruction::obstruction_task(pin)).unwrap();
+    }
+    #[cfg(tof)]
+    {
+        let i2c = esp_hal::i2c::master::I2c::new(peripherals.I2C0, Default::default())
+            .unwrap()
+            .with_sda(pin!(peripherals, tof_sda))
+            .with_scl(pin!(peripherals, tof_scl))
+            .into_async();
+        spawner.spawn(obstruction::tof_task(i2c)).unwrap();
+    }
+    config_store::init_storage(flash).await;
+    watchdog::load_crash().await;
+    presets::load_presets().await;
+    quiet::load_quiet_hours().await;
+    arbiter::load_lockout().await;
+    spawner
+        .spawn(watchdog::watchdog_task(peripherals.TIMG0))
         .unwrap();
-
-    spawner.spawn(home_button_task(home_button)).unwrap();
-    spawner.spawn(raise_button_task(raise_button)).unwrap();
-    spawner.spawn(lower_button_task(lower_button)).unwrap();
-    spawner.spawn(bottom_button_task(bottom_button)).unwrap();
-    spawner.spawn(error_led_task(red_led_pin)).unwrap();
-    spawner.spawn(confirm_led_task(green_led_pin)).unwrap();
+    // Boards with more than one motor spawn a motor_task per axis, each with its own pins, and
+    // the matching TMC2209 address strapped to the axis number.
     step_spawner
-        .spawn(motor_task(step_pin, dir_pin, endstop_pin, flash))
+        .spawn(motor_task(
+            0,
+            step_output,
+            dir_pin,
+            endstop_pin,
+            bottom_endstop_pin,
+            diag_pin,
+            enable_pin,
+        ))
         .unwrap();
 
     info!("Motor tasks spawned!");
+    indicator::booted();
+    if let Some(sleep_after) = SLEEP_AFTER {
+        spawner
+            .spawn(sleep_task(peripherals.LPWR, sleep_after))
+            .unwrap();
+    }
 
     static RADIO_CONTROLLER: StaticCell<Controller> = StaticCell::new();
     let radio_controller = RADIO_CONTROLLER.init_with(|| esp_radio::init().unwrap());
 
     let (controller, interfaces) =
         esp_radio::wifi::new(radio_controller, peripherals.WIFI, Default::default()).unwrap();
-
-    let wifi_interface = interfaces.sta;
-
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let mac = interfaces.sta.mac_address();
+    // up in provisioning mode too, for setting up from a phone.
+    if BLE {
+        let connector =
+            BleConnector::new(radio_controller, peripherals.BT, Default::default()).unwrap();
+        spawner.spawn(ble_task(connector, mac)).unwrap();
+    }
 
     let rng = esp_hal::rng::Rng::new();
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
-    static STACK_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
-    let stack_resources = STACK_RESOURCES.init_with(StackResources::<3>::new);
+    // DHCP, DNS, MQTT, HTTP, SNTP, mDNS and the ESPHome API, or the portal's DHCP, DNS and HTTP.
+    static STACK_RESOURCES: StaticCell<StackResources<7>> = StaticCell::new();
+    let stack_resources = STACK_RESOURCES.init_with(StackResources::<7>::new);
+
+    // loaded for the portal too, so it can tell whether the form changed it.
+    let network_config = load_network_config().await;
+    // checked even without credentials, so the request is cleared.
+    let provision = take_provisioning_request().await;
+    let credentials = match load_credentials().await {
+        Some(credentials) if !provision => credentials,
+        _ => {
+            warn!("Starting wifi provisioning");
+            let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(AP_ADDRESS, 24),
+                gateway: Some(AP_ADDRESS),
+                dns_servers: Default::default(),
+            });
+            let (stack, runner) = embassy_net::new(interfaces.ap, config, stack_resources, seed);
+            spawner.spawn(access_point(controller)).unwrap();
+            spawner.spawn(net_task(runner)).unwrap();
+            spawner.spawn(portal_task(stack)).unwrap();
+            spawner.spawn(dhcp_task(stack)).unwrap();
+            spawner.spawn(dns_task(stack)).unwrap();
+            return;
+        }
+    };
+    // only ever set here.
+    let _ = CREDENTIALS.init(credentials);
+    let credentials = CREDENTIALS.try_get().unwrap();
+
+    let wifi_interface = interfaces.sta;
+    if ESP_NOW {
+        spawner.spawn(espnow_task(interfaces.esp_now)).unwrap();
+    }
 
     // Init network stack
-    let (stack, runner) = embassy_net::new(wifi_interface, config, stack_resources, seed);
+    let (stack, runner) = embassy_net::new(
+        wifi_interface,
+        network_config.stack_config(),
+        stack_resources,
+        seed,
+    );
 
-    spawner.spawn(connection(controller)).unwrap();
+    spawner.spawn(connection(controller, credentials)).unwrap();
     spawner.spawn(net_task(runner)).unwrap();
-    spawner.spawn(mqtt_task(stack)).unwrap();
+    spawner.spawn(mqtt_task(stack, credentials)).unwrap();
+    spawner.spawn(http_task(stack)).unwrap();
+    spawner.spawn(schedule_task(stack)).unwrap();
+    spawner.spawn(mdns_task(stack, mac)).unwrap();
+    #[cfg(feature = "esphome_api")]
+    spawner.spawn(esphome_task(stack, mac)).unwrap();
+    spawner.spawn(diagnostics_task(stack)).unwrap();
 }
 
-#[derive(Eq, PartialEq)]
+// How many motors this board drives, see motor_task.
+const AXES: usize = 1;
+
+type DriverBus = Tmc2209Bus<CriticalSectionRawMutex, Uart<'static, Async>>;
+type Driver = Tmc2209Handle<'static, CriticalSectionRawMutex, Uart<'static, Async>>;
+type DriverError = UartError<<Uart<'static, Async> as ErrorType>::Error>;
+
+// Set `tmc_baud` in the board file (or TMC_BAUD when building) to change the baud rate the TMC2209s
+// are first tried at, before falling back to 115200.
+const TMC_BAUD: u32 = match option_env!("TMC_BAUD") {
+    Some(baud) => match u32::from_str_radix(baud, 10) {
+        Ok(baud) if baud >= 9600 && baud <= 500_000 => baud,
+        _ => panic!("TMC_BAUD must be from 9600 to 500000"),
+    },
+    None => 500_000,
+};
+const TMC_FALLBACK_BAUD: u32 = 115_200;
+
+fn tmc_uart_config(baud: u32) -> Config {
+    Config::default()
+        .with_baudrate(baud)
+        .with_parity(esp_hal::uart::Parity::None)
+}
+
+// how long to wait for the driver to answer a raw register request.
+const TMC_REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+// how often to check the TMC2209s for faults.
+const DRIVER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// how often to check whether the motor needs slowing down.
+const DERATING_POLL_INTERVAL: Duration = Duration::from_secs(10);
+// The board file's microstepping, see board.toml.
+const REQUESTED_MICROSTEPS: u32 = match board::MICROSTEPS {
+    m if m.is_power_of_two() && m <= 256 => m,
+    _ => panic!("microsteps must be a power of two up to 256"),
+};
+// the fastest we can reliably pulse the step pin, in steps per second. The RMT only needs the CPU
+// to plan the steps, not to time them.
+const MAX_STEP_RATE: u32 = if RMT_STEPS { 32768 } else { 8192 };
+// Unlike some of its siblings, the TMC2209 can't switch itself to fullstep at high speed, so if
+// the requested resolution would need steps faster than we can make them at full speed, fall
+// back to fullstep for everything.
+const MICROSTEPS: u32 = if REQUESTED_MICROSTEPS * settings::MAX_FULLSTEP_VEL > MAX_STEP_RATE {
+    1
+} else {
+    REQUESTED_MICROSTEPS
+};
+
+async fn configure_driver(driver: &Driver) -> Result<(), DriverError> {
+    let settings = driver_settings(driver.slave_address());
+    driver.set_send_delay().await?;
+    // setup general config
+    driver
+        .write_register(0, settings.gconf().into_bits())
+        .await?;
+
+    // set up the chopper, then the microstepping on top.
+    driver
+        .write_register(0x6c, settings.chopper().into_bits())
+        .await?;
+    driver
+        .set_microstepping(settings.microsteps, settings.intpol)
+        .await?;
+
+    // set current limiting
+    driver
+        .write_register(0x10, settings.current().into_bits())
+        .await
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
 enum Command {
     Home,
     StartJog(Direction),
     StopJog,
+    /// Bring any move in progress to a controlled stop.
+    Stop,
     SetBottom,
-    MoveToPos(i8),
+    /// Home, then find the bottom with the bottom endstop and save it as the travel limit.
+    Calibrate,
+    MoveToPos(Permille),
+    /// Move to a position no faster than a fraction of the max speed, like a quiet move at night.
+    MoveToPosAt(Permille, SpeedFactor),
+    /// Move to an absolute position in steps from home.
+    MoveToStep(u32),
+    /// Set whether user facing percentages are inverted (0 = closed instead of 0 = open).
+    SetInvertPercent(bool),
+    /// Change some of the axis's settings, and save them.
+    Configure(SettingsUpdate),
+    /// Save everything, power down the driver and go idle, so power can safely be cut.
+    Shutdown,
+    /// Save the position to flash now, whenever it would otherwise be saved.
+    SavePosition,
+    /// Turn a venetian blind's slats to a percentage of their tilt, 0 as lowering leaves them and
+    /// 100 as raising does, by moving the blind no further than the slats turn.
+    Tilt(u8),
+    /// Set the tilt to how far the blind is from the bottom, after lowering it all the way and
+    /// raising it just until the slats have turned.
+    SetTilt,
 }
 
 #[derive(Eq, PartialEq, Format)]
@@ -179,97 +535,356 @@ enum ErrorSeverity {
     Hard,
 }
 
-static DIR_TO_HOME: RwLock<CriticalSectionRawMutex, Level> = RwLock::new(Level::Low);
-static LAST_COMMAND: Signal<CriticalSectionRawMutex, Command> = Signal::new();
-// in percentage, if -1, current position is unknown. Should also try to replace with an atomic.
-static CURRENT_POS: Signal<CriticalSectionRawMutex, i8> = Signal::new();
-//TODO: Surely theres a way to use an atomicbool here? The main thing is we need to be able to
-//await it.
-static ERROR_SIGNAL: Signal<CriticalSectionRawMutex, ErrorSeverity> = Signal::new();
-static CONFIRM_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// A raw register access, from expert mode or a motion backend.
+#[derive(Clone, Copy, Format)]
+struct TmcRequest {
+    // None for the first driver found.
+    addr: Option<u8>,
+    register: u8,
+    // None to read the register.
+    value: Option<u32>,
+}
 
-#[embassy_executor::task]
-async fn error_led_task(mut led: Output<'static>) {
-    loop {
-        let error = ERROR_SIGNAL.wait().await;
-        led.set_high();
-        Timer::after_secs(1).await;
-        led.set_low();
-        match error {
-            ErrorSeverity::Soft => (),
-            ErrorSeverity::Hard => software_reset(),
+#[derive(Clone, Copy, Format)]
+struct Position {
+    percent: i8,
+    permille: Permille,
+    // None if the position is unknown.
+    steps: Option<u32>,
+    travel_limit: u32,
+    // in fullsteps/sec, 0 once the move is over.
+    velocity: u32,
+}
+
+/// A position as a fraction of the travel limit, in thousandths, 0 = home. Finer than a
+/// percentage for long blinds, and scaled in 64 bits so it can't overflow.
+#[derive(Clone, Copy, Eq, PartialEq, Format)]
+struct Permille(u16);
+
+impl Permille {
+    const HOME: Self = Self(0);
+    const BOTTOM: Self = Self(1000);
+
+    /// None past the bottom.
+    const fn new(permille: u16) -> Option<Self> {
+        if permille <= 1000 {
+            Some(Self(permille))
+        } else {
+            None
         }
     }
+
+    /// Clamped to 0 to 100.
+    const fn from_percent(percent: i8) -> Self {
+        let percent = if percent < 0 {
+            0
+        } else if percent > 100 {
+            100
+        } else {
+            percent
+        };
+        Self(percent as u16 * 10)
+    }
+
+    fn from_steps(steps: u32, travel_limit: u32) -> Self {
+        let permille = u64::from(steps) * 1000 / u64::from(travel_limit.max(1));
+        Self(permille.min(1000) as u16)
+    }
+
+    fn to_steps(self, travel_limit: u32) -> u32 {
+        // can't overflow, it is at most the travel limit.
+        (u64::from(self.0) * u64::from(travel_limit) / 1000) as u32
+    }
+
+    /// The other way up, for inverted percentages.
+    fn inverted(self) -> Self {
+        Self(1000 - self.0)
+    }
+
+    fn get(self) -> u16 {
+        self.0
+    }
 }
 
-#[embassy_executor::task]
-async fn confirm_led_task(mut led: Output<'static>) {
-    loop {
-        CONFIRM_SIGNAL.wait().await;
-        led.set_high();
-        Timer::after_secs(1).await;
-        led.set_low();
+/// A cap on the speed of one move, as a percentage of the axis's max speed.
+#[derive(Clone, Copy, Eq, PartialEq, Format)]
+struct SpeedFactor(u8);
+
+impl SpeedFactor {
+    const FULL: Self = Self(100);
+
+    /// None outside 1 to 100.
+    fn new(percent: u8) -> Option<Self> {
+        (1..=100).contains(&percent).then_some(Self(percent))
+    }
+
+    /// Never slows it to nothing.
+    fn apply(self, max_speed: NonZeroU32) -> NonZeroU32 {
+        // can't overflow, it only gets smaller.
+        let speed = (u64::from(max_speed.get()) * u64::from(self.0) / 100) as u32;
+        NonZeroU32::new(speed).unwrap_or(NonZeroU32::MIN)
+    }
+
+    fn get(self) -> u8 {
+        self.0
     }
 }
 
-#[embassy_executor::task]
-async fn home_button_task(mut button: Input<'static>) {
-    loop {
-        button.wait_for_low().await;
-        let start_press = Instant::now();
-        Timer::after_millis(50).await;
-        button.wait_for_high().await;
-        if start_press.elapsed() > Duration::from_secs(1) {
-            LAST_COMMAND.signal(Command::Home);
-            info!("home button long pushed");
-        } else {
-            LAST_COMMAND.signal(Command::MoveToPos(0));
-            info!("home button pushed");
+// Commands, positions and the LED flashes are passed around through `state`.
+// Set when a TMC2209 reports a fault, motion is refused until it is cleared by homing.
+static DRIVER_FAULT: AtomicBool = AtomicBool::new(false);
+// Tell the driver and MQTT tasks to wind down for a shutdown.
+static DRIVER_SHUTDOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static MQTT_SHUTDOWN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Set once the drivers have been turned off for a shutdown.
+static DRIVERS_OFF: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Whether user facing percentages are inverted, loaded from flash by the motor task.
+// Command::MoveToPos and Position are always 0 = home, this only applies at the edges.
+static INVERT_PERCENT: [AtomicBool; AXES] = [const { AtomicBool::new(false) }; AXES];
+// Kept up to date by the wifi and MQTT tasks, read with `connectivity()`.
+static CONNECTIVITY: Mutex<CriticalSectionRawMutex, Cell<Connectivity>> =
+    Mutex::new(Cell::new(Connectivity::Offline));
+// The TMC2209s' UART, shared by the driver monitor task and anything making a `tmc_request()`. Set once
+// the drivers have been found at boot.
+static TMC_BUS: OnceLock<DriverBus> = OnceLock::new();
+// the last supply voltage reported over MQTT, 0 if we haven't had one.
+static BATTERY_MV: AtomicU32 = AtomicU32::new(0);
+// Kept up to date by the derating task, read with `derating()`.
+static DERATING: Mutex<CriticalSectionRawMutex, Cell<Derating>> =
+    Mutex::new(Cell::new(Derating::None));
+// signaled with the new level whenever it changes, for MQTT to publish.
+static DERATING_CHANGED: Signal<CriticalSectionRawMutex, Derating> = Signal::new();
+// The fault flags each axis's driver last reported, None until it has been read.
+static DRIVER_STATUS: [Mutex<CriticalSectionRawMutex, Cell<Option<DrvStatus>>>; AXES] =
+    [const { Mutex::new(Cell::new(None)) }; AXES];
+// Set by each axis's motor task while its current is turned down for an overheating driver.
+static THERMAL_THROTTLED: [AtomicBool; AXES] = [const { AtomicBool::new(false) }; AXES];
+// Set by each axis's motor task when homing gives up before the endstop, motion is refused until
+// a home gets there.
+static HOMING_FAILED: [AtomicBool; AXES] = [const { AtomicBool::new(false) }; AXES];
+// signaled whenever any of them change, for MQTT to publish.
+static DRIVER_STATUS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// simulated endstops, OR'd with the real ones. Only ever set with TEST_RIG.
+static SIM_HOME_ENDSTOP: AtomicBool = AtomicBool::new(false);
+static SIM_BOTTOM_ENDSTOP: AtomicBool = AtomicBool::new(false);
+// a simulated stall, taken by the next move. Only ever set with TEST_RIG.
+static SIM_STALL: AtomicBool = AtomicBool::new(false);
+
+/// Carries out a register access on the shared bus, returning the register value afterwards, or
+/// None if it failed.
+async fn tmc_request(request: TmcRequest) -> Option<u32> {
+    let bus = TMC_BUS.get().await;
+    let present = bus.present();
+    let driver = bus.handle(
+        request
+            .addr
+            .unwrap_or(present.iter().position(|p| *p).unwrap_or(0) as u8),
+    );
+    let result = match request.value {
+        Some(value) => driver
+            .write_register(request.register, value)
+            .await
+            .map(|_| value),
+        None => driver
+            .read_register_or_shadow(request.register)
+            .with_timeout(TMC_REQUEST_TIMEOUT)
+            .await
+            .unwrap_or(Err(UartError::BusTimeout)),
+    };
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            error!("Error accessing TMC2209 register: {}", e);
+            None
         }
-        Timer::after_millis(50).await;
     }
 }
 
+// the driver for each axis is strapped to the axis number.
+fn driver_addr(axis: usize) -> u8 {
+    axis as u8
+}
+
+fn connectivity() -> Connectivity {
+    CONNECTIVITY.lock(|c| c.get())
+}
+
+fn set_connectivity(connectivity: Connectivity) {
+    CONNECTIVITY.lock(|c| {
+        let old = c.replace(connectivity);
+        if old != connectivity {
+            info!("Connectivity is now {}", connectivity);
+            indicator::connectivity_changed();
+            diagnostics::connectivity_changed(old, connectivity);
+        }
+    });
+}
+
+fn derating() -> Derating {
+    DERATING.lock(|d| d.get())
+}
+
 #[embassy_executor::task]
-async fn raise_button_task(mut button: Input<'static>) {
+async fn derating_task(temp_sensor: TemperatureSensor<'static>) {
+    let mut policy = DeratingPolicy::new();
     loop {
-        button.wait_for_low().await;
-        info!("raise button pushed");
-        LAST_COMMAND.signal(Command::StartJog(Direction::ToHome));
-        Timer::after_millis(50).await;
-        button.wait_for_high().await;
-        LAST_COMMAND.signal(Command::StopJog);
-        Timer::after_millis(50).await;
+        let battery_mv = match BATTERY_MV.load(Ordering::Relaxed) {
+            0 => None,
+            mv => Some(mv),
+        };
+        let temp_c = temp_sensor.get_temperature().to_celsius() as i32;
+        let level = policy.update(battery_mv, Some(temp_c));
+        if DERATING.lock(|d| d.replace(level)) != level {
+            warn!(
+                "Derating now {}, at {}mV and {}C",
+                level, battery_mv, temp_c
+            );
+            DERATING_CHANGED.signal(level);
+        }
+        Timer::after(DERATING_POLL_INTERVAL).await;
     }
 }
 
 #[embassy_executor::task]
-async fn lower_button_task(mut button: Input<'static>) {
+async fn driver_monitor_task(tmc2209: &'static DriverBus) {
+    let present = tmc2209.present();
+    // the fault flags last seen at each address, so only changes are reported.
+    let mut last_status: [Option<DrvStatus>; 4] = [None; 4];
     loop {
-        button.wait_for_low().await;
-        info!("lower button pushed");
-        LAST_COMMAND.signal(Command::StartJog(Direction::AwayFromHome));
-        Timer::after_millis(50).await;
-        button.wait_for_high().await;
-        LAST_COMMAND.signal(Command::StopJog);
-        Timer::after_millis(50).await;
+        match select(Timer::after(DRIVER_POLL_INTERVAL), DRIVER_SHUTDOWN.wait()).await {
+            Either::First(_) => (),
+            Either::Second(_) => {
+                for addr in (0..present.len() as u8).filter(|a| present[*a as usize]) {
+                    // TOFF=0 turns off the drivers output stage.
+                    if let Err(e) = tmc2209
+                        .handle(addr)
+                        .write_register(
+                            0x6c,
+                            driver_settings(addr).chopconf().with_toff(0).into_bits(),
+                        )
+                        .await
+                    {
+                        error!("Error de-energizing TMC2209: {}", e);
+                    }
+                }
+                info!("Driver de-energized");
+                DRIVERS_OFF.signal(());
+                return;
+            }
+        }
+        for addr in (0..present.len() as u8).filter(|a| present[*a as usize]) {
+            let driver = tmc2209.handle(addr);
+            match driver.read_and_clear_gstat().await {
+                Ok(gstat) if gstat.any() => {
+                    error!("TMC2209 {} reported fault: {}", addr, gstat);
+                    DRIVER_FAULT.store(true, Ordering::Relaxed);
+                    (0..AXES)
+                        .filter(|axis| driver_addr(*axis) == addr)
+                        .for_each(stats::count_driver_fault);
+                    raise_error(ErrorSeverity::Soft);
+                    if gstat.reset() {
+                        // all our config is gone, put it back.
+                        if let Err(e) = configure_driver(&driver).await {
+                            error!("Error reconfiguring TMC2209: {}", e);
+                            raise_error(ErrorSeverity::Hard);
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => error!("Error reading GSTAT: {}", e),
+            }
+            match driver.read_status().await {
+                Ok(status) => {
+                    let status = status.faults();
+                    let last = last_status[addr as usize].replace(status);
+                    if last.map(|s| s.into_bits()) == Some(status.into_bits()) {
+                        continue;
+                    }
+                    if status.hard_fault() && !last.is_some_and(|s| s.hard_fault()) {
+                        // GSTAT will have drv_err set too, this says why. Rebooting wouldn't
+                        // help a short or a hot driver, so latch it like any other fault.
+                        error!("TMC2209 {} shut down: {}", addr, status);
+                        DRIVER_FAULT.store(true, Ordering::Relaxed);
+                        raise_error(ErrorSeverity::Soft);
+                    } else if status.any() {
+                        warn!("TMC2209 {} status: {}", addr, status);
+                    }
+                    for axis in (0..AXES).filter(|axis| driver_addr(*axis) == addr) {
+                        DRIVER_STATUS[axis].lock(|s| s.set(Some(status)));
+                    }
+                    DRIVER_STATUS_CHANGED.signal(());
+                }
+                Err(e) => error!("Error reading DRV_STATUS: {}", e),
+            }
+        }
     }
 }
 
+// The buttons, top to bottom: open (long press to home), jog up while held, jog down while held,
+// and close (long press to set the bottom). They all drive the first axis. Open and close have a
+// button each, so there is no toggle. Holding both jog buttons for a second reverses the motor.
+// Double pressing open or close recalls the first or second preset.
 #[embassy_executor::task]
-async fn bottom_button_task(mut button: Input<'static>) {
+async fn button_handler_task() {
+    // raise, lower.
+    let mut held = [false; 2];
+    // so both long presses of one combo only reverse it once.
+    let mut reversed = false;
     loop {
-        button.wait_for_low().await;
-        let start_press = Instant::now();
-        Timer::after_millis(50).await;
-        button.wait_for_high().await;
-        if start_press.elapsed() > Duration::from_secs(1) {
-            LAST_COMMAND.signal(Command::SetBottom);
-            info!("bottom button long pushed");
-        } else {
-            LAST_COMMAND.signal(Command::MoveToPos(100));
-            info!("bottom button pushed");
+        let (button, event) = next_event().await;
+        if let Some(i) = [ButtonId::Raise, ButtonId::Lower]
+            .iter()
+            .position(|b| *b == button)
+        {
+            match event {
+                ButtonEvent::Pressed => held[i] = true,
+                ButtonEvent::Released => held[i] = false,
+                _ => (),
+            }
+            if held == [true; 2] {
+                let command = match event {
+                    // the first press already started a jog.
+                    ButtonEvent::Pressed => Command::StopJog,
+                    ButtonEvent::Long if !reversed => {
+                        reversed = true;
+                        info!("Both jog buttons held, reversing the motor");
+                        Command::Configure(SettingsUpdate {
+                            invert_dir: Some(!settings(0).invert_dir),
+                            ..Default::default()
+                        })
+                    }
+                    _ => continue,
+                };
+                send_local_command(0, Source::Button, command);
+                continue;
+            }
+            if held == [false; 2] {
+                reversed = false;
+            }
+            // don't start jogging again when one of a combo is let go first.
+            if reversed {
+                continue;
+            }
         }
-        Timer::after_millis(50).await;
+        let command = match (button, event) {
+            (ButtonId::Home, ButtonEvent::Short) => Command::MoveToPos(Permille::HOME),
+            (ButtonId::Home, ButtonEvent::Long) => Command::Home,
+            (ButtonId::Raise, ButtonEvent::Pressed) => Command::StartJog(Direction::ToHome),
+            (ButtonId::Lower, ButtonEvent::Pressed) => Command::StartJog(Direction::AwayFromHome),
+            (ButtonId::Raise | ButtonId::Lower, ButtonEvent::Released) => Command::StopJog,
+            (ButtonId::Bottom, ButtonEvent::Short) => Command::MoveToPos(Permille::BOTTOM),
+            (ButtonId::Bottom, ButtonEvent::Long) => Command::SetBottom,
+            (ButtonId::Home | ButtonId::Bottom, ButtonEvent::Double) => {
+                let n = if button == ButtonId::Home { 0 } else { 1 };
+                let Some(command) = recall_nth_preset(0, n) else {
+                    warn!("No preset {} to recall", n + 1);
+                    continue;
+                };
+                command
+            }
+            _ => continue,
+        };
+        info!("{} button {}", button, event);
+        send_local_command(0, Source::Button, command);
     }
 }
@@ -0,0 +1,315 @@
+use defmt::{error, info, warn};
+use embassy_net::{
+    IpEndpoint, Ipv4Address, Stack,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_time::{Duration, Timer};
+#[cfg(feature = "esphome_api")]
+use heapless::format;
+use heapless::{String, Vec};
+
+#[cfg(feature = "esphome_api")]
+use crate::esphome::ESPHOME_PORT;
+use crate::http::HTTP_PORT;
+
+// Set HOSTNAME when building to find the board somewhere other than crabroll.local, for more
+// than one blind on the same network. Also given to the DHCP server.
+pub(crate) const HOSTNAME: &str = match option_env!("HOSTNAME") {
+    Some(hostname) if !hostname.is_empty() && hostname.len() <= 32 => hostname,
+    Some(_) => panic!("HOSTNAME must be from 1 to 32 characters"),
+    None => "crabroll",
+};
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+// How long the answers can be cached for, as the RFC suggests for records with a hostname in.
+const TTL_SECS: u32 = 120;
+// Unsolicited announcements after getting an address, a second apart.
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// every record uncompressed, for up to three services.
+const PACKET_LEN: usize = 1024;
+// the longest name we ever decode, longer ones can't be ours.
+const NAME_LEN: usize = 96;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+// set on records only we answer for, so caches drop anything older.
+const CACHE_FLUSH: u16 = 0x8000;
+
+const SERVICES: &str = "_services._dns-sd._udp.local";
+const VERSION_TXT: &str = concat!("version=", env!("CARGO_PKG_VERSION"));
+// the ESPHome API is the only one that needs more.
+const TXT_STRINGS: usize = 2;
+
+/// A service type we offer, its port and its TXT record.
+struct Service {
+    name: &'static str,
+    port: u16,
+    txt: Vec<String<32>, TXT_STRINGS>,
+}
+
+impl Service {
+    fn new(name: &'static str, port: u16, txt: &[&str]) -> Self {
+        // can't overflow, every string is short and there are never more than TXT_STRINGS.
+        let txt = txt.iter().map(|t| String::try_from(*t).unwrap()).collect();
+        Self { name, port, txt }
+    }
+}
+
+/// Everything we serve. Home Assistant only takes an ESPHome device it finds with its MAC.
+fn services(
+    #[cfg_attr(not(feature = "esphome_api"), allow(unused_variables))] mac: [u8; 6],
+) -> Vec<Service, 3> {
+    let mut services = Vec::new();
+    // can't overflow, there are only ever 3.
+    services
+        .push(Service::new("_crabroll._tcp", HTTP_PORT, &[VERSION_TXT]))
+        .ok();
+    services
+        .push(Service::new("_http._tcp", HTTP_PORT, &["path=/"]))
+        .ok();
+    #[cfg(feature = "esphome_api")]
+    {
+        let [a, b, c, d, e, f] = mac;
+        let mac = format!(
+            16;
+            "mac={:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            a, b, c, d, e, f
+        )
+        .unwrap();
+        services
+            .push(Service::new(
+                "_esphomelib._tcp",
+                ESPHOME_PORT,
+                &[&mac, VERSION_TXT],
+            ))
+            .ok();
+    }
+    services
+}
+
+/// Answers mDNS queries for `HOSTNAME.local` and the services we offer, so the board can be
+/// found without looking through the router's DHCP leases.
+#[embassy_executor::task]
+pub(crate) async fn mdns_task(stack: Stack<'static>, mac: [u8; 6]) {
+    let services = services(mac);
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 2 * PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(MDNS_PORT).unwrap();
+    if let Err(e) = stack.join_multicast_group(MDNS_GROUP) {
+        error!("Error joining the mDNS group: {:?}", e);
+        return;
+    }
+    let group = IpEndpoint::new(MDNS_GROUP.into(), MDNS_PORT);
+    let mut packet = [0; PACKET_LEN];
+    loop {
+        stack.wait_config_up().await;
+        let Some(address) = stack.config_v4().map(|c| c.address.address()) else {
+            continue;
+        };
+        info!("Announcing {}.local at {}", HOSTNAME, address);
+        for _ in 0..ANNOUNCEMENTS {
+            if let Some(response) = response(0, address, &services)
+                && let Err(e) = socket.send_to(&response, group).await
+            {
+                error!("Error sending mDNS announcement: {:?}", e);
+            }
+            Timer::after(ANNOUNCE_INTERVAL).await;
+        }
+        loop {
+            let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                continue;
+            };
+            // a new address needs announcing again.
+            if stack.config_v4().map(|c| c.address.address()) != Some(address) {
+                break;
+            }
+            let Some(id) = asks_for_us(&packet[..len], &services) else {
+                continue;
+            };
+            // a one-shot query from a plain DNS resolver wants a plain unicast answer, with its
+            // id.
+            let (id, to) = if meta.endpoint.port == MDNS_PORT {
+                (0, group)
+            } else {
+                (id, meta.endpoint)
+            };
+            let Some(response) = response(id, address, &services) else {
+                warn!("mDNS response didn't fit");
+                continue;
+            };
+            if let Err(e) = socket.send_to(&response, to).await {
+                error!("Error sending mDNS response: {:?}", e);
+            }
+        }
+    }
+}
+
+/// The query's id if any of its questions are about us.
+fn asks_for_us(packet: &[u8], services: &[Service]) -> Option<u16> {
+    const HEADER: usize = 12;
+    // responses are other devices answering, not questions.
+    if packet.len() < HEADER || packet[2] & 0x80 != 0 {
+        return None;
+    }
+    let id = u16::from_be_bytes([packet[0], packet[1]]);
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let mut i = HEADER;
+    for _ in 0..questions {
+        let (name, end) = read_name(packet, i)?;
+        // then its type and class.
+        i = end + 4;
+        if i > packet.len() {
+            return None;
+        }
+        if is_ours(&name, services) {
+            return Some(id);
+        }
+    }
+    None
+}
+
+fn is_ours(name: &str, services: &[Service]) -> bool {
+    let matches = |parts: &[&str]| {
+        let mut rest = name;
+        parts.iter().all(|part| {
+            let matched = rest
+                .get(..part.len())
+                .is_some_and(|p| p.eq_ignore_ascii_case(part));
+            rest = rest.get(part.len()..).unwrap_or("");
+            matched
+        }) && rest.is_empty()
+    };
+    matches(&[HOSTNAME, ".local"])
+        || matches(&[SERVICES])
+        || services
+            .iter()
+            .any(|s| matches(&[s.name, ".local"]) || matches(&[HOSTNAME, ".", s.name, ".local"]))
+}
+
+/// Decodes the name at `start` as dotted labels, following compression pointers, returning it
+/// and where the name ends in the packet.
+fn read_name(packet: &[u8], start: usize) -> Option<(String<NAME_LEN>, usize)> {
+    let mut name = String::new();
+    let mut i = start;
+    let mut end = None;
+    // a loop of pointers would go round forever otherwise.
+    for _ in 0..PACKET_LEN {
+        let len = *packet.get(i)?;
+        if len == 0 {
+            return Some((name, end.unwrap_or(i + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(i + 2);
+            i = usize::from(u16::from_be_bytes([len & 0x3f, *packet.get(i + 1)?]));
+            continue;
+        }
+        let label = packet.get(i + 1..i + 1 + usize::from(len))?;
+        if !name.is_empty() {
+            name.push('.').ok()?;
+        }
+        name.push_str(core::str::from_utf8(label).ok()?).ok()?;
+        i += 1 + usize::from(len);
+    }
+    None
+}
+
+/// Every record we have, as one response: the services, where they are and our address.
+fn response(id: u16, address: Ipv4Address, services: &[Service]) -> Option<Vec<u8, PACKET_LEN>> {
+    let mut packet = Packet(Vec::new());
+    let answers = 1 + 4 * services.len() as u16;
+    // a response and authoritative, no questions.
+    packet.u16(id)?;
+    packet.bytes(&[0x84, 0, 0, 0])?;
+    packet.u16(answers)?;
+    packet.bytes(&[0, 0, 0, 0])?;
+    for &Service {
+        name: service,
+        port,
+        ref txt,
+    } in services
+    {
+        packet.record(&[SERVICES], TYPE_PTR, CLASS_IN, |p| {
+            p.name(&[service, ".local"])
+        })?;
+        packet.record(&[service, ".local"], TYPE_PTR, CLASS_IN, |p| {
+            p.name(&[HOSTNAME, ".", service, ".local"])
+        })?;
+        let instance = [HOSTNAME, ".", service, ".local"];
+        packet.record(&instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, |p| {
+            // priority and weight.
+            p.bytes(&[0, 0, 0, 0])?;
+            p.u16(port)?;
+            p.name(&[HOSTNAME, ".local"])
+        })?;
+        packet.record(&instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, |p| {
+            for txt in txt {
+                p.bytes(&[u8::try_from(txt.len()).ok()?])?;
+                p.bytes(txt.as_bytes())?;
+            }
+            Some(())
+        })?;
+    }
+    packet.record(&[HOSTNAME, ".local"], TYPE_A, CLASS_IN | CACHE_FLUSH, |p| {
+        p.bytes(&address.octets())
+    })?;
+    Some(packet.0)
+}
+
+/// Builds a DNS packet, each method giving None once it's full.
+struct Packet(Vec<u8, PACKET_LEN>);
+
+impl Packet {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.0.extend_from_slice(bytes).ok()
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes the name the parts make up when put together, uncompressed.
+    fn name(&mut self, parts: &[&str]) -> Option<()> {
+        let mut name = String::<NAME_LEN>::new();
+        for part in parts {
+            name.push_str(part).ok()?;
+        }
+        for label in name.split('.') {
+            self.bytes(&[u8::try_from(label.len()).ok()?])?;
+            self.bytes(label.as_bytes())?;
+        }
+        self.bytes(&[0])
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        ty: u16,
+        class: u16,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(ty)?;
+        self.u16(class)?;
+        self.bytes(&TTL_SECS.to_be_bytes())?;
+        // the data's length, filled in once it's written.
+        let len_at = self.0.len();
+        self.u16(0)?;
+        data(self)?;
+        let len = u16::try_from(self.0.len() - len_at - 2).ok()?;
+        self.0[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+        Some(())
+    }
+}
@@ -1,210 +1,1604 @@
-use core::{iter::FusedIterator, num::NonZeroU32};
+use core::{
+    cell::Cell,
+    cmp::max,
+    num::NonZeroU32,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
 
-use super::LAST_COMMAND;
-use crate::{CONFIRM_SIGNAL, CURRENT_POS, Command, DIR_TO_HOME, ERROR_SIGNAL, ErrorSeverity};
+use crate::{
+    AXES, Command, DRIVER_FAULT, DRIVER_SHUTDOWN, DRIVER_STATUS, DRIVER_STATUS_CHANGED,
+    ErrorSeverity, HOMING_FAILED, INVERT_PERCENT, MQTT_SHUTDOWN, Permille, Position,
+    SIM_BOTTOM_ENDSTOP, SIM_HOME_ENDSTOP, SIM_STALL, STALL_RECOVERY, SpeedFactor, TEST_RIG,
+    THERMAL_THROTTLED, VACTUAL_JOGS, VACTUAL_MODE,
+    backend::{
+        GpioStepDriver, MSCNT_CYCLE, MotionBackend, RmtStepDir, StepDir, StepOutput, Vactual,
+        coil_currents, microstep_count,
+    },
+    board,
+    config_store::{self, load_settings, recover_storage, store_settings},
+    derating, encoder,
+    indicator::Moving,
+    obstruction,
+    power::StayAwake,
+    quiet,
+    settings::{
+        Settings, SettingsUpdate, driver_current, set_driver_current, set_settings, set_stallguard,
+        settings, stallguard_result, wanted_current, write_driver_settings,
+    },
+    state::{
+        confirm, next_command, publish_calibration, publish_pos, publish_recovery, raise_error,
+        try_next_command,
+    },
+    stats::{self, count_homing, count_move, count_running, count_stall},
+    watchdog::{Watched, feed, feeding},
+};
 
-use defmt::{error, info};
-use embassy_embedded_hal::adapter::BlockingAsync;
+use defmt::{Format, error, info, warn};
+use embassy_futures::select::{Either3, select, select3};
 use embassy_time::{Duration, Instant, Timer};
-use esp_bootloader_esp_idf::partitions::{
-    self, DataPartitionSubType, PARTITION_TABLE_MAX_LEN, PartitionType,
-};
-use esp_hal::gpio::{Input, Output};
-use esp_storage::FlashStorage;
-use iter_step_gen::{Direction, Stepper, StepperError};
-use sequential_storage::{
-    cache::NoCache,
-    map::{MapConfig, MapStorage},
+use esp_hal::{
+    gpio::{Input, Output},
+    system::software_reset,
 };
+use iter_step_gen::{Direction, Stepper, StepperError, TwoPhaseHoming};
 
-const DEFAULT_TRAVEL_LIMIT: NonZeroU32 = NonZeroU32::new(2048).unwrap();
-const MAX_VEL: NonZeroU32 = NonZeroU32::new(2048).unwrap();
-const MAX_ACCEL: NonZeroU32 = NonZeroU32::new(225).unwrap();
-const START_VEL: u32 = 64;
+#[cfg(feature = "ramp_telemetry")]
+use crate::state::publish_ramp;
+
+// Everything is planned in microsteps, so these are given in fullsteps and scaled by the axis's
+// microstepping.
+const DEFAULT_TRAVEL_LIMIT: u32 = board::TRAVEL_LIMIT;
+// How far from home the endstop can be triggered before we call it drift.
+const DRIFT_MARGIN: u32 = 16;
+// How far the bottom endstop can be from the travel limit before we suspect the coupling slipped.
+const SLIP_TOLERANCE: u32 = 16;
+// How far homing backs off the endstop before coming back to it slowly.
+const HOMING_RETRACT: u32 = 16;
+// How often to check for position drift while idle.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// How long an overheating driver gets to cool before the move carries on, at most. The driver
+// monitor reads its flags every 5 seconds, so any sooner than that it can't tell us.
+const COOL_DOWN: Duration = Duration::from_secs(30);
+const COOL_DOWN_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// How often to report the position while moving, so Home Assistant can follow the blind along.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// Calibrating takes the motor stalling against the bottom as having found it once StallGuard
+// (out of 510) reads this or lower.
+const STALL_SG_RESULT: u32 = 10;
+// StallGuard reads low as the motor gets going, so it is only looked at after this.
+const STALL_SETTLE_TIME: Duration = Duration::from_millis(200);
+const STALL_SAMPLE_INTERVAL: Duration = Duration::from_millis(20);
+// DIAG is ignored for the first fullsteps of a move, as StallGuard can't judge the load until
+// the motor is up to speed.
+const DIAG_BLANKING_FULLSTEPS: u32 = 32;
+// Set `soft_limit` in the board file (or SOFT_LIMIT when building) to keep planned moves this many
+// fullsteps short of the endstop and of the travel limit.
+const SOFT_LIMIT: u32 = match option_env!("SOFT_LIMIT") {
+    Some(fullsteps) => match u32::from_str_radix(fullsteps, 10) {
+        Ok(fullsteps) => fullsteps,
+        _ => panic!("SOFT_LIMIT must be a whole number"),
+    },
+    None => 0,
+};
+// Set RAMP_DECIMATION when building to change how many steps apart the ramp telemetry samples
+// planned moves.
+#[cfg(feature = "ramp_telemetry")]
+const RAMP_DECIMATION: NonZeroU32 = match option_env!("RAMP_DECIMATION") {
+    Some(decimation) => match u32::from_str_radix(decimation, 10) {
+        Ok(decimation) if decimation > 0 => NonZeroU32::new(decimation).unwrap(),
+        _ => panic!("RAMP_DECIMATION must be a whole number above 0"),
+    },
+    None => NonZeroU32::new(8).unwrap(),
+};
 
-// storage consts
-const TRAVEL_LIMIT_KEY: u8 = 0;
+// how many axes have saved their position for a shutdown.
+static AXES_SHUT_DOWN: AtomicUsize = AtomicUsize::new(0);
+// how far each axis's slats are turned, in percent, see `tilt()`.
+static TILT: [AtomicU8; AXES] = [const { AtomicU8::new(100) }; AXES];
+// whether each axis's current was last set for the quiet hours.
+static QUIET_CURRENT: [AtomicBool; AXES] = [const { AtomicBool::new(false) }; AXES];
 
-#[embassy_executor::task]
+/// Drives one axis (one motor, with its own driver address). Spawn one per axis, after
+/// [`config_store::init_storage`].
+#[embassy_executor::task(pool_size = AXES)]
 pub(crate) async fn motor_task(
-    mut step_pin: Output<'static>,
-    mut dir_pin: Output<'static>,
+    axis: usize,
+    step_output: StepOutput,
+    dir_pin: Output<'static>,
     endstop_pin: Input<'static>,
-    mut flash: FlashStorage<'static>,
+    // optional switch at the bottom of travel, used to keep the travel limit calibrated.
+    bottom_endstop_pin: Option<Input<'static>>,
+    // optional DIAG output from the driver, for stopping moves that stall.
+    diag_pin: Option<Input<'static>>,
+    // optional EN input of the driver, for powering the motor down while idle.
+    enable_pin: Option<Output<'static>>,
 ) {
-    let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
-    let pt = partitions::read_partition_table(&mut flash, &mut pt_mem).unwrap();
-    let nvs = pt
-        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
-        .unwrap()
-        .unwrap();
-    let partition = nvs.as_embedded_storage(&mut flash);
-    let mut flash = MapStorage::<u8, _, _>::new(
-        BlockingAsync::new(partition),
-        MapConfig::new(0x0000..0x6000),
-        NoCache::new(),
-    );
+    let power = MotorPower::new(enable_pin);
+    match step_output {
+        _ if VACTUAL_MODE => {
+            run_axis(
+                axis,
+                Vactual::new(axis),
+                power,
+                endstop_pin,
+                bottom_endstop_pin,
+                diag_pin,
+            )
+            .await
+        }
+        StepOutput::Pin(step_pin) => {
+            let backend = StepDir::new(GpioStepDriver::new(step_pin, dir_pin, axis), axis);
+            run_axis(
+                axis,
+                backend,
+                power,
+                endstop_pin,
+                bottom_endstop_pin,
+                diag_pin,
+            )
+            .await
+        }
+        StepOutput::Rmt(channel) => {
+            let backend = RmtStepDir::new(channel, dir_pin, axis);
+            run_axis(
+                axis,
+                backend,
+                power,
+                endstop_pin,
+                bottom_endstop_pin,
+                diag_pin,
+            )
+            .await
+        }
+    }
+}
 
-    let mut flash_buffer = [0u8; 4096];
-    let travel_limit = match flash
-        .fetch_item::<u32>(&mut flash_buffer, &TRAVEL_LIMIT_KEY)
-        .await
-    {
-        Ok(Some(l)) => {
-            CONFIRM_SIGNAL.signal(());
+/// Powers an axis's motor up before it moves, and down again once it has sat still for the
+/// axis's [`Settings::idle_timeout`], so it doesn't heat up holding a blind that stays put.
+struct MotorPower {
+    enable_pin: Option<Output<'static>>,
+    energized: bool,
+    last_moved: Instant,
+}
+
+impl MotorPower {
+    fn new(enable_pin: Option<Output<'static>>) -> Self {
+        Self {
+            enable_pin,
+            energized: false,
+            last_moved: Instant::now(),
+        }
+    }
+
+    async fn energize(&mut self, backend: &mut impl MotionBackend) {
+        if self.energized {
+            return;
+        }
+        // EN is active low.
+        if let Some(pin) = &mut self.enable_pin {
+            pin.set_low();
+        }
+        backend.hold().await;
+        self.energized = true;
+    }
+
+    async fn de_energize(&mut self, backend: &mut impl MotionBackend) {
+        if let Some(pin) = &mut self.enable_pin {
+            pin.set_high();
+        }
+        backend.de_energize().await;
+        self.energized = false;
+    }
+
+    fn moved(&mut self) {
+        self.last_moved = Instant::now();
+    }
+
+    /// Waits for the motor to have been idle for the timeout, forever if it has none or is
+    /// already powered down.
+    async fn idle_timeout(&self, axis: usize) {
+        match settings(axis).idle_timeout() {
+            Some(timeout) if self.energized => Timer::at(self.last_moved + timeout).await,
+            _ => core::future::pending().await,
+        }
+    }
+}
+
+async fn run_axis(
+    axis: usize,
+    mut backend: impl MotionBackend,
+    mut power: MotorPower,
+    endstop_pin: Input<'static>,
+    bottom_endstop_pin: Option<Input<'static>>,
+    diag_pin: Option<Input<'static>>,
+) -> ! {
+    // the travel limit is in microsteps, so the settings have to come first.
+    let axis_settings = match load_settings(axis).await {
+        Ok(s) => s,
+        Err(_) => {
+            error!("Error getting item in flash");
+            if recover_storage().await.is_err() {
+                error!("Error recovering flash");
+                raise_error(ErrorSeverity::Hard);
+            }
+            Settings::DEFAULT
+        }
+    };
+    set_settings(axis, axis_settings);
+    stats::load(axis).await;
+    let default_travel_limit =
+        NonZeroU32::new(DEFAULT_TRAVEL_LIMIT * axis_settings.microsteps).unwrap();
+
+    // false if the stored travel limit is unusable, until the bottom is set again.
+    let mut calibrated = true;
+    let travel_limit = match config_store::travel_limit(axis).await {
+        Ok(Some(l)) if l != 0 => {
+            confirm();
             NonZeroU32::new(l).unwrap()
         }
+        Ok(Some(_)) => {
+            warn!(
+                "Axis {} has no usable travel limit stored, set the bottom before moving by percent",
+                axis
+            );
+            raise_error(ErrorSeverity::Soft);
+            calibrated = false;
+            default_travel_limit
+        }
         Ok(None) => {
-            match flash
-                .store_item(
-                    &mut flash_buffer,
-                    &TRAVEL_LIMIT_KEY,
-                    &DEFAULT_TRAVEL_LIMIT.get(),
-                )
-                .await
-            {
+            match config_store::store_travel_limit(axis, default_travel_limit.get()).await {
                 Ok(()) => {
-                    CONFIRM_SIGNAL.signal(());
+                    confirm();
                 }
                 Err(_) => {
                     error!("Error storing item in flash");
-                    ERROR_SIGNAL.signal(ErrorSeverity::Hard);
+                    raise_error(ErrorSeverity::Hard);
                 }
             };
-            DEFAULT_TRAVEL_LIMIT
+            default_travel_limit
         }
         Err(_) => {
             error!("Error getting item in flash");
-            ERROR_SIGNAL.signal(ErrorSeverity::Hard);
-            DEFAULT_TRAVEL_LIMIT
+            if recover_storage().await.is_err() {
+                error!("Error recovering flash");
+                raise_error(ErrorSeverity::Hard);
+            }
+            default_travel_limit
         }
     };
+    // a recovery writes back a zero, so the next boot still knows the limit needs setting.
+    config_store::keep_travel_limit(axis, if calibrated { travel_limit.get() } else { 0 });
 
-    let mut stepper = Stepper::new(travel_limit, MAX_VEL, MAX_ACCEL, START_VEL);
-    execute_home(&mut step_pin, &mut dir_pin, &mut stepper, &endstop_pin).await;
+    match config_store::invert_percent(axis).await {
+        Ok(invert) => INVERT_PERCENT[axis].store(invert.unwrap_or(false), Ordering::Relaxed),
+        Err(_) => {
+            error!("Error getting item in flash");
+            if recover_storage().await.is_err() {
+                error!("Error recovering flash");
+                raise_error(ErrorSeverity::Hard);
+            }
+        }
+    }
+
+    let mut stepper = Stepper::new(
+        travel_limit,
+        axis_settings.max_speed(),
+        axis_settings.max_accel(),
+        axis_settings.start_vel(),
+    );
+    stepper.set_backlash(axis_settings.backlash());
+    stepper.set_soft_limit(SOFT_LIMIT * axis_settings.microsteps);
+    #[cfg(feature = "ramp_telemetry")]
+    stepper.set_ramp_decimation(RAMP_DECIMATION);
+    // the driver was set up with the default settings, and may have been powered down by a
+    // shutdown before a soft reset.
+    write_driver_settings(axis).await;
+    power.energize(&mut backend).await;
+    // the motor stopped wherever it was when the power went, and whatever position was saved
+    // before the move is stale.
+    let interrupted = config_store::take_move(axis).await.unwrap_or_else(|_| {
+        error!("Error getting item in flash");
+        raise_error(ErrorSeverity::Hard);
+        None
+    });
+    let sent_while_homing = match config_store::take_position(axis).await {
+        Ok(Some(pos)) if interrupted.is_none() && stepper.assume_pos(pos).is_ok() => {
+            encoder::set_position(axis, pos, axis_settings.microsteps);
+            info!("Restored axis {} position {} from flash", axis, pos);
+            None
+        }
+        result => {
+            if result.is_err() {
+                error!("Error getting item in flash");
+                raise_error(ErrorSeverity::Hard);
+            }
+            execute_home(
+                axis,
+                &mut backend,
+                &mut stepper,
+                &endstop_pin,
+                bottom_endstop_pin.as_ref(),
+            )
+            .await
+            .1
+        }
+    };
+    power.moved();
+    let mut rehome_pending = false;
+    // drift found while idle, rehomed for at the next idle check outside the quiet hours.
+    let mut drift_rehome = false;
+    // where MSCNT was at the first idle check since the axis last moved, see `position_drifted`.
+    let mut idle_mscnt = None;
+    // microsteps the slats are turned from where lowering leaves them. Homing raises the blind, so
+    // without having homed that is the best guess.
+    let mut tilt = axis_settings.tilt();
+    // a command that arrived mid-move, and has to wait for the motor to stop.
+    let mut pending_command = sent_while_homing;
+    // a move held back until the quiet hours end, see `quiet::deferring`.
+    let mut deferred = None;
+    // set while going back to where a stalled move was going, see `Recovery`.
+    let mut recovering = false;
+    if let Some(state) = interrupted {
+        warn!(
+            "Axis {} went down {} steps short of {}, its position was uncertain",
+            axis, state.remaining, state.target
+        );
+        raise_error(ErrorSeverity::Soft);
+        // homing just now made it certain again, so carry on to where it was going, unless
+        // something else was sent meanwhile.
+        if stepper.pos().is_some() && pending_command.is_none() {
+            pending_command = Some(Command::MoveToStep(state.target));
+        }
+    }
     loop {
-        match LAST_COMMAND.wait().await {
+        feed(Watched::Motor(axis));
+        let event = match pending_command.take() {
+            Some(command) => Either3::First(command),
+            None => {
+                select3(
+                    next_command(axis),
+                    Timer::after(IDLE_CHECK_INTERVAL),
+                    power.idle_timeout(axis),
+                )
+                .await
+            }
+        };
+        let command = match event {
+            Either3::First(command) => command,
+            Either3::Third(()) => {
+                info!("Powering axis {} down after idling", axis);
+                power.de_energize(&mut backend).await;
+                // the driver may not keep its place in the microstep table powered down.
+                idle_mscnt = None;
+                continue;
+            }
+            Either3::Second(_) => {
+                restore_current(axis).await;
+                if config_store::position_idle(axis).await.is_err() {
+                    error!("Error storing item in flash");
+                    raise_error(ErrorSeverity::Soft);
+                }
+                if !rehome_pending
+                    && !drift_rehome
+                    && position_drifted(axis, &stepper, &endstop_pin, &mut idle_mscnt).await
+                {
+                    warn!("Position drift detected, will rehome outside the quiet hours");
+                    drift_rehome = true;
+                    raise_error(ErrorSeverity::Soft);
+                }
+                if deferred.is_some() && !quiet::deferring() {
+                    info!("Quiet hours are over, making axis {}'s deferred move", axis);
+                    pending_command = deferred.take();
+                }
+                // not while a fault is latched, homing clears that, which is for someone to do.
+                if drift_rehome
+                    && quiet::active().is_none()
+                    && !DRIVER_FAULT.load(Ordering::Relaxed)
+                    && !HOMING_FAILED[axis].load(Ordering::Relaxed)
+                {
+                    drift_rehome = false;
+                    if pending_command.is_some() {
+                        // before the deferred move, rather than throwing it away.
+                        rehome_pending = true;
+                    } else {
+                        info!("rehoming axis {} to correct drift", axis);
+                        pending_command = Some(Command::Home);
+                    }
+                }
+                continue;
+            }
+        };
+        let _awake = StayAwake::new();
+        // whatever it is may step the motor, so MSCNT is read afresh at the next idle check.
+        idle_mscnt = None;
+        let homing_failed = HOMING_FAILED[axis].load(Ordering::Relaxed);
+        if (DRIVER_FAULT.load(Ordering::Relaxed) || homing_failed)
+            && !matches!(
+                command,
+                Command::Home
+                    | Command::StopJog
+                    | Command::Stop
+                    | Command::Shutdown
+                    | Command::Configure(_)
+                    | Command::SavePosition
+            )
+        {
+            if homing_failed {
+                info!("Refusing to move until a home finds the endstop");
+            } else {
+                info!("Refusing to move while a driver fault is latched, home to clear");
+            }
+            raise_error(ErrorSeverity::Soft);
+            continue;
+        }
+        if matches!(command, Command::MoveToPos(_) | Command::MoveToPosAt(..)) && quiet::deferring()
+        {
+            info!("Deferring axis {}'s move until the quiet hours end", axis);
+            deferred = Some(command);
+            continue;
+        }
+        // stopping or homing calls off a deferred move too.
+        if matches!(command, Command::Stop | Command::Home) {
+            deferred = None;
+        }
+        let moving = matches!(
+            command,
+            Command::MoveToStep(_)
+                | Command::MoveToPos(_)
+                | Command::MoveToPosAt(..)
+                | Command::StartJog(_)
+                | Command::Calibrate
+        );
+        if moving || command == Command::Home {
+            power.energize(&mut backend).await;
+        }
+        if rehome_pending
+            && matches!(
+                command,
+                Command::MoveToPos(_) | Command::MoveToPosAt(..) | Command::MoveToStep(_)
+            )
+        {
+            info!("rehoming to correct drift");
+            let (homed, next) = execute_home(
+                axis,
+                &mut backend,
+                &mut stepper,
+                &endstop_pin,
+                bottom_endstop_pin.as_ref(),
+            )
+            .await;
+            rehome_pending = false;
+            // anything sent meanwhile goes after the move, like it would have mid-move.
+            pending_command = next;
+            // a failed home has already said so, and a stopped one was asked for.
+            if !homed {
+                continue;
+            }
+        }
+        apply_derating(axis, &mut stepper);
+        restore_current(axis).await;
+        let _moving = (moving || command == Command::Home).then(Moving::new);
+        let start_pos = stepper.pos();
+        let started = Instant::now();
+        match command {
             Command::Home => {
                 info!("homing");
-                execute_home(&mut step_pin, &mut dir_pin, &mut stepper, &endstop_pin).await;
-                CONFIRM_SIGNAL.signal(());
-                info!("homed");
+                rehome_pending = false;
+                drift_rehome = false;
+                DRIVER_FAULT.store(false, Ordering::Relaxed);
+                let (homed, next) = execute_home(
+                    axis,
+                    &mut backend,
+                    &mut stepper,
+                    &endstop_pin,
+                    bottom_endstop_pin.as_ref(),
+                )
+                .await;
+                pending_command = next;
+                if homed {
+                    confirm();
+                    info!("homed");
+                }
             }
             Command::StartJog(direction) => {
                 info!("jogging in {} direction", direction);
-                match execute_jog(&mut step_pin, &mut dir_pin, &mut stepper, direction).await {
-                    Ok(_) => info!("jogged"),
+                let jogged = if VACTUAL_JOGS && !VACTUAL_MODE {
+                    execute_vactual_jog(axis, &mut stepper, bottom_endstop_pin.as_ref(), direction)
+                        .await
+                } else {
+                    execute_jog(
+                        axis,
+                        &mut backend,
+                        &mut stepper,
+                        bottom_endstop_pin.as_ref(),
+                        direction,
+                    )
+                    .await
+                };
+                match jogged {
+                    Ok(next) => {
+                        info!("jogged");
+                        pending_command = next;
+                    }
                     Err(e) => {
                         info!("Error: {}", e);
-                        ERROR_SIGNAL.signal(ErrorSeverity::Soft);
+                        raise_error(ErrorSeverity::Soft);
                     }
                 };
             }
-            Command::StopJog => (),
+            // anything in progress has already been stopped.
+            Command::StopJog | Command::Stop => (),
+            // the position can't be saved if it isn't known.
+            Command::SavePosition if stepper.pos().is_none() => raise_error(ErrorSeverity::Soft),
+            Command::SavePosition => match config_store::flush_position(axis).await {
+                Ok(()) => confirm(),
+                Err(_) => {
+                    error!("Error storing item in flash");
+                    raise_error(ErrorSeverity::Soft);
+                }
+            },
             Command::SetBottom => {
                 if let Some(pos) = stepper.pos() {
                     info!("Setting current position as bottom");
-                    let pos = NonZeroU32::new(pos).unwrap_or(NonZeroU32::MIN);
-                    stepper.set_travel_limit(pos);
-                    match flash
-                        .store_item(&mut flash_buffer, &TRAVEL_LIMIT_KEY, &pos.get())
-                        .await
-                    {
-                        Ok(()) => CONFIRM_SIGNAL.signal(()),
+                    set_bottom(axis, &mut stepper, pos).await;
+                    calibrated = true;
+                } else {
+                    info!("Attempted to set travel limit while unhomed");
+                    raise_error(ErrorSeverity::Soft);
+                }
+            }
+            Command::Calibrate => {
+                info!("calibrating");
+                rehome_pending = false;
+                drift_rehome = false;
+                let (calibrated_to, next) = execute_calibrate(
+                    axis,
+                    &mut backend,
+                    &mut stepper,
+                    &endstop_pin,
+                    bottom_endstop_pin.as_ref(),
+                )
+                .await;
+                pending_command = next;
+                match calibrated_to {
+                    Some((pos, found)) => {
+                        info!("Found the bottom at {} by {}", pos, found);
+                        set_bottom(axis, &mut stepper, pos).await;
+                        calibrated = true;
+                        publish_calibration(Calibration {
+                            axis,
+                            travel: pos,
+                            fullsteps: pos / settings(axis).microsteps,
+                            found,
+                        });
+                    }
+                    None => raise_error(ErrorSeverity::Soft),
+                }
+            }
+            Command::SetInvertPercent(invert) => {
+                info!("Setting percent inversion to {}", invert);
+                INVERT_PERCENT[axis].store(invert, Ordering::Relaxed);
+                match config_store::store_invert_percent(axis, invert).await {
+                    Ok(()) => confirm(),
+                    Err(_) => {
+                        error!("Error storing item in flash");
+                        raise_error(ErrorSeverity::Hard);
+                    }
+                };
+            }
+            Command::Configure(update) => {
+                let old = settings(axis);
+                match old.update(update) {
+                    // the retained config comes back every time we reconnect.
+                    Ok(new) if new == old => (),
+                    Ok(new) => {
+                        info!("Applying settings {}", new);
+                        let mut stored = Ok(());
+                        if new.microsteps != old.microsteps {
+                            // keep the travel limit and position in the same place on the blind.
+                            let rescale = |steps| new.rescale(&old, steps);
+                            let limit = NonZeroU32::new(rescale(stepper.travel_limit().get()))
+                                .unwrap_or(NonZeroU32::MIN);
+                            stepper.set_travel_limit(limit);
+                            if let Some(pos) = stepper.pos()
+                                && stepper.assume_pos(rescale(pos)).is_err()
+                            {
+                                rehome_pending = true;
+                            }
+                            tilt = rescale(tilt);
+                            // a stand in limit is no more right for being rescaled.
+                            if calibrated {
+                                stored = config_store::store_travel_limit(axis, limit.get()).await;
+                            }
+                        }
+                        // home is now the other way, so the position is counted from the wrong
+                        // end.
+                        if new.invert_dir != old.invert_dir && stepper.pos().is_some() {
+                            rehome_pending = true;
+                        }
+                        stepper.set_max_speed(new.max_speed());
+                        stepper.set_max_accel(new.max_accel());
+                        stepper.set_start_vel(new.start_vel());
+                        stepper.set_backlash(new.backlash());
+                        stepper.set_soft_limit(SOFT_LIMIT * new.microsteps);
+                        // a new tilt is usually set from the end of it, see `Command::SetTilt`.
+                        if new.tilt != old.tilt {
+                            tilt = new.tilt();
+                        }
+                        set_settings(axis, new);
+                        write_driver_settings(axis).await;
+                        // which switches a powered down driver back on.
+                        if !power.energized {
+                            power.de_energize(&mut backend).await;
+                        }
+                        match stored.and(store_settings(axis, &new).await) {
+                            Ok(()) => confirm(),
+                            Err(_) => {
+                                error!("Error storing item in flash");
+                                raise_error(ErrorSeverity::Hard);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Rejected settings: {}", e);
+                        raise_error(ErrorSeverity::Soft);
+                    }
+                }
+            }
+            Command::Shutdown => {
+                info!("Shutting down");
+                power.de_energize(&mut backend).await;
+                stats::save(axis).await;
+                if stepper.pos().is_some() {
+                    match config_store::flush_position(axis).await {
+                        Ok(()) => confirm(),
                         Err(_) => {
                             error!("Error storing item in flash");
-                            ERROR_SIGNAL.signal(ErrorSeverity::Hard);
+                            raise_error(ErrorSeverity::Soft);
                         }
                     };
-                } else {
-                    info!("Attempted to set travel limit while unhomed");
-                    ERROR_SIGNAL.signal(ErrorSeverity::Soft);
                 }
+                // the last axis to save its position powers everything down.
+                if AXES_SHUT_DOWN.fetch_add(1, Ordering::Relaxed) + 1 == AXES {
+                    DRIVER_SHUTDOWN.signal(());
+                    MQTT_SHUTDOWN.signal(());
+                }
+                // Nothing left to do but wait for the power to be cut. If we get poked again
+                // instead, start back up as if we had been power cycled.
+                feeding(Watched::Motor(axis), next_command(axis)).await;
+                software_reset();
+            }
+            Command::MoveToStep(pos) => {
+                info!("moving to step {}", pos);
+                match execute_move(
+                    axis,
+                    &mut backend,
+                    &mut stepper,
+                    bottom_endstop_pin.as_ref(),
+                    diag_pin.as_ref(),
+                    pos,
+                    SpeedFactor::FULL,
+                )
+                .await
+                {
+                    Ok(next) => {
+                        info!("moved to pos");
+                        // the latest command wins, over one sent while rehoming.
+                        if next.is_some() {
+                            pending_command = next;
+                        }
+                    }
+                    Err(e) => {
+                        info!("Error: {}", e);
+                        raise_error(ErrorSeverity::Soft);
+                    }
+                };
+            }
+            Command::Tilt(_) if settings(axis).tilt == 0 => {
+                info!("Axis {} has no tilt set", axis);
+                raise_error(ErrorSeverity::Soft);
+            }
+            Command::Tilt(percent) => match stepper.pos() {
+                Some(pos) => {
+                    let target = settings(axis).tilt() * u32::from(percent.min(100)) / 100;
+                    info!("tilting to {}%", percent);
+                    // raising turns the slats towards 100, lowering back towards 0.
+                    let pos = if target > tilt {
+                        pos.saturating_sub(target - tilt)
+                    } else {
+                        (pos + (tilt - target)).min(stepper.travel_limit().get())
+                    };
+                    pending_command = Some(Command::MoveToStep(pos));
+                }
+                None => {
+                    info!("Attempted to tilt while unhomed");
+                    raise_error(ErrorSeverity::Soft);
+                }
+            },
+            Command::SetTilt => match stepper.pos() {
+                Some(pos) if calibrated => {
+                    let fullsteps = (stepper.travel_limit().get().saturating_sub(pos))
+                        / settings(axis).microsteps;
+                    info!("Setting the tilt to {} fullsteps", fullsteps);
+                    pending_command = Some(Command::Configure(SettingsUpdate {
+                        tilt: Some(fullsteps),
+                        ..Default::default()
+                    }));
+                }
+                _ => {
+                    info!("Attempted to set the tilt without a bottom to measure it from");
+                    raise_error(ErrorSeverity::Soft);
+                }
+            },
+            Command::MoveToPos(_) | Command::MoveToPosAt(..) if !calibrated => {
+                info!("Refusing to move by percent until the bottom has been set");
+                raise_error(ErrorSeverity::Soft);
             }
-            Command::MoveToPos(percent) => {
-                info!("moving to {}", percent);
-                let pos = (percent as u32 * stepper.travel_limit().get()) / 100_u32;
+            Command::MoveToPos(permille) | Command::MoveToPosAt(permille, _) => {
+                let speed = match command {
+                    Command::MoveToPosAt(_, speed) => speed,
+                    _ => SpeedFactor::FULL,
+                };
+                info!(
+                    "moving to {} permille at {}% speed",
+                    permille.get(),
+                    speed.get()
+                );
+                let pos = permille.to_steps(stepper.travel_limit().get());
                 info!("moving to {}", pos);
-                match execute_move(&mut step_pin, &mut dir_pin, &mut stepper, pos).await {
-                    Ok(_) => info!("moved to pos"),
+                match execute_move(
+                    axis,
+                    &mut backend,
+                    &mut stepper,
+                    bottom_endstop_pin.as_ref(),
+                    diag_pin.as_ref(),
+                    pos,
+                    speed,
+                )
+                .await
+                {
+                    Ok(next) => {
+                        info!("moved to pos");
+                        // the latest command wins, over one sent while rehoming.
+                        if next.is_some() {
+                            pending_command = next;
+                        }
+                    }
                     Err(e) => {
                         info!("Error: {}", e);
-                        ERROR_SIGNAL.signal(ErrorSeverity::Soft);
+                        raise_error(ErrorSeverity::Soft);
                     }
                 };
             }
         }
-        CURRENT_POS.signal(if let Some(p) = stepper.pos() {
-            ((p * 100_u32) / stepper.travel_limit())
+        // a stall loses the position, so get it back before the next move.
+        let stalled = moving && start_pos.is_some() && stepper.pos().is_none();
+        if stalled {
+            rehome_pending = true;
+        }
+        let target = match command {
+            Command::MoveToStep(pos) => Some(pos),
+            Command::MoveToPos(permille) | Command::MoveToPosAt(permille, _) => {
+                Some(permille.to_steps(stepper.travel_limit().get()))
+            }
+            _ => None,
+        };
+        // only once, a blind that stalls on the way back too is properly stuck.
+        if stalled && recovering {
+            publish_recovery(axis, Recovery::Failed);
+            recovering = false;
+        } else if stalled
+            && STALL_RECOVERY
+            && let Some(target) = target
+        {
+            info!("Recovering axis {} from the stall, homing", axis);
+            publish_recovery(axis, Recovery::Homing);
+            rehome_pending = false;
+            let (homed, next) = execute_home(
+                axis,
+                &mut backend,
+                &mut stepper,
+                &endstop_pin,
+                bottom_endstop_pin.as_ref(),
+            )
+            .await;
+            if next.is_some() {
+                pending_command = next;
+            }
+            if homed {
+                info!("Homed, going back to {}", target);
+                publish_recovery(axis, Recovery::Returning);
+                // anything sent while the move was stalling wins.
+                if pending_command.is_none() {
+                    pending_command = Some(Command::MoveToStep(target));
+                }
+                recovering = true;
+            } else {
+                publish_recovery(axis, Recovery::Failed);
+            }
+        } else if recovering && moving {
+            publish_recovery(axis, Recovery::Recovered);
+            recovering = false;
+        }
+        if moving
+            && let (Some(start), Some(end)) = (start_pos, stepper.pos())
+            && start != end
+        {
+            let dir = if end > start {
+                Direction::AwayFromHome
+            } else {
+                Direction::ToHome
+            };
+            count_move(axis, start.abs_diff(end) / settings(axis).microsteps, dir);
+        }
+        if moving || command == Command::Home {
+            count_running(axis, started.elapsed());
+            power.moved();
+        }
+        if let Some(pos) = stepper.pos() {
+            let settings = settings(axis);
+            if command == Command::Calibrate {
+                // stalling against the bottom skips steps on purpose.
+                encoder::set_position(axis, pos, settings.microsteps);
+            } else if moving
+                && let Some(drift) = encoder::drift(axis, pos, settings.microsteps)
+                // the encoder sees the motor turn while the slack is taken up.
+                && drift > encoder::DRIFT_LIMIT + settings.backlash
+            {
+                warn!(
+                    "Encoder puts axis {} {} fullsteps from {}, skipped steps?",
+                    axis, drift, pos
+                );
+                raise_error(ErrorSeverity::Soft);
+                count_stall(axis);
+                if encoder::REHOME_ON_DRIFT {
+                    rehome_pending = true;
+                } else {
+                    // carry on from where we think we are, so each slip is only reported once.
+                    encoder::set_position(axis, pos, settings.microsteps);
+                }
+            }
+        }
+        if let Some(pos) = stepper.pos()
+            && bottom_triggered(bottom_endstop_pin.as_ref())
+            && pos != stepper.travel_limit().get()
+        {
+            let pos = NonZeroU32::new(pos).unwrap_or(NonZeroU32::MIN);
+            let tolerance = SLIP_TOLERANCE * settings(axis).microsteps;
+            if calibrated && pos.get().abs_diff(stepper.travel_limit().get()) > tolerance {
+                warn!(
+                    "Reached bottom at {} but travel limit is {}, has the coupling slipped?",
+                    pos,
+                    stepper.travel_limit()
+                );
+                raise_error(ErrorSeverity::Soft);
+            }
+            info!("Recalibrating travel limit to {}", pos);
+            calibrated = true;
+            stepper.set_travel_limit(pos);
+            if config_store::store_travel_limit(axis, pos.get())
+                .await
+                .is_err()
+            {
+                error!("Error storing item in flash");
+                raise_error(ErrorSeverity::Hard);
+            }
+        }
+        let full_tilt = settings(axis).tilt();
+        tilt = match (start_pos, stepper.pos()) {
+            _ if command == Command::Home => full_tilt,
+            (Some(start), Some(end)) if moving && end < start => tilt + (start - end),
+            (Some(start), Some(end)) if moving => tilt.saturating_sub(end - start),
+            _ => tilt,
+        }
+        .min(full_tilt);
+        if full_tilt != 0 {
+            // can't truncate, tilt is at most full_tilt.
+            let percent = (u64::from(tilt) * 100 / u64::from(full_tilt)) as u8;
+            TILT[axis].store(percent, Ordering::Relaxed);
+        }
+        if config_store::position_moved(axis, stepper.pos(), stepper.travel_limit().get())
+            .await
+            .is_err()
+        {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Soft);
+        }
+        publish_pos(
+            axis,
+            position(stepper.pos(), stepper.travel_limit().get(), 0),
+        );
+    }
+}
+
+/// How far an axis's slats are turned, in percent, see [`Command::Tilt`]. None for blinds without
+/// slats.
+pub(crate) fn tilt(axis: usize) -> Option<u8> {
+    (settings(axis).tilt != 0).then(|| TILT[axis].load(Ordering::Relaxed))
+}
+
+/// How calibration found the bottom.
+#[derive(Clone, Copy, Format)]
+pub(crate) enum BottomFound {
+    Endstop,
+    /// The motor stalled against whatever stops the blind.
+    Stall,
+}
+
+impl BottomFound {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Endstop => "endstop",
+            Self::Stall => "stall",
+        }
+    }
+}
+
+/// The travel an axis measured, see [`Command::Calibrate`].
+#[derive(Clone, Copy, Format)]
+pub(crate) struct Calibration {
+    pub(crate) axis: usize,
+    /// In microsteps, the new travel limit.
+    pub(crate) travel: u32,
+    pub(crate) fullsteps: u32,
+    pub(crate) found: BottomFound,
+}
+
+/// How far an axis has got recovering from a stall, with [`board::STALL_RECOVERY`].
+#[derive(Clone, Copy, Format)]
+pub(crate) enum Recovery {
+    /// A move stalled and lost the position, so the axis is homing.
+    Homing,
+    /// Homed, and carrying on to where the move was going.
+    Returning,
+    /// Back where it was going, or wherever it was sent instead on the way.
+    Recovered,
+    /// Homing or going back failed, the axis waits for a command as it would without recovery.
+    Failed,
+}
+
+impl Recovery {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Homing => "homing",
+            Self::Returning => "returning",
+            Self::Recovered => "recovered",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Stores `pos` as the travel limit, flashing the confirm LED once it is saved.
+async fn set_bottom(axis: usize, stepper: &mut Stepper, pos: u32) {
+    let pos = NonZeroU32::new(pos).unwrap_or(NonZeroU32::MIN);
+    stepper.set_travel_limit(pos);
+    match config_store::store_travel_limit(axis, pos.get()).await {
+        Ok(()) => confirm(),
+        Err(_) => {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Hard);
+        }
+    };
+}
+
+fn position(steps: Option<u32>, travel_limit: u32, velocity: u32) -> Position {
+    Position {
+        percent: if let Some(p) = steps {
+            ((u64::from(p) * 100) / u64::from(travel_limit))
                 .try_into()
                 .unwrap_or(100)
         } else {
             0
-        });
+        },
+        permille: steps.map_or(Permille::HOME, |p| Permille::from_steps(p, travel_limit)),
+        steps,
+        travel_limit,
+        velocity,
     }
 }
 
-async fn execute_home<'a>(
-    step_pin: &mut Output<'a>,
-    dir_pin: &mut Output<'a>,
+/// Reports the position every [`PROGRESS_INTERVAL`] during a move, as the plan itself holds the
+/// stepper until it is done.
+struct Progress {
+    axis: usize,
+    start: u32,
+    dir: Direction,
+    steps: u32,
+    travel_limit: u32,
+    next_report: Instant,
+}
+
+impl Progress {
+    /// None if the axis isn't homed, as there is nothing to report.
+    fn new(axis: usize, stepper: &Stepper, dir: Direction) -> Option<Self> {
+        Some(Self {
+            axis,
+            start: stepper.pos()?,
+            dir,
+            steps: 0,
+            travel_limit: stepper.travel_limit().get(),
+            next_report: Instant::now() + PROGRESS_INTERVAL,
+        })
+    }
+
+    /// Call after every step, with the velocity the plan is at, in microsteps/sec.
+    fn step(&mut self, velocity: u32) {
+        self.steps += 1;
+        if Instant::now() < self.next_report {
+            return;
+        }
+        self.next_report += PROGRESS_INTERVAL;
+        let pos = match self.dir {
+            Direction::ToHome => self.start.saturating_sub(self.steps),
+            Direction::AwayFromHome => self.start + self.steps,
+        };
+        let velocity = velocity / settings(self.axis).microsteps;
+        publish_pos(self.axis, position(Some(pos), self.travel_limit, velocity));
+    }
+}
+
+/// Slows the stepper down from the axis's settings by however much the board is derated, and
+/// for the quiet hours.
+fn apply_derating(axis: usize, stepper: &mut Stepper) {
+    let settings = settings(axis);
+    let percent = derating().percent() * quiet::speed_percent() / 100;
+    let scale = |v: u32| v * percent / 100;
+    let max_speed = NonZeroU32::new(scale(settings.max_speed().get())).unwrap_or(NonZeroU32::MIN);
+    let max_accel = NonZeroU32::new(scale(settings.max_accel().get())).unwrap_or(NonZeroU32::MIN);
+    let start_vel = scale(settings.start_vel()).max(1);
+    if (
+        stepper.max_speed(),
+        stepper.max_accel(),
+        stepper.start_vel(),
+    ) != (max_speed, max_accel, start_vel)
+    {
+        stepper.set_max_speed(max_speed);
+        stepper.set_max_accel(max_accel);
+        stepper.set_start_vel(start_vel);
+    }
+}
+
+/// Whether an axis's driver last gave its overtemperature prewarning, as read by the driver
+/// monitor.
+fn driver_hot(axis: usize) -> bool {
+    DRIVER_STATUS[axis]
+        .lock(|s| s.get())
+        .is_some_and(|s| s.otpw())
+}
+
+/// Turns the current down on an overheating driver, and gives it a rest. The current stays down
+/// until the driver stops warning, see [`restore_current`].
+async fn cool_down(axis: usize) {
+    warn!(
+        "Driver of axis {} is overheating, turning the current down",
+        axis
+    );
+    THERMAL_THROTTLED[axis].store(true, Ordering::Relaxed);
+    DRIVER_STATUS_CHANGED.signal(());
+    set_driver_current(axis, wanted_current(axis)).await;
+    let cooled = async {
+        while driver_hot(axis) {
+            Timer::after(COOL_DOWN_CHECK_INTERVAL).await;
+        }
+    };
+    feeding(
+        Watched::Motor(axis),
+        select(cooled, Timer::after(COOL_DOWN)),
+    )
+    .await;
+}
+
+/// Puts the current back once an overheating driver stops warning, and turns it down and back up
+/// as the quiet hours come and go.
+async fn restore_current(axis: usize) {
+    let mut changed = false;
+    if THERMAL_THROTTLED[axis].load(Ordering::Relaxed) && !driver_hot(axis) {
+        info!("Driver of axis {} has cooled down", axis);
+        THERMAL_THROTTLED[axis].store(false, Ordering::Relaxed);
+        DRIVER_STATUS_CHANGED.signal(());
+        changed = true;
+    }
+    let quiet = quiet::active().is_some();
+    if QUIET_CURRENT[axis].swap(quiet, Ordering::Relaxed) != quiet {
+        changed = true;
+    }
+    if changed {
+        set_driver_current(axis, wanted_current(axis)).await;
+    }
+}
+
+/// Checks that the endstop, the encoder and the driver's MSCNT agree with where we think we are,
+/// for an idle axis. The endstop being triggered well away from home, or the encoder having
+/// turned, means the blind has been moved by hand. MSCNT having moved on since `idle_mscnt`, the
+/// last idle check's reading (set here for the next), means the driver took steps we never sent,
+/// like from noise on the step pin.
+async fn position_drifted(
+    axis: usize,
+    stepper: &Stepper,
+    endstop_pin: &Input<'_>,
+    idle_mscnt: &mut Option<u32>,
+) -> bool {
+    let Some(pos) = stepper.pos() else {
+        return false;
+    };
+    let settings = settings(axis);
+    if pos > DRIFT_MARGIN * settings.microsteps && home_triggered(endstop_pin) {
+        return true;
+    }
+    if let Some(drift) = encoder::drift(axis, pos, settings.microsteps)
+        && drift > encoder::DRIFT_LIMIT + settings.backlash
+    {
+        warn!(
+            "Encoder puts idle axis {} {} fullsteps from {}",
+            axis, drift, pos
+        );
+        return true;
+    }
+    let Some(mscnt) = microstep_count(axis, Direction::AwayFromHome).await else {
+        return false;
+    };
+    match idle_mscnt.replace(mscnt) {
+        Some(before) => {
+            let turned = (mscnt + MSCNT_CYCLE - before) % MSCNT_CYCLE;
+            let error = mscnt_error(0, turned, settings.microsteps);
+            if error != 0 {
+                warn!(
+                    "Idle axis {}'s driver has taken {} steps nobody sent",
+                    axis, error
+                );
+            }
+            error != 0
+        }
+        None => false,
+    }
+}
+
+fn home_triggered(endstop_pin: &Input<'_>) -> bool {
+    endstop_pin.is_low() || (TEST_RIG && SIM_HOME_ENDSTOP.load(Ordering::Relaxed))
+}
+
+fn bottom_triggered(bottom_endstop_pin: Option<&Input<'_>>) -> bool {
+    bottom_endstop_pin.is_some_and(|b| b.is_low())
+        || (TEST_RIG && SIM_BOTTOM_ENDSTOP.load(Ordering::Relaxed))
+}
+
+fn stall_triggered(diag_pin: Option<&Input<'_>>) -> bool {
+    diag_pin.is_some_and(|d| d.is_high()) || (TEST_RIG && SIM_STALL.swap(false, Ordering::Relaxed))
+}
+
+/// Homes at the homing current, giving up if the endstop hasn't triggered within twice the travel
+/// from the bottom, or if we run into the bottom endstop instead. Once at the endstop it backs off
+/// and comes back slowly, so the zero is the same every time. A stop stops it where it is.
+/// Returns whether the axis is now homed, and the last command other than a stop that came in
+/// meanwhile, to run once it has.
+async fn execute_home(
+    axis: usize,
+    backend: &mut impl MotionBackend,
     stepper: &mut Stepper,
-    endstop_pin: &Input<'a>,
-) {
-    dir_pin.set_level(*DIR_TO_HOME.read().await);
-    let plan = stepper.homing_move(|| endstop_pin.is_low());
-    execute_step_plan(step_pin, plan).await;
+    endstop_pin: &Input<'_>,
+    bottom_endstop_pin: Option<&Input<'_>>,
+) -> (bool, Option<Command>) {
+    let settings = settings(axis);
+    // put back whatever the driver had, rather than what the settings say it should have.
+    let current = driver_current(axis).await.unwrap_or(settings.current());
+    set_driver_current(axis, current.with_irun(settings.homing_current)).await;
+    // a stand in travel limit may well be short.
+    let max_travel = 2 * max(
+        stepper.travel_limit().get(),
+        DEFAULT_TRAVEL_LIMIT * settings.microsteps,
+    );
+    // we may well be starting from the bottom, only a fresh press means we are going the wrong way.
+    let at_bottom = bottom_triggered(bottom_endstop_pin);
+    let wrong_way = || !at_bottom && bottom_triggered(bottom_endstop_pin);
+    let mut homing = TwoPhaseHoming::new(max_travel, HOMING_RETRACT * settings.microsteps);
+    let mut went_wrong_way = false;
+    let mut stopped = false;
+    let mut next = None;
+    while let Some(mut leg) = homing.next_leg(stepper, || home_triggered(endstop_pin)) {
+        backend
+            .execute_plan(leg.dir(), &mut leg, |_| {
+                feed(Watched::Motor(axis));
+                match try_next_command(axis) {
+                    Some(Command::Stop) => {
+                        stopped = true;
+                        next = None;
+                    }
+                    Some(command) => next = Some(command),
+                    None => (),
+                }
+                went_wrong_way = wrong_way();
+                went_wrong_way || stopped
+            })
+            .await;
+        if went_wrong_way || stopped {
+            break;
+        }
+    }
+    let result = homing.result();
+    set_driver_current(axis, current).await;
+    if stopped {
+        info!("Homing stopped, the position is unknown");
+    } else if went_wrong_way {
+        error!("Reached the bottom endstop while homing, should invert_dir be set?");
+        raise_error(ErrorSeverity::Soft);
+    } else if let Err(e) = result {
+        // not a hard error, as rebooting would only home again into the same broken switch, over
+        // and over. Latching it keeps anything from moving until a home gets there instead.
+        error!("{}, is the endstop working?", e);
+        if !HOMING_FAILED[axis].swap(true, Ordering::Relaxed) {
+            DRIVER_STATUS_CHANGED.signal(());
+        }
+        raise_error(ErrorSeverity::Soft);
+    } else {
+        if HOMING_FAILED[axis].swap(false, Ordering::Relaxed) {
+            DRIVER_STATUS_CHANGED.signal(());
+        }
+        count_homing(axis);
+        encoder::set_position(axis, 0, settings.microsteps);
+    }
+    (stepper.pos().is_some(), next)
+}
+
+/// Homes, then creeps down at the start velocity and homing current until the bottom endstop
+/// triggers or the motor stalls against the bottom, returning where and how it was found. None
+/// if homing failed, another command came in, or the bottom wasn't found within twice the travel
+/// we expected. A command that came in is returned alongside, to run once we have stopped.
+async fn execute_calibrate(
+    axis: usize,
+    backend: &mut impl MotionBackend,
+    stepper: &mut Stepper,
+    endstop_pin: &Input<'_>,
+    bottom_endstop_pin: Option<&Input<'_>>,
+) -> (Option<(u32, BottomFound)>, Option<Command>) {
+    // anything that came in while homing interrupts the calibration just the same.
+    match execute_home(axis, backend, stepper, endstop_pin, bottom_endstop_pin).await {
+        (true, None) => (),
+        (false, None) => return (None, None),
+        (_, next) => {
+            info!("Calibration interrupted");
+            return (None, next);
+        }
+    }
+    let settings = settings(axis);
+    let max_travel = 2 * max(
+        stepper.travel_limit().get(),
+        DEFAULT_TRAVEL_LIMIT * settings.microsteps,
+    );
+    let current = driver_current(axis).await.unwrap_or(settings.current());
+    set_driver_current(axis, current.with_irun(settings.homing_current)).await;
+    set_stallguard(axis, true).await;
+    let stalled = Cell::new(false);
+    let mut travelled = 0;
+    let mut interrupted = None;
+    // the bottom is past the soft limit, and may well be past a stand in travel limit.
+    stepper.set_seek_endstop(true);
+    let Ok(plan) = stepper.continuous_jog(
+        || {
+            travelled += 1;
+            feed(Watched::Motor(axis));
+            // whatever it was, it can wait until we have stopped.
+            interrupted = try_next_command(axis);
+            interrupted.is_none()
+                && travelled <= max_travel
+                && !stalled.get()
+                && !bottom_triggered(bottom_endstop_pin)
+        },
+        Direction::AwayFromHome,
+    ) else {
+        return (None, None);
+    };
+    let watch_stall = async {
+        Timer::after(STALL_SETTLE_TIME).await;
+        loop {
+            if stallguard_result(axis)
+                .await
+                .is_some_and(|sg| sg <= STALL_SG_RESULT)
+            {
+                stalled.set(true);
+            }
+            Timer::after(STALL_SAMPLE_INTERVAL).await;
+        }
+    };
+    select(
+        backend.execute_plan(Direction::AwayFromHome, plan.fuse(), |_| false),
+        watch_stall,
+    )
+    .await;
+    stepper.set_seek_endstop(false);
+    set_stallguard(axis, false).await;
+    set_driver_current(axis, current).await;
+    if interrupted.is_some() {
+        info!("Calibration interrupted");
+        (None, interrupted)
+    } else if bottom_triggered(bottom_endstop_pin) {
+        (stepper.pos().map(|p| (p, BottomFound::Endstop)), None)
+    } else if stalled.get() {
+        (stepper.pos().map(|p| (p, BottomFound::Stall)), None)
+    } else {
+        error!("Didn't find the bottom, is there anything to stop the blind there?");
+        (None, None)
+    }
 }
 
 async fn execute_move<'a>(
-    step_pin: &mut Output<'a>,
-    dir_pin: &mut Output<'a>,
+    axis: usize,
+    backend: &mut impl MotionBackend,
     stepper: &mut Stepper,
+    bottom_endstop_pin: Option<&Input<'a>>,
+    diag_pin: Option<&Input<'a>>,
     target_pos: u32,
-) -> Result<(), StepperError> {
-    let (plan, dir) = stepper.planned_move(target_pos)?;
-    let home_level = *DIR_TO_HOME.read().await;
-    if dir == Direction::ToHome {
-        dir_pin.set_level(home_level);
+    speed: SpeedFactor,
+) -> Result<Option<Command>, StepperError> {
+    let travel_limit = stepper.travel_limit().get();
+    // automations tend to re-send the target we are already at, don't twitch for them.
+    if let Some(pos) = stepper.pos()
+        && pos.abs_diff(target_pos) <= settings(axis).position_tolerance()
+    {
+        info!("already within tolerance of {}", target_pos);
+        return Ok(None);
+    }
+    // the plan holds onto the stepper, so work out which way it will go first.
+    let dir = match stepper.pos() {
+        Some(pos) if target_pos < pos => Direction::ToHome,
+        _ => Direction::AwayFromHome,
+    };
+    let mut progress = Progress::new(axis, stepper, dir);
+    let mut blanking = DIAG_BLANKING_FULLSTEPS * settings(axis).microsteps;
+    let max_speed = speed.apply(stepper.max_speed());
+    let (mut plan, dir) = stepper.guarded_move_with_speed(target_pos, max_speed, || {
+        blanking = blanking.saturating_sub(1);
+        blanking > 0 || !stall_triggered(diag_pin)
+    })?;
+    if config_store::start_move(axis, &plan.state()).await.is_err() {
+        error!("Error storing item in flash");
+        raise_error(ErrorSeverity::Soft);
+    }
+    if diag_pin.is_some() {
+        set_stallguard(axis, true).await;
+    }
+    info!(
+        "moving {} steps, about {}ms",
+        plan.len(),
+        plan.total_duration_estimate().as_millis()
+    );
+    // MSCNT catches pulses the driver missed, though not the motor slipping, which it can't see.
+    // VACTUAL moves only walk the plan by the clock, so they are never quite right.
+    let mscnt_before = if VACTUAL_MODE {
+        None
+    } else {
+        microstep_count(axis, dir).await
+    };
+    let mut steps = 0;
+    let mut pending_command = None;
+    let throttled = THERMAL_THROTTLED[axis].load(Ordering::Relaxed);
+    let mut overheated = false;
+    let mut obstructed = false;
+    backend
+        .execute_plan(dir, &mut plan, |plan| {
+            steps += 1;
+            feed(Watched::Motor(axis));
+            if let Some(progress) = &mut progress {
+                progress.step(plan.current_velocity());
+            }
+            if dir == Direction::AwayFromHome && bottom_triggered(bottom_endstop_pin) {
+                return true;
+            }
+            // stopped dead, as a controlled stop would carry on into it.
+            if dir == Direction::AwayFromHome && obstruction::detected(axis) {
+                obstructed = true;
+                return true;
+            }
+            // the current can only be turned down between moves, so stop and pick the move up
+            // again after a rest.
+            if !throttled && !overheated && driver_hot(axis) {
+                overheated = true;
+                plan.abort();
+                pending_command = Some(Command::MoveToStep(target_pos));
+            }
+            // new targets are taken on the fly where possible, anything else stops the move first.
+            // a new speed needs a new plan. Once the driver is hot nothing is retargeted, as that
+            // would carry on at full current, it all waits for the cool down instead.
+            if let Some(command) = try_next_command(axis) {
+                let target = match command {
+                    _ if overheated => None,
+                    Command::MoveToStep(pos) => Some(pos),
+                    Command::MoveToPos(permille) => Some(permille.to_steps(travel_limit)),
+                    _ => None,
+                };
+                match target.map(|t| plan.retarget(t)) {
+                    Some(Ok(true)) => info!("retargeted move"),
+                    None if command == Command::Stop => {
+                        info!("stopping");
+                        plan.abort();
+                        // not picked up again after cooling down either.
+                        pending_command = None;
+                    }
+                    Some(Ok(false)) | None => {
+                        plan.abort();
+                        pending_command = Some(command);
+                    }
+                    Some(Err(e)) => {
+                        info!("Error: {}", e);
+                        raise_error(ErrorSeverity::Soft);
+                    }
+                }
+            }
+            false
+        })
+        .await;
+    if config_store::finish_move(axis).await.is_err() {
+        error!("Error storing item in flash");
+        raise_error(ErrorSeverity::Soft);
+    }
+    if let Some(before) = mscnt_before {
+        let error = reconcile_mscnt(axis, stepper, dir, before, steps).await;
+        if error != 0 {
+            warn!(
+                "Axis {}'s driver took {} of the {} steps sent, coil currents {}",
+                axis,
+                steps.saturating_add_signed(error),
+                steps,
+                coil_currents(axis).await
+            );
+            raise_error(ErrorSeverity::Soft);
+            count_stall(axis);
+        }
+    }
+    if diag_pin.is_some() {
+        set_stallguard(axis, false).await;
+    }
+    if overheated {
+        cool_down(axis).await;
+    }
+    let result = plan.result();
+    #[cfg(feature = "ramp_telemetry")]
+    {
+        let ramp = stepper.ramp();
+        info!(
+            "ramp of axis {}: {} steps, every {}th as step,delay_us:",
+            axis,
+            ramp.steps(),
+            ramp.decimation()
+        );
+        for (step, delay) in ramp.samples() {
+            info!("{},{}", step, delay.as_micros());
+        }
+        publish_ramp(axis, ramp);
+    }
+    if let Err(e) = result {
+        warn!("Axis {} stalled, it will rehome before the next move", axis);
+        count_stall(axis);
+        return Err(e);
+    }
+    if dir == Direction::AwayFromHome {
+        obstruction::set_obstructed(axis, obstructed);
+    }
+    if obstructed {
+        return Ok(back_off(axis, stepper));
+    }
+    Ok(pending_command)
+}
+
+/// Reports an obstruction that stopped a move down, and backs off up away from it.
+fn back_off(axis: usize, stepper: &Stepper) -> Option<Command> {
+    warn!("Axis {} stopped for an obstruction, backing off", axis);
+    raise_error(ErrorSeverity::Soft);
+    let back_off = obstruction::BACK_OFF * settings(axis).microsteps;
+    Some(Command::MoveToStep(stepper.pos()?.saturating_sub(back_off)))
+}
+
+/// Jogs with the driver generating the steps from VACTUAL, then corrects the position by how far
+/// MSCNT says the driver turned it. The velocity only reaches the driver every so often, so
+/// the steps walked from the plan can be a little off, but MSCNT is exact to within its 4
+/// fullsteps, which is far more than the two can drift apart in one jog.
+async fn execute_vactual_jog<'a>(
+    axis: usize,
+    stepper: &mut Stepper,
+    bottom_endstop_pin: Option<&Input<'a>>,
+    dir: Direction,
+) -> Result<Option<Command>, StepperError> {
+    let mut backend = Vactual::new(axis);
+    let before = microstep_count(axis, dir).await;
+    let jogged = execute_jog(axis, &mut backend, stepper, bottom_endstop_pin, dir).await;
+    if let Some(before) = before {
+        let error = reconcile_mscnt(axis, stepper, dir, before, backend.steps()).await;
+        if error != 0 {
+            info!(
+                "MSCNT puts the jog {} microsteps off from the {} walked",
+                error,
+                backend.steps()
+            );
+        }
+    }
+    jogged
+}
+
+/// Moves the position on by however many more steps MSCNT says the driver took, since it read
+/// `before`, than the `steps` we think it did. Returns how many that was, 0 if they agree or
+/// MSCNT can't be read.
+async fn reconcile_mscnt(
+    axis: usize,
+    stepper: &mut Stepper,
+    dir: Direction,
+    before: u32,
+    steps: u32,
+) -> i32 {
+    let (Some(after), Some(pos)) = (microstep_count(axis, dir).await, stepper.pos()) else {
+        return 0;
+    };
+    let turned = (after + MSCNT_CYCLE - before) % MSCNT_CYCLE;
+    let error = mscnt_error(steps, turned, settings(axis).microsteps);
+    if error != 0 {
+        let pos = match dir {
+            Direction::AwayFromHome => pos.saturating_add_signed(error),
+            Direction::ToHome => pos.saturating_add_signed(-error),
+        };
+        // can't fail, clamped to the travel.
+        stepper
+            .assume_pos(pos.min(stepper.travel_limit().get()))
+            .unwrap();
+    }
+    error
+}
+
+/// How many microsteps further the driver turned the motor than the steps walked, from how far
+/// MSCNT went round, to the nearest half of its cycle either way.
+fn mscnt_error(steps: u32, turned: u32, microsteps: u32) -> i32 {
+    // one cycle of MSCNT, in microsteps.
+    let cycle = (MSCNT_CYCLE * microsteps / 256) as i32;
+    let turned = ((turned * microsteps + 128) / 256) as i32;
+    let error = (turned - (steps % cycle as u32) as i32).rem_euclid(cycle);
+    if error > cycle / 2 {
+        error - cycle
     } else {
-        dir_pin.set_level(!home_level);
+        error
     }
-    execute_step_plan(step_pin, plan).await;
-    Ok(())
 }
 
 async fn execute_jog<'a>(
-    step_pin: &mut Output<'a>,
-    dir_pin: &mut Output<'a>,
+    axis: usize,
+    backend: &mut impl MotionBackend,
     stepper: &mut Stepper,
+    bottom_endstop_pin: Option<&Input<'a>>,
     dir: Direction,
-) -> Result<(), StepperError> {
-    let plan = stepper.continuous_jog(
-        || {
-            !LAST_COMMAND
-                .try_take()
-                .is_some_and(|c| c == Command::StopJog)
+) -> Result<Option<Command>, StepperError> {
+    let mut progress = Progress::new(axis, stepper, dir);
+    let mut obstructed = false;
+    // jogging down is how the bottom is found by hand, so only jogs up stop at the soft limit.
+    stepper.pos().ok_or(StepperError::NotHomed)?;
+    stepper.set_seek_endstop(dir == Direction::AwayFromHome);
+    // ramps down once told to stop, or given anything else to do, but stops dead at the bottom
+    // endstop or an obstruction.
+    let mut next = None;
+    let plan = stepper.continuous_jog_with_speed(
+        || match try_next_command(axis) {
+            Some(Command::StopJog | Command::Stop) => false,
+            Some(command) => {
+                next = Some(command);
+                false
+            }
+            None => true,
         },
         dir,
+        settings(axis).jog_speed(),
     )?;
-    let home_level = *DIR_TO_HOME.read().await;
-    if dir == Direction::ToHome {
-        dir_pin.set_level(home_level);
-    } else {
-        dir_pin.set_level(!home_level);
-    }
-    execute_step_plan(step_pin, plan.fuse()).await;
-    Ok(())
-}
-
-async fn execute_step_plan<'a>(
-    step_pin: &mut Output<'a>,
-    plan: impl FusedIterator<Item = Duration>,
-) {
-    for delay in plan {
-        let now = Instant::now();
-        step_pin.set_high();
-        Timer::after_nanos(100).await;
-        step_pin.set_low();
-        Timer::at(now.saturating_add(delay)).await;
+    backend
+        .execute_plan(dir, plan, |plan| {
+            feed(Watched::Motor(axis));
+            if let Some(progress) = &mut progress {
+                progress.step(plan.current_velocity());
+            }
+            obstructed = dir == Direction::AwayFromHome && obstruction::detected(axis);
+            obstructed || dir == Direction::AwayFromHome && bottom_triggered(bottom_endstop_pin)
+        })
+        .await;
+    stepper.set_seek_endstop(false);
+    if dir == Direction::AwayFromHome {
+        obstruction::set_obstructed(axis, obstructed);
     }
+    Ok(if obstructed {
+        back_off(axis, stepper)
+    } else {
+        next
+    })
 }
@@ -1,9 +1,12 @@
-use core::net::Ipv4Addr;
-use defmt::{error, info};
-use embassy_futures::select::{Either3, select3};
+use core::{cmp::max, sync::atomic::Ordering};
+use defmt::{debug, error, info};
+use embassy_futures::select::{Either3, Either4, select_array, select3, select4};
 use embassy_net::{IpAddress, Stack, tcp::TcpSocket};
-use embassy_time::{Duration, Timer, WithTimeout};
-use heapless::format;
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use heapless::{String, format};
+use iter_step_gen::Direction;
+#[cfg(feature = "ramp_telemetry")]
+use iter_step_gen::{RAMP_SAMPLES, RampRecorder};
 use rust_mqtt::{
     Bytes,
     buffer::AllocBuffer,
@@ -17,34 +20,318 @@ use rust_mqtt::{
     config::{KeepAlive, SessionExpiryInterval},
     types::{MqttBinary, MqttString, QoS, TopicName},
 };
+use serde::Deserialize;
+
+#[cfg(feature = "ramp_telemetry")]
+use crate::state::take_ramp;
+use crate::{
+    AXES, BATTERY_MV, Command, Connectivity, DERATING_CHANGED, DRIVER_FAULT, DRIVER_STATUS,
+    DRIVER_STATUS_CHANGED, EXPERT_MODE, HOMING_FAILED, INVERT_PERCENT, MQTT_SHUTDOWN, Permille,
+    Position, SIM_BOTTOM_ENDSTOP, SIM_HOME_ENDSTOP, SIM_STALL, SpeedFactor, TEST_RIG,
+    THERMAL_THROTTLED, TmcRequest,
+    arbiter::{Lockout, Source, set_lockout},
+    automation::{HvacMode, SolarAutomation},
+    derating,
+    diagnostics::{next_diagnostics, refresh_diagnostics},
+    motor::tilt,
+    network::{NetworkConfig, set_network_config},
+    obstruction::obstructed,
+    presets::{NAME_LEN, Presets, recall_preset, set_presets},
+    provisioning::Credentials,
+    quiet::{QuietHours, set_quiet_hours},
+    schedule::{Schedule, set_schedule},
+    set_connectivity,
+    settings::SettingsUpdate,
+    state::{
+        PositionReceiver, QueueState, last_pos, local_latency, next_calibration, next_recovery,
+        queue_state, send_command, take_jitter, watch_pos,
+    },
+    stats::stats,
+    tmc_request,
+    tmc2209::DrvStatus,
+    watchdog::{Watched, crash_published, feed, feeding, unpublished_crash, unwatch},
+};
 
-use crate::{CURRENT_POS, Command, LAST_COMMAND};
-
-const HOST_ID: MqttString = unsafe { MqttString::from_slice_unchecked(env!("HOST_ID")) };
-const COMMAND_TOPIC: MqttString =
-    unsafe { MqttString::from_slice_unchecked(concat!(env!("MQTT_TOPIC_PREFIX"), "command")) };
-const POS_TOPIC: MqttString =
-    unsafe { MqttString::from_slice_unchecked(concat!(env!("MQTT_TOPIC_PREFIX"), "pos")) };
-const MQTT_USERNAME: MqttString =
-    unsafe { MqttString::from_slice_unchecked(env!("MQTT_USERNAME")) };
-const MQTT_PASSWORD: MqttString =
-    unsafe { MqttString::from_slice_unchecked(env!("MQTT_PASSWORD")) };
-const MQTT_BROKER_IP: &str = env!("MQTT_BROKER_IP");
 const KEEPALIVE_TIME: u16 = 60;
+// Never ping more often than this, however bad the link gets.
+const MIN_PING_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+// Give up on the connection after this many pings in a row go unanswered.
+const MAX_MISSED_PINGS: u8 = 3;
+// How long to wait before reconnecting, doubling with each failed attempt up to the max.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+// How long the broker keeps our session (and subscriptions) after we drop, long enough to ride
+// out the longest backoff.
+const SESSION_EXPIRY: u32 = 15 * 60;
+
+/// The topics for the whole board.
+struct BoardTopics {
+    availability: String<64>,
+    // inputs for the solar automation.
+    lux: String<64>,
+    hvac_mode: String<64>,
+    // the supply voltage, for derating on a flagging battery.
+    battery_voltage: String<64>,
+    derating: String<64>,
+    // JSON signal strength, uptime and the like, see `Diagnostics::json`.
+    diagnostics: String<64>,
+    // how quickly button presses and remotes reach the motor, in milliseconds.
+    local_latency: String<64>,
+    // raw TMC2209 register access, only subscribed to with EXPERT_MODE.
+    tmc: String<64>,
+    tmc_reply: String<64>,
+    // hardware in the loop test hooks, only subscribed to with TEST_RIG.
+    rig_endstop: String<64>,
+    rig_stall: String<64>,
+    rig_query: String<64>,
+    rig_state: String<64>,
+    // JSON static addresses for the next boot, see `NetworkConfig::parse`.
+    network: String<64>,
+    // JSON named positions for every axis, see `Presets::parse`.
+    presets: String<64>,
+    // the JSON nightly window for every axis, see `QuietHours::parse`.
+    quiet_hours: String<64>,
+    // JSON sources to ignore, see `Lockout::parse`.
+    lockout: String<64>,
+    // why the board last crashed, published once after the reboot.
+    last_crash: String<64>,
+}
+
+impl BoardTopics {
+    fn new(prefix: &str) -> Self {
+        // can't overflow, the prefix leaves room for the longest name.
+        let topic = |name| format!(64; "{}{}", prefix, name).unwrap();
+        Self {
+            availability: topic("availability"),
+            lux: topic("lux"),
+            hvac_mode: topic("hvac_mode"),
+            battery_voltage: topic("battery_voltage"),
+            derating: topic("derating"),
+            diagnostics: topic("diagnostics"),
+            local_latency: topic("local_latency"),
+            tmc: topic("tmc"),
+            tmc_reply: topic("tmc_reply"),
+            rig_endstop: topic("rig/endstop"),
+            rig_stall: topic("rig/stall"),
+            rig_query: topic("rig/query"),
+            rig_state: topic("rig/state"),
+            network: topic("network"),
+            presets: topic("presets"),
+            quiet_hours: topic("quiet_hours"),
+            lockout: topic("lockout"),
+            last_crash: topic("last_crash"),
+        }
+    }
+}
+
+/// The topics for one axis. The first axis uses the bare names, later ones get an `_<axis>`
+/// suffix, like `command_1`.
+struct AxisTopics {
+    command: String<64>,
+    pos: String<64>,
+    // absolute positions in steps, for calibration and power users.
+    command_steps: String<64>,
+    pos_steps: String<64>,
+    // thousandths of the travel, for when a percent is too coarse.
+    pos_permille: String<64>,
+    travel_limit: String<64>,
+    invert_percent: String<64>,
+    // JSON settings, see SettingsUpdate.
+    config: String<64>,
+    // JSON daily moves, see Schedule.
+    schedule: String<64>,
+    // JSON lifetime counters, see Stats.
+    stats: String<64>,
+    // JSON fault flags from the driver, see `driver_status_json`.
+    driver_status: String<64>,
+    // `throttled` while the current is turned down for an overheating driver, `normal` otherwise.
+    thermal: String<64>,
+    // `failed` while a homing that didn't find the endstop keeps the axis still, `ok` otherwise.
+    homing: String<64>,
+    // JSON travel measured by a calibration, not retained.
+    calibration: String<64>,
+    // each stage of recovering from a stall, see `motor::Recovery`, not retained.
+    recovery: String<64>,
+    // JSON command queue state, see QueueState.
+    queue: String<64>,
+    // JSON delays of the last planned move, see `ramp_json`.
+    #[cfg(feature = "ramp_telemetry")]
+    ramp: String<64>,
+    // JSON timing error of the last move's steps, see `Jitter`.
+    step_jitter: String<64>,
+    // fullsteps/sec while moving, updated with the position.
+    speed: String<64>,
+    // percent a venetian blind's slats are turned, see `Command::Tilt`.
+    tilt: String<64>,
+    tilt_command: String<64>,
+    // `true` if the last move down was stopped by the obstruction sensor, see `obstruction`.
+    obstructed: String<64>,
+}
+
+impl AxisTopics {
+    fn new(prefix: &str, axis: usize) -> Self {
+        let topic = |name| {
+            if axis == 0 {
+                format!(64; "{}{}", prefix, name)
+            } else {
+                format!(64; "{}{}_{}", prefix, name, axis)
+            }
+            .unwrap()
+        };
+        Self {
+            command: topic("command"),
+            pos: topic("pos"),
+            command_steps: topic("command_steps"),
+            pos_steps: topic("pos_steps"),
+            pos_permille: topic("pos_permille"),
+            travel_limit: topic("travel_limit"),
+            invert_percent: topic("invert_percent"),
+            config: topic("config"),
+            schedule: topic("schedule"),
+            stats: topic("stats"),
+            driver_status: topic("driver_status"),
+            thermal: topic("thermal"),
+            homing: topic("homing"),
+            calibration: topic("calibration"),
+            recovery: topic("recovery"),
+            queue: topic("queue"),
+            #[cfg(feature = "ramp_telemetry")]
+            ramp: topic("ramp"),
+            step_jitter: topic("step_jitter"),
+            speed: topic("speed"),
+            tilt: topic("tilt"),
+            tilt_command: topic("tilt_command"),
+            obstructed: topic("obstructed"),
+        }
+    }
+}
+
+fn mqtt_string(topic: &str) -> MqttString<'_> {
+    MqttString::try_from(topic).unwrap()
+}
+
+fn topic_name(topic: &str) -> TopicName<'_> {
+    // saftey: we only build topics from fixed names and a prefix checked when it was provisioned,
+    // see `valid_topic_prefix`, so they are valid. Also, since this is not a memory saftey issue, I
+    // disagree this function needs to be unsafe at all.
+    unsafe { TopicName::new_unchecked(mqtt_string(topic)) }
+}
+
+/// Retained, so Home Assistant picks up the state when it restarts.
+fn state_options(topic: &str) -> PublicationOptions<'_> {
+    PublicationOptions {
+        retain: true,
+        topic: topic_name(topic),
+        qos: QoS::AtMostOnce,
+    }
+}
+
+/// Tracks how well the link to the broker is doing, so ping cadence and socket timeouts can
+/// adapt to it instead of racing each other.
+struct LinkQuality {
+    // smoothed round trip time of pings.
+    rtt: Duration,
+    missed_pings: u8,
+    ping_sent: Option<Instant>,
+}
+
+impl LinkQuality {
+    const fn new() -> Self {
+        Self {
+            rtt: Duration::from_millis(100),
+            missed_pings: 0,
+            ping_sent: None,
+        }
+    }
+
+    /// Starts at half the keepalive, halving for every missed ping so that a dying connection is
+    /// noticed well before the broker gives up on us.
+    fn ping_interval(&self) -> Duration {
+        max(
+            Duration::from_secs(KEEPALIVE_TIME.into())
+                / 2
+                / (1_u32 << self.missed_pings.min(MAX_MISSED_PINGS)),
+            MIN_PING_INTERVAL,
+        )
+    }
+
+    /// A generous multiple of the round trip time, but always shorter than the ping interval so
+    /// a dead socket is caught before we ping into the void.
+    fn socket_timeout(&self) -> Duration {
+        (self.rtt * 8).clamp(MIN_SOCKET_TIMEOUT, self.ping_interval())
+    }
+
+    fn on_ping(&mut self) {
+        if self.ping_sent.is_some() {
+            self.missed_pings = self.missed_pings.saturating_add(1);
+        }
+        self.ping_sent = Some(Instant::now());
+    }
+
+    /// Any packet from the broker after a ping counts as the ping getting through.
+    fn on_packet(&mut self) {
+        if let Some(sent) = self.ping_sent.take() {
+            self.rtt = (self.rtt * 7 + sent.elapsed()) / 8;
+            self.missed_pings = 0;
+            debug!("broker rtt {}ms", self.rtt.as_millis());
+        }
+    }
+}
+
+/// Spaces out reconnection attempts, so a broker that is down isn't hammered.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const fn new() -> Self {
+        Self {
+            delay: Duration::from_ticks(0),
+        }
+    }
+
+    /// Waits before the next attempt, straight through for the first after a good connection.
+    async fn wait(&mut self) {
+        if self.delay.as_ticks() > 0 {
+            info!("Reconnecting to MQTT in {}s", self.delay.as_secs());
+            Timer::after(self.delay).await;
+        }
+        self.delay = (self.delay * 2).clamp(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+    }
+
+    fn reset(&mut self) {
+        self.delay = Duration::from_ticks(0);
+    }
+}
 
 // TODO: this is messy, needs better error handling.
 #[embassy_executor::task]
-pub(crate) async fn mqtt_task(stack: Stack<'static>) {
+pub(crate) async fn mqtt_task(stack: Stack<'static>, credentials: &'static Credentials) {
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
+    let mut link = LinkQuality::new();
+    let mut solar = SolarAutomation::new();
+    let board_topics = BoardTopics::new(&credentials.topic_prefix);
+    let axis_topics: [AxisTopics; AXES] =
+        core::array::from_fn(|axis| AxisTopics::new(&credentials.topic_prefix, axis));
+    let mut positions: [PositionReceiver; AXES] = core::array::from_fn(watch_pos);
+    let mut backoff = Backoff::new();
 
-    loop {
+    'connection: loop {
+        feeding(Watched::Mqtt, backoff.wait()).await;
+        if !stack.is_link_up() {
+            // a fresh wifi connection is worth trying straight away.
+            backoff.reset();
+        }
         while !stack.is_link_up() {
+            feed(Watched::Mqtt);
             Timer::after(Duration::from_millis(500)).await;
         }
 
         info!("Waiting to get IP address...");
         loop {
+            feed(Watched::Mqtt);
             if let Some(config) = stack.config_v4() {
                 info!("Got IP: {}", config.address);
                 break;
@@ -55,12 +342,13 @@ pub(crate) async fn mqtt_task(stack: Stack<'static>) {
         let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
 
         socket.set_keep_alive(Some(Duration::from_secs(5)));
-        socket.set_timeout(Some(Duration::from_secs(10)));
+        link.ping_sent = None;
+        socket.set_timeout(Some(link.socket_timeout()));
 
         let mut buffer = AllocBuffer;
 
         let mut client = Client::<_, _, 5, 3, 3>::new(&mut buffer);
-        let addr: IpAddress = MQTT_BROKER_IP.parse::<Ipv4Addr>().unwrap().into();
+        let addr: IpAddress = credentials.broker_ip.into();
         if let Err(e) = socket.connect((addr, 1883)).await {
             error!("Error connecting to mqtt server: {}", e);
             socket.abort();
@@ -70,36 +358,45 @@ pub(crate) async fn mqtt_task(stack: Stack<'static>) {
             continue;
         };
 
+        // whether the broker still has our subscriptions from last time.
+        let session_present;
         match client
             .connect(
                 socket,
                 &ConnectOptions {
                     clean_start: false,
                     keep_alive: KeepAlive::Seconds(KEEPALIVE_TIME),
-                    session_expiry_interval: SessionExpiryInterval::Seconds(
-                        (KEEPALIVE_TIME * 2).into(),
+                    session_expiry_interval: SessionExpiryInterval::Seconds(SESSION_EXPIRY),
+                    // can't fail, the credentials are far shorter than MQTT's limit.
+                    user_name: Some(
+                        MqttString::try_from(credentials.mqtt_username.as_str()).unwrap(),
+                    ),
+                    password: Some(
+                        MqttString::try_from(credentials.mqtt_password.as_str())
+                            .unwrap()
+                            .into(),
                     ),
-                    user_name: Some(MQTT_USERNAME),
-                    password: Some(MQTT_PASSWORD.into()),
+                    // marks us unavailable if we drop without saying goodbye.
                     will: Some(WillOptions {
                         will_qos: QoS::ExactlyOnce,
                         will_retain: true,
-                        will_topic: MqttString::try_from("crabroll-dead").unwrap(),
-                        will_payload: MqttBinary::try_from("crabroll died :(").unwrap(),
+                        will_topic: mqtt_string(&board_topics.availability),
+                        will_payload: MqttBinary::try_from("offline").unwrap(),
                         will_delay_interval: 10,
                         is_payload_utf8: true,
-                        message_expiry_interval: Some(20),
+                        message_expiry_interval: None,
                         content_type: Some(MqttString::try_from("txt").unwrap()),
                         response_topic: None,
                         correlation_data: None,
                     }),
                 },
-                Some(HOST_ID),
+                Some(mqtt_string(&credentials.host_id)),
             )
             .await
         {
             Ok(c) => {
                 info!("Connected to server: {:?}", c);
+                session_present = c.session_present;
                 info!("{:?}", client.client_config());
                 info!("{:?}", client.server_config());
                 info!("{:?}", client.shared_config());
@@ -114,57 +411,144 @@ pub(crate) async fn mqtt_task(stack: Stack<'static>) {
             }
         }
 
-        let sub_options = SubscriptionOptions {
-            retain_handling: RetainHandling::SendIfNotSubscribedBefore,
-            retain_as_published: true,
-            no_local: false,
-            qos: QoS::ExactlyOnce,
+        let lux_topic = topic_name(&board_topics.lux);
+        let hvac_mode_topic = topic_name(&board_topics.hvac_mode);
+        let battery_voltage_topic = topic_name(&board_topics.battery_voltage);
+        let network_topic = topic_name(&board_topics.network);
+        let presets_topic = topic_name(&board_topics.presets);
+        let quiet_hours_topic = topic_name(&board_topics.quiet_hours);
+        let lockout_topic = topic_name(&board_topics.lockout);
+
+        let rig_state_pub_options = PublicationOptions {
+            retain: false,
+            topic: topic_name(&board_topics.rig_state),
+            qos: QoS::AtMostOnce,
         };
 
-        // saftey: The string is static, we know it is the correct syntax. Also, since this is not a
-        // memory saftey issue, I disagree this function needs to be unsafe at all.
-        let command_topic = unsafe { TopicName::new_unchecked(COMMAND_TOPIC) };
-        let pos_topic = unsafe { TopicName::new_unchecked(POS_TOPIC) };
+        let tmc_topic = topic_name(&board_topics.tmc);
+        let rig_topics = [
+            &board_topics.rig_endstop,
+            &board_topics.rig_stall,
+            &board_topics.rig_query,
+        ]
+        .map(|t| topic_name(t));
+        for topic in axis_topics
+            .iter()
+            .flat_map(|t| {
+                [
+                    &t.command,
+                    &t.command_steps,
+                    &t.tilt_command,
+                    &t.invert_percent,
+                    &t.config,
+                    &t.schedule,
+                ]
+            })
+            .map(|t| topic_name(t))
+            .chain([
+                lux_topic,
+                hvac_mode_topic,
+                battery_voltage_topic,
+                network_topic,
+                presets_topic,
+                quiet_hours_topic,
+                lockout_topic,
+            ])
+            .chain(EXPERT_MODE.then_some(tmc_topic))
+            .chain(rig_topics.into_iter().filter(|_| TEST_RIG))
+            .filter(|_| !session_present)
+        {
+            let sub_options = SubscriptionOptions {
+                retain_handling: RetainHandling::SendIfNotSubscribedBefore,
+                retain_as_published: true,
+                no_local: false,
+                qos: QoS::ExactlyOnce,
+            };
+            client.subscribe(topic.into(), sub_options).await.unwrap();
 
-        let pub_options = PublicationOptions {
+            match client.poll().await {
+                Ok(Event::Suback(Suback {
+                    packet_identifier: _,
+                    reason_code,
+                })) => info!("Subscribed with reason code {:?}", reason_code),
+                Ok(e) => {
+                    error!("Expected Suback but received event {:?}", e);
+                    if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
+                        error!("error aborting connection: {:?}", e);
+                    };
+                    continue 'connection;
+                }
+                Err(e) => {
+                    error!("Failed to receive Suback {:?}", e);
+                    if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
+                        error!("error aborting connection: {:?}", e);
+                    };
+                    continue 'connection;
+                }
+            };
+        }
+        let availability_options = PublicationOptions {
             retain: true,
-            topic: pos_topic,
-            qos: QoS::AtMostOnce,
+            topic: topic_name(&board_topics.availability),
+            qos: QoS::AtLeastOnce,
         };
-        client
-            .subscribe(command_topic.clone().into(), sub_options)
+        if let Err(e) = client
+            .publish(&availability_options, Bytes::Borrowed(b"online"))
             .await
-            .unwrap();
-
-        match client.poll().await {
-            Ok(Event::Suback(Suback {
-                packet_identifier: _,
-                reason_code,
-            })) => info!("Subscribed with reason code {:?}", reason_code),
-            Ok(e) => {
-                error!("Expected Suback but received event {:?}", e);
-                if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
-                    error!("error aborting connection: {:?}", e);
-                };
-                continue;
-            }
-            Err(e) => {
-                error!("Failed to receive Suback {:?}", e);
-                if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
-                    error!("error aborting connection: {:?}", e);
-                };
-                continue;
+        {
+            error!("failed to publish: {:?}", e);
+            if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
+                error!("error aborting connection: {:?}", e);
+            };
+            continue 'connection;
+        }
+        if let Some(crash) = unpublished_crash() {
+            let crash_options = PublicationOptions {
+                retain: true,
+                topic: topic_name(&board_topics.last_crash),
+                qos: QoS::AtLeastOnce,
+            };
+            match client
+                .publish(&crash_options, Bytes::Borrowed(crash.as_bytes()))
+                .await
+            {
+                Ok(_) => crash_published().await,
+                // tried again on the next connection.
+                Err(e) => error!("failed to publish: {:?}", e),
             }
-        };
-        loop {
-            match select3(
-                Timer::after_secs(KEEPALIVE_TIME.into()),
+        }
+        set_connectivity(Connectivity::Mqtt);
+        backoff.reset();
+        // so a derating that started while we were offline isn't missed.
+        DERATING_CHANGED.signal(derating());
+        DRIVER_STATUS_CHANGED.signal(());
+        refresh_diagnostics(stack);
+        // what the queue topics last said, None to publish them afresh.
+        let mut reported_queues: [Option<QueueState>; AXES] = [None; AXES];
+        'session: loop {
+            feed(Watched::Mqtt);
+            let event = select4(
+                Timer::after(link.ping_interval()),
                 client.poll_header(),
-                CURRENT_POS.wait(),
+                select4(
+                    select_array(positions.each_mut().map(|p| p.changed())),
+                    DERATING_CHANGED.wait(),
+                    DRIVER_STATUS_CHANGED.wait(),
+                    select3(next_calibration(), next_diagnostics(), next_recovery()),
+                ),
+                MQTT_SHUTDOWN.wait(),
             )
-            .await
-            {
-                Either3::First(_) => {
+            .await;
+            if let Either4::Second(Ok(_)) = event {
+                link.on_packet();
+            }
+            match event {
+                Either4::First(_) => {
+                    link.on_ping();
+                    if link.missed_pings >= MAX_MISSED_PINGS {
+                        error!("broker missed {} pings", link.missed_pings);
+                        break;
+                    }
                     if let Err(e) = client.ping().await {
                         error!("failed to ping: {:?}", e);
                         break;
@@ -172,17 +556,54 @@ pub(crate) async fn mqtt_task(stack: Stack<'static>) {
                         info!("pinged broker");
                     }
                 }
-                Either3::Second(Err(e)) => {
+                Either4::Second(Err(e)) => {
                     error!("error polling: {:?}", e);
                     break;
                 }
-                Either3::Second(Ok(header)) => match client.poll_body(header).await {
+                Either4::Second(Ok(header)) => match client.poll_body(header).await {
                     Ok(Event::Publish(e)) => {
                         info!("Received Message {:?}", e);
-                        if e.topic == COMMAND_TOPIC {
-                            if let Ok(str) = str::from_utf8(&e.message) {
-                                if let Ok(int) = str::parse::<i8>(str) {
-                                    LAST_COMMAND.signal(Command::MoveToPos(int));
+                        let axis_of = |topic: fn(&AxisTopics) -> &String<64>| {
+                            axis_topics
+                                .iter()
+                                .position(|t| e.topic == mqtt_string(topic(t)))
+                        };
+                        if let Some(axis) = axis_of(|t| &t.command) {
+                            if e.message.starts_with(b"{") {
+                                match JsonCommand::parse(&e.message) {
+                                    // shutting down is for the whole board, not just one axis.
+                                    Some(JsonCommand {
+                                        action: Action::Shutdown,
+                                        ..
+                                    }) => (0..AXES).for_each(|axis| {
+                                        send_command(axis, Source::Network, Command::Shutdown)
+                                    }),
+                                    Some(command) => match command.command(axis) {
+                                        Some(command) => {
+                                            send_command(axis, Source::Network, command)
+                                        }
+                                        None => {
+                                            error!("Received incomplete command: {:?}", e.message)
+                                        }
+                                    },
+                                    None => error!("Received invalid command: {:?}", e.message),
+                                }
+                            } else if let Ok(str) = str::from_utf8(&e.message) {
+                                if str == "stop" {
+                                    send_command(axis, Source::Network, Command::Stop);
+                                } else if str == "calibrate" {
+                                    send_command(axis, Source::Network, Command::Calibrate);
+                                } else if str == "shutdown" {
+                                    // shutting down is for the whole board, not just one axis.
+                                    (0..AXES).for_each(|axis| {
+                                        send_command(axis, Source::Network, Command::Shutdown)
+                                    });
+                                } else if let Some(permille) = parse_percent(str) {
+                                    send_command(
+                                        axis,
+                                        Source::Network,
+                                        Command::MoveToPos(invert_permille(axis, permille)),
+                                    );
                                 } else {
                                     error!("Received invalid number: {:?}", e.message);
                                     break;
@@ -191,7 +612,187 @@ pub(crate) async fn mqtt_task(stack: Stack<'static>) {
                                 error!("Received invalid utf-8: {:?}", e.message);
                                 break;
                             }
+                        } else if let Some(axis) = axis_of(|t| &t.command_steps) {
+                            if let Some(steps) = str::from_utf8(&e.message)
+                                .ok()
+                                .and_then(|s| str::parse::<u32>(s).ok())
+                            {
+                                send_command(axis, Source::Network, Command::MoveToStep(steps));
+                            } else {
+                                error!("Received invalid step count: {:?}", e.message);
+                                break;
+                            }
+                        } else if let Some(axis) = axis_of(|t| &t.tilt_command) {
+                            if let Some(percent) = str::from_utf8(&e.message)
+                                .ok()
+                                .and_then(|s| str::parse::<u8>(s).ok())
+                                .filter(|p| *p <= 100)
+                            {
+                                send_command(axis, Source::Network, Command::Tilt(percent));
+                            } else {
+                                error!("Received invalid tilt: {:?}", e.message);
+                                break;
+                            }
+                        } else if let Some(axis) = axis_of(|t| &t.invert_percent) {
+                            match str::from_utf8(&e.message) {
+                                Ok("true") => send_command(
+                                    axis,
+                                    Source::Network,
+                                    Command::SetInvertPercent(true),
+                                ),
+                                Ok("false") => send_command(
+                                    axis,
+                                    Source::Network,
+                                    Command::SetInvertPercent(false),
+                                ),
+                                _ => {
+                                    error!("Received invalid bool: {:?}", e.message);
+                                    break;
+                                }
+                            }
+                        } else if let Some(axis) = axis_of(|t| &t.config) {
+                            match SettingsUpdate::parse(&e.message) {
+                                Some(update) => {
+                                    send_command(axis, Source::Network, Command::Configure(update))
+                                }
+                                None => error!("Received invalid config: {:?}", e.message),
+                            }
+                        } else if let Some(axis) = axis_of(|t| &t.schedule) {
+                            match Schedule::parse(&e.message) {
+                                Some(schedule) => set_schedule(axis, schedule).await,
+                                None => error!("Received invalid schedule: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.network) {
+                            match NetworkConfig::parse(&e.message) {
+                                Some(config) => set_network_config(config).await,
+                                None => error!("Received invalid network config: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.presets) {
+                            match Presets::parse(&e.message) {
+                                Some(presets) => set_presets(presets).await,
+                                None => error!("Received invalid presets: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.quiet_hours) {
+                            match QuietHours::parse(&e.message) {
+                                Some(quiet) => set_quiet_hours(quiet).await,
+                                None => error!("Received invalid quiet hours: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.lockout) {
+                            match Lockout::parse(&e.message) {
+                                Some(lockout) => set_lockout(lockout).await,
+                                None => error!("Received invalid lockout: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.lux) {
+                            // lux sensors often report fractional values, we only need integers.
+                            match str::from_utf8(&e.message)
+                                .ok()
+                                .and_then(|s| s.split('.').next())
+                                .and_then(|s| s.parse::<u32>().ok())
+                            {
+                                Some(lux) => {
+                                    if let Some(command) = solar.on_lux(lux) {
+                                        (0..AXES).for_each(|axis| {
+                                            send_command(axis, Source::Automation, command)
+                                        });
+                                    }
+                                }
+                                None => error!("Received invalid lux: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.battery_voltage) {
+                            match str::from_utf8(&e.message)
+                                .ok()
+                                .and_then(|s| s.parse::<f32>().ok())
+                            {
+                                Some(volts) => {
+                                    BATTERY_MV.store((volts * 1000.0) as u32, Ordering::Relaxed)
+                                }
+                                None => error!("Received invalid voltage: {:?}", e.message),
+                            }
+                        } else if e.topic == mqtt_string(&board_topics.hvac_mode) {
+                            match str::from_utf8(&e.message).ok().and_then(HvacMode::parse) {
+                                Some(mode) => {
+                                    if let Some(command) = solar.on_hvac_mode(mode) {
+                                        (0..AXES).for_each(|axis| {
+                                            send_command(axis, Source::Automation, command)
+                                        });
+                                    }
+                                }
+                                None => error!("Received invalid hvac mode: {:?}", e.message),
+                            }
+                        } else if EXPERT_MODE && e.topic == mqtt_string(&board_topics.tmc) {
+                            let Some(request) =
+                                str::from_utf8(&e.message).ok().and_then(parse_tmc_request)
+                            else {
+                                error!("Received invalid tmc request: {:?}", e.message);
+                                continue;
+                            };
+                            let reply = match tmc_request(request).await {
+                                Some(value) => {
+                                    format!(32; "{:#04x} = {:#010x}", request.register, value)
+                                }
+                                None => format!(32; "{:#04x} failed", request.register),
+                            }
+                            .unwrap();
+                            let options = PublicationOptions {
+                                retain: false,
+                                topic: topic_name(&board_topics.tmc_reply),
+                                qos: QoS::AtMostOnce,
+                            };
+                            if let Err(e) = client
+                                .publish(&options, Bytes::Borrowed(reply.as_bytes()))
+                                .await
+                            {
+                                error!("failed to publish: {:?}", e);
+                                break;
+                            }
+                        } else if TEST_RIG && e.topic == mqtt_string(&board_topics.rig_endstop) {
+                            let (home, bottom) = match str::from_utf8(&e.message) {
+                                Ok("home") => (true, false),
+                                Ok("bottom") => (false, true),
+                                Ok("none") => (false, false),
+                                _ => {
+                                    error!("Received invalid endstop: {:?}", e.message);
+                                    continue;
+                                }
+                            };
+                            SIM_HOME_ENDSTOP.store(home, Ordering::Relaxed);
+                            SIM_BOTTOM_ENDSTOP.store(bottom, Ordering::Relaxed);
+                        } else if TEST_RIG && e.topic == mqtt_string(&board_topics.rig_stall) {
+                            info!("Simulating a stall");
+                            SIM_STALL.store(true, Ordering::Relaxed);
+                        } else if TEST_RIG && e.topic == mqtt_string(&board_topics.rig_query) {
+                            for axis in 0..AXES {
+                                let state = rig_state(axis, last_pos(axis));
+                                if let Err(e) = client
+                                    .publish(
+                                        &rig_state_pub_options,
+                                        Bytes::Borrowed(state.as_bytes()),
+                                    )
+                                    .await
+                                {
+                                    error!("failed to publish: {:?}", e);
+                                    break 'session;
+                                }
+                            }
                         };
+                        // the message may have queued a command, or replaced or flushed some.
+                        for axis in 0..AXES {
+                            let queue = queue_state(axis);
+                            if reported_queues[axis] == Some(queue) {
+                                continue;
+                            }
+                            if let Err(e) = client
+                                .publish(
+                                    &state_options(&axis_topics[axis].queue),
+                                    Bytes::Borrowed(queue.json().as_bytes()),
+                                )
+                                .await
+                            {
+                                error!("failed to publish: {:?}", e);
+                                break 'session;
+                            }
+                            reported_queues[axis] = Some(queue);
+                        }
                     }
                     Ok(e) => info!("Received Event {:?}", e),
                     Err(e) => {
@@ -199,21 +800,462 @@ pub(crate) async fn mqtt_task(stack: Stack<'static>) {
                         break;
                     }
                 },
-                Either3::Third(pos) => {
-                    let payload = format!(4; "{}", pos).unwrap();
-                    let payload = Bytes::Borrowed(payload.as_bytes());
-                    if let Err(e) = client.publish(&pub_options, payload).await {
+                Either4::Third(Either4::Fourth(Either3::Second(diagnostics))) => {
+                    if let Err(e) = client
+                        .publish(
+                            &state_options(&board_topics.diagnostics),
+                            Bytes::Borrowed(diagnostics.json().as_bytes()),
+                        )
+                        .await
+                    {
                         error!("failed to publish: {:?}", e);
                         break;
-                    } else {
-                        info!("publised pos")
+                    }
+                }
+                Either4::Third(Either4::Fourth(Either3::Third((axis, recovery)))) => {
+                    let options = PublicationOptions {
+                        retain: false,
+                        topic: topic_name(&axis_topics[axis].recovery),
+                        qos: QoS::AtMostOnce,
+                    };
+                    if let Err(e) = client
+                        .publish(&options, Bytes::Borrowed(recovery.name().as_bytes()))
+                        .await
+                    {
+                        error!("failed to publish: {:?}", e);
+                        break;
+                    }
+                }
+                Either4::Third(Either4::Fourth(Either3::First(calibration))) => {
+                    let payload = format!(
+                        80;
+                        "{{\"travel\":{},\"fullsteps\":{},\"found_by\":\"{}\"}}",
+                        calibration.travel,
+                        calibration.fullsteps,
+                        calibration.found.name()
+                    )
+                    .unwrap();
+                    let options = PublicationOptions {
+                        retain: false,
+                        topic: topic_name(&axis_topics[calibration.axis].calibration),
+                        qos: QoS::AtMostOnce,
                     };
+                    if let Err(e) = client
+                        .publish(&options, Bytes::Borrowed(payload.as_bytes()))
+                        .await
+                    {
+                        error!("failed to publish: {:?}", e);
+                        break;
+                    }
+                }
+                Either4::Third(Either4::Third(_)) => {
+                    for axis in 0..AXES {
+                        let thermal: &[u8] = if THERMAL_THROTTLED[axis].load(Ordering::Relaxed) {
+                            b"throttled"
+                        } else {
+                            b"normal"
+                        };
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&axis_topics[axis].thermal),
+                                Bytes::Borrowed(thermal),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                        let homing: &[u8] = if HOMING_FAILED[axis].load(Ordering::Relaxed) {
+                            b"failed"
+                        } else {
+                            b"ok"
+                        };
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&axis_topics[axis].homing),
+                                Bytes::Borrowed(homing),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                        let Some(status) = DRIVER_STATUS[axis].lock(|s| s.get()) else {
+                            continue;
+                        };
+                        let payload = driver_status_json(status);
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&axis_topics[axis].driver_status),
+                                Bytes::Borrowed(payload.as_bytes()),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                }
+                Either4::Third(Either4::Second(level)) => {
+                    let payload = Bytes::Borrowed(level.name().as_bytes());
+                    if let Err(e) = client
+                        .publish(&state_options(&board_topics.derating), payload)
+                        .await
+                    {
+                        error!("failed to publish: {:?}", e);
+                        break;
+                    }
+                }
+                Either4::Third(Either4::First((pos, axis))) => {
+                    let topics = &axis_topics[axis];
+                    let percent = format!(10; "{}", invert_percent(axis, pos.percent)).unwrap();
+                    let steps = pos.steps.map(|s| format!(10; "{}", s).unwrap());
+                    let permille =
+                        format!(10; "{}", invert_permille(axis, pos.permille).get()).unwrap();
+                    let travel_limit = format!(10; "{}", pos.travel_limit).unwrap();
+                    let speed = format!(10; "{}", pos.velocity).unwrap();
+                    let tilt = tilt(axis).map(|t| format!(10; "{}", t).unwrap());
+                    let obstructed = obstructed(axis).map(|o| format!(10; "{}", o).unwrap());
+                    for (options, payload) in [
+                        (state_options(&topics.pos), Some(percent)),
+                        (state_options(&topics.pos_steps), steps),
+                        (state_options(&topics.pos_permille), Some(permille)),
+                        (state_options(&topics.travel_limit), Some(travel_limit)),
+                        (state_options(&topics.speed), Some(speed)),
+                        (state_options(&topics.tilt), tilt),
+                        (state_options(&topics.obstructed), obstructed),
+                    ] {
+                        // unknown step positions, and whatever the blind has no hardware for, are
+                        // simply not published.
+                        let Some(payload) = payload else { continue };
+                        let payload = Bytes::Borrowed(payload.as_bytes());
+                        if let Err(e) = client.publish(&options, payload).await {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                    if let Some(stats) = stats(axis) {
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&topics.stats),
+                                Bytes::Borrowed(stats.json().as_bytes()),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                    // the move that just finished has come off the queue.
+                    let queue = queue_state(axis);
+                    if reported_queues[axis] != Some(queue) {
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&topics.queue),
+                                Bytes::Borrowed(queue.json().as_bytes()),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                        reported_queues[axis] = Some(queue);
+                    }
+                    #[cfg(feature = "ramp_telemetry")]
+                    if let Some(ramp) = take_ramp(axis) {
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&topics.ramp),
+                                Bytes::Borrowed(ramp_json(&ramp).as_bytes()),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                    if let Some(jitter) = take_jitter(axis) {
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&topics.step_jitter),
+                                Bytes::Borrowed(jitter.json().as_bytes()),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                    if let Some(latency) = local_latency() {
+                        let payload = format!(
+                            48;
+                            "{{\"last_ms\":{},\"max_ms\":{}}}",
+                            latency.last.as_millis(),
+                            latency.max.as_millis()
+                        )
+                        .unwrap();
+                        if let Err(e) = client
+                            .publish(
+                                &state_options(&board_topics.local_latency),
+                                Bytes::Borrowed(payload.as_bytes()),
+                            )
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                    if TEST_RIG {
+                        let state = rig_state(axis, Some(pos));
+                        if let Err(e) = client
+                            .publish(&rig_state_pub_options, Bytes::Borrowed(state.as_bytes()))
+                            .await
+                        {
+                            error!("failed to publish: {:?}", e);
+                            break 'session;
+                        }
+                    }
+                    info!("publised pos")
+                }
+                Either4::Fourth(_) => {
+                    if let Err(e) = client
+                        .publish(&availability_options, Bytes::Borrowed(b"offline"))
+                        .await
+                    {
+                        error!("failed to publish: {:?}", e);
+                    }
+                    if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
+                        error!("error aborting connection: {:?}", e);
+                    };
+                    set_connectivity(Connectivity::Offline);
+                    info!("MQTT shut down");
+                    unwatch(Watched::Mqtt);
+                    return;
                 }
             };
         }
+        set_connectivity(if stack.is_link_up() {
+            Connectivity::Wifi
+        } else {
+            Connectivity::Offline
+        });
         if let Err(e) = client.abort().with_timeout(Duration::from_secs(5)).await {
             error!("error aborting connection: {:?}", e);
             continue;
         };
     }
 }
+
+/// Converts between the percentages we use internally (0 = home) and the user facing ones,
+/// flipping them if the user has asked for that. Works both ways.
+pub(crate) fn invert_percent(axis: usize, percent: i8) -> i8 {
+    if INVERT_PERCENT[axis].load(Ordering::Relaxed) {
+        100_i8.saturating_sub(percent)
+    } else {
+        percent
+    }
+}
+
+/// As [`invert_percent`], for finer positions.
+pub(crate) fn invert_permille(axis: usize, permille: Permille) -> Permille {
+    if INVERT_PERCENT[axis].load(Ordering::Relaxed) {
+        permille.inverted()
+    } else {
+        permille
+    }
+}
+
+/// Parses a percentage with up to one decimal place, like `42` or `42.5`.
+fn parse_percent(str: &str) -> Option<Permille> {
+    let (whole, tenths) = match str.split_once('.') {
+        Some((whole, tenths)) if tenths.len() == 1 => (whole, tenths.parse::<u16>().ok()?),
+        Some(_) => return None,
+        None => (str, 0),
+    };
+    Permille::new(whole.parse::<u16>().ok()?.checked_mul(10)? + tenths)
+}
+
+/// The fault flags from DRV_STATUS, with the two phases of each folded together.
+fn driver_status_json(status: DrvStatus) -> String<128> {
+    format!(
+        128;
+        "{{\"overtemp_warning\":{},\"overtemp\":{},\"short_to_ground\":{},\
+        \"short_to_supply\":{},\"open_load\":{}}}",
+        status.otpw(),
+        status.ot(),
+        status.short_to_ground(),
+        status.short_to_supply(),
+        status.open_load(),
+    )
+    .unwrap()
+}
+
+// long enough for every sample at its longest, `[4294967295,18446744073709551615],`.
+#[cfg(feature = "ramp_telemetry")]
+const RAMP_JSON_LEN: usize = 96 + RAMP_SAMPLES * 34;
+
+/// The delays of a planned move, like `{"steps":1000,"decimation":8,"samples":[[0,15811],...]}`,
+/// with the delays in microseconds.
+#[cfg(feature = "ramp_telemetry")]
+fn ramp_json(ramp: &RampRecorder) -> String<RAMP_JSON_LEN> {
+    use core::fmt::Write;
+
+    let mut json = format!(
+        RAMP_JSON_LEN;
+        "{{\"steps\":{},\"decimation\":{},\"samples\":[",
+        ramp.steps(),
+        ramp.decimation(),
+    )
+    .unwrap();
+    for (i, (step, delay)) in ramp.samples().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        // can't overflow, see RAMP_JSON_LEN.
+        write!(json, "{}[{},{}]", separator, step, delay.as_micros()).unwrap();
+    }
+    json.push_str("]}").unwrap();
+    json
+}
+
+/// Internal state for test rigs, as space separated `key=value` pairs.
+fn rig_state(axis: usize, pos: Option<Position>) -> String<128> {
+    let steps = pos.and_then(|p| p.steps);
+    format!(
+        128;
+        "axis={} percent={} steps={} travel_limit={} fault={} invert={} sim_home={} sim_bottom={}",
+        axis,
+        // -1 if unknown.
+        pos.map_or(-1, |p| p.percent),
+        steps.map_or(-1, i64::from),
+        pos.map_or(0, |p| p.travel_limit),
+        DRIVER_FAULT.load(Ordering::Relaxed),
+        INVERT_PERCENT[axis].load(Ordering::Relaxed),
+        SIM_HOME_ENDSTOP.load(Ordering::Relaxed),
+        SIM_BOTTOM_ENDSTOP.load(Ordering::Relaxed),
+    )
+    .unwrap()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    /// To `pos`, a user facing percentage, or `permille` for finer positions.
+    Move,
+    /// To `steps` from home.
+    MoveSteps,
+    Home,
+    /// Towards `dir` until `stop_jog` or `stop`.
+    Jog,
+    StopJog,
+    Stop,
+    SetBottom,
+    Calibrate,
+    /// Sets percentage inversion to `invert`.
+    InvertPercent,
+    /// Applies `settings`, as on the config topic.
+    Configure,
+    Shutdown,
+    SavePosition,
+    /// To the preset called `name`.
+    Preset,
+    /// The slats to `pos`, a percentage of their tilt.
+    Tilt,
+    SetTilt,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JogDir {
+    Up,
+    Down,
+}
+
+/// A command as JSON, so every command can be sent on the command topic, like
+/// `{"action": "move", "pos": 42}` or `{"action": "jog", "dir": "up"}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonCommand {
+    action: Action,
+    pos: Option<i8>,
+    permille: Option<u16>,
+    // percent of the max speed, for moves.
+    speed: Option<u8>,
+    steps: Option<u32>,
+    dir: Option<JogDir>,
+    invert: Option<bool>,
+    settings: Option<SettingsUpdate>,
+    name: Option<String<NAME_LEN>>,
+}
+
+impl JsonCommand {
+    fn parse(json: &[u8]) -> Option<Self> {
+        serde_json_core::from_slice(json).ok().map(|(c, _)| c)
+    }
+
+    /// None if the action's argument is missing, or names no preset.
+    fn command(self, axis: usize) -> Option<Command> {
+        Some(match self.action {
+            Action::Move => {
+                let permille = match (self.pos, self.permille) {
+                    (_, Some(permille)) => Permille::new(permille)?,
+                    (Some(pos), None) => Permille::from_percent(pos),
+                    (None, None) => return None,
+                };
+                let permille = invert_permille(axis, permille);
+                match self.speed {
+                    Some(speed) => Command::MoveToPosAt(permille, SpeedFactor::new(speed)?),
+                    None => Command::MoveToPos(permille),
+                }
+            }
+            Action::MoveSteps => Command::MoveToStep(self.steps?),
+            Action::Home => Command::Home,
+            // up is always towards home, as the blind rolls up to it.
+            Action::Jog => Command::StartJog(match self.dir? {
+                JogDir::Up => Direction::ToHome,
+                JogDir::Down => Direction::AwayFromHome,
+            }),
+            Action::StopJog => Command::StopJog,
+            Action::Stop => Command::Stop,
+            Action::SetBottom => Command::SetBottom,
+            Action::Calibrate => Command::Calibrate,
+            Action::InvertPercent => Command::SetInvertPercent(self.invert?),
+            Action::Configure => Command::Configure(self.settings?),
+            Action::Shutdown => Command::Shutdown,
+            Action::SavePosition => Command::SavePosition,
+            Action::Preset => recall_preset(axis, &self.name?)?,
+            Action::Tilt => Command::Tilt(u8::try_from(self.pos?).ok().filter(|p| *p <= 100)?),
+            Action::SetTilt => Command::SetTilt,
+        })
+    }
+}
+
+/// Parses `read <reg>` or `write <reg> <val>`, with numbers in decimal, or hex/binary with a
+/// `0x`/`0b` prefix.
+fn parse_tmc_request(request: &str) -> Option<TmcRequest> {
+    fn parse_int(int: &str) -> Option<u32> {
+        if let Some(hex) = int.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16).ok()
+        } else if let Some(bin) = int.strip_prefix("0b") {
+            u32::from_str_radix(bin, 2).ok()
+        } else {
+            int.parse().ok()
+        }
+    }
+
+    let mut words = request.split_whitespace();
+    let action = words.next()?;
+    let register = parse_int(words.next()?)?.try_into().ok()?;
+    let value = match action {
+        "read" => None,
+        "write" => Some(parse_int(words.next()?)?),
+        _ => return None,
+    };
+    if words.next().is_some() {
+        return None;
+    }
+    // raw access always goes to the first driver found.
+    Some(TmcRequest {
+        addr: None,
+        register,
+        value,
+    })
+}
@@ -0,0 +1,250 @@
+use core::{
+    cell::Cell,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use defmt::{Format, error, info};
+use embassy_net::{
+    Config, ConfigV6, DhcpConfig, Ipv4Cidr, Ipv6Cidr, StaticConfigV4, StaticConfigV6,
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::Vec;
+use serde::Deserialize;
+
+use crate::{
+    ErrorSeverity,
+    config_store::{NETWORK_KEY, fetch_board_item, store_board_item},
+    mdns::HOSTNAME,
+    state::{confirm, raise_error},
+};
+
+// whether each part is set, then the IPv4 address, prefix, gateway and DNS server, then the IPv6
+// address, prefix and gateway.
+pub(crate) const NETWORK_BYTES: usize = 2 + 4 + 1 + 4 + 4 + 16 + 1 + 16;
+
+// Loaded from flash by main for building the network stack, and changed on the `network` topic
+// or the portal for the next boot.
+static NETWORK: Mutex<CriticalSectionRawMutex, Cell<NetworkConfig>> =
+    Mutex::new(Cell::new(NetworkConfig::DHCP));
+
+/// How the board gets its addresses. DHCP for IPv4 and no IPv6 unless set otherwise.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct NetworkConfig {
+    ipv4: Option<StaticIpv4>,
+    ipv6: Option<StaticIpv6>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+struct StaticIpv4 {
+    address: Ipv4Addr,
+    prefix_len: u8,
+    gateway: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+struct StaticIpv6 {
+    address: Ipv6Addr,
+    prefix_len: u8,
+    gateway: Option<Ipv6Addr>,
+}
+
+/// As taken on the `network` topic, every field optional.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NetworkJson<'a> {
+    address: Option<&'a str>,
+    gateway: Option<&'a str>,
+    dns: Option<&'a str>,
+    ipv6_address: Option<&'a str>,
+    ipv6_gateway: Option<&'a str>,
+}
+
+impl NetworkConfig {
+    pub(crate) const DHCP: Self = Self {
+        ipv4: None,
+        ipv6: None,
+    };
+
+    /// Builds a config from its fields as text, None if any of them don't make sense. Empty
+    /// fields count as not given, and addresses can have a prefix length, like `/24`.
+    pub(crate) fn from_fields(
+        address: Option<&str>,
+        gateway: Option<&str>,
+        dns: Option<&str>,
+        ipv6_address: Option<&str>,
+        ipv6_gateway: Option<&str>,
+    ) -> Option<Self> {
+        let given = |field: Option<&str>| field.map(str::trim).filter(|f| !f.is_empty());
+        let (address, gateway, dns) = (given(address), given(gateway), given(dns));
+        let ipv4 = match address {
+            Some(address) => {
+                let (address, prefix_len) = with_prefix(address, 24, 32)?;
+                Some(StaticIpv4 {
+                    address,
+                    prefix_len,
+                    gateway: gateway.map(str::parse).transpose().ok()?,
+                    dns: dns.map(str::parse).transpose().ok()?,
+                })
+            }
+            // a gateway or DNS server with DHCP would just be ignored.
+            None if gateway.is_some() || dns.is_some() => return None,
+            None => None,
+        };
+        let ipv6_gateway = given(ipv6_gateway);
+        let ipv6 = match given(ipv6_address) {
+            Some(address) => {
+                let (address, prefix_len) = with_prefix(address, 64, 128)?;
+                Some(StaticIpv6 {
+                    address,
+                    prefix_len,
+                    gateway: ipv6_gateway.map(str::parse).transpose().ok()?,
+                })
+            }
+            None if ipv6_gateway.is_some() => return None,
+            None => None,
+        };
+        Some(Self { ipv4, ipv6 })
+    }
+
+    /// Parses a JSON object, like `{"address": "192.168.1.50/24", "gateway": "192.168.1.1",
+    /// "dns": "192.168.1.1"}`, or `{}` to go back to DHCP.
+    pub(crate) fn parse(json: &[u8]) -> Option<Self> {
+        let (json, _): (NetworkJson, _) = serde_json_core::from_slice(json).ok()?;
+        Self::from_fields(
+            json.address,
+            json.gateway,
+            json.dns,
+            json.ipv6_address,
+            json.ipv6_gateway,
+        )
+    }
+
+    /// The network stack's config, with our hostname for the DHCP server.
+    pub(crate) fn stack_config(&self) -> Config {
+        let mut config = match self.ipv4 {
+            Some(ipv4) => Config::ipv4_static(StaticConfigV4 {
+                address: Ipv4Cidr::new(ipv4.address, ipv4.prefix_len),
+                gateway: ipv4.gateway,
+                dns_servers: ipv4.dns.into_iter().collect(),
+            }),
+            None => {
+                let mut dhcp = DhcpConfig::default();
+                // can't fail, HOSTNAME is checked to fit.
+                dhcp.hostname = HOSTNAME.try_into().ok();
+                Config::dhcpv4(dhcp)
+            }
+        };
+        if let Some(ipv6) = self.ipv6 {
+            config.ipv6 = ConfigV6::Static(StaticConfigV6 {
+                address: Ipv6Cidr::new(ipv6.address, ipv6.prefix_len),
+                gateway: ipv6.gateway,
+                dns_servers: Default::default(),
+            });
+        }
+        config
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8, NETWORK_BYTES> {
+        let mut bytes = Vec::new();
+        let ipv4 = self.ipv4.unwrap_or(StaticIpv4 {
+            address: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            gateway: None,
+            dns: None,
+        });
+        let ipv6 = self.ipv6.unwrap_or(StaticIpv6 {
+            address: Ipv6Addr::UNSPECIFIED,
+            prefix_len: 0,
+            gateway: None,
+        });
+        let v4 = |a: Option<Ipv4Addr>| a.unwrap_or(Ipv4Addr::UNSPECIFIED).octets();
+        // can't overflow, it adds up to NETWORK_BYTES.
+        bytes
+            .extend_from_slice(&[self.ipv4.is_some().into(), self.ipv6.is_some().into()])
+            .unwrap();
+        bytes.extend_from_slice(&ipv4.address.octets()).unwrap();
+        bytes.push(ipv4.prefix_len).unwrap();
+        bytes.extend_from_slice(&v4(ipv4.gateway)).unwrap();
+        bytes.extend_from_slice(&v4(ipv4.dns)).unwrap();
+        bytes.extend_from_slice(&ipv6.address.octets()).unwrap();
+        bytes.push(ipv6.prefix_len).unwrap();
+        bytes
+            .extend_from_slice(&ipv6.gateway.unwrap_or(Ipv6Addr::UNSPECIFIED).octets())
+            .unwrap();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; NETWORK_BYTES] = bytes.try_into().ok()?;
+        let v4 = |at: usize| {
+            let a = Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[at..at + 4]).unwrap());
+            (!a.is_unspecified()).then_some(a)
+        };
+        let v6 = |at: usize| {
+            let a = Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[at..at + 16]).unwrap());
+            (!a.is_unspecified()).then_some(a)
+        };
+        Some(Self {
+            ipv4: (bytes[0] != 0).then(|| StaticIpv4 {
+                address: v4(2).unwrap_or(Ipv4Addr::UNSPECIFIED),
+                prefix_len: bytes[6],
+                gateway: v4(7),
+                dns: v4(11),
+            }),
+            ipv6: (bytes[1] != 0).then(|| StaticIpv6 {
+                address: v6(15).unwrap_or(Ipv6Addr::UNSPECIFIED),
+                prefix_len: bytes[31],
+                gateway: v6(32),
+            }),
+        })
+    }
+}
+
+/// Splits off an address's prefix length, if it has one.
+fn with_prefix<A: core::str::FromStr>(text: &str, default: u8, max: u8) -> Option<(A, u8)> {
+    let (address, prefix_len) = match text.split_once('/') {
+        Some((address, prefix_len)) => (address, prefix_len.parse().ok()?),
+        None => (text, default),
+    };
+    if prefix_len > max {
+        return None;
+    }
+    Some((address.parse().ok()?, prefix_len))
+}
+
+pub(crate) fn network_config() -> NetworkConfig {
+    NETWORK.lock(|n| n.get())
+}
+
+/// Loads the network config for building the stack. For main only, after setting up the storage.
+pub(crate) async fn load_network_config() -> NetworkConfig {
+    let config = match fetch_board_item::<Vec<u8, NETWORK_BYTES>>(NETWORK_KEY).await {
+        Ok(bytes) => bytes
+            .and_then(|b| NetworkConfig::from_bytes(&b))
+            .unwrap_or(NetworkConfig::DHCP),
+        Err(_) => {
+            error!("Error getting item in flash");
+            raise_error(ErrorSeverity::Soft);
+            NetworkConfig::DHCP
+        }
+    };
+    NETWORK.lock(|n| n.set(config));
+    config
+}
+
+/// Saves a new network config for the next boot, if it is any different. The stack is only built
+/// at boot, so it doesn't change anything until then.
+pub(crate) async fn set_network_config(config: NetworkConfig) {
+    if NETWORK.lock(|n| n.replace(config)) == config {
+        return;
+    }
+    info!("New network config, applied on the next boot: {}", config);
+    match store_board_item(NETWORK_KEY, &config.to_bytes()).await {
+        Ok(()) => confirm(),
+        Err(_) => {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Soft);
+        }
+    }
+}
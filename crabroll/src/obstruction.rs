@@ -0,0 +1,213 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{Format, error, info};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{
+    Async,
+    gpio::Input,
+    i2c::master::{self, I2c},
+};
+
+// Set `obstruction_back_off` in the board file (or OBSTRUCTION_BACK_OFF when building) to change
+// how many fullsteps the blind backs off upwards after an obstruction stops it.
+pub(crate) const BACK_OFF: u32 = match option_env!("OBSTRUCTION_BACK_OFF") {
+    Some(steps) => match u32::from_str_radix(steps, 10) {
+        Ok(steps) => steps,
+        _ => panic!("OBSTRUCTION_BACK_OFF must be a whole number"),
+    },
+    None => 64,
+};
+// Set `obstruction_mm` in the board file (or OBSTRUCTION_MM when building) to change how close
+// something has to be to the time-of-flight sensor to count as in the way.
+const THRESHOLD_MM: u16 = match option_env!("OBSTRUCTION_MM") {
+    Some(mm) => match u16::from_str_radix(mm, 10) {
+        Ok(mm) if mm > 0 => mm,
+        _ => panic!("OBSTRUCTION_MM must be a whole number above 0"),
+    },
+    None => 100,
+};
+// The sensor watches under the first axis's blind.
+const OBSTRUCTION_AXIS: usize = 0;
+const HAS_SENSOR: bool = cfg!(obstruction) || cfg!(tof);
+
+// The VL53L0X's fixed I2C address, and the registers we use.
+const VL53L0X_ADDR: u8 = 0x29;
+const SYSRANGE_START: u8 = 0x00;
+const SYSTEM_SEQUENCE_CONFIG: u8 = 0x01;
+const SYSTEM_INTERRUPT_CONFIG_GPIO: u8 = 0x0a;
+const SYSTEM_INTERRUPT_CLEAR: u8 = 0x0b;
+const RESULT_INTERRUPT_STATUS: u8 = 0x13;
+const RESULT_RANGE_MM: u8 = 0x1e;
+const MSRC_CONFIG_CONTROL: u8 = 0x60;
+const FINAL_RANGE_MIN_SIGNAL_RATE: u8 = 0x44;
+const GPIO_HV_MUX_ACTIVE_HIGH: u8 = 0x84;
+const VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV: u8 = 0x89;
+const MODEL_ID: u8 = 0xc0;
+const VL53L0X_MODEL_ID: u8 = 0xee;
+// Once the first range is in, there is a new one about every 30ms.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const READY_TIMEOUT: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Format)]
+enum TofError {
+    I2c(master::Error),
+    /// Something else answered at the VL53L0X's address, with this model ID.
+    WrongModel(u8),
+    /// A range or calibration didn't finish.
+    Timeout,
+}
+
+impl From<master::Error> for TofError {
+    fn from(e: master::Error) -> Self {
+        Self::I2c(e)
+    }
+}
+
+// Whether the sensor sees something in the way right now.
+static DETECTED: AtomicBool = AtomicBool::new(false);
+// Whether the last move down was stopped by an obstruction, for MQTT.
+static OBSTRUCTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether an axis's sensor sees something in the way. Always false for axes without one.
+pub(crate) fn detected(axis: usize) -> bool {
+    axis == OBSTRUCTION_AXIS && DETECTED.load(Ordering::Relaxed)
+}
+
+/// For the motor task after every move down.
+pub(crate) fn set_obstructed(axis: usize, obstructed: bool) {
+    if axis == OBSTRUCTION_AXIS {
+        OBSTRUCTED.store(obstructed, Ordering::Relaxed);
+    }
+}
+
+/// Whether the last move down was stopped by an obstruction, None for axes without a sensor.
+pub(crate) fn obstructed(axis: usize) -> Option<bool> {
+    (HAS_SENSOR && axis == OBSTRUCTION_AXIS).then(|| OBSTRUCTED.load(Ordering::Relaxed))
+}
+
+/// Follows a pulled up sensor that pulls its output low while something is in the way, like an
+/// IR beam across the window.
+#[embassy_executor::task]
+pub(crate) async fn obstruction_task(mut pin: Input<'static>) {
+    loop {
+        DETECTED.store(pin.is_low(), Ordering::Relaxed);
+        pin.wait_for_any_edge().await;
+    }
+}
+
+/// Ranges continuously with a VL53L0X, counting anything closer than [`THRESHOLD_MM`] as in the
+/// way.
+#[embassy_executor::task]
+pub(crate) async fn tof_task(mut i2c: I2c<'static, Async>) {
+    loop {
+        match range_continuously(&mut i2c).await {
+            Ok(()) => info!("VL53L0X started ranging"),
+            Err(e) => {
+                error!("Error starting the VL53L0X: {}", e);
+                Timer::after(RETRY_INTERVAL).await;
+                continue;
+            }
+        }
+        loop {
+            match read_range(&mut i2c).await {
+                Ok(mm) => DETECTED.store(mm < THRESHOLD_MM, Ordering::Relaxed),
+                Err(e) => {
+                    // a broken sensor shouldn't keep the blind from closing.
+                    error!("Error reading the VL53L0X: {}", e);
+                    DETECTED.store(false, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
+
+async fn write(i2c: &mut I2c<'static, Async>, register: u8, value: u8) -> Result<(), TofError> {
+    Ok(i2c.write_async(VL53L0X_ADDR, &[register, value]).await?)
+}
+
+async fn read(i2c: &mut I2c<'static, Async>, register: u8) -> Result<u8, TofError> {
+    let mut value = [0];
+    i2c.write_read_async(VL53L0X_ADDR, &[register], &mut value)
+        .await?;
+    Ok(value[0])
+}
+
+/// Reads the private stop variable, or writes it back, behind the page the sensor hides it on.
+async fn stop_variable(i2c: &mut I2c<'static, Async>, value: Option<u8>) -> Result<u8, TofError> {
+    for (register, value) in [(0x80, 0x01), (0xff, 0x01), (0x00, 0x00)] {
+        write(i2c, register, value).await?;
+    }
+    let value = match value {
+        Some(value) => {
+            write(i2c, 0x91, value).await?;
+            value
+        }
+        None => read(i2c, 0x91).await?,
+    };
+    for (register, value) in [(0x00, 0x01), (0xff, 0x00), (0x80, 0x00)] {
+        write(i2c, register, value).await?;
+    }
+    Ok(value)
+}
+
+/// Waits for a range or calibration to finish.
+async fn wait_ready(i2c: &mut I2c<'static, Async>) -> Result<(), TofError> {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    while read(i2c, RESULT_INTERRUPT_STATUS).await? & 0x07 == 0 {
+        if Instant::now() > deadline {
+            return Err(TofError::Timeout);
+        }
+        Timer::after(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// Sets the sensor up and starts it ranging back to back. This is the bare minimum of ST's
+/// bring up, without their tuning or SPAD calibration, so ranges are rough, but plenty for telling
+/// whether something is under the blind.
+async fn range_continuously(i2c: &mut I2c<'static, Async>) -> Result<(), TofError> {
+    let model = read(i2c, MODEL_ID).await?;
+    if model != VL53L0X_MODEL_ID {
+        return Err(TofError::WrongModel(model));
+    }
+    // 2.8V I/O, and standard mode I2C.
+    let pads = read(i2c, VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV).await?;
+    write(i2c, VHV_CONFIG_PAD_SCL_SDA_EXTSUP_HV, pads | 0x01).await?;
+    write(i2c, 0x88, 0x00).await?;
+    let stop = stop_variable(i2c, None).await?;
+    // no signal rate limits before the final range, and 0.25 MCPS for it.
+    let msrc = read(i2c, MSRC_CONFIG_CONTROL).await?;
+    write(i2c, MSRC_CONFIG_CONTROL, msrc | 0x12).await?;
+    i2c.write_async(VL53L0X_ADDR, &[FINAL_RANGE_MIN_SIGNAL_RATE, 0x00, 0x20])
+        .await?;
+    // a new sample ready interrupt, active low.
+    write(i2c, SYSTEM_INTERRUPT_CONFIG_GPIO, 0x04).await?;
+    let mux = read(i2c, GPIO_HV_MUX_ACTIVE_HIGH).await?;
+    write(i2c, GPIO_HV_MUX_ACTIVE_HIGH, mux & !0x10).await?;
+    write(i2c, SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+    // the VHV and phase calibrations, each on its own, then the normal sequence of steps.
+    for (sequence, start) in [(0x01, 0x40), (0x02, 0x00)] {
+        write(i2c, SYSTEM_SEQUENCE_CONFIG, sequence).await?;
+        write(i2c, SYSRANGE_START, 0x01 | start).await?;
+        wait_ready(i2c).await?;
+        write(i2c, SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+        write(i2c, SYSRANGE_START, 0x00).await?;
+    }
+    write(i2c, SYSTEM_SEQUENCE_CONFIG, 0xe8).await?;
+    stop_variable(i2c, Some(stop)).await?;
+    // back to back.
+    write(i2c, SYSRANGE_START, 0x02).await
+}
+
+/// Waits for the next range, in mm.
+async fn read_range(i2c: &mut I2c<'static, Async>) -> Result<u16, TofError> {
+    wait_ready(i2c).await?;
+    let mut range = [0; 2];
+    i2c.write_read_async(VL53L0X_ADDR, &[RESULT_RANGE_MM], &mut range)
+        .await?;
+    write(i2c, SYSTEM_INTERRUPT_CLEAR, 0x01).await?;
+    Ok(u16::from_be_bytes(range))
+}
@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use defmt::info;
+use embassy_futures::select::{Either, select};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    gpio::RtcPinWithResistors,
+    peripherals::LPWR,
+    rtc_cntl::{
+        Rtc, SleepSource,
+        sleep::{RtcioWakeupSource, TimerWakeupSource, WakeupLevel},
+        wakeup_cause,
+    },
+};
+
+use crate::{
+    AXES, Command, DRIVERS_OFF,
+    arbiter::Source,
+    board::{HomeButton, LowerButton, RaiseButton},
+    state::send_command,
+};
+
+// Set SLEEP_AFTER_SECS when building to deep sleep after that long without a command, for battery
+// powered blinds.
+pub(crate) const SLEEP_AFTER: Option<Duration> = match option_env!("SLEEP_AFTER_SECS") {
+    Some(secs) => Some(Duration::from_secs(parse_secs(secs))),
+    None => None,
+};
+// Set SLEEP_WAKE_SECS when building to change how often a sleeping board wakes to check the
+// broker for retained commands.
+const SLEEP_WAKE: core::time::Duration = match option_env!("SLEEP_WAKE_SECS") {
+    Some(secs) => core::time::Duration::from_secs(parse_secs(secs)),
+    None => core::time::Duration::from_secs(15 * 60),
+};
+
+const fn parse_secs(secs: &str) -> u64 {
+    match u64::from_str_radix(secs, 10) {
+        Ok(s) => s,
+        Err(_) => panic!("sleep times must be whole seconds"),
+    }
+}
+
+// Anything that resets the idle timer.
+static ACTIVITY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// How many things are holding the board awake, see `StayAwake`.
+static AWAKE_HOLDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps the board awake while it is held, and restarts the idle timer when dropped.
+pub(crate) struct StayAwake;
+
+impl StayAwake {
+    pub(crate) fn new() -> Self {
+        AWAKE_HOLDS.fetch_add(1, Ordering::Relaxed);
+        ACTIVITY.signal(());
+        Self
+    }
+}
+
+impl Drop for StayAwake {
+    fn drop(&mut self) {
+        AWAKE_HOLDS.fetch_sub(1, Ordering::Relaxed);
+        ACTIVITY.signal(());
+    }
+}
+
+/// Whether we booted from deep sleep, rather than being powered on or reset.
+pub(crate) fn woke_from_sleep() -> bool {
+    !matches!(wakeup_cause(), SleepSource::Undefined)
+}
+
+/// Waits for the board to go idle, then shuts the motors down like the `shutdown` command and
+/// deep sleeps. It wakes on the home, raise or lower buttons, or on a timer, booting up again as
+/// if it had been power cycled.
+#[embassy_executor::task]
+pub(crate) async fn sleep_task(lpwr: LPWR<'static>, sleep_after: Duration) {
+    loop {
+        if let Either::First(_) = select(ACTIVITY.wait(), Timer::after(sleep_after)).await {
+            continue;
+        }
+        if AWAKE_HOLDS.load(Ordering::Relaxed) == 0 {
+            break;
+        }
+    }
+    info!("Idle for {}s, going to sleep", sleep_after.as_secs());
+    // saves the positions to flash and turns the drivers off.
+    (0..AXES).for_each(|axis| send_command(axis, Source::Board, Command::Shutdown));
+    DRIVERS_OFF.wait().await;
+    // give the MQTT task a moment to disconnect cleanly.
+    Timer::after_secs(1).await;
+
+    let mut rtc = Rtc::new(lpwr);
+    let timer = TimerWakeupSource::new(SLEEP_WAKE);
+    // Safety: the button tasks are the only other users of these pins, and they won't be reading
+    // them again.
+    let (mut home, mut raise, mut lower) = unsafe {
+        (
+            HomeButton::steal(),
+            RaiseButton::steal(),
+            LowerButton::steal(),
+        )
+    };
+    let mut buttons: [(&mut dyn RtcPinWithResistors, WakeupLevel); 3] = [
+        (&mut home, WakeupLevel::Low),
+        (&mut raise, WakeupLevel::Low),
+        (&mut lower, WakeupLevel::Low),
+    ];
+    let rtcio = RtcioWakeupSource::new(&mut buttons);
+    rtc.sleep_deep(&[&timer, &rtcio]);
+}
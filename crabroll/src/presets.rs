@@ -0,0 +1,144 @@
+use core::cell::RefCell;
+
+use defmt::{Format, error, info};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::{String, Vec};
+use serde::Deserialize;
+
+use crate::{
+    Command, ErrorSeverity, Permille,
+    config_store::{PRESETS_KEY, fetch_board_item, store_board_item},
+    mqtt::invert_permille,
+    state::{confirm, raise_error},
+};
+
+// How many named positions the board keeps, shared by every axis.
+pub(crate) const MAX_PRESETS: usize = 8;
+pub(crate) const NAME_LEN: usize = 16;
+// position, name length, then the name.
+const PRESET_BYTES: usize = 2 + NAME_LEN;
+pub(crate) const PRESETS_BYTES: usize = MAX_PRESETS * PRESET_BYTES;
+
+// Loaded from flash by main, and changed on the `presets` topic.
+static PRESETS: Mutex<CriticalSectionRawMutex, RefCell<Presets>> =
+    Mutex::new(RefCell::new(Presets::EMPTY));
+
+/// A named position, as given on the `presets` topic.
+#[derive(Clone, PartialEq, Eq, Format, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Preset {
+    name: String<NAME_LEN>,
+    /// A user facing percentage, like the command topic takes.
+    pos: i8,
+}
+
+/// The named positions, in the order they were given, so the buttons can recall the first ones.
+#[derive(Clone, PartialEq, Eq, Format)]
+pub(crate) struct Presets {
+    presets: Vec<Preset, MAX_PRESETS>,
+}
+
+impl Presets {
+    const EMPTY: Self = Self {
+        presets: Vec::new(),
+    };
+
+    /// Parses a JSON list of presets, like `[{"name": "vent", "pos": 85}]`, or `[]` to clear them.
+    pub(crate) fn parse(json: &[u8]) -> Option<Self> {
+        let (presets, _): (Vec<Preset, MAX_PRESETS>, _) = serde_json_core::from_slice(json).ok()?;
+        for (i, preset) in presets.iter().enumerate() {
+            if preset.name.is_empty()
+                || !(0..=100).contains(&preset.pos)
+                || presets[..i].iter().any(|p| p.name == preset.name)
+            {
+                return None;
+            }
+        }
+        Some(Self { presets })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8, PRESETS_BYTES> {
+        let mut bytes = Vec::new();
+        for preset in &self.presets {
+            // can't overflow, every preset fits in PRESET_BYTES.
+            bytes
+                .extend_from_slice(&[preset.pos as u8, preset.name.len() as u8])
+                .unwrap();
+            bytes.extend_from_slice(preset.name.as_bytes()).unwrap();
+        }
+        bytes
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Self {
+        let mut presets = Self::EMPTY;
+        while let [pos, len, rest @ ..] = bytes {
+            let Some((name, rest)) = rest.split_at_checked(usize::from(*len)) else {
+                break;
+            };
+            let Some(name) = core::str::from_utf8(name)
+                .ok()
+                .and_then(|n| String::try_from(n).ok())
+            else {
+                break;
+            };
+            let preset = Preset {
+                name,
+                pos: *pos as i8,
+            };
+            if presets.presets.push(preset).is_err() {
+                break;
+            }
+            bytes = rest;
+        }
+        presets
+    }
+}
+
+pub(crate) fn presets() -> Presets {
+    PRESETS.lock(|p| p.borrow().clone())
+}
+
+/// Loads the presets. For main only, after setting up the storage.
+pub(crate) async fn load_presets() {
+    match fetch_board_item::<Vec<u8, PRESETS_BYTES>>(PRESETS_KEY).await {
+        Ok(Some(bytes)) => PRESETS.lock(|p| *p.borrow_mut() = Presets::from_bytes(&bytes)),
+        Ok(None) => (),
+        Err(_) => {
+            error!("Error getting item in flash");
+            raise_error(ErrorSeverity::Soft);
+        }
+    }
+}
+
+/// Changes the presets and saves them, if they are any different.
+pub(crate) async fn set_presets(presets: Presets) {
+    if PRESETS.lock(|p| p.replace(presets.clone())) == presets {
+        return;
+    }
+    info!("New presets: {}", presets);
+    match store_board_item(PRESETS_KEY, &presets.to_bytes()).await {
+        Ok(()) => confirm(),
+        Err(_) => {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Hard);
+        }
+    }
+}
+
+/// The move to the preset with this name, None if there isn't one.
+pub(crate) fn recall_preset(axis: usize, name: &str) -> Option<Command> {
+    PRESETS.lock(|p| {
+        let presets = p.borrow();
+        let preset = presets.presets.iter().find(|p| p.name.as_str() == name)?;
+        Some(move_to(axis, preset))
+    })
+}
+
+/// The move to the nth preset, for the buttons. None if there aren't that many.
+pub(crate) fn recall_nth_preset(axis: usize, n: usize) -> Option<Command> {
+    PRESETS.lock(|p| Some(move_to(axis, p.borrow().presets.get(n)?)))
+}
+
+fn move_to(axis: usize, preset: &Preset) -> Command {
+    Command::MoveToPos(invert_permille(axis, Permille::from_percent(preset.pos)))
+}
@@ -0,0 +1,438 @@
+use core::net::Ipv4Addr;
+
+use defmt::{error, info, warn};
+use embassy_futures::select::select;
+use embassy_net::{
+    IpAddress, Stack,
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::{Duration, Timer};
+use esp_hal::system::software_reset;
+use heapless::{String, Vec, format};
+
+use crate::{
+    config_store::{self, provisioning_requested, store_credentials, store_provisioning_request},
+    http::{read_request, respond},
+    network::{NetworkConfig, set_network_config},
+    strings::STRINGS,
+};
+
+// The address of the board on its own access point, and the pool it hands out to clients.
+pub(crate) const AP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+const DHCP_POOL_START: u8 = 2;
+const DHCP_POOL_SIZE: usize = 8;
+const DHCP_LEASE_SECS: u32 = 3600;
+// Go back to trying the wifi if nobody provisions us in this long, in case the access point was
+// just down for a while.
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(600);
+// What the board calls itself to the broker, and puts in front of every topic, unless it has been
+// provisioned with something else. Set HOST_ID and MQTT_TOPIC_PREFIX when building to change them.
+pub(crate) const DEFAULT_HOST_ID: &str = env!("HOST_ID");
+const DEFAULT_TOPIC_PREFIX: &str = env!("MQTT_TOPIC_PREFIX");
+// leaves room for the longest topic name after the prefix, with an axis suffix.
+pub(crate) const TOPIC_PREFIX_LEN: usize = 48;
+
+/// What we need to get onto the network and the broker.
+pub(crate) struct Credentials {
+    pub(crate) ssid: String<32>,
+    pub(crate) password: String<64>,
+    pub(crate) broker_ip: Ipv4Addr,
+    pub(crate) mqtt_username: String<64>,
+    pub(crate) mqtt_password: String<64>,
+    /// The MQTT client ID.
+    pub(crate) host_id: String<32>,
+    pub(crate) topic_prefix: String<TOPIC_PREFIX_LEN>,
+    // whether these came from flash, rather than being built in.
+    pub(crate) stored: bool,
+}
+
+/// The credentials in use, set once at boot.
+pub(crate) static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+
+impl Credentials {
+    /// The credentials set when building, if there are any.
+    fn built_in() -> Option<Self> {
+        Some(Self {
+            ssid: String::try_from(option_env!("SSID")?).ok()?,
+            password: String::try_from(option_env!("PASSWORD").unwrap_or("")).ok()?,
+            broker_ip: option_env!("MQTT_BROKER_IP")?.parse().ok()?,
+            mqtt_username: String::try_from(option_env!("MQTT_USERNAME").unwrap_or("")).ok()?,
+            mqtt_password: String::try_from(option_env!("MQTT_PASSWORD").unwrap_or("")).ok()?,
+            host_id: default_host_id(),
+            topic_prefix: default_topic_prefix(),
+            stored: false,
+        })
+    }
+
+    /// Reads the portal's form, as sent by the browser.
+    fn from_form(form: &str) -> Option<Self> {
+        Some(Self {
+            ssid: form_value(form, "ssid").filter(|s: &String<32>| !s.is_empty())?,
+            password: form_value(form, "password")?,
+            broker_ip: form_value::<15>(form, "broker")?.parse().ok()?,
+            mqtt_username: form_value(form, "mqtt_username")?,
+            mqtt_password: form_value(form, "mqtt_password")?,
+            host_id: form_value(form, "host_id")
+                .filter(|h: &String<32>| !h.is_empty())
+                .unwrap_or_else(default_host_id),
+            topic_prefix: Some(
+                form_value(form, "topic_prefix")
+                    .filter(|p: &String<TOPIC_PREFIX_LEN>| !p.is_empty())
+                    .unwrap_or_else(default_topic_prefix),
+            )
+            .filter(|p| valid_topic_prefix(p))?,
+            stored: true,
+        })
+    }
+}
+
+pub(crate) fn default_host_id() -> String<32> {
+    // can't fail, checked when building.
+    String::try_from(DEFAULT_HOST_ID).unwrap()
+}
+
+pub(crate) fn default_topic_prefix() -> String<TOPIC_PREFIX_LEN> {
+    String::try_from(DEFAULT_TOPIC_PREFIX).unwrap()
+}
+
+// the built in identity has to be usable, as it is what stored credentials from before there was
+// one fall back to.
+const _: () = assert!(DEFAULT_HOST_ID.len() <= 32, "HOST_ID is too long");
+const _: () = assert!(
+    DEFAULT_TOPIC_PREFIX.len() <= TOPIC_PREFIX_LEN,
+    "MQTT_TOPIC_PREFIX is too long"
+);
+
+/// Whether a provisioned topic prefix can go in front of our topic names. The wildcards would make
+/// them filters rather than topics.
+pub(crate) fn valid_topic_prefix(prefix: &str) -> bool {
+    !prefix.contains(['+', '#', '\0'])
+}
+
+/// The stored credentials if there are any, or else the built in ones.
+pub(crate) async fn load_credentials() -> Option<Credentials> {
+    match config_store::credentials().await {
+        Ok(Some(credentials)) => Some(credentials),
+        Ok(None) => Credentials::built_in(),
+        Err(_) => {
+            error!("Error getting item in flash");
+            Credentials::built_in()
+        }
+    }
+}
+
+/// Whether we were asked to come up in provisioning mode, clearing the request so that the next
+/// boot goes back to the wifi.
+pub(crate) async fn take_provisioning_request() -> bool {
+    match provisioning_requested().await {
+        Ok(true) => {
+            if store_provisioning_request(false).await.is_err() {
+                error!("Error storing item in flash");
+            }
+            true
+        }
+        Ok(_) => false,
+        Err(_) => {
+            error!("Error getting item in flash");
+            false
+        }
+    }
+}
+
+/// Reboots into provisioning mode.
+pub(crate) async fn request_provisioning() -> ! {
+    if store_provisioning_request(true).await.is_err() {
+        error!("Error storing item in flash");
+    }
+    software_reset()
+}
+
+/// Gets a value from an `application/x-www-form-urlencoded` form.
+fn form_value<const N: usize>(form: &str, key: &str) -> Option<String<N>> {
+    let raw = form
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find_map(|(k, v)| (k == key).then_some(v))?;
+    let mut bytes = raw.bytes();
+    let mut decoded = Vec::<u8, N>::new();
+    while let Some(b) = bytes.next() {
+        let b = match b {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b => b,
+        };
+        decoded.push(b).ok()?;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Reads the portal's optional static addresses, all empty for DHCP.
+fn network_from_form(form: &str) -> Option<NetworkConfig> {
+    let [address, gateway, dns, ipv6_address, ipv6_gateway] =
+        ["address", "gateway", "dns", "ipv6_address", "ipv6_gateway"]
+            .map(|key| form_value::<48>(form, key));
+    NetworkConfig::from_fields(
+        address.as_deref(),
+        gateway.as_deref(),
+        dns.as_deref(),
+        ipv6_address.as_deref(),
+        ipv6_gateway.as_deref(),
+    )
+}
+
+fn portal_page() -> String<2048> {
+    let s = STRINGS;
+    // well under the buffer size, even with the longest translations.
+    format!(
+        2048;
+        "<!DOCTYPE html><html lang={}><head><meta charset=utf-8>\
+        <meta name=viewport content=\"width=device-width\"><title>crabroll {}</title></head>\
+        <body><h1>crabroll {}</h1><form method=post action=\"/provision\">\
+        <p>{}<br><input name=ssid></p>\
+        <p>{}<br><input name=password type=password></p>\
+        <p>{}<br><input name=broker></p>\
+        <p>{}<br><input name=mqtt_username></p>\
+        <p>{}<br><input name=mqtt_password type=password></p>\
+        <p>{}<br><input name=host_id placeholder=\"{}\"></p>\
+        <p>{}<br><input name=topic_prefix placeholder=\"{}\"></p>\
+        <p>{}<br><input name=address placeholder=192.168.1.50/24></p>\
+        <p>{}<br><input name=gateway></p>\
+        <p>{}<br><input name=dns></p>\
+        <p>{}<br><input name=ipv6_address placeholder=fd00::50/64></p>\
+        <p>{}<br><input name=ipv6_gateway></p>\
+        <button>{}</button></form></body></html>",
+        s.code,
+        s.setup,
+        s.setup,
+        s.wifi_network,
+        s.wifi_password,
+        s.broker_ip,
+        s.mqtt_username,
+        s.mqtt_password,
+        s.host_id,
+        DEFAULT_HOST_ID,
+        s.topic_prefix,
+        DEFAULT_TOPIC_PREFIX,
+        s.static_address,
+        s.gateway,
+        s.dns_server,
+        s.ipv6_address,
+        s.ipv6_gateway,
+        s.save
+    )
+    .unwrap()
+}
+
+/// Serves the form for entering the wifi and MQTT details. Every other page gets the form too,
+/// so phones and laptops pop it up as a captive portal when they join the access point.
+#[embassy_executor::task]
+pub(crate) async fn portal_task(stack: Stack<'static>) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+    let mut request_buffer = [0; 1024];
+    let serve = async {
+        loop {
+            let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+            socket.set_timeout(Some(Duration::from_secs(10)));
+            if let Err(e) = socket.accept(80).await {
+                error!("Error accepting http connection: {:?}", e);
+                continue;
+            }
+            let provisioned = match read_request(&mut socket, &mut request_buffer).await {
+                Ok(Some(request)) if request.method == "POST" && request.path == "/provision" => {
+                    str::from_utf8(request.body).ok().and_then(|form| {
+                        Some((Credentials::from_form(form)?, network_from_form(form)?))
+                    })
+                }
+                _ => None,
+            };
+            let page = match &provisioned {
+                Some(_) => String::try_from(STRINGS.saved).unwrap(),
+                None => portal_page(),
+            };
+            if let Err(e) = respond(&mut socket, "200 OK", &page).await {
+                error!("Error serving http request: {:?}", e);
+            }
+            socket.close();
+            if let Err(e) = socket.flush().await {
+                error!("Error closing http connection: {:?}", e);
+            }
+            if let Some((credentials, network)) = provisioned {
+                info!("Provisioned for {}", credentials.ssid.as_str());
+                if store_credentials(&credentials).await.is_err() {
+                    error!("Error storing item in flash");
+                }
+                set_network_config(network).await;
+                Timer::after_secs(1).await;
+                software_reset();
+            }
+        }
+    };
+    select(serve, Timer::after(PROVISIONING_TIMEOUT)).await;
+    warn!("Nobody provisioned us, trying the wifi again");
+    software_reset();
+}
+
+/// Hands out addresses on the access point, just enough for one or two clients to reach the
+/// portal.
+#[embassy_executor::task]
+pub(crate) async fn dhcp_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(67).unwrap();
+    let mut leases: [Option<[u8; 6]>; DHCP_POOL_SIZE] = [None; DHCP_POOL_SIZE];
+    let mut packet = [0; 576];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let Some(reply_len) = dhcp_reply(&mut packet, len, &mut leases) else {
+            continue;
+        };
+        let broadcast = (IpAddress::v4(255, 255, 255, 255), 68);
+        if let Err(e) = socket.send_to(&packet[..reply_len], broadcast).await {
+            error!("Error sending DHCP reply: {:?}", e);
+        }
+    }
+}
+
+/// Turns a DHCP discover or request into an offer or ack in place, returning its length.
+fn dhcp_reply(
+    packet: &mut [u8],
+    len: usize,
+    leases: &mut [Option<[u8; 6]>; DHCP_POOL_SIZE],
+) -> Option<usize> {
+    // the fixed header, then the magic cookie.
+    const OPTIONS: usize = 240;
+    if len < OPTIONS || packet[0] != 1 || packet[236..240] != [99, 130, 83, 99] {
+        return None;
+    }
+    let received = packet.get(..len)?;
+    let mut message_type = None;
+    let mut i = OPTIONS;
+    // stops at the end option, or at one cut off by the end of the packet.
+    loop {
+        let (Some(&code), Some(&length)) = (received.get(i), received.get(i + 1)) else {
+            break;
+        };
+        let Some(value) = received.get(i + 2..i + 2 + usize::from(length)) else {
+            break;
+        };
+        if code == 255 {
+            break;
+        }
+        if code == 53 {
+            message_type = value.first().copied();
+        }
+        i += 2 + value.len();
+    }
+    let reply_type = match message_type? {
+        // discover, offer.
+        1 => 2,
+        // request, ack.
+        3 => 5,
+        _ => return None,
+    };
+    let mac: [u8; 6] = packet[28..34].try_into().ok()?;
+    let lease = match leases.iter().position(|l| *l == Some(mac)) {
+        Some(lease) => lease,
+        None => {
+            // recycle the first lease if we run out, it's only for the portal.
+            let lease = leases.iter().position(Option::is_none).unwrap_or(0);
+            leases[lease] = Some(mac);
+            lease
+        }
+    };
+    let [a, b, c, _] = AP_ADDRESS.octets();
+    packet[0] = 2;
+    // ciaddr, yiaddr, siaddr.
+    packet[12..16].fill(0);
+    packet[16..20].copy_from_slice(&[a, b, c, DHCP_POOL_START + lease as u8]);
+    packet[20..24].copy_from_slice(&AP_ADDRESS.octets());
+    let server = AP_ADDRESS.octets();
+    let mut options: Vec<u8, 64> = Vec::new();
+    for option in [
+        &[53, 1, reply_type][..],
+        &[54, 4, server[0], server[1], server[2], server[3]],
+        &[1, 4, 255, 255, 255, 0],
+        &[3, 4, server[0], server[1], server[2], server[3]],
+        &[6, 4, server[0], server[1], server[2], server[3]],
+        &[51, 4],
+        &DHCP_LEASE_SECS.to_be_bytes(),
+        &[255],
+    ] {
+        options.extend_from_slice(option).ok()?;
+    }
+    let end = OPTIONS + options.len();
+    packet.get_mut(OPTIONS..end)?.copy_from_slice(&options);
+    Some(end)
+}
+
+/// Answers every DNS query with our own address, so any page a client opens lands on the portal.
+#[embassy_executor::task]
+pub(crate) async fn dns_task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(53).unwrap();
+    let mut packet = [0; 512];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let Some(reply_len) = dns_reply(&mut packet, len) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&packet[..reply_len], meta.endpoint).await {
+            error!("Error sending DNS reply: {:?}", e);
+        }
+    }
+}
+
+/// Turns a query into an answer pointing at us in place, returning its length.
+fn dns_reply(packet: &mut [u8], len: usize) -> Option<usize> {
+    const HEADER: usize = 12;
+    if len < HEADER {
+        return None;
+    }
+    // skip the name of the first question, then its type and class.
+    let mut i = HEADER;
+    while *packet.get(i)? != 0 {
+        i += 1 + usize::from(packet[i]);
+    }
+    let question_end = i + 5;
+    if question_end > len {
+        return None;
+    }
+    // a response, recursion available, one question and one answer.
+    packet[2..4].copy_from_slice(&[0x81, 0x80]);
+    packet[4..12].copy_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]);
+    let [a, b, c, d] = AP_ADDRESS.octets();
+    // a pointer back to the question's name, type A, class IN, 60s TTL.
+    let answer = [0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, a, b, c, d];
+    let end = question_end + answer.len();
+    packet.get_mut(question_end..end)?.copy_from_slice(&answer);
+    Some(end)
+}
@@ -0,0 +1,174 @@
+use core::cell::Cell;
+
+use defmt::{Format, error, info};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use heapless::Vec;
+use serde::Deserialize;
+
+use crate::{
+    ErrorSeverity,
+    config_store::{QUIET_HOURS_KEY, fetch_board_item, store_board_item},
+    schedule::local_minute_of_day,
+    state::{confirm, raise_error},
+    tmc2209::IholdIrun,
+};
+
+// start and end hour and minute, speed, current and whether to defer.
+const QUIET_HOURS_BYTES: usize = 7;
+
+// Loaded from flash by main, and changed on the `quiet_hours` topic.
+static QUIET_HOURS: Mutex<CriticalSectionRawMutex, Cell<Option<QuietHours>>> =
+    Mutex::new(Cell::new(None));
+
+/// A nightly window to run the blinds quieter in, as given on the `quiet_hours` topic. It can run
+/// past midnight, like 22:00 to 07:00.
+#[derive(Clone, Copy, PartialEq, Eq, Format, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct QuietHours {
+    start_hour: u8,
+    start_minute: u8,
+    end_hour: u8,
+    end_minute: u8,
+    /// The percentage of the speed and acceleration to run at.
+    #[serde(default = "half")]
+    speed: u8,
+    /// The percentage of the run current to move with.
+    #[serde(default = "half")]
+    current: u8,
+    /// Whether to hold positioning moves back until the window ends rather than make them.
+    #[serde(default)]
+    defer: bool,
+}
+
+fn half() -> u8 {
+    50
+}
+
+impl QuietHours {
+    /// Parses the window as JSON, like `{"start_hour": 22, "start_minute": 0, "end_hour": 7,
+    /// "end_minute": 0}`. `null` or an empty message clears it, as [`None`].
+    pub(crate) fn parse(json: &[u8]) -> Option<Option<Self>> {
+        if json.is_empty() {
+            return Some(None);
+        }
+        let (quiet, _): (Option<Self>, _) = serde_json_core::from_slice(json).ok()?;
+        match quiet {
+            Some(q)
+                if q.start_hour > 23
+                    || q.end_hour > 23
+                    || q.start_minute > 59
+                    || q.end_minute > 59
+                    || !(1..=100).contains(&q.speed)
+                    || !(1..=100).contains(&q.current) =>
+            {
+                None
+            }
+            quiet => Some(quiet),
+        }
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        let start = u32::from(self.start_hour) * 60 + u32::from(self.start_minute);
+        let end = u32::from(self.end_hour) * 60 + u32::from(self.end_minute);
+        if start <= end {
+            (start..end).contains(&minute)
+        } else {
+            minute >= start || minute < end
+        }
+    }
+
+    fn to_bytes(self) -> Vec<u8, QUIET_HOURS_BYTES> {
+        Vec::from_array([
+            self.start_hour,
+            self.start_minute,
+            self.end_hour,
+            self.end_minute,
+            self.speed,
+            self.current,
+            self.defer.into(),
+        ])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let [
+            start_hour,
+            start_minute,
+            end_hour,
+            end_minute,
+            speed,
+            current,
+            defer,
+        ] = *bytes.first_chunk()?;
+        Some(Self {
+            start_hour,
+            start_minute,
+            end_hour,
+            end_minute,
+            speed,
+            current,
+            defer: defer != 0,
+        })
+    }
+}
+
+pub(crate) fn quiet_hours() -> Option<QuietHours> {
+    QUIET_HOURS.lock(|q| q.get())
+}
+
+/// The quiet hours, if we are in them now. Never before the clock has been synced.
+pub(crate) fn active() -> Option<QuietHours> {
+    let minute = local_minute_of_day()?;
+    quiet_hours().filter(|q| q.contains(minute))
+}
+
+/// The percentage of the speed to run at just now, 100 outside the quiet hours.
+pub(crate) fn speed_percent() -> u32 {
+    active().map_or(100, |q| q.speed.into())
+}
+
+/// Turns the run current down while in the quiet hours, leaving the hold current as it is.
+pub(crate) fn quiet_current(current: IholdIrun) -> IholdIrun {
+    match active() {
+        // can't overflow, it is at most the current it is scaled from.
+        Some(q) => current
+            .with_irun(((u16::from(current.irun()) * u16::from(q.current) / 100) as u8).max(1)),
+        None => current,
+    }
+}
+
+/// Whether positioning moves should wait for the end of the quiet hours.
+pub(crate) fn deferring() -> bool {
+    active().is_some_and(|q| q.defer)
+}
+
+/// Loads the quiet hours. For main only, after setting up the storage.
+pub(crate) async fn load_quiet_hours() {
+    match fetch_board_item::<Vec<u8, QUIET_HOURS_BYTES>>(QUIET_HOURS_KEY).await {
+        Ok(Some(bytes)) => QUIET_HOURS.lock(|q| q.set(QuietHours::from_bytes(&bytes))),
+        Ok(None) => (),
+        Err(_) => {
+            error!("Error getting item in flash");
+            raise_error(ErrorSeverity::Soft);
+        }
+    }
+}
+
+/// The quiet hours as stored, empty when there are none.
+pub(crate) fn quiet_hours_bytes() -> Vec<u8, QUIET_HOURS_BYTES> {
+    quiet_hours().map(QuietHours::to_bytes).unwrap_or_default()
+}
+
+/// Changes the quiet hours and saves them, if they are any different.
+pub(crate) async fn set_quiet_hours(quiet: Option<QuietHours>) {
+    if QUIET_HOURS.lock(|q| q.replace(quiet)) == quiet {
+        return;
+    }
+    info!("New quiet hours: {}", quiet);
+    match store_board_item(QUIET_HOURS_KEY, &quiet_hours_bytes()).await {
+        Ok(()) => confirm(),
+        Err(_) => {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Hard);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use core::cell::Cell;
+
+use defmt::{Format, error, info, warn};
+use embassy_net::{
+    Stack,
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use heapless::Vec;
+use serde::Deserialize;
+
+use crate::{
+    AXES, Command, ErrorSeverity, Permille,
+    arbiter::Source,
+    config_store::{SCHEDULE_KEY, fetch_item, store_item},
+    mqtt::invert_permille,
+    state::{confirm, raise_error, send_command},
+};
+
+// How many times a day each axis can be scheduled to move.
+pub(crate) const MAX_ENTRIES: usize = 8;
+// hour, minute and position.
+const ENTRY_BYTES: usize = 3;
+// Set NTP_SERVER when building to sync the clock from somewhere else.
+const NTP_SERVER: &str = match option_env!("NTP_SERVER") {
+    Some(server) => server,
+    None => "pool.ntp.org",
+};
+// Set UTC_OFFSET_MINUTES when building to run the schedules in local time, like `60` for CET.
+// There is no daylight saving, so this wants changing twice a year where it applies.
+const UTC_OFFSET_MINUTES: i32 = match option_env!("UTC_OFFSET_MINUTES") {
+    Some(offset) => match i32::from_str_radix(offset, 10) {
+        Ok(offset) => offset,
+        Err(_) => panic!("UTC_OFFSET_MINUTES must be a whole number"),
+    },
+    None => 0,
+};
+// NTP counts from 1900, unix from 1970.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const NTP_PORT: u16 = 123;
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Our clock drifts slowly, so there is no need to sync often.
+const SYNC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// The unix time the board booted at, None until the clock has been synced.
+static BOOT_TIME: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+// Loaded from flash by the schedule task, and changed on the `schedule` topic.
+static SCHEDULES: [Mutex<CriticalSectionRawMutex, Cell<Schedule>>; AXES] =
+    [const { Mutex::new(Cell::new(Schedule::EMPTY)) }; AXES];
+
+/// A daily move, as given on the `schedule` topic.
+#[derive(Clone, Copy, PartialEq, Eq, Format, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    hour: u8,
+    minute: u8,
+    /// A user facing percentage, like the command topic takes.
+    pos: i8,
+}
+
+impl Entry {
+    fn minute_of_day(&self) -> u32 {
+        u32::from(self.hour) * 60 + u32::from(self.minute)
+    }
+}
+
+/// The daily moves for an axis.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct Schedule {
+    entries: [Option<Entry>; MAX_ENTRIES],
+}
+
+impl Schedule {
+    const EMPTY: Self = Self {
+        entries: [None; MAX_ENTRIES],
+    };
+
+    /// Parses a JSON list of moves, like `[{"hour": 7, "minute": 30, "pos": 100}]`, or `[]` to
+    /// clear the schedule.
+    pub(crate) fn parse(json: &[u8]) -> Option<Self> {
+        let (entries, _): (Vec<Entry, MAX_ENTRIES>, _) = serde_json_core::from_slice(json).ok()?;
+        let mut schedule = Self::EMPTY;
+        for (slot, entry) in schedule.entries.iter_mut().zip(entries) {
+            if entry.hour > 23 || entry.minute > 59 || !(0..=100).contains(&entry.pos) {
+                return None;
+            }
+            *slot = Some(entry);
+        }
+        Some(schedule)
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8, { MAX_ENTRIES * ENTRY_BYTES }> {
+        self.entries
+            .into_iter()
+            .flatten()
+            .flat_map(|e| [e.hour, e.minute, e.pos as u8])
+            .collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut schedule = Self::EMPTY;
+        for (slot, entry) in schedule
+            .entries
+            .iter_mut()
+            .zip(bytes.chunks_exact(ENTRY_BYTES))
+        {
+            *slot = Some(Entry {
+                hour: entry[0],
+                minute: entry[1],
+                pos: entry[2] as i8,
+            });
+        }
+        schedule
+    }
+}
+
+pub(crate) fn schedule(axis: usize) -> Schedule {
+    SCHEDULES[axis].lock(|s| s.get())
+}
+
+/// Changes an axis's schedule and saves it, if it is any different.
+pub(crate) async fn set_schedule(axis: usize, schedule: Schedule) {
+    if SCHEDULES[axis].lock(|s| s.replace(schedule)) == schedule {
+        return;
+    }
+    info!("New schedule for axis {}: {}", axis, schedule);
+    match store_item(axis, SCHEDULE_KEY, &schedule.to_bytes()).await {
+        Ok(()) => confirm(),
+        Err(_) => {
+            error!("Error storing item in flash");
+            raise_error(ErrorSeverity::Hard);
+        }
+    }
+}
+
+/// The unix time, None until the clock has been synced.
+pub(crate) fn unix_time() -> Option<u64> {
+    BOOT_TIME
+        .lock(|t| t.get())
+        .map(|boot| boot + Instant::now().as_secs())
+}
+
+/// Minutes since local midnight, None until the clock has been synced.
+pub(crate) fn local_minute_of_day() -> Option<u32> {
+    let local = unix_time()? as i64 + i64::from(UTC_OFFSET_MINUTES) * 60;
+    Some((local.rem_euclid(24 * 60 * 60) / 60) as u32)
+}
+
+/// Asks the NTP server for the unix time, just to the second.
+async fn sntp_time(stack: Stack<'static>) -> Option<u64> {
+    let server = *stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .ok()?
+        .first()?;
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // any free port.
+    socket.bind(0).ok()?;
+    let mut packet = [0; 48];
+    // no leap second warning, version 4, client.
+    packet[0] = 0b00_100_011;
+    socket.send_to(&packet, (server, NTP_PORT)).await.ok()?;
+    let (len, _) = socket
+        .recv_from(&mut packet)
+        .with_timeout(NTP_TIMEOUT)
+        .await
+        .ok()?
+        .ok()?;
+    if len < packet.len() {
+        return None;
+    }
+    // the seconds of the transmit timestamp.
+    let seconds = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]);
+    u64::from(seconds).checked_sub(NTP_UNIX_OFFSET)
+}
+
+/// Keeps the clock synced, and moves the blinds at their scheduled times.
+#[embassy_executor::task]
+pub(crate) async fn schedule_task(stack: Stack<'static>) {
+    for axis in 0..AXES {
+        match fetch_item::<Vec<u8, { MAX_ENTRIES * ENTRY_BYTES }>>(axis, SCHEDULE_KEY).await {
+            Ok(Some(bytes)) => SCHEDULES[axis].lock(|s| s.set(Schedule::from_bytes(&bytes))),
+            Ok(None) => (),
+            Err(_) => error!("Error getting item in flash"),
+        }
+    }
+    let mut next_sync = Instant::now();
+    loop {
+        if Instant::now() >= next_sync {
+            match sntp_time(stack).await {
+                Some(unix) => {
+                    BOOT_TIME.lock(|t| t.set(Some(unix - Instant::now().as_secs())));
+                    info!("Clock synced to {}", unix);
+                    next_sync = Instant::now() + SYNC_INTERVAL;
+                }
+                None => {
+                    warn!("Couldn't sync the clock from {}", NTP_SERVER);
+                    next_sync = Instant::now() + SYNC_RETRY_INTERVAL;
+                }
+            }
+        }
+        let Some(now) = unix_time() else {
+            Timer::at(next_sync).await;
+            continue;
+        };
+        // check on the minute.
+        Timer::after_secs(60 - now % 60).await;
+        let Some(minute) = local_minute_of_day() else {
+            continue;
+        };
+        for axis in 0..AXES {
+            for entry in schedule(axis).entries.into_iter().flatten() {
+                if entry.minute_of_day() == minute {
+                    info!("Scheduled move of axis {} to {}", axis, entry.pos);
+                    send_command(
+                        axis,
+                        Source::Automation,
+                        Command::MoveToPos(invert_permille(
+                            axis,
+                            Permille::from_percent(entry.pos),
+                        )),
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use core::{cell::Cell, num::NonZeroU32, sync::atomic::Ordering};
+
+use defmt::{Format, error};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::Duration;
+use esp_hal::gpio::Level;
+use heapless::{String, format};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    AXES, INTPOL, MAX_STEP_RATE, MICROSTEPS, SPREADCYCLE, THERMAL_THROTTLED, TmcRequest, board,
+    driver_addr, quiet, tmc_request,
+    tmc2209::{Chopconf, Gconf, IholdIrun},
+};
+
+// The build time defaults from the board file, in fullsteps so they hold whatever the
+// microstepping.
+pub(crate) const MAX_FULLSTEP_VEL: u32 = board::MAX_VEL;
+const MAX_FULLSTEP_ACCEL: u32 = board::MAX_ACCEL;
+const FULLSTEP_START_VEL: u32 = board::START_VEL;
+// only set in the board file, the flash has no room left for it.
+const FULLSTEP_JOG_VEL: u32 = board::JOG_VEL;
+const DEFAULT_RUN_CURRENT: u8 = board::RUN_CURRENT;
+// homing is done gently, so a broken endstop stalls the motor rather than grinding the blind.
+const DEFAULT_HOMING_CURRENT: u8 = board::HOMING_CURRENT;
+const DEFAULT_HOLD_CURRENT: u8 = board::HOLD_CURRENT;
+// reaching the fastest step rate from a standstill within a millisecond, well past anything a
+// blind can use, and low enough that scaling it by a percentage stays in a u32.
+const MAX_STEP_ACCEL: u32 = MAX_STEP_RATE * 1000;
+// IRUN and IHOLD are 5 bits.
+const MAX_CURRENT: u8 = 31;
+// stored in 16 bits, see `config_store::DriverConfig`.
+const MAX_TILT: u32 = u16::MAX as u32;
+// stored in 7 bits, see `config_store::DriverConfig`.
+const MAX_IDLE_TIMEOUT: u8 = 127;
+// How much of the run current is left to an overheating driver.
+const THROTTLED_CURRENT_PERCENT: u16 = 50;
+
+const GCONF: u8 = 0x00;
+const CHOPCONF: u8 = 0x6c;
+const IHOLD_IRUN: u8 = 0x10;
+const TCOOLTHRS: u8 = 0x14;
+const SG_RESULT: u8 = 0x41;
+const SGTHRS: u8 = 0x40;
+// Set `stall_threshold` in the board file (or STALL_THRESHOLD when building) to change how readily
+// DIAG reports a stall: it is raised once SG_RESULT falls to twice this, so higher is more
+// sensitive.
+const STALL_THRESHOLD: u32 = match option_env!("STALL_THRESHOLD") {
+    Some(threshold) => match u32::from_str_radix(threshold, 10) {
+        Ok(threshold) if threshold <= 255 => threshold,
+        _ => panic!("STALL_THRESHOLD must be from 0 to 255"),
+    },
+    None => 50,
+};
+// TSTEP and TCOOLTHRS are 20 bits, this turns StallGuard on at every speed.
+const STALLGUARD_ALWAYS: u32 = 0xf_ffff;
+
+/// The settings for each axis, loaded from flash by the motor task and changed with
+/// [`crate::Command::Configure`].
+static SETTINGS: [Mutex<CriticalSectionRawMutex, Cell<Settings>>; AXES] =
+    [const { Mutex::new(Cell::new(Settings::DEFAULT)) }; AXES];
+
+/// Motion and driver tuning for one axis. Speeds are in fullsteps, so they don't need changing
+/// along with the microstepping.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct Settings {
+    /// fullsteps/sec.
+    pub(crate) max_vel: u32,
+    /// fullsteps/sec^2.
+    pub(crate) max_accel: u32,
+    /// fullsteps/sec, see [`iter_step_gen::Stepper::new`].
+    pub(crate) start_vel: u32,
+    pub(crate) microsteps: u32,
+    /// IRUN, in 32nds of full scale current.
+    pub(crate) run_current: u8,
+    /// IRUN while homing.
+    pub(crate) homing_current: u8,
+    /// IHOLD, the current once the motor has stopped. 0 lets it turn freely.
+    pub(crate) hold_current: u8,
+    /// Drive the motor with SpreadCycle rather than StealthChop. StallGuard, and so stall
+    /// detection, only works with StealthChop.
+    pub(crate) spreadcycle: bool,
+    /// Drive DIR high to move towards home, rather than low.
+    pub(crate) invert_dir: bool,
+    /// fullsteps, moves to within this of where we already are are skipped.
+    pub(crate) position_tolerance: u32,
+    /// fullsteps of slack in the gears, taken up whenever the blind changes direction.
+    pub(crate) backlash: u32,
+    /// Have the driver interpolate each step to 256 microsteps.
+    pub(crate) intpol: bool,
+    /// fullsteps the motor turns a venetian blind's slats through before the blind starts to
+    /// lift, see [`crate::Command::Tilt`]. 0 for blinds without slats.
+    pub(crate) tilt: u32,
+    /// seconds the motor is left powered after it stops, before it is switched off to stop it
+    /// heating up. 0 leaves it powered.
+    pub(crate) idle_timeout: u8,
+}
+
+impl Settings {
+    pub(crate) const DEFAULT: Self = Self {
+        max_vel: MAX_FULLSTEP_VEL,
+        max_accel: MAX_FULLSTEP_ACCEL,
+        start_vel: FULLSTEP_START_VEL,
+        microsteps: MICROSTEPS,
+        run_current: DEFAULT_RUN_CURRENT,
+        homing_current: DEFAULT_HOMING_CURRENT,
+        hold_current: DEFAULT_HOLD_CURRENT,
+        spreadcycle: SPREADCYCLE,
+        invert_dir: false,
+        position_tolerance: 0,
+        backlash: 0,
+        intpol: INTPOL,
+        tilt: 0,
+        idle_timeout: 0,
+    };
+
+    /// Applies the fields that are set in an update, checking the result makes sense.
+    pub(crate) fn update(mut self, update: SettingsUpdate) -> Result<Self, SettingsError> {
+        self.max_vel = update.max_vel.unwrap_or(self.max_vel);
+        self.max_accel = update.max_accel.unwrap_or(self.max_accel);
+        self.start_vel = update.start_vel.unwrap_or(self.start_vel);
+        self.microsteps = update.microsteps.unwrap_or(self.microsteps);
+        self.run_current = update.run_current.unwrap_or(self.run_current);
+        self.homing_current = update.homing_current.unwrap_or(self.homing_current);
+        self.hold_current = update.hold_current.unwrap_or(self.hold_current);
+        self.spreadcycle = update.spreadcycle.unwrap_or(self.spreadcycle);
+        self.invert_dir = update.invert_dir.unwrap_or(self.invert_dir);
+        self.position_tolerance = update.position_tolerance.unwrap_or(self.position_tolerance);
+        self.backlash = update.backlash.unwrap_or(self.backlash);
+        self.intpol = update.intpol.unwrap_or(self.intpol);
+        self.tilt = update.tilt.unwrap_or(self.tilt);
+        self.idle_timeout = update.idle_timeout.unwrap_or(self.idle_timeout);
+        self.validate().map(|_| self)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), SettingsError> {
+        if !self.microsteps.is_power_of_two() || self.microsteps > 256 {
+            Err(SettingsError::InvalidMicrosteps(self.microsteps))
+        } else if self.max_vel == 0 || self.max_accel == 0 || self.start_vel == 0 {
+            Err(SettingsError::Zero)
+        } else if self.start_vel > self.max_vel {
+            Err(SettingsError::StartAboveMax)
+        } else if self.max_vel.saturating_mul(self.microsteps) > MAX_STEP_RATE {
+            Err(SettingsError::TooFast)
+        } else if self.max_accel.saturating_mul(self.microsteps) > MAX_STEP_ACCEL {
+            Err(SettingsError::TooQuick)
+        } else if self.run_current > MAX_CURRENT {
+            Err(SettingsError::InvalidCurrent(self.run_current))
+        } else if self.homing_current > MAX_CURRENT {
+            Err(SettingsError::InvalidCurrent(self.homing_current))
+        } else if self.hold_current > MAX_CURRENT {
+            Err(SettingsError::InvalidCurrent(self.hold_current))
+        } else if self.tilt > MAX_TILT {
+            Err(SettingsError::TiltTooFar)
+        } else if self.idle_timeout > MAX_IDLE_TIMEOUT {
+            Err(SettingsError::IdleTimeoutTooLong)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// In microsteps/sec, for the [`iter_step_gen::Stepper`].
+    pub(crate) fn max_speed(&self) -> NonZeroU32 {
+        NonZeroU32::new(self.max_vel * self.microsteps).unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// In microsteps/sec^2, for the [`iter_step_gen::Stepper`].
+    pub(crate) fn max_accel(&self) -> NonZeroU32 {
+        NonZeroU32::new(self.max_accel * self.microsteps).unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// In microsteps/sec, for the [`iter_step_gen::Stepper`].
+    pub(crate) fn start_vel(&self) -> u32 {
+        self.start_vel * self.microsteps
+    }
+
+    /// In microsteps/sec, what jogs ramp up to, see
+    /// [`iter_step_gen::Stepper::continuous_jog_with_speed`].
+    pub(crate) fn jog_speed(&self) -> NonZeroU32 {
+        NonZeroU32::new(FULLSTEP_JOG_VEL.saturating_mul(self.microsteps)).unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// In microsteps, see [`Settings::position_tolerance`].
+    pub(crate) fn position_tolerance(&self) -> u32 {
+        self.position_tolerance.saturating_mul(self.microsteps)
+    }
+
+    /// In microsteps, for the [`iter_step_gen::Stepper`].
+    pub(crate) fn backlash(&self) -> u32 {
+        self.backlash.saturating_mul(self.microsteps)
+    }
+
+    /// In microsteps, see [`Settings::tilt`].
+    pub(crate) fn tilt(&self) -> u32 {
+        self.tilt.saturating_mul(self.microsteps)
+    }
+
+    /// How long the motor is left powered after it stops, None to leave it powered.
+    pub(crate) fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout != 0).then(|| Duration::from_secs(self.idle_timeout.into()))
+    }
+
+    /// The DIR level that moves towards home.
+    pub(crate) fn dir_to_home(&self) -> Level {
+        if self.invert_dir {
+            Level::High
+        } else {
+            Level::Low
+        }
+    }
+
+    /// The settings in the same form as the `config` topic takes them.
+    pub(crate) fn json(&self) -> String<392> {
+        format!(
+            392;
+            "{{\"max_vel\":{},\"max_accel\":{},\"start_vel\":{},\"microsteps\":{},\
+            \"run_current\":{},\"homing_current\":{},\"hold_current\":{},\
+            \"spreadcycle\":{},\"invert_dir\":{},\
+            \"position_tolerance\":{},\"backlash\":{},\"intpol\":{},\"tilt\":{},\
+            \"idle_timeout\":{}}}",
+            self.max_vel,
+            self.max_accel,
+            self.start_vel,
+            self.microsteps,
+            self.run_current,
+            self.homing_current,
+            self.hold_current,
+            self.spreadcycle,
+            self.invert_dir,
+            self.position_tolerance,
+            self.backlash,
+            self.intpol,
+            self.tilt,
+            self.idle_timeout,
+        )
+        .unwrap()
+    }
+
+    /// What GCONF is written with. The microstepping is always set over UART, as are the
+    /// currents unless VREF is used.
+    pub(crate) fn gconf(&self) -> Gconf {
+        Gconf::new()
+            .with_i_scale_analog(true)
+            .with_pdn_disable(true)
+            .with_mstep_reg_select(true)
+            .with_multistep_filt(true)
+            .with_en_spreadcycle(self.spreadcycle)
+    }
+
+    /// CHOPCONF without the microstepping, see [`Settings::chopconf`].
+    pub(crate) fn chopper(&self) -> Chopconf {
+        Chopconf::new().with_toff(3).with_hstrt(1).with_hend(3)
+    }
+
+    pub(crate) fn chopconf(&self) -> Chopconf {
+        self.chopper()
+            .with_microsteps(self.microsteps)
+            // can't fail, validated with the rest of the settings.
+            .unwrap_or(self.chopper())
+            .with_intpol(self.intpol)
+    }
+
+    /// Converts a count of microsteps at `old`'s resolution to this one's, so it stays in the same
+    /// place on the blind.
+    pub(crate) fn rescale(&self, old: &Settings, steps: u32) -> u32 {
+        (u64::from(steps) * u64::from(self.microsteps) / u64::from(old.microsteps)) as u32
+    }
+
+    pub(crate) fn current(&self) -> IholdIrun {
+        IholdIrun::new()
+            .with_irun(self.run_current)
+            .with_ihold(self.hold_current)
+    }
+
+    /// The current for a driver that has warned it is overheating.
+    pub(crate) fn throttled_current(&self) -> IholdIrun {
+        // can't overflow, it is at most the current it is scaled from.
+        let throttle = |current| (u16::from(current) * THROTTLED_CURRENT_PERCENT / 100) as u8;
+        IholdIrun::new()
+            .with_irun(throttle(self.run_current).max(1))
+            .with_ihold(throttle(self.hold_current))
+    }
+}
+
+/// A change to some of the [`Settings`], as received on the `config` topic. Anything left out
+/// is left as it is, so `{"max_vel": 1024}` only changes the speed.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SettingsUpdate {
+    pub(crate) max_vel: Option<u32>,
+    pub(crate) max_accel: Option<u32>,
+    pub(crate) start_vel: Option<u32>,
+    pub(crate) microsteps: Option<u32>,
+    pub(crate) run_current: Option<u8>,
+    pub(crate) homing_current: Option<u8>,
+    pub(crate) hold_current: Option<u8>,
+    pub(crate) spreadcycle: Option<bool>,
+    pub(crate) invert_dir: Option<bool>,
+    pub(crate) position_tolerance: Option<u32>,
+    pub(crate) backlash: Option<u32>,
+    pub(crate) intpol: Option<bool>,
+    pub(crate) tilt: Option<u32>,
+    pub(crate) idle_timeout: Option<u8>,
+}
+
+impl SettingsUpdate {
+    /// Parses a JSON object, like `{"microsteps": 16, "run_current": 12}`.
+    pub(crate) fn parse(json: &[u8]) -> Option<Self> {
+        serde_json_core::from_slice(json).ok().map(|(u, _)| u)
+    }
+}
+
+#[derive(Format, Error, Debug, Clone, Copy)]
+pub(crate) enum SettingsError {
+    #[error("Microsteps must be a power of two up to 256, got {0}")]
+    InvalidMicrosteps(u32),
+    #[error("Speeds and acceleration can't be zero")]
+    Zero,
+    #[error("Start velocity is above the max speed")]
+    StartAboveMax,
+    #[error("Max speed at this microstepping is faster than we can step")]
+    TooFast,
+    #[error("Max acceleration at this microstepping is quicker than we can step")]
+    TooQuick,
+    #[error("Current must be 0 to 31, got {0}")]
+    InvalidCurrent(u8),
+    #[error("Tilt can't be more than 65535 fullsteps")]
+    TiltTooFar,
+    #[error("Idle timeout can't be more than 127 seconds")]
+    IdleTimeoutTooLong,
+}
+
+pub(crate) fn settings(axis: usize) -> Settings {
+    SETTINGS[axis].lock(|s| s.get())
+}
+
+pub(crate) fn set_settings(axis: usize, settings: Settings) {
+    SETTINGS[axis].lock(|s| s.set(settings));
+}
+
+/// The settings for the driver at an address, drivers without an axis get the defaults.
+pub(crate) fn driver_settings(addr: u8) -> Settings {
+    if usize::from(addr) < AXES {
+        settings(addr.into())
+    } else {
+        Settings::DEFAULT
+    }
+}
+
+/// Writes the driver config, microstepping and current to an axis's driver, for after the
+/// settings change.
+pub(crate) async fn write_driver_settings(axis: usize) {
+    let settings = settings(axis);
+    write_register(axis, GCONF, settings.gconf().into_bits()).await;
+    write_register(axis, CHOPCONF, settings.chopconf().into_bits()).await;
+    set_driver_current(axis, wanted_current(axis)).await;
+}
+
+/// The current an axis's driver should be at just now, turned down while it is overheating or
+/// in the quiet hours.
+pub(crate) fn wanted_current(axis: usize) -> IholdIrun {
+    let settings = settings(axis);
+    let current = if THERMAL_THROTTLED[axis].load(Ordering::Relaxed) {
+        settings.throttled_current()
+    } else {
+        settings.current()
+    };
+    quiet::quiet_current(current)
+}
+
+/// The current last written to an axis's driver, from the driver's shadow of IHOLD_IRUN.
+pub(crate) async fn driver_current(axis: usize) -> Option<IholdIrun> {
+    let request = TmcRequest {
+        addr: Some(driver_addr(axis)),
+        register: IHOLD_IRUN,
+        value: None,
+    };
+    tmc_request(request).await.map(IholdIrun::from_bits)
+}
+
+pub(crate) async fn set_driver_current(axis: usize, current: IholdIrun) {
+    write_register(axis, IHOLD_IRUN, current.into_bits()).await;
+}
+
+/// Has an axis's driver measure the load with StallGuard at every speed, or at none.
+/// DIAG reports stalls while it is on.
+pub(crate) async fn set_stallguard(axis: usize, on: bool) {
+    if on {
+        write_register(axis, SGTHRS, STALL_THRESHOLD).await;
+    }
+    let threshold = if on { STALLGUARD_ALWAYS } else { 0 };
+    write_register(axis, TCOOLTHRS, threshold).await;
+}
+
+/// The load StallGuard last measured, out of 510. The motor is stalling as it nears 0, and it is
+/// only meaningful at a steady speed with [`set_stallguard`] on.
+pub(crate) async fn stallguard_result(axis: usize) -> Option<u32> {
+    let request = TmcRequest {
+        addr: Some(driver_addr(axis)),
+        register: SG_RESULT,
+        value: None,
+    };
+    tmc_request(request).await
+}
+
+async fn write_register(axis: usize, register: u8, value: u32) {
+    let request = TmcRequest {
+        addr: Some(driver_addr(axis)),
+        register,
+        value: Some(value),
+    };
+    if tmc_request(request).await.is_none() {
+        error!("Error writing driver settings");
+    }
+}
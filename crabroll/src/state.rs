@@ -0,0 +1,299 @@
+use core::cell::Cell;
+#[cfg(feature = "ramp_telemetry")]
+use core::cell::RefCell;
+
+use defmt::{Format, warn};
+use embassy_sync::{
+    blocking_mutex::{Mutex, raw::CriticalSectionRawMutex},
+    channel::Channel,
+    watch::{Receiver, Watch},
+};
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec, format};
+#[cfg(feature = "ramp_telemetry")]
+use iter_step_gen::RampRecorder;
+
+use crate::{
+    AXES, Command, ErrorSeverity, Position,
+    arbiter::{Source, admit},
+    backend::Jitter,
+    motor::{Calibration, Recovery},
+};
+
+// How many commands can be waiting on each axis's motor task.
+const COMMAND_QUEUE: usize = 4;
+// How many flashes can be waiting on each LED.
+const LED_QUEUE: usize = 4;
+// The tasks waiting on positions: MQTT, the ESPHome API and BLE, anything else looks with
+// `last_pos()`.
+const POSITION_WATCHERS: usize = 3;
+
+// Sent by anything, received only by the axis's motor task.
+static COMMANDS: [Channel<CriticalSectionRawMutex, Command, COMMAND_QUEUE>; AXES] =
+    [const { Channel::new() }; AXES];
+// Sent only by the axis's motor task, see `watch_pos()`.
+static POSITIONS: [Watch<CriticalSectionRawMutex, Position, POSITION_WATCHERS>; AXES] =
+    [const { Watch::new() }; AXES];
+// How many commands each axis has had to drop for lack of room.
+static DROPPED: [Mutex<CriticalSectionRawMutex, Cell<u32>>; AXES] =
+    [const { Mutex::new(Cell::new(0)) }; AXES];
+// When the local command waiting on each axis was sent, see `send_local_command()`.
+static LOCAL_SENT: [Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>>; AXES] =
+    [const { Mutex::new(Cell::new(None)) }; AXES];
+// How long local commands have taken to reach the motor tasks.
+static LOCAL_LATENCY: Mutex<CriticalSectionRawMutex, Cell<Option<Latency>>> =
+    Mutex::new(Cell::new(None));
+// The delays of each axis's last planned move, with the ramp_telemetry feature. Taken by MQTT.
+#[cfg(feature = "ramp_telemetry")]
+static RAMPS: [Mutex<CriticalSectionRawMutex, RefCell<Option<RampRecorder>>>; AXES] =
+    [const { Mutex::new(RefCell::new(None)) }; AXES];
+// How far off time each axis's last move stepped, when stepped from the CPU. Taken by MQTT.
+static JITTERS: [Mutex<CriticalSectionRawMutex, Cell<Option<Jitter>>>; AXES] =
+    [const { Mutex::new(Cell::new(None)) }; AXES];
+// Sent only by the motor tasks, received only by MQTT.
+static CALIBRATIONS: Channel<CriticalSectionRawMutex, Calibration, AXES> = Channel::new();
+// Sent only by the motor tasks, received only by MQTT. Room for a whole recovery from every axis.
+static RECOVERIES: Channel<CriticalSectionRawMutex, (usize, Recovery), { AXES * 4 }> =
+    Channel::new();
+// Raised by anything, received only by the indicator task.
+static ERRORS: Channel<CriticalSectionRawMutex, ErrorSeverity, LED_QUEUE> = Channel::new();
+// Raised by anything, received only by the indicator task.
+static CONFIRMATIONS: Channel<CriticalSectionRawMutex, (), LED_QUEUE> = Channel::new();
+
+pub(crate) type PositionReceiver =
+    Receiver<'static, CriticalSectionRawMutex, Position, POSITION_WATCHERS>;
+
+/// Queues a command for an axis. Commands are carried out in order, a move in progress takes
+/// whatever comes next as soon as it can. Only the latest target matters, so a move to a
+/// position replaces any still waiting, and a stop flushes the queue. Dropped if the arbiter
+/// doesn't let the source through, see [`admit`].
+pub(crate) fn send_command(axis: usize, source: Source, command: Command) {
+    if !admit(axis, source, &command) {
+        return;
+    }
+    queue_command(axis, command);
+}
+
+fn queue_command(axis: usize, command: Command) {
+    // nothing else can get at the queue while it is rearranged.
+    critical_section::with(|_| {
+        match command {
+            Command::Stop => COMMANDS[axis].clear(),
+            _ if is_target(&command) => retain_queued(axis, |queued| !is_target(queued)),
+            _ => (),
+        }
+        if COMMANDS[axis].try_send(command).is_err() {
+            warn!("Axis {} has too many commands waiting, dropping one", axis);
+            DROPPED[axis].lock(|d| d.set(d.get().saturating_add(1)));
+        }
+    });
+}
+
+/// Whether a command sets where the blind should be, of which only the latest matters.
+fn is_target(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::MoveToPos(_)
+            | Command::MoveToPosAt(..)
+            | Command::MoveToStep(_)
+            | Command::Tilt(_)
+    )
+}
+
+/// Whether a command moves the blind, for someone at the blind to take over from.
+fn moves(command: &Command) -> bool {
+    is_target(command)
+        || matches!(
+            command,
+            Command::Home
+                | Command::StartJog(_)
+                | Command::StopJog
+                | Command::Stop
+                | Command::Calibrate
+                | Command::SetTilt
+        )
+}
+
+/// Takes the commands `keep` doesn't want out of an axis's queue, keeping the rest in order. For
+/// within a critical section only.
+fn retain_queued(axis: usize, keep: impl Fn(&Command) -> bool) {
+    let mut kept: Vec<Command, COMMAND_QUEUE> = Vec::new();
+    while let Ok(queued) = COMMANDS[axis].try_receive() {
+        if keep(&queued) {
+            // can't overflow, it all came out of the queue.
+            kept.push(queued).ok();
+        }
+    }
+    for queued in kept {
+        COMMANDS[axis].try_send(queued).ok();
+    }
+}
+
+/// How full an axis's command queue is.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct QueueState {
+    pub(crate) queued: usize,
+    /// Since boot.
+    pub(crate) dropped: u32,
+}
+
+impl QueueState {
+    pub(crate) fn json(&self) -> String<48> {
+        format!(
+            48;
+            "{{\"queued\":{},\"dropped\":{}}}",
+            self.queued,
+            self.dropped,
+        )
+        .unwrap()
+    }
+}
+
+pub(crate) fn queue_state(axis: usize) -> QueueState {
+    QueueState {
+        queued: COMMANDS[axis].len(),
+        dropped: DROPPED[axis].lock(|d| d.get()),
+    }
+}
+
+/// Sends a command from someone stood at the blind, like a button press, jumping any moves still
+/// queued from automations or the broker. Anything else queued, like new settings, is kept. A
+/// move in progress takes it within a step.
+pub(crate) fn send_local_command(axis: usize, source: Source, command: Command) {
+    if !admit(axis, source, &command) {
+        return;
+    }
+    critical_section::with(|_| {
+        retain_queued(axis, |queued| !moves(queued));
+        LOCAL_SENT[axis].lock(|s| s.set(Some(Instant::now())));
+        queue_command(axis, command);
+    });
+}
+
+/// For the axis's motor task only.
+pub(crate) async fn next_command(axis: usize) -> Command {
+    let command = COMMANDS[axis].receive().await;
+    note_latency(axis);
+    command
+}
+
+/// For the axis's motor task only, while it is busy moving.
+pub(crate) fn try_next_command(axis: usize) -> Option<Command> {
+    let command = COMMANDS[axis].try_receive().ok();
+    if command.is_some() {
+        note_latency(axis);
+    }
+    command
+}
+
+/// How long local commands take between being sent and reaching the motor task.
+#[derive(Clone, Copy, Format)]
+pub(crate) struct Latency {
+    pub(crate) last: Duration,
+    pub(crate) max: Duration,
+}
+
+fn note_latency(axis: usize) {
+    let Some(sent) = LOCAL_SENT[axis].lock(|s| s.take()) else {
+        return;
+    };
+    let last = sent.elapsed();
+    LOCAL_LATENCY.lock(|l| {
+        let max = l.get().map_or(last, |l| l.max.max(last));
+        l.set(Some(Latency { last, max }));
+    });
+}
+
+/// None until a local command has been carried out.
+pub(crate) fn local_latency() -> Option<Latency> {
+    LOCAL_LATENCY.lock(|l| l.get())
+}
+
+/// Tells everyone interested where an axis is after a move. For the axis's motor task only.
+pub(crate) fn publish_pos(axis: usize, pos: Position) {
+    POSITIONS[axis].sender().send(pos);
+}
+
+/// Where an axis was last reported to be, None before it has reported anything.
+pub(crate) fn last_pos(axis: usize) -> Option<Position> {
+    POSITIONS[axis].try_get()
+}
+
+/// Waits on an axis's positions, for the MQTT, ESPHome API and BLE tasks.
+pub(crate) fn watch_pos(axis: usize) -> PositionReceiver {
+    POSITIONS[axis]
+        .receiver()
+        .expect("more position watchers than POSITION_WATCHERS")
+}
+
+/// Reports the travel an axis measured when calibrating. For the axis's motor task only.
+pub(crate) fn publish_calibration(calibration: Calibration) {
+    // dropped if MQTT isn't there to take it, the travel limit topic has it too.
+    CALIBRATIONS.try_send(calibration).ok();
+}
+
+/// Reports how far an axis has got recovering from a stall. For the axis's motor task only.
+pub(crate) fn publish_recovery(axis: usize, recovery: Recovery) {
+    // dropped if MQTT isn't there to take it.
+    RECOVERIES.try_send((axis, recovery)).ok();
+}
+
+/// For the MQTT task only.
+pub(crate) async fn next_recovery() -> (usize, Recovery) {
+    RECOVERIES.receive().await
+}
+
+/// Hands over the delays of a planned move for publishing, replacing any that weren't. For the
+/// axis's motor task only.
+#[cfg(feature = "ramp_telemetry")]
+pub(crate) fn publish_ramp(axis: usize, ramp: &RampRecorder) {
+    RAMPS[axis].lock(|r| r.replace(Some(ramp.clone())));
+}
+
+/// For the MQTT task only.
+#[cfg(feature = "ramp_telemetry")]
+pub(crate) fn take_ramp(axis: usize) -> Option<RampRecorder> {
+    RAMPS[axis].lock(|r| r.take())
+}
+
+/// For the axis's step output only, after every move.
+pub(crate) fn publish_jitter(axis: usize, jitter: Jitter) {
+    JITTERS[axis].lock(|j| j.set(Some(jitter)));
+}
+
+/// For the MQTT task only.
+pub(crate) fn take_jitter(axis: usize) -> Option<Jitter> {
+    JITTERS[axis].lock(|j| j.take())
+}
+
+/// For the MQTT task only.
+pub(crate) async fn next_calibration() -> Calibration {
+    CALIBRATIONS.receive().await
+}
+
+/// Flashes the error LED, rebooting afterwards for a hard error.
+pub(crate) fn raise_error(severity: ErrorSeverity) {
+    if let Err(e) = ERRORS.try_send(severity) {
+        // a hard error has to get through, the soft ones can go.
+        if e.0 == ErrorSeverity::Hard {
+            ERRORS.clear();
+            ERRORS.try_send(ErrorSeverity::Hard).ok();
+        }
+    }
+}
+
+/// For the indicator task only.
+pub(crate) async fn next_error() -> ErrorSeverity {
+    ERRORS.receive().await
+}
+
+/// Flashes the confirm LED.
+pub(crate) fn confirm() {
+    // nobody needs more than a few flashes in a row.
+    CONFIRMATIONS.try_send(()).ok();
+}
+
+/// For the indicator task only.
+pub(crate) async fn next_confirm() {
+    CONFIRMATIONS.receive().await
+}
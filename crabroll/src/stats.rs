@@ -0,0 +1,204 @@
+use core::cell::Cell;
+
+use defmt::{Format, error};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec, format};
+use iter_step_gen::Direction;
+
+use crate::{
+    AXES, ErrorSeverity,
+    config_store::{STATS_KEY, fetch_item, store_item},
+    state::raise_error,
+};
+
+// moves, fullsteps, stalls, homings, reversals, milliseconds running and driver faults, little
+// endian.
+pub(crate) const STATS_BYTES: usize = 36;
+// what was saved before reversals and running time were counted.
+const OLD_STATS_BYTES: usize = 20;
+// and before driver faults were counted apart from stalls.
+const UNSPLIT_STATS_BYTES: usize = 32;
+// Counting is cheap but flash wears, so only save every so often, and on shutdown.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// None until the motor task has loaded them, so nothing half counted gets saved over them.
+static STATS: [Mutex<CriticalSectionRawMutex, Cell<Option<Stats>>>; AXES] =
+    [const { Mutex::new(Cell::new(None)) }; AXES];
+// Whether each axis has counted anything since it was last saved.
+static UNSAVED: [Mutex<CriticalSectionRawMutex, Cell<bool>>; AXES] =
+    [const { Mutex::new(Cell::new(false)) }; AXES];
+// Which way each axis last moved, to count reversals. Forgotten on reboot, as homing goes up
+// anyway.
+static LAST_DIR: [Mutex<CriticalSectionRawMutex, Cell<Option<Direction>>>; AXES] =
+    [const { Mutex::new(Cell::new(None)) }; AXES];
+
+/// What an axis has done over its lifetime, for judging wear on the blind.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub(crate) struct Stats {
+    pub(crate) moves: u32,
+    /// Fullsteps, so changing the microstepping doesn't skew it.
+    pub(crate) fullsteps: u64,
+    /// Moves stopped by a stall, and steps the driver or encoder say were missed.
+    pub(crate) stalls: u32,
+    pub(crate) homings: u32,
+    /// Moves the other way from the last one, which is what wears the gearbox's slack.
+    pub(crate) reversals: u32,
+    /// How long the motor has spent moving, homing included.
+    pub(crate) running_ms: u64,
+    /// Faults the driver reported in GSTAT, like the charge pump under-voltage.
+    pub(crate) driver_faults: u32,
+}
+
+impl Stats {
+    pub(crate) fn to_bytes(self) -> Vec<u8, STATS_BYTES> {
+        let mut bytes = Vec::new();
+        // can't overflow, they add up to STATS_BYTES.
+        bytes.extend_from_slice(&self.moves.to_le_bytes()).unwrap();
+        bytes
+            .extend_from_slice(&self.fullsteps.to_le_bytes())
+            .unwrap();
+        bytes.extend_from_slice(&self.stalls.to_le_bytes()).unwrap();
+        bytes
+            .extend_from_slice(&self.homings.to_le_bytes())
+            .unwrap();
+        bytes
+            .extend_from_slice(&self.reversals.to_le_bytes())
+            .unwrap();
+        bytes
+            .extend_from_slice(&self.running_ms.to_le_bytes())
+            .unwrap();
+        bytes
+            .extend_from_slice(&self.driver_faults.to_le_bytes())
+            .unwrap();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if ![STATS_BYTES, UNSPLIT_STATS_BYTES, OLD_STATS_BYTES].contains(&bytes.len()) {
+            return None;
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let new = bytes.len() >= UNSPLIT_STATS_BYTES;
+        let split = bytes.len() == STATS_BYTES;
+        Some(Self {
+            moves: u32_at(0),
+            fullsteps: u64_at(4),
+            stalls: u32_at(12),
+            homings: u32_at(16),
+            // counting from now on.
+            reversals: if new { u32_at(20) } else { 0 },
+            running_ms: if new { u64_at(24) } else { 0 },
+            // older stalls still include them, they can't be told apart.
+            driver_faults: if split { u32_at(32) } else { 0 },
+        })
+    }
+
+    pub(crate) fn json(&self) -> String<192> {
+        format!(
+            192;
+            "{{\"moves\":{},\"fullsteps\":{},\"stalls\":{},\"homings\":{},\"reversals\":{},\"running_secs\":{},\"driver_faults\":{}}}",
+            self.moves,
+            self.fullsteps,
+            self.stalls,
+            self.homings,
+            self.reversals,
+            self.running_ms / 1000,
+            self.driver_faults,
+        )
+        .unwrap()
+    }
+}
+
+/// None until the axis's motor task has started.
+pub(crate) fn stats(axis: usize) -> Option<Stats> {
+    STATS[axis].lock(|s| s.get())
+}
+
+fn count(axis: usize, f: impl FnOnce(&mut Stats)) {
+    STATS[axis].lock(|s| {
+        if let Some(mut stats) = s.get() {
+            f(&mut stats);
+            s.set(Some(stats));
+            UNSAVED[axis].lock(|u| u.set(true));
+        }
+    });
+}
+
+pub(crate) fn count_move(axis: usize, fullsteps: u32, dir: Direction) {
+    let reversed = LAST_DIR[axis]
+        .lock(|d| d.replace(Some(dir)))
+        .is_some_and(|d| d != dir);
+    count(axis, |s| {
+        s.moves = s.moves.saturating_add(1);
+        s.fullsteps = s.fullsteps.saturating_add(fullsteps.into());
+        if reversed {
+            s.reversals = s.reversals.saturating_add(1);
+        }
+    });
+}
+
+/// Adds to how long the motor has spent moving, after anything that moved it.
+pub(crate) fn count_running(axis: usize, running: Duration) {
+    count(axis, |s| {
+        s.running_ms = s.running_ms.saturating_add(running.as_millis())
+    });
+}
+
+pub(crate) fn count_stall(axis: usize) {
+    count(axis, |s| s.stalls = s.stalls.saturating_add(1));
+}
+
+pub(crate) fn count_driver_fault(axis: usize) {
+    count(axis, |s| {
+        s.driver_faults = s.driver_faults.saturating_add(1)
+    });
+}
+
+pub(crate) fn count_homing(axis: usize) {
+    count(axis, |s| s.homings = s.homings.saturating_add(1));
+}
+
+/// Loads an axis's stats from flash, starting from zero if there are none. For the axis's motor
+/// task only, before it moves.
+pub(crate) async fn load(axis: usize) {
+    let stats = match fetch_item::<Vec<u8, STATS_BYTES>>(axis, STATS_KEY).await {
+        Ok(bytes) => bytes
+            .and_then(|b| Stats::from_bytes(&b))
+            .unwrap_or_default(),
+        Err(_) => {
+            error!("Error getting item in flash");
+            raise_error(ErrorSeverity::Soft);
+            Stats::default()
+        }
+    };
+    STATS[axis].lock(|s| s.set(Some(stats)));
+}
+
+/// Saves an axis's stats, if anything has been counted since they were last saved.
+pub(crate) async fn save(axis: usize) {
+    if !UNSAVED[axis].lock(|u| u.replace(false)) {
+        return;
+    }
+    let Some(stats) = stats(axis) else { return };
+    if store_item(axis, STATS_KEY, &stats.to_bytes())
+        .await
+        .is_err()
+    {
+        error!("Error storing item in flash");
+        // losing a few counts isn't worth rebooting for.
+        raise_error(ErrorSeverity::Soft);
+    }
+}
+
+/// Saves the stats every so often.
+#[embassy_executor::task]
+pub(crate) async fn stats_task() {
+    loop {
+        Timer::after(SAVE_INTERVAL).await;
+        for axis in 0..AXES {
+            save(axis).await;
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Set LANGUAGE when building to serve the web pages in another language: `en` (the default), `de`
+// or `es`.
+pub(crate) const STRINGS: &Strings = match option_env!("LANGUAGE") {
+    None => &EN,
+    Some(language) if is(language, "en") => &EN,
+    Some(language) if is(language, "de") => &DE,
+    Some(language) if is(language, "es") => &ES,
+    Some(_) => panic!("LANGUAGE must be one of en, de or es"),
+};
+
+/// Everything the web pages say. The console is left in English, it is only for installers.
+pub(crate) struct Strings {
+    /// For the `lang` attribute.
+    pub(crate) code: &'static str,
+    pub(crate) axis: &'static str,
+    pub(crate) position_unknown: &'static str,
+    /// As in "40% (800 of 2048 steps)".
+    pub(crate) of: &'static str,
+    pub(crate) steps: &'static str,
+    pub(crate) open: &'static str,
+    pub(crate) close: &'static str,
+    pub(crate) home: &'static str,
+    pub(crate) stop: &'static str,
+    pub(crate) save_settings: &'static str,
+    pub(crate) setup: &'static str,
+    pub(crate) wifi_network: &'static str,
+    pub(crate) wifi_password: &'static str,
+    pub(crate) broker_ip: &'static str,
+    pub(crate) mqtt_username: &'static str,
+    pub(crate) mqtt_password: &'static str,
+    pub(crate) host_id: &'static str,
+    pub(crate) topic_prefix: &'static str,
+    pub(crate) static_address: &'static str,
+    pub(crate) gateway: &'static str,
+    pub(crate) dns_server: &'static str,
+    pub(crate) ipv6_address: &'static str,
+    pub(crate) ipv6_gateway: &'static str,
+    pub(crate) save: &'static str,
+    pub(crate) saved: &'static str,
+}
+
+const EN: Strings = Strings {
+    code: "en",
+    axis: "Axis",
+    position_unknown: "Position unknown",
+    of: "of",
+    steps: "steps",
+    open: "Open",
+    close: "Close",
+    home: "Home",
+    stop: "Stop",
+    save_settings: "Save settings",
+    setup: "setup",
+    wifi_network: "Wifi network",
+    wifi_password: "Wifi password",
+    broker_ip: "MQTT broker IP",
+    mqtt_username: "MQTT username",
+    mqtt_password: "MQTT password",
+    host_id: "MQTT client ID, empty for the default",
+    topic_prefix: "MQTT topic prefix, empty for the default",
+    static_address: "Static IP address, empty for DHCP",
+    gateway: "Gateway",
+    dns_server: "DNS server",
+    ipv6_address: "IPv6 address, optional",
+    ipv6_gateway: "IPv6 gateway",
+    save: "Save",
+    saved: "Saved, rebooting.",
+};
+
+const DE: Strings = Strings {
+    code: "de",
+    axis: "Achse",
+    position_unknown: "Position unbekannt",
+    of: "von",
+    steps: "Schritten",
+    open: "Öffnen",
+    close: "Schließen",
+    home: "Referenzfahrt",
+    stop: "Stopp",
+    save_settings: "Einstellungen speichern",
+    setup: "Einrichtung",
+    wifi_network: "WLAN-Name",
+    wifi_password: "WLAN-Passwort",
+    broker_ip: "IP des MQTT-Brokers",
+    mqtt_username: "MQTT-Benutzername",
+    mqtt_password: "MQTT-Passwort",
+    host_id: "MQTT-Client-ID, leer für die Vorgabe",
+    topic_prefix: "MQTT-Topic-Präfix, leer für die Vorgabe",
+    static_address: "Feste IP-Adresse, leer für DHCP",
+    gateway: "Gateway",
+    dns_server: "DNS-Server",
+    ipv6_address: "IPv6-Adresse, optional",
+    ipv6_gateway: "IPv6-Gateway",
+    save: "Speichern",
+    saved: "Gespeichert, starte neu.",
+};
+
+const ES: Strings = Strings {
+    code: "es",
+    axis: "Eje",
+    position_unknown: "Posición desconocida",
+    of: "de",
+    steps: "pasos",
+    open: "Abrir",
+    close: "Cerrar",
+    home: "Ir al inicio",
+    stop: "Parar",
+    save_settings: "Guardar ajustes",
+    setup: "configuración",
+    wifi_network: "Red wifi",
+    wifi_password: "Contraseña wifi",
+    broker_ip: "IP del broker MQTT",
+    mqtt_username: "Usuario MQTT",
+    mqtt_password: "Contraseña MQTT",
+    host_id: "ID de cliente MQTT, vacío para el predeterminado",
+    topic_prefix: "Prefijo de temas MQTT, vacío para el predeterminado",
+    static_address: "Dirección IP fija, vacía para DHCP",
+    gateway: "Puerta de enlace",
+    dns_server: "Servidor DNS",
+    ipv6_address: "Dirección IPv6, opcional",
+    ipv6_gateway: "Puerta de enlace IPv6",
+    save: "Guardar",
+    saved: "Guardado, reiniciando.",
+};
+
+// str's == isn't const.
+pub(crate) const fn is(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
@@ -1,64 +1,424 @@
-use defmt::{Format, debug, error};
+use bitfield_struct::bitfield;
+use defmt::{Debug2Format, Format, debug, error};
 
+use embassy_sync::{
+    blocking_mutex::raw::RawMutex,
+    mutex::{Mutex, MutexGuard},
+};
+use embassy_time::{Duration, WithTimeout};
 use embedded_io_async::{Error, ErrorType, Read, Write};
 use thiserror::Error;
 
+// How long to wait for a reply from an address before deciding nothing is there.
+const SCAN_TIMEOUT: Duration = Duration::from_millis(10);
+// How long a driver that is there gets to reply to a read, and how many more times it is asked
+// before giving up. A reply takes about 1ms at 115200 baud.
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+const READ_RETRIES: u8 = 3;
+// Set `tmc_write_retries` in the board file (or TMC_WRITE_RETRIES when building) to change how many
+// more times a write that didn't take is tried before giving up.
+const WRITE_RETRIES: u8 = match option_env!("TMC_WRITE_RETRIES") {
+    Some(retries) => match u8::from_str_radix(retries, 10) {
+        Ok(retries) => retries,
+        _ => panic!("TMC_WRITE_RETRIES must be from 0 to 255"),
+    },
+    None => 2,
+};
+// How long the bus has to be quiet for a half read reply to count as cleared out.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(2);
+
+// Registers we keep a copy of the last written value of.
+const SHADOWED_REGISTERS: [u8; 12] = [
+    0x00, 0x03, 0x07, 0x10, 0x11, 0x13, 0x14, 0x22, 0x40, 0x42, 0x6c, 0x70,
+];
+// Of those, the ones that can't be read back from the driver.
+const WRITE_ONLY_REGISTERS: [u8; 8] = [0x03, 0x10, 0x11, 0x13, 0x14, 0x22, 0x40, 0x42];
+
 #[derive(Format, Error, Debug, Clone, Copy)]
 pub enum UartError<U: Error> {
     #[error("TxError: {0:?}")]
     TxError(U),
     #[error("RxError: {0:?}")]
     RxError(U),
-    #[error("CRC mismatch")]
-    CrcMismatch,
     #[error("Unexpected end of stream")]
     UnexpectedEos,
     #[error("Adress was not populated at init")]
     UnpopulatedAdress,
     #[error("Incorrect Interface Transmission Counter, write did not take")]
     IncorrectIfcnt,
-    #[error("Got reply from wrong register address, expected {0}, got {1}")]
-    UnexpectedAdress(u8, u8),
+    #[error("Register is write only, and has not been written yet")]
+    NotShadowed,
+    #[error("Microsteps must be a power of two up to 256, got {0}")]
+    InvalidMicrosteps(u32),
+    #[error("No reply from the driver, even after retrying")]
+    BusTimeout,
+}
+
+/// Global configuration (GCONF, 0x00).
+#[bitfield(u32, defmt = true)]
+pub struct Gconf {
+    /// Scale the current with the voltage on VREF.
+    pub i_scale_analog: bool,
+    pub internal_rsense: bool,
+    /// SpreadCycle rather than StealthChop, louder but with more torque at speed.
+    pub en_spreadcycle: bool,
+    /// Reverse the motor.
+    pub shaft: bool,
+    pub index_otpw: bool,
+    pub index_step: bool,
+    /// Free PDN_UART for UART use, it powers the driver down otherwise.
+    pub pdn_disable: bool,
+    /// Take the microstepping from MRES rather than the MS1 and MS2 pins.
+    pub mstep_reg_select: bool,
+    /// Smooth out STEP frequency jitter.
+    pub multistep_filt: bool,
+    pub test_mode: bool,
+    #[bits(22)]
+    __: u32,
+}
+
+/// Global status flags (GSTAT, 0x01). Flags are cleared by writing a 1 to them.
+#[bitfield(u32, defmt = true)]
+pub struct Gstat {
+    /// The driver has been reset since the last clear, all registers are back to defaults.
+    pub reset: bool,
+    /// The driver has been shut down due to overtemperature or a short circuit.
+    pub drv_err: bool,
+    /// The charge pump is undervoltage, the driver is disabled while this is set.
+    pub uv_cp: bool,
+    #[bits(29)]
+    __: u32,
+}
+
+impl Gstat {
+    /// Returns true if any flag is set.
+    pub fn any(&self) -> bool {
+        self.into_bits() != 0
+    }
+}
+
+/// Driver status flags (DRV_STATUS, 0x6f), read only. There is no stall flag on the TMC2209,
+/// stalls only show on DIAG and in SG_RESULT.
+#[bitfield(u32, defmt = true)]
+pub struct DrvStatus {
+    /// Overtemperature prewarning, the driver is still running.
+    pub otpw: bool,
+    /// Overtemperature, the driver has shut down until it cools.
+    pub ot: bool,
+    /// Short to ground on phase A or B, the driver has shut down.
+    pub s2ga: bool,
+    pub s2gb: bool,
+    /// Short to the supply on phase A or B, by way of the low side MOSFETs.
+    pub s2vsa: bool,
+    pub s2vsb: bool,
+    /// Open load on phase A or B. Only meaningful while moving slowly, a fast move can set it
+    /// with no fault.
+    pub ola: bool,
+    pub olb: bool,
+    /// Temperature thresholds passed, 120C, 143C, 150C and 157C.
+    pub t120: bool,
+    pub t143: bool,
+    pub t150: bool,
+    pub t157: bool,
+    #[bits(4)]
+    __: u8,
+    /// The current actually being driven, in 32nds of full scale.
+    #[bits(5)]
+    pub cs_actual: u8,
+    #[bits(9)]
+    __: u16,
+    /// Running in StealthChop rather than SpreadCycle.
+    pub stealth: bool,
+    /// The motor is at standstill.
+    pub stst: bool,
+}
+
+impl DrvStatus {
+    /// Just the fault flags, otpw to olb, without the readings that change with every step.
+    pub fn faults(&self) -> Self {
+        Self::from_bits(self.into_bits() & 0xff)
+    }
+
+    /// Returns true if any fault flag is set.
+    pub fn any(&self) -> bool {
+        self.faults().into_bits() != 0
+    }
+
+    /// Returns true if the driver has shut its outputs down: overheated or shorted.
+    pub fn hard_fault(&self) -> bool {
+        self.ot() || self.short_to_ground() || self.short_to_supply()
+    }
+
+    pub fn short_to_ground(&self) -> bool {
+        self.s2ga() || self.s2gb()
+    }
+
+    pub fn short_to_supply(&self) -> bool {
+        self.s2vsa() || self.s2vsb()
+    }
+
+    pub fn open_load(&self) -> bool {
+        self.ola() || self.olb()
+    }
+}
+
+/// Chopper and driver configuration (CHOPCONF, 0x6c).
+#[bitfield(u32, defmt = true)]
+pub struct Chopconf {
+    /// Off time, 0 disables the driver outputs.
+    #[bits(4)]
+    pub toff: u8,
+    #[bits(3)]
+    pub hstrt: u8,
+    #[bits(4)]
+    pub hend: u8,
+    #[bits(4)]
+    __: u8,
+    #[bits(2)]
+    pub tbl: u8,
+    pub vsense: bool,
+    #[bits(6)]
+    __: u8,
+    /// Microstep resolution, 0 for 256 microsteps up to 8 for fullstep.
+    #[bits(4)]
+    pub mres: u8,
+    /// Interpolate each step to 256 microsteps, for smoother, quieter motion.
+    pub intpol: bool,
+    pub dedge: bool,
+    pub diss2g: bool,
+    pub diss2vs: bool,
+}
+
+impl Chopconf {
+    /// Microsteps per fullstep, from MRES.
+    pub fn microsteps(&self) -> u32 {
+        256 >> self.mres().min(8)
+    }
+
+    /// Sets MRES from microsteps per fullstep, None unless it is a power of two up to 256.
+    pub fn with_microsteps(self, microsteps: u32) -> Option<Self> {
+        if microsteps.is_power_of_two() && microsteps <= 256 {
+            Some(self.with_mres(8 - microsteps.trailing_zeros() as u8))
+        } else {
+            None
+        }
+    }
+}
+
+/// Driver current control (IHOLD_IRUN, 0x10), currents are in 32nds of full scale.
+#[bitfield(u32, defmt = true)]
+pub struct IholdIrun {
+    /// Standstill current.
+    #[bits(5)]
+    pub ihold: u8,
+    #[bits(3)]
+    __: u8,
+    /// Motor run current.
+    #[bits(5)]
+    pub irun: u8,
+    #[bits(3)]
+    __: u8,
+    /// How gradually to drop to the hold current after the motor stops.
+    #[bits(4)]
+    pub iholddelay: u8,
+    #[bits(12)]
+    __: u16,
+}
+
+/// The current the driver is giving each coil (MSCURACT, 0x6b), from -255 to 255 of full scale.
+#[bitfield(u32, defmt = true)]
+pub struct Mscuract {
+    #[bits(9)]
+    pub cur_a: i16,
+    #[bits(7)]
+    __: u8,
+    #[bits(9)]
+    pub cur_b: i16,
+    #[bits(7)]
+    __: u8,
+}
+
+/// UART reply delay (SLAVECONF, 0x03).
+#[bitfield(u32, defmt = true)]
+pub struct Slaveconf {
+    #[bits(8)]
+    __: u8,
+    /// How long to wait before replying to a read: 0 and 1 are 8 bit times, then 3, 5, 7... times
+    /// 8 for every two up to 15.
+    #[bits(4)]
+    pub senddelay: u8,
+    #[bits(20)]
+    __: u32,
 }
 
 #[derive(Format, Debug)]
 pub struct Tmc2209<U: Read + Write + ErrorType> {
     uart: U,
     pub ifcnt: [Option<u8>; 4],
+    // last written value of each of SHADOWED_REGISTERS, per address.
+    shadow: [[Option<u32>; SHADOWED_REGISTERS.len()]; 4],
 }
 
 impl<U: Read + Write + ErrorType> Tmc2209<U> {
-    pub async fn new(uart: U, addrs_present: [bool; 4]) -> Result<Self, UartError<U::Error>> {
+    /// Probes all four slave addresses by reading IFCNT, and constructs a driver with whichever
+    /// addresses replied in time marked as present.
+    pub async fn scan(uart: U) -> Self {
         let mut tmp = Self {
             uart,
-            ifcnt: addrs_present.map(|b| if b { Some(0) } else { None }),
+            ifcnt: [None; 4],
+            shadow: [[None; SHADOWED_REGISTERS.len()]; 4],
         };
-        let mut present = tmp.ifcnt;
-        for (addr, ifcnt) in present.iter_mut().enumerate() {
-            if ifcnt.is_some() {
-                *ifcnt = Some(tmp.read_register(addr as u8, 0x02).await? as u8)
+        for addr in 0..tmp.ifcnt.len() as u8 {
+            // absent addresses are the usual case, so they aren't retried.
+            match tmp
+                .try_read_register(addr, 0x02)
+                .with_timeout(SCAN_TIMEOUT)
+                .await
+            {
+                Ok(Ok(ifcnt)) => tmp.ifcnt[addr as usize] = Some(ifcnt as u8),
+                Ok(Err(e)) => debug!("error probing address {}: {}", addr, Debug2Format(&e)),
+                Err(_) => debug!("no reply from address {}", addr),
             }
         }
-        tmp.ifcnt = present;
-        Ok(tmp)
+        tmp
     }
 
+    /// Hands back the UART, for trying another baud rate.
+    pub fn release(self) -> U {
+        self.uart
+    }
+
+    /// Sets how long a driver waits before replying. The datasheet asks for at least 3 * 8 bit
+    /// times once more than one driver shares the wire, a lone one can answer straight away.
+    pub async fn set_send_delay(&mut self, slave_address: u8) -> Result<(), UartError<U::Error>> {
+        let shared = self.present().iter().filter(|p| **p).count() > 1;
+        let slaveconf = Slaveconf::new().with_senddelay(if shared { 2 } else { 0 });
+        self.write_register(slave_address, 0x03, slaveconf.into_bits())
+            .await
+    }
+
+    /// Returns which of the four slave addresses have a driver present.
+    pub fn present(&self) -> [bool; 4] {
+        self.ifcnt.map(|i| i.is_some())
+    }
+
+    /// Returns the last value written to a register, if it is shadowed and has been written.
+    pub fn shadow(&self, slave_address: u8, register: u8) -> Option<u32> {
+        let index = SHADOWED_REGISTERS.iter().position(|r| *r == register)?;
+        self.shadow[slave_address as usize][index]
+    }
+
+    /// Reads a register, falling back to the last written value for write only registers.
+    pub async fn read_register_or_shadow(
+        &mut self,
+        slave_address: u8,
+        register: u8,
+    ) -> Result<u32, UartError<U::Error>> {
+        if WRITE_ONLY_REGISTERS.contains(&register) {
+            self.shadow(slave_address, register)
+                .ok_or(UartError::NotShadowed)
+        } else {
+            self.read_register(slave_address, register).await
+        }
+    }
+
+    /// Reads GSTAT and clears whichever flags were set.
+    /// If the driver was reset, its IFCNT will have been reset too, so it is re-read before
+    /// clearing.
+    pub async fn read_and_clear_gstat(
+        &mut self,
+        slave_address: u8,
+    ) -> Result<Gstat, UartError<U::Error>> {
+        let gstat = Gstat::from_bits(self.read_register(slave_address, 0x01).await?);
+        if gstat.reset() {
+            self.resync(slave_address).await?;
+        }
+        if gstat.any() {
+            self.write_register(slave_address, 0x01, gstat.into_bits())
+                .await?;
+        }
+        Ok(gstat)
+    }
+
+    /// Reads DRV_STATUS.
+    pub async fn read_status(
+        &mut self,
+        slave_address: u8,
+    ) -> Result<DrvStatus, UartError<U::Error>> {
+        Ok(DrvStatus::from_bits(
+            self.read_register(slave_address, 0x6f).await?,
+        ))
+    }
+
+    /// Reads MSCNT, where the driver is in its microstep table, from 0 to 1023. It counts the
+    /// steps the driver has taken, 256 to a fullstep whatever the microstepping.
+    pub async fn read_mscnt(&mut self, slave_address: u8) -> Result<u16, UartError<U::Error>> {
+        Ok((self.read_register(slave_address, 0x6a).await? & 0x3ff) as u16)
+    }
+
+    /// Reads MSCURACT.
+    pub async fn read_mscuract(
+        &mut self,
+        slave_address: u8,
+    ) -> Result<Mscuract, UartError<U::Error>> {
+        Ok(Mscuract::from_bits(
+            self.read_register(slave_address, 0x6b).await?,
+        ))
+    }
+
+    /// Changes the microstep resolution and interpolation, leaving the rest of CHOPCONF as it is.
+    pub async fn set_microstepping(
+        &mut self,
+        slave_address: u8,
+        microsteps: u32,
+        intpol: bool,
+    ) -> Result<(), UartError<U::Error>> {
+        let chopconf = Chopconf::from_bits(self.read_register(slave_address, 0x6c).await?)
+            .with_microsteps(microsteps)
+            .ok_or(UartError::InvalidMicrosteps(microsteps))?
+            .with_intpol(intpol);
+        self.write_register(slave_address, 0x6c, chopconf.into_bits())
+            .await
+    }
+
+    /// Catches our count of a driver's successful writes back up with its IFCNT, for after a
+    /// write went missing or the driver was reset.
+    pub async fn resync(&mut self, slave_address: u8) -> Result<(), UartError<U::Error>> {
+        self.ifcnt[slave_address as usize] =
+            Some(self.read_register(slave_address, 0x02).await? as u8);
+        Ok(())
+    }
+
+    /// Writes a register, checking it took by IFCNT going up, and writing it again up to
+    /// [`WRITE_RETRIES`] times if it didn't.
     pub async fn write_register(
         &mut self,
         slave_address: u8,
         register: u8,
         data: u32,
     ) -> Result<(), UartError<U::Error>> {
-        self.write_register_unchecked(slave_address, register, data)
-            .await?;
-
-        if self.ifcnt[slave_address as usize].ok_or(UartError::UnpopulatedAdress)?
-            == self.read_register(slave_address, 0x02).await? as u8
-        {
-            debug!("writing {=u32:02x} succeded", data);
-            Ok(())
-        } else {
-            Err(UartError::IncorrectIfcnt)
+        let mut retries = 0;
+        loop {
+            self.write_register_unchecked(slave_address, register, data)
+                .await?;
+            let expected =
+                self.ifcnt[slave_address as usize].ok_or(UartError::UnpopulatedAdress)?;
+            let ifcnt = self.read_register(slave_address, 0x02).await? as u8;
+            if ifcnt == expected {
+                debug!("writing {=u32:02x} succeded", data);
+                return Ok(());
+            }
+            // either the write was lost, or an earlier one we thought was. The driver's count is
+            // the one to go by from here either way.
+            self.ifcnt[slave_address as usize] = Some(ifcnt);
+            if retries == WRITE_RETRIES {
+                return Err(UartError::IncorrectIfcnt);
+            }
+            retries += 1;
+            debug!(
+                "write to {=u8:02x} on address {} didn't take, retrying",
+                register, slave_address
+            );
         }
     }
 
@@ -75,24 +435,75 @@ impl<U: Read + Write + ErrorType> Tmc2209<U> {
                 .await
                 .map_err(UartError::TxError)?;
             self.ifcnt[slave_address as usize] = Some(ifcnt.wrapping_add(1));
+            if let Some(index) = SHADOWED_REGISTERS.iter().position(|r| *r == register) {
+                self.shadow[slave_address as usize][index] = Some(data);
+            }
             Ok(())
         } else {
             Err(UartError::UnpopulatedAdress)
         }
     }
 
-    // FIXME: Techincally, the magic bytes of [0x05, 0xff] could be part of the body of the message.
+    /// Reads a register, asking again if no reply comes within [`READ_TIMEOUT`], up to
+    /// [`READ_RETRIES`] times. A garbled reply is skipped over like noise, so it times out too.
     pub async fn read_register(
         &mut self,
         slave_address: u8,
         register: u8,
     ) -> Result<u32, UartError<U::Error>> {
-        const REPLY_BYTES: [u8; 2] = [0x05, 0xff];
+        let mut retries = 0;
+        loop {
+            match self
+                .try_read_register(slave_address, register)
+                .with_timeout(READ_TIMEOUT)
+                .await
+            {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(e)) => return Err(e),
+                Err(_) if retries < READ_RETRIES => (),
+                Err(_) => {
+                    error!(
+                        "no reply from address {} after {} retries",
+                        slave_address, retries
+                    );
+                    return Err(UartError::BusTimeout);
+                }
+            }
+            retries += 1;
+            debug!(
+                "retrying read of {=u8:02x} from address {}",
+                register, slave_address
+            );
+            self.drain().await;
+        }
+    }
+
+    /// Throws away whatever is left of an earlier reply, so it isn't taken for the next one.
+    async fn drain(&mut self) {
+        let mut buffer = [0; 8];
+        while let Ok(Ok(len)) = self
+            .uart
+            .read(&mut buffer)
+            .with_timeout(DRAIN_TIMEOUT)
+            .await
+        {
+            debug!("discarding: {=[u8]:02x}", buffer[..len]);
+        }
+    }
+
+    async fn try_read_register(
+        &mut self,
+        slave_address: u8,
+        register: u8,
+    ) -> Result<u32, UartError<U::Error>> {
         self.uart
             .write_all(&Self::construct_read_uart_message(slave_address, register))
             .await
             .map_err(UartError::TxError)?;
 
+        // our own request echoes back on the single wire before the reply, and a reply to an
+        // earlier read can still be on its way, so we pick the reply out of whatever comes.
+        let mut reply = Reply::new(register);
         let mut buffer: [u8; 8] = [0; 8];
         loop {
             let len = self
@@ -100,39 +511,14 @@ impl<U: Read + Write + ErrorType> Tmc2209<U> {
                 .read(&mut buffer)
                 .await
                 .map_err(UartError::RxError)?;
+            if len == 0 {
+                return Err(UartError::UnexpectedEos);
+            }
             debug!("received: {=[u8]:02x}", buffer[..len]);
-
-            // search for the 'magic bytes' indicating the start of a message.
-            if let Some(message_start) = buffer[..len].windows(2).position(|b| b == REPLY_BYTES) {
-                // if we find it, we put it at the very start of the buffer.
-                buffer.copy_within(message_start..len, 0);
-                let fragment_end = len - message_start;
-                debug!("Got fragment! {=[u8]:02x}", buffer[..fragment_end]);
-                // now we continue reading till we fill the rest of the buffer.
-                self.uart
-                    .read_exact(&mut buffer[fragment_end..])
-                    .await
-                    .map_err(|e| match e {
-                        embedded_io::ReadExactError::UnexpectedEof => UartError::UnexpectedEos,
-                        embedded_io::ReadExactError::Other(i) => UartError::RxError(i),
-                    })?;
-                debug!("Message is: {=[u8;8]:02x}", buffer);
-
-                let returned_address = buffer[2];
-                // That was a reply from a different register adress than expected, hope no other task was
-                // waiting for that!
-                if returned_address != (register & 0x7F) {
-                    return Err(UartError::UnexpectedAdress(register, returned_address));
+            for byte in &buffer[..len] {
+                if let Some(value) = reply.push(*byte) {
+                    return Ok(value);
                 }
-
-                // calc the CRC and return either the message or the CRC error.
-                return if Self::calc_uart_crc(&buffer[..buffer.len() - 1])
-                    == buffer[buffer.len() - 1]
-                {
-                    Ok(u32::from_be_bytes(buffer[3..7].try_into().unwrap()))
-                } else {
-                    Err(UartError::CrcMismatch)
-                };
             }
         }
     }
@@ -149,27 +535,196 @@ impl<U: Read + Write + ErrorType> Tmc2209<U> {
             d4,
             0,
         ];
-        msg[7] = Self::calc_uart_crc(&msg[..7]);
+        msg[7] = calc_uart_crc(&msg[..7]);
         msg
     }
 
     fn construct_read_uart_message(slave_address: u8, register: u8) -> [u8; 4] {
         let mut msg: [u8; 4] = [0b1010_0101, (slave_address & 0x7F), register, 0];
-        msg[3] = Self::calc_uart_crc(&msg[..3]);
+        msg[3] = calc_uart_crc(&msg[..3]);
         msg
     }
+}
 
-    fn calc_uart_crc(message: &[u8]) -> u8 {
-        let mut crc: u8 = 0;
-        for byte in message {
-            for i in 0..8 {
-                crc = if ((crc >> 7) ^ ((byte >> i) & 0x01)) != 0 {
-                    (crc << 1) ^ 0x07
-                } else {
-                    crc << 1
-                }
+fn calc_uart_crc(message: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for byte in message {
+        for i in 0..8 {
+            crc = if ((crc >> 7) ^ ((byte >> i) & 0x01)) != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
             }
         }
-        crc
+    }
+    crc
+}
+
+/// A reply to a read, put together a byte at a time.
+struct Reply {
+    register: u8,
+    datagram: [u8; 8],
+    len: usize,
+}
+
+impl Reply {
+    // the sync nibble, with the four reserved bits after it left as they come.
+    const SYNC: u8 = 0x05;
+    const SYNC_MASK: u8 = 0x0f;
+    // replies are addressed to the master.
+    const MASTER_ADDRESS: u8 = 0xff;
+
+    fn new(register: u8) -> Self {
+        Self {
+            register: register & 0x7f,
+            datagram: [0; 8],
+            len: 0,
+        }
+    }
+
+    /// Whether what we have so far could be the start of the reply.
+    fn plausible(&self) -> bool {
+        let datagram = &self.datagram[..self.len];
+        datagram
+            .first()
+            .is_none_or(|b| b & Self::SYNC_MASK == Self::SYNC)
+            && datagram.get(1).is_none_or(|b| *b == Self::MASTER_ADDRESS)
+            && datagram.get(2).is_none_or(|b| *b == self.register)
+            && (self.len < self.datagram.len()
+                || calc_uart_crc(&self.datagram[..7]) == self.datagram[7])
+    }
+
+    /// Takes the next byte off the wire, returning the register's value once the whole reply is
+    /// in. Anything that turns out not to be the reply is dropped a byte at a time, so a reply
+    /// that starts part way through something else is still found.
+    fn push(&mut self, byte: u8) -> Option<u32> {
+        self.datagram[self.len] = byte;
+        self.len += 1;
+        while !self.plausible() {
+            debug!("resyncing past: {=[u8]:02x}", self.datagram[..self.len]);
+            self.datagram.copy_within(1..self.len, 0);
+            self.len -= 1;
+        }
+        (self.len == self.datagram.len())
+            .then(|| u32::from_be_bytes(self.datagram[3..7].try_into().unwrap()))
+    }
+}
+
+/// A [`Tmc2209`] shared between tasks. The UART is one wire shared by every driver on it, so
+/// each transaction holds the bus until its reply is in, and replies can't interleave.
+pub struct Tmc2209Bus<M: RawMutex, U: Read + Write + ErrorType> {
+    driver: Mutex<M, Tmc2209<U>>,
+    present: [bool; 4],
+}
+
+impl<M: RawMutex, U: Read + Write + ErrorType> Tmc2209Bus<M, U> {
+    pub fn new(driver: Tmc2209<U>) -> Self {
+        Self {
+            present: driver.present(),
+            driver: Mutex::new(driver),
+        }
+    }
+
+    /// See [`Tmc2209::present`].
+    pub fn present(&self) -> [bool; 4] {
+        self.present
+    }
+
+    /// The driver strapped to `slave_address`.
+    pub fn handle(&self, slave_address: u8) -> Tmc2209Handle<'_, M, U> {
+        Tmc2209Handle {
+            bus: self,
+            slave_address,
+        }
+    }
+
+    /// Takes the bus for several transactions in a row.
+    pub async fn lock(&self) -> MutexGuard<'_, M, Tmc2209<U>> {
+        self.driver.lock().await
+    }
+}
+
+/// One driver on a [`Tmc2209Bus`]. Each call is a transaction of its own, see [`Tmc2209`] for
+/// what they do.
+pub struct Tmc2209Handle<'a, M: RawMutex, U: Read + Write + ErrorType> {
+    bus: &'a Tmc2209Bus<M, U>,
+    slave_address: u8,
+}
+
+impl<M: RawMutex, U: Read + Write + ErrorType> Tmc2209Handle<'_, M, U> {
+    pub fn slave_address(&self) -> u8 {
+        self.slave_address
+    }
+
+    pub async fn read_register(&self, register: u8) -> Result<u32, UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .read_register(self.slave_address, register)
+            .await
+    }
+
+    pub async fn read_register_or_shadow(&self, register: u8) -> Result<u32, UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .read_register_or_shadow(self.slave_address, register)
+            .await
+    }
+
+    pub async fn write_register(&self, register: u8, data: u32) -> Result<(), UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .write_register(self.slave_address, register, data)
+            .await
+    }
+
+    pub async fn set_send_delay(&self) -> Result<(), UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .set_send_delay(self.slave_address)
+            .await
+    }
+
+    pub async fn resync(&self) -> Result<(), UartError<U::Error>> {
+        self.bus.lock().await.resync(self.slave_address).await
+    }
+
+    pub async fn read_and_clear_gstat(&self) -> Result<Gstat, UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .read_and_clear_gstat(self.slave_address)
+            .await
+    }
+
+    pub async fn read_status(&self) -> Result<DrvStatus, UartError<U::Error>> {
+        self.bus.lock().await.read_status(self.slave_address).await
+    }
+
+    pub async fn read_mscnt(&self) -> Result<u16, UartError<U::Error>> {
+        self.bus.lock().await.read_mscnt(self.slave_address).await
+    }
+
+    pub async fn read_mscuract(&self) -> Result<Mscuract, UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .read_mscuract(self.slave_address)
+            .await
+    }
+
+    pub async fn set_microstepping(
+        &self,
+        microsteps: u32,
+        intpol: bool,
+    ) -> Result<(), UartError<U::Error>> {
+        self.bus
+            .lock()
+            .await
+            .set_microstepping(self.slave_address, microsteps, intpol)
+            .await
     }
 }
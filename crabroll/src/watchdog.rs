@@ -0,0 +1,291 @@
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+    panic::PanicInfo,
+};
+
+use defmt::{Display2Format, Format, error, warn};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use esp_hal::{
+    peripherals::TIMG0,
+    system::software_reset,
+    timer::timg::{MwdtStage, TimerGroup},
+};
+use heapless::{String, Vec, format};
+
+use crate::{
+    AXES, Command, ErrorSeverity,
+    arbiter::Source,
+    config_store::{CRASH_KEY, fetch_board_item, store_board_item},
+    state::{raise_error, send_local_command},
+};
+
+// Every watched task has to feed the watchdog at least this often. The motor tasks feed it every
+// step and once a minute while idle, MQTT at least every ping.
+const TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// The hardware watchdog catches the executor itself hanging, as nothing would be checking then.
+const HARDWARE_TIMEOUT: esp_hal::time::Duration = esp_hal::time::Duration::from_secs(10);
+// How long the motor tasks still going get to save their positions before we reset.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+// Longer messages are cut short.
+const CRASH_MESSAGE_LEN: usize = 128;
+// whether it has been published, then the message.
+const CRASH_BYTES: usize = CRASH_MESSAGE_LEN + 1;
+// "panc", marks the panic record as written by the panic handler rather than left over garbage.
+const PANIC_MAGIC: u32 = 0x7061_6e63;
+
+const WATCHED: usize = AXES + 2;
+
+// When each watched task last fed the watchdog, None until it first does.
+static FED: [Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>>; WATCHED] =
+    [const { Mutex::new(Cell::new(None)) }; WATCHED];
+// The last crash, loaded at boot.
+static LAST_CRASH: Mutex<CriticalSectionRawMutex, RefCell<Option<Crash>>> =
+    Mutex::new(RefCell::new(None));
+
+// Left by the panic handler for the next boot, in RTC memory as it survives a reset. Only the
+// panic handler writes them, and only `load_crash()` reads them.
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PANIC_MARKER: u32 = 0;
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PANIC_LEN: u32 = 0;
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut PANIC_MESSAGE: [u8; CRASH_MESSAGE_LEN] = [0; CRASH_MESSAGE_LEN];
+
+/// The tasks the watchdog keeps an eye on.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Watched {
+    Motor(usize),
+    Mqtt,
+    Wifi,
+}
+
+impl Watched {
+    fn slot(self) -> usize {
+        match self {
+            Self::Motor(axis) => axis,
+            Self::Mqtt => AXES,
+            Self::Wifi => AXES + 1,
+        }
+    }
+
+    fn from_slot(slot: usize) -> Self {
+        match slot {
+            AXES => Self::Mqtt,
+            s if s > AXES => Self::Wifi,
+            axis => Self::Motor(axis),
+        }
+    }
+
+    fn crash_message(self) -> String<CRASH_MESSAGE_LEN> {
+        // can't overflow, the longest is far shorter.
+        match self {
+            Self::Motor(axis) => {
+                format!(CRASH_MESSAGE_LEN; "watchdog: motor task {} stalled", axis)
+            }
+            Self::Mqtt => format!(CRASH_MESSAGE_LEN; "watchdog: mqtt task stalled"),
+            Self::Wifi => format!(CRASH_MESSAGE_LEN; "watchdog: wifi task stalled"),
+        }
+        .unwrap()
+    }
+}
+
+/// Tells the watchdog a task is still going. Tasks are only watched from their first feed.
+pub(crate) fn feed(task: Watched) {
+    FED[task.slot()].lock(|f| f.set(Some(Instant::now())));
+}
+
+/// Stops watching a task, for one that is finishing on purpose.
+pub(crate) fn unwatch(task: Watched) {
+    FED[task.slot()].lock(|f| f.set(None));
+}
+
+/// Runs `future`, feeding the watchdog for `task` until it is done. For waits that can rightly
+/// take longer than the timeout, like for the wifi to drop.
+pub(crate) async fn feeding<F: Future>(task: Watched, future: F) -> F::Output {
+    let feeder = async {
+        loop {
+            feed(task);
+            Timer::after(TIMEOUT / 2).await;
+        }
+    };
+    match select(future, feeder).await {
+        Either::First(output) => output,
+        Either::Second(_) => unreachable!(),
+    }
+}
+
+/// Resets the board if a watched task stops feeding the watchdog, after logging which one it was
+/// and letting the others save their positions. Keeps the hardware watchdog fed for as long as the
+/// executor is running.
+#[embassy_executor::task]
+pub(crate) async fn watchdog_task(timg0: TIMG0<'static>) {
+    let mut wdt = TimerGroup::new(timg0).wdt;
+    wdt.set_timeout(MwdtStage::Stage0, HARDWARE_TIMEOUT);
+    wdt.enable();
+    let culprit = loop {
+        wdt.feed();
+        Timer::after(CHECK_INTERVAL).await;
+        let stalled = FED.iter().position(|f| {
+            f.lock(|f| f.get())
+                .is_some_and(|fed| fed.elapsed() > TIMEOUT)
+        });
+        if let Some(slot) = stalled {
+            break Watched::from_slot(slot);
+        }
+    };
+    error!("{} stopped feeding the watchdog, rebooting", culprit);
+    wdt.feed();
+    // whatever is stuck may be holding the flash.
+    store_crash(&Crash::new(culprit.crash_message()))
+        .with_timeout(SHUTDOWN_GRACE)
+        .await
+        .ok();
+    // a stuck motor task can't save its position, so it rehomes instead.
+    for axis in (0..AXES).filter(|a| culprit != Watched::Motor(*a)) {
+        send_local_command(axis, Source::Board, Command::Shutdown);
+    }
+    wdt.feed();
+    Timer::after(SHUTDOWN_GRACE).await;
+    software_reset();
+}
+
+/// Why the board last went down without meaning to, kept in flash until it has been published.
+struct Crash {
+    message: String<CRASH_MESSAGE_LEN>,
+    published: bool,
+}
+
+impl Crash {
+    fn new(message: String<CRASH_MESSAGE_LEN>) -> Self {
+        Self {
+            message,
+            published: false,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8, CRASH_BYTES> {
+        let mut bytes = Vec::new();
+        // can't overflow, the message is at most CRASH_MESSAGE_LEN.
+        bytes.push(self.published.into()).unwrap();
+        bytes.extend_from_slice(self.message.as_bytes()).unwrap();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (published, message) = bytes.split_first()?;
+        Some(Self {
+            message: String::try_from(core::str::from_utf8(message).ok()?).ok()?,
+            published: *published != 0,
+        })
+    }
+}
+
+/// Picks up the panic left by the last boot, if there was one, and the last crash stored in flash
+/// otherwise. For main only, after setting up the storage.
+pub(crate) async fn load_crash() {
+    // safety: nothing else touches these, see their declaration.
+    let panicked = unsafe {
+        if (&raw const PANIC_MARKER).read() == PANIC_MAGIC {
+            (&raw mut PANIC_MARKER).write(0);
+            let len = ((&raw const PANIC_LEN).read() as usize).min(CRASH_MESSAGE_LEN);
+            Some(((&raw const PANIC_MESSAGE).read(), len))
+        } else {
+            None
+        }
+    };
+    let crash = match panicked {
+        Some((message, len)) => {
+            let message = core::str::from_utf8(&message[..len]).unwrap_or("unreadable panic");
+            warn!("Rebooted after a panic: {}", message);
+            // can't overflow, it was cut short to fit when it was written.
+            let crash = Crash::new(String::try_from(message).unwrap());
+            store_crash(&crash).await;
+            Some(crash)
+        }
+        None => match fetch_board_item::<Vec<u8, CRASH_BYTES>>(CRASH_KEY).await {
+            Ok(bytes) => bytes.and_then(|b| Crash::from_bytes(&b)),
+            Err(_) => {
+                error!("Error getting item in flash");
+                raise_error(ErrorSeverity::Soft);
+                None
+            }
+        },
+    };
+    LAST_CRASH.lock(|c| c.replace(crash));
+}
+
+async fn store_crash(crash: &Crash) {
+    if store_board_item(CRASH_KEY, &crash.to_bytes())
+        .await
+        .is_err()
+    {
+        error!("Error storing item in flash");
+        raise_error(ErrorSeverity::Soft);
+    }
+}
+
+/// The last crash, if it hasn't been published yet.
+pub(crate) fn unpublished_crash() -> Option<String<CRASH_MESSAGE_LEN>> {
+    LAST_CRASH.lock(|c| {
+        c.borrow()
+            .as_ref()
+            .filter(|c| !c.published)
+            .map(|c| c.message.clone())
+    })
+}
+
+/// Marks the last crash as published, so it isn't published again after the next boot.
+pub(crate) async fn crash_published() {
+    let crash = LAST_CRASH.lock(|c| {
+        let mut c = c.borrow_mut();
+        let crash = c.as_mut()?;
+        crash.published = true;
+        Some(Crash {
+            message: crash.message.clone(),
+            published: true,
+        })
+    });
+    if let Some(crash) = crash {
+        store_crash(&crash).await;
+    }
+}
+
+/// The last crash as stored in flash, for rewriting it after a flash recovery.
+pub(crate) fn crash_bytes() -> Option<Vec<u8, CRASH_BYTES>> {
+    LAST_CRASH.lock(|c| c.borrow().as_ref().map(Crash::to_bytes))
+}
+
+/// Writes as much as fits, cutting the rest.
+struct Truncating(String<CRASH_MESSAGE_LEN>);
+
+impl Write for Truncating {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    error!("{}", Display2Format(info));
+    let mut message = Truncating(String::new());
+    write!(message, "{}", info).ok();
+    let bytes = message.0.as_bytes();
+    // safety: nothing else touches these, see their declaration.
+    unsafe {
+        (&raw mut PANIC_MESSAGE)
+            .cast::<u8>()
+            .copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+        (&raw mut PANIC_LEN).write(bytes.len() as u32);
+        (&raw mut PANIC_MARKER).write(PANIC_MAGIC);
+    }
+    software_reset()
+}
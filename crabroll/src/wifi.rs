@@ -1,56 +1,149 @@
-use defmt::info;
+use defmt::{error, info, warn};
+use embassy_futures::select::{Either, select};
 use embassy_net::Runner;
-use embassy_time::{Duration, Timer};
-use esp_radio::wifi::{ClientConfig, ModeConfig, ScanConfig, WifiDevice};
+use embassy_time::{Duration, Instant, Timer};
+use esp_radio::wifi::{AccessPointConfig, ClientConfig, ModeConfig, ScanConfig, WifiDevice};
 use esp_radio::wifi::{WifiController, WifiEvent, WifiStaState};
 
-pub(crate) const SSID: &str = env!("SSID");
+use crate::{
+    Connectivity,
+    config_store::{credentials_connected, store_credentials_connected},
+    diagnostics::set_rssi,
+    power::woke_from_sleep,
+    provisioning::{Credentials, request_provisioning},
+    set_connectivity,
+    watchdog::{Watched, feed, feeding},
+};
 
-pub(crate) const PASSWORD: &str = env!("PASSWORD");
+// How often to read the signal strength while connected, for the diagnostics.
+const RSSI_INTERVAL: Duration = Duration::from_secs(60);
+// Give up and go into provisioning mode after failing to connect this many times in a row, about
+// a minute with the retry delay, if the credentials have never connected.
+const MAX_CONNECT_FAILURES: u32 = 12;
+// Credentials that have connected before are only given up on after this long without the wifi,
+// as after a power cut the router can take a while to come back, and the portal is open to anyone
+// nearby.
+const PROVISION_AFTER_OUTAGE: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+// retrying backs off up to this, for credentials that have connected before.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+const PROVISIONING_SSID: &str = concat!(env!("HOST_ID"), "-setup");
 
 #[embassy_executor::task]
-pub(crate) async fn connection(mut controller: WifiController<'static>) {
+pub(crate) async fn connection(
+    mut controller: WifiController<'static>,
+    credentials: &'static Credentials,
+) {
     info!("start connection task");
     info!("Device capabilities: {:?}", controller.capabilities());
+    let mut connected_before = credentials_connected().await.unwrap_or_else(|_| {
+        error!("Error getting item in flash");
+        // don't give up on credentials that may well work.
+        true
+    });
+    let mut failures = 0;
+    let mut retry_delay = RETRY_DELAY;
+    let mut offline_since = Instant::now();
     loop {
+        feed(Watched::Wifi);
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_millis(5000)).await
+            // wait until we're no longer connected, reading the signal strength every so often.
+            // waiting for the event again clears it, so the state is checked too.
+            while esp_radio::wifi::sta_state() == WifiStaState::Connected {
+                if let Ok(rssi) = controller.rssi() {
+                    set_rssi(rssi);
+                }
+                let disconnected = feeding(
+                    Watched::Wifi,
+                    select(
+                        controller.wait_for_event(WifiEvent::StaDisconnected),
+                        Timer::after(RSSI_INTERVAL),
+                    ),
+                )
+                .await;
+                if let Either::First(()) = disconnected {
+                    break;
+                }
+            }
+            set_connectivity(Connectivity::Offline);
+            offline_since = Instant::now();
+            Timer::after(RETRY_DELAY).await
         }
         if !matches!(controller.is_started(), Ok(true)) {
             let station_config = ModeConfig::Client(
                 ClientConfig::default()
-                    .with_ssid(SSID.into())
-                    .with_password(PASSWORD.into()),
+                    .with_ssid(credentials.ssid.as_str().into())
+                    .with_password(credentials.password.as_str().into()),
             );
             controller.set_config(&station_config).unwrap();
             info!("Starting wifi");
             controller.start_async().await.unwrap();
             info!("Wifi started!");
 
-            info!("Scan");
-            let scan_config = ScanConfig::default().with_max(10);
-            let result = controller
-                .scan_with_config_async(scan_config)
-                .await
-                .unwrap();
-            for ap in result {
-                info!("{:?}", ap);
+            // just for the logs, so skipped to get back online quicker after sleeping.
+            if !woke_from_sleep() {
+                info!("Scan");
+                let scan_config = ScanConfig::default().with_max(10);
+                let result = controller
+                    .scan_with_config_async(scan_config)
+                    .await
+                    .unwrap();
+                for ap in result {
+                    info!("{:?}", ap);
+                }
             }
         }
         info!("About to connect...");
 
         match controller.connect_async().await {
-            Ok(_) => info!("Wifi connected!"),
+            Ok(_) => {
+                info!("Wifi connected!");
+                set_connectivity(Connectivity::Wifi);
+                failures = 0;
+                retry_delay = RETRY_DELAY;
+                if !connected_before {
+                    connected_before = true;
+                    if store_credentials_connected(true).await.is_err() {
+                        error!("Error storing item in flash");
+                    }
+                }
+            }
             Err(e) => {
                 info!("Failed to connect to wifi: {:?}", e);
-                Timer::after(Duration::from_millis(5000)).await
+                failures += 1;
+                let give_up = if connected_before {
+                    offline_since.elapsed() >= PROVISION_AFTER_OUTAGE
+                } else {
+                    failures >= MAX_CONNECT_FAILURES
+                };
+                if give_up {
+                    warn!(
+                        "Can't connect to {}, rebooting to provision",
+                        credentials.ssid.as_str()
+                    );
+                    request_provisioning().await;
+                }
+                feeding(Watched::Wifi, Timer::after(retry_delay)).await;
+                if connected_before {
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                }
             }
         }
     }
 }
 
+/// Runs the open access point the provisioning portal is served on.
+#[embassy_executor::task]
+pub(crate) async fn access_point(mut controller: WifiController<'static>) {
+    let ap_config =
+        ModeConfig::AccessPoint(AccessPointConfig::default().with_ssid(PROVISIONING_SSID.into()));
+    controller.set_config(&ap_config).unwrap();
+    controller.start_async().await.unwrap();
+    info!("Provisioning access point {} started", PROVISIONING_SSID);
+    // the access point stays up as long as the controller is around.
+    core::future::pending::<()>().await
+}
+
 #[embassy_executor::task]
 pub(crate) async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
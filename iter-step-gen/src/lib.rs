@@ -18,6 +18,10 @@ pub enum StepperError {
     MoveOutOfBounds,
     #[error("Attempted a planned move while not homed")]
     NotHomed,
+    #[error("Stalled during a move")]
+    Stalled,
+    #[error("Didn't reach the endstop within the travel allowed for homing")]
+    EndstopNotFound,
 }
 
 #[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,6 +58,20 @@ pub struct Stepper {
     accel_divisor: u64,
     // precomputed delay of the first step
     inital_delay: u64,
+    // steps of slack in the drive train, taken up without moving whenever the direction reverses.
+    backlash: u32,
+    // how far into the slack we are, 0 when it was last taken up towards home, `backlash` when
+    // away from home.
+    slack: u32,
+    // steps planned moves and jogs stay clear of home and the travel limit by.
+    soft_limit: u32,
+    // soft limits are ignored while seeking an endstop.
+    seek_endstop: bool,
+    // steps/sec of the last step a plan emitted, 0 once it has finished.
+    velocity: u32,
+    // the delays of the last planned move.
+    #[cfg(feature = "telemetry")]
+    ramp: RampRecorder,
 }
 
 impl Stepper {
@@ -82,6 +100,13 @@ impl Stepper {
             cruise_delay: Self::compute_cruise_delay(max_speed),
             accel_divisor: Self::compute_accel_divisor(max_accel),
             inital_delay: Self::compute_inital_delay(start_vel, max_accel),
+            backlash: 0,
+            slack: 0,
+            soft_limit: 0,
+            seek_endstop: false,
+            velocity: 0,
+            #[cfg(feature = "telemetry")]
+            ramp: RampRecorder::new(),
         }
     }
 
@@ -109,32 +134,80 @@ impl Stepper {
         Duration::from_hz(max_speed.get() as u64)
     }
 
-    pub fn homing_move<F: FnMut() -> bool>(&mut self, endstop_fn: F) -> HomingMove<'_, F> {
+    /// Homes at the start velocity, giving up after `max_travel` steps without reaching the
+    /// endstop, see [`HomingMove::result`].
+    pub fn homing_move<F: FnMut() -> bool>(
+        &mut self,
+        max_travel: u32,
+        endstop_fn: F,
+    ) -> HomingMove<'_, F> {
+        self.homing_move_at(self.start_vel, max_travel, endstop_fn)
+    }
+
+    fn homing_move_at<F: FnMut() -> bool>(
+        &mut self,
+        vel: u32,
+        max_travel: u32,
+        endstop_fn: F,
+    ) -> HomingMove<'_, F> {
         self.curent_pos = None;
-        let delay = Duration::from_ticks(TICK_HZ / u64::from(self.start_vel));
+        // pressing into the endstop takes up all the slack towards home.
+        self.slack = 0;
+        let delay = Duration::from_ticks(TICK_HZ / u64::from(vel.max(1)));
         HomingMove {
             stepper: self,
             delay,
             endstop_fn,
             steps_moved: 0,
+            max_travel,
+            gave_up: false,
         }
     }
 
+    /// Plans an accelerated move to `target_pos`, returning the plan and the direction to move in.
+    /// A target between a soft limit and the end of travel it guards is moved back to the soft
+    /// limit, see [`Stepper::set_soft_limit`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::NotHomed`] if the stepper has not been homed, or
+    /// [`StepperError::MoveOutOfBounds`] if `target_pos` is past the travel limit.
     //TODO: Refactor as a typestate for the NotHomed check?
     pub fn planned_move(
         &mut self,
         target_pos: u32,
+    ) -> Result<(PlannedMove<'_>, Direction), StepperError> {
+        self.planned_move_with_speed(target_pos, self.max_speed)
+    }
+
+    /// Plans a move like [`Stepper::planned_move`], but cruising at no more than
+    /// `max_speed_override` steps/sec, for this plan only. The configured max speed still caps it,
+    /// and it can't go slower than the start velocity.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Stepper::planned_move`].
+    pub fn planned_move_with_speed(
+        &mut self,
+        target_pos: u32,
+        max_speed_override: NonZeroU32,
     ) -> Result<(PlannedMove<'_>, Direction), StepperError> {
         match self.curent_pos {
             None => Err(StepperError::NotHomed),
             Some(_) if target_pos > self.travel_limit.get() => Err(StepperError::MoveOutOfBounds),
             Some(current_pos) => {
+                let target_pos = self.within_soft_limits(target_pos);
                 let move_distance: u32 = current_pos.abs_diff(target_pos);
+                let max_speed = min(max_speed_override, self.max_speed);
+                #[cfg(feature = "telemetry")]
+                self.ramp.clear();
+                let max_stopping_distance =
+                    Self::compute_max_stopping_distance(max_speed, self.start_vel, self.max_accel);
 
                 // TODO: Not sure why I need that +2, but somewhere we have an off-by-2, as without
                 // this we have too much deccel on the last step of a move.
-                let stopping_distance = if move_distance > self.max_stopping_distance * 2 {
-                    self.max_stopping_distance
+                let stopping_distance = if move_distance > max_stopping_distance * 2 {
+                    max_stopping_distance
                 } else {
                     move_distance.div_ceil(2)
                 } + 2;
@@ -153,6 +226,10 @@ impl Stepper {
                         steps_to_travel: move_distance,
                         dir,
                         rem: 0,
+                        max_speed,
+                        max_stopping_distance,
+                        cruise_delay: Self::compute_cruise_delay(max_speed),
+                        cruise_err: u64::from(max_speed.get() / 2),
                     },
                     dir,
                 ))
@@ -160,17 +237,82 @@ impl Stepper {
         }
     }
 
+    /// Plans a move like [`Stepper::planned_move`], but checks `guard_fn` before every step and
+    /// stops dead if it returns false, for example because the motor has stalled. The position is
+    /// unknown after that, see [`GuardedMove::result`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Stepper::planned_move`].
+    pub fn guarded_move<F: FnMut() -> bool>(
+        &mut self,
+        target_pos: u32,
+        guard_fn: F,
+    ) -> Result<(GuardedMove<'_, F>, Direction), StepperError> {
+        self.guarded_move_with_speed(target_pos, self.max_speed, guard_fn)
+    }
+
+    /// A [`Stepper::guarded_move`] with a speed override, see
+    /// [`Stepper::planned_move_with_speed`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Stepper::planned_move`].
+    pub fn guarded_move_with_speed<F: FnMut() -> bool>(
+        &mut self,
+        target_pos: u32,
+        max_speed_override: NonZeroU32,
+        guard_fn: F,
+    ) -> Result<(GuardedMove<'_, F>, Direction), StepperError> {
+        let (plan, dir) = self.planned_move_with_speed(target_pos, max_speed_override)?;
+        Ok((
+            GuardedMove {
+                plan,
+                guard_fn,
+                stalled: false,
+            },
+            dir,
+        ))
+    }
+
+    /// Starts a constant speed jog at the start velocity in `dir` that continues while
+    /// `continue_fn` returns true, stopping dead once it doesn't, or at the soft limit.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::NotHomed`] if the stepper has not been homed.
     pub fn continuous_jog<F: FnMut() -> bool>(
         &mut self,
         continue_fn: F,
         dir: Direction,
+    ) -> Result<ContinuousJog<'_, F>, StepperError> {
+        let jog_speed = NonZeroU32::new(self.start_vel).unwrap_or(NonZeroU32::MIN);
+        self.continuous_jog_with_speed(continue_fn, dir, jog_speed)
+    }
+
+    /// Starts a jog in `dir` that accelerates up to `jog_speed` steps/sec, and decelerates to a
+    /// stop once `continue_fn` returns false, or in time to stop at the soft limit. The configured
+    /// max speed still caps it. At or below the start velocity it goes at `jog_speed` throughout
+    /// and stops dead, like [`Stepper::continuous_jog`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::NotHomed`] if the stepper has not been homed.
+    pub fn continuous_jog_with_speed<F: FnMut() -> bool>(
+        &mut self,
+        continue_fn: F,
+        dir: Direction,
+        jog_speed: NonZeroU32,
     ) -> Result<ContinuousJog<'_, F>, StepperError> {
         match self.curent_pos {
             Some(_) => {
-                let delay = Duration::from_ticks(TICK_HZ / u64::from(self.start_vel));
+                let cruise_delay = Self::compute_cruise_delay(min(jog_speed, self.max_speed));
                 Ok(ContinuousJog {
                     stepper: self,
-                    delay,
+                    prev_delay: Duration::MAX,
+                    cruise_delay,
+                    rem: 0,
+                    stopping: false,
                     continue_fn,
                     dir,
                 })
@@ -196,6 +338,19 @@ impl Stepper {
         self.max_speed
     }
 
+    /// The delays generated by the last planned move, see [`RampRecorder`].
+    #[cfg(feature = "telemetry")]
+    #[must_use]
+    pub fn ramp(&self) -> &RampRecorder {
+        &self.ramp
+    }
+
+    /// Samples every `decimation`th step of planned moves from the next one on.
+    #[cfg(feature = "telemetry")]
+    pub fn set_ramp_decimation(&mut self, decimation: NonZeroU32) {
+        self.ramp.decimation = decimation;
+    }
+
     /// Sets the max speed of this [`Stepper`] in steps/sec.
     pub fn set_max_speed(&mut self, max_speed: NonZeroU32) {
         self.max_speed = max_speed;
@@ -233,12 +388,137 @@ impl Stepper {
         self.inital_delay = Self::compute_inital_delay(start_vel, self.max_accel);
     }
 
+    /// Returns the backlash of this [`Stepper`] in steps.
+    #[must_use]
+    pub fn backlash(&self) -> u32 {
+        self.backlash
+    }
+
+    /// Sets the backlash of this [`Stepper`] in steps. Whenever a move or jog reverses direction,
+    /// this many extra steps are taken at the start velocity before the position starts to
+    /// change, to take up the slack in the drive train.
+    pub fn set_backlash(&mut self, backlash: u32) {
+        self.backlash = backlash;
+        self.slack = min(self.slack, backlash);
+    }
+
+    /// Returns the soft limit of this [`Stepper`] in steps.
+    #[must_use]
+    pub fn soft_limit(&self) -> u32 {
+        self.soft_limit
+    }
+
+    /// Sets how many steps short of home and of the travel limit planned moves and jogs stop, so
+    /// that normal operation never runs into either end. Homing moves go all the way regardless,
+    /// and [`Stepper::set_seek_endstop`] lets everything else do the same.
+    pub fn set_soft_limit(&mut self, soft_limit: u32) {
+        self.soft_limit = soft_limit;
+    }
+
+    /// The nearest and furthest positions planned moves and jogs go to. A soft limit more than
+    /// half the travel limit is cut to half of it.
+    #[must_use]
+    pub fn soft_limits(&self) -> (u32, u32) {
+        let margin = min(self.soft_limit, self.travel_limit.get() / 2);
+        (margin, self.travel_limit.get() - margin)
+    }
+
+    /// Whether soft limits are being ignored, see [`Stepper::set_seek_endstop`].
+    #[must_use]
+    pub fn seeking_endstop(&self) -> bool {
+        self.seek_endstop
+    }
+
+    /// Ignores the soft limits until turned off again, for running up to an endstop that isn't
+    /// home, like one at the bottom of travel. Planned moves still can't go past the travel limit,
+    /// but jogs aren't limited at all.
+    pub fn set_seek_endstop(&mut self, seek_endstop: bool) {
+        self.seek_endstop = seek_endstop;
+    }
+
+    /// Moves `pos` back to the soft limits, unless seeking an endstop.
+    fn within_soft_limits(&self, pos: u32) -> u32 {
+        if self.seek_endstop {
+            return pos;
+        }
+        let (nearest, furthest) = self.soft_limits();
+        pos.clamp(nearest, furthest)
+    }
+
+    /// The soft limit a jog in `dir` stops at, None while seeking an endstop.
+    fn jog_limit(&self, dir: Direction) -> Option<u32> {
+        let (nearest, furthest) = self.soft_limits();
+        match dir {
+            _ if self.seek_endstop => None,
+            Direction::ToHome => Some(nearest),
+            Direction::AwayFromHome => Some(furthest),
+        }
+    }
+
+    /// The number of steps it would take to stop from a step `delay` long at `max_accel`.
+    fn stopping_distance(&self, delay: Duration) -> u32 {
+        let vel = TICK_HZ / delay.as_ticks().max(1);
+        let distance = vel
+            .saturating_pow(2)
+            .saturating_sub(u64::from(self.start_vel).pow(2))
+            / (2 * u64::from(self.max_accel.get()));
+        u32::try_from(distance).unwrap_or(u32::MAX)
+    }
+
+    /// The velocity in steps/sec of the last step any plan emitted, or 0 once the plan has
+    /// finished. While a plan is running, ask it instead, see [`PlannedMove::current_velocity`].
+    #[must_use]
+    pub fn current_velocity(&self) -> u32 {
+        self.velocity
+    }
+
+    /// Notes the velocity of a step a plan is about to emit, passing the delay through.
+    fn emit(&mut self, delay: Option<Duration>) -> Option<Duration> {
+        self.velocity = delay.map_or(0, |d| {
+            u32::try_from(TICK_HZ / d.as_ticks().max(1)).unwrap_or(u32::MAX)
+        });
+        delay
+    }
+
     /// Returns the curent pos of this [`Stepper`].
     #[must_use]
     pub fn pos(&self) -> Option<u32> {
         self.curent_pos
     }
 
+    /// Sets the current position of this [`Stepper`] without moving, marking it as homed.
+    /// Intended for restoring a position that was saved before a clean power off.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::MoveOutOfBounds`] if `pos` is past the travel limit.
+    pub fn assume_pos(&mut self, pos: u32) -> Result<(), StepperError> {
+        if pos > self.travel_limit.get() {
+            Err(StepperError::MoveOutOfBounds)
+        } else {
+            self.curent_pos = Some(pos);
+            Ok(())
+        }
+    }
+
+    /// The steps of slack there are left to take up before moving in `dir`.
+    fn slack_in(&self, dir: Direction) -> u32 {
+        match dir {
+            Direction::AwayFromHome => self.backlash - self.slack,
+            Direction::ToHome => self.slack,
+        }
+    }
+
+    /// Takes one step of slack up in `dir`, if there is any left, returning whether it did.
+    fn take_up_slack(&mut self, dir: Direction) -> bool {
+        match dir {
+            Direction::AwayFromHome if self.slack < self.backlash => self.slack += 1,
+            Direction::ToHome if self.slack > 0 => self.slack -= 1,
+            _ => return false,
+        }
+        true
+    }
+
     fn update_pos_one_step(&mut self, dir: Direction) {
         self.curent_pos = Some(
             self.curent_pos
@@ -252,22 +532,76 @@ impl Stepper {
     }
 }
 
-#[derive(Format, Debug, Clone, Copy)]
-enum Phase {
+/// Where a [`PlannedMove`] is on its ramp.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
     Accelerate,
     Cruise,
     Decelerate,
 }
 
+/// A snapshot of a move in progress, see [`PlannedMove::state`]. Saved somewhere that survives a
+/// reset, it tells whoever comes back up that the move never finished, so the position can't be
+/// trusted, and where it was going.
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveState {
+    /// Where the move was going, in steps.
+    pub target: u32,
+    /// The steps it had left to go.
+    pub remaining: u32,
+    pub phase: Phase,
+}
+
+impl MoveState {
+    /// The length of [`MoveState::to_bytes`].
+    pub const BYTES: usize = 9;
+
+    /// The target and remaining steps in little endian, then the phase.
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; Self::BYTES] {
+        let mut bytes = [0; Self::BYTES];
+        bytes[..4].copy_from_slice(&self.target.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.remaining.to_le_bytes());
+        bytes[8] = match self.phase {
+            Phase::Accelerate => 0,
+            Phase::Cruise => 1,
+            Phase::Decelerate => 2,
+        };
+        bytes
+    }
+
+    /// The inverse of [`MoveState::to_bytes`], None if `bytes` isn't one.
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let [t0, t1, t2, t3, r0, r1, r2, r3, phase] = *bytes else {
+            return None;
+        };
+        Some(Self {
+            target: u32::from_le_bytes([t0, t1, t2, t3]),
+            remaining: u32::from_le_bytes([r0, r1, r2, r3]),
+            phase: match phase {
+                0 => Phase::Accelerate,
+                1 => Phase::Cruise,
+                2 => Phase::Decelerate,
+                _ => return None,
+            },
+        })
+    }
+}
+
 /// A move towards 0 that continues until some function is true. This function is intended to poll
 /// and endstop of some kind. Once it hits the endstop, it sets `pos()` to zero. After the iterator
-/// ends, you can call `steps_moved` to get how far the stepper had to move in order to home.
+/// ends, you can call `steps_moved` to get how far the stepper had to move in order to home, and
+/// `result` to find out whether it got there at all.
 #[derive(Format, Debug)]
 pub struct HomingMove<'a, F: FnMut() -> bool> {
     stepper: &'a mut Stepper,
     delay: Duration,
     endstop_fn: F,
     steps_moved: u32,
+    max_travel: u32,
+    // set once max_travel is used up, the endstop is no longer polled after.
+    gave_up: bool,
 }
 
 impl<F: FnMut() -> bool> HomingMove<'_, F> {
@@ -275,6 +609,20 @@ impl<F: FnMut() -> bool> HomingMove<'_, F> {
     pub fn steps_moved(&self) -> u32 {
         self.steps_moved
     }
+
+    /// How homing went, so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::EndstopNotFound`] if the endstop wasn't reached within the
+    /// travel allowed.
+    pub fn result(&self) -> Result<(), StepperError> {
+        if self.gave_up {
+            Err(StepperError::EndstopNotFound)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl<F: FnMut() -> bool> FusedIterator for HomingMove<'_, F> {}
@@ -283,17 +631,21 @@ impl<F: FnMut() -> bool> Iterator for HomingMove<'_, F> {
     type Item = Duration;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.stepper.curent_pos.is_none() {
+        let delay = if self.stepper.curent_pos.is_none() && !self.gave_up {
             if (self.endstop_fn)() {
                 self.stepper.curent_pos = Some(0);
                 None
+            } else if self.steps_moved >= self.max_travel {
+                self.gave_up = true;
+                None
             } else {
                 self.steps_moved += 1;
                 Some(self.delay)
             }
         } else {
             None
-        }
+        };
+        self.stepper.emit(delay)
     }
 }
 
@@ -307,16 +659,156 @@ pub struct PlannedMove<'a> {
     stopping_distance: u32,
     steps_to_travel: u32,
     rem: u64,
+    // the stepper's, or lower for a move with a speed override.
+    max_speed: NonZeroU32,
+    max_stopping_distance: u32,
+    cruise_delay: Duration,
+    // the ticks the cruise is ahead of the ideal profile, in 1/max_speed ticks.
+    cruise_err: u64,
+}
+
+impl PlannedMove<'_> {
+    /// The number of steps it would take to stop from the current velocity at `max_accel`.
+    fn current_stopping_distance(&self) -> u32 {
+        self.stepper.stopping_distance(self.prev_delay)
+    }
+
+    /// Estimates how long the rest of this move will take, assuming it isn't aborted or
+    /// retargeted. This works from the ideal ramp rather than stepping through the plan, so it is
+    /// cheap enough to call every step, but can be off by a few percent.
+    #[must_use]
+    pub fn total_duration_estimate(&self) -> Duration {
+        if self.steps_to_travel == 0 {
+            return Duration::from_ticks(0);
+        }
+        let accel = u64::from(self.stepper.max_accel.get());
+        let start_vel = u64::from(self.stepper.start_vel);
+        let vel = max(TICK_HZ / self.prev_delay.as_ticks().max(1), start_vel);
+        let steps = u64::from(self.steps_to_travel);
+        // the fastest we can go and still stop in time, accelerating from here then decelerating.
+        let peak = ((2 * accel * steps + vel.pow(2) + start_vel.pow(2)) / 2)
+            .isqrt()
+            .clamp(vel, u64::from(self.max_speed.get()))
+            .max(1);
+        let ramp_steps =
+            (2 * peak.pow(2)).saturating_sub(vel.pow(2) + start_vel.pow(2)) / (2 * accel);
+        let ramps = (2 * peak).saturating_sub(vel + start_vel) * TICK_HZ / accel;
+        let cruise = steps.saturating_sub(ramp_steps) * TICK_HZ / peak;
+        let slack = u64::from(self.stepper.slack_in(self.dir)) * self.stepper.inital_delay;
+        Duration::from_ticks(ramps + cruise + slack)
+    }
+
+    /// The velocity in steps/sec of the step this plan last emitted, 0 before the first and once
+    /// it has finished.
+    #[must_use]
+    pub fn current_velocity(&self) -> u32 {
+        self.stepper.velocity
+    }
+
+    /// Decelerates to a stop as quickly as `max_accel` allows, without going past the original
+    /// target. The position of the [`Stepper`] stays valid wherever the move ends up.
+    pub fn abort(&mut self) {
+        // same margin as in `Stepper::planned_move`.
+        self.steps_to_travel = min(self.steps_to_travel, self.current_stopping_distance() + 2);
+        self.phase = Phase::Decelerate;
+        self.rem = 0;
+    }
+
+    /// Where this move is going and how far it has left, to be saved while it runs.
+    #[must_use]
+    pub fn state(&self) -> MoveState {
+        let pos = self.stepper.curent_pos.unwrap_or(0);
+        MoveState {
+            target: match self.dir {
+                Direction::AwayFromHome => pos.saturating_add(self.steps_to_travel),
+                Direction::ToHome => pos.saturating_sub(self.steps_to_travel),
+            },
+            remaining: self.steps_to_travel,
+            phase: self.phase,
+        }
+    }
+
+    /// Changes the target of this move while it is in progress.
+    /// If the new target is in the direction we are already moving, and far enough away that we
+    /// can still stop in time for it, the rest of the move is re-planned in place and `true` is
+    /// returned. Otherwise, the move is aborted (see [`PlannedMove::abort`]) and `false` is
+    /// returned, so the caller can plan a new move to the target once this one has stopped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::MoveOutOfBounds`] if `target_pos` is past the travel limit, in
+    /// which case the move carries on unchanged.
+    pub fn retarget(&mut self, target_pos: u32) -> Result<bool, StepperError> {
+        if target_pos > self.stepper.travel_limit.get() {
+            return Err(StepperError::MoveOutOfBounds);
+        }
+        let target_pos = self.stepper.within_soft_limits(target_pos);
+        let current_pos = self.stepper.curent_pos.ok_or(StepperError::NotHomed)?;
+        let same_dir = match self.dir {
+            Direction::AwayFromHome => target_pos > current_pos,
+            Direction::ToHome => target_pos < current_pos,
+        };
+        let remaining = current_pos.abs_diff(target_pos);
+        let current_stopping_distance = self.current_stopping_distance();
+        if !same_dir || remaining <= current_stopping_distance + 2 {
+            self.abort();
+            return Ok(false);
+        }
+
+        // Like `Stepper::planned_move`, but we may already be part way up the ramp, so the
+        // point where accelerating meets decelerating moves out by the distance it would take
+        // to stop from here.
+        self.stopping_distance = min(
+            (remaining + current_stopping_distance).div_ceil(2),
+            self.max_stopping_distance,
+        ) + 2;
+        self.steps_to_travel = remaining;
+        self.rem = 0;
+        self.phase = match self.phase {
+            _ if remaining <= self.stopping_distance => Phase::Decelerate,
+            Phase::Cruise => Phase::Cruise,
+            Phase::Accelerate | Phase::Decelerate => Phase::Accelerate,
+        };
+        Ok(true)
+    }
 }
 
 impl FusedIterator for PlannedMove<'_> {}
 
+impl ExactSizeIterator for PlannedMove<'_> {}
+
 impl Iterator for PlannedMove<'_> {
     type Item = Duration;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        let delay = self.next_delay();
+        #[cfg(feature = "telemetry")]
+        if let Some(delay) = delay {
+            self.stepper.ramp.record(delay);
+        }
+        self.stepper.emit(delay)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let slack = if self.steps_to_travel > 0 {
+            self.stepper.slack_in(self.dir)
+        } else {
+            0
+        };
+        let len = self.steps_to_travel.saturating_add(slack) as usize;
+        (len, Some(len))
+    }
+}
+
+impl PlannedMove<'_> {
     // TODO: For some reason the acceleration curve goes over the set acceleration sometimes? the
     // output is 'jagged'...
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_delay(&mut self) -> Option<Duration> {
+        // the slack is taken up before the move proper starts, so the ramp starts from rest once
+        // the blind actually moves.
+        if self.steps_to_travel > 0 && self.stepper.take_up_slack(self.dir) {
+            return Some(Duration::from_ticks(self.stepper.inital_delay));
+        }
         match self.phase {
             Phase::Accelerate => {
                 if self.steps_to_travel == 0 {
@@ -335,14 +827,11 @@ impl Iterator for PlannedMove<'_> {
                 let pdiff = pdividend / self.stepper.accel_divisor;
                 self.rem = pdividend % self.stepper.accel_divisor;
                 self.prev_delay = Duration::from_ticks(min(
-                    max(
-                        p.saturating_sub(pdiff),
-                        self.stepper.cruise_delay.as_ticks(),
-                    ),
+                    max(p.saturating_sub(pdiff), self.cruise_delay.as_ticks()),
                     self.stepper.inital_delay,
                 ));
 
-                if self.prev_delay == self.stepper.cruise_delay {
+                if self.prev_delay == self.cruise_delay {
                     self.phase = Phase::Cruise;
                 }
 
@@ -355,7 +844,7 @@ impl Iterator for PlannedMove<'_> {
                     self.phase = Phase::Decelerate;
                     self.rem = 0;
                 }
-                Some(self.prev_delay)
+                Some(self.next_cruise_delay())
             }
             Phase::Decelerate => {
                 if self.steps_to_travel == 0 {
@@ -370,53 +859,399 @@ impl Iterator for PlannedMove<'_> {
                 let pdiff = pdividend / self.stepper.accel_divisor;
                 self.rem = pdividend % self.stepper.accel_divisor;
                 self.prev_delay = Duration::from_ticks(min(
-                    max(
-                        p.saturating_add(pdiff),
-                        self.stepper.cruise_delay.as_ticks(),
-                    ),
+                    max(p.saturating_add(pdiff), self.cruise_delay.as_ticks()),
                     self.stepper.inital_delay,
                 ));
                 Some(self.prev_delay)
             }
         }
     }
+
+    /// The cruise delay is rounded to a whole tick, which adds up over a long cruise, so this
+    /// alternates between the ticks either side of the ideal period, Bresenham style, to keep the
+    /// cruise within a tick of it overall.
+    fn next_cruise_delay(&mut self) -> Duration {
+        let speed = u64::from(self.max_speed.get());
+        self.cruise_err += TICK_HZ % speed;
+        let carry = self.cruise_err / speed;
+        self.cruise_err %= speed;
+        Duration::from_ticks(TICK_HZ / speed + carry)
+    }
+}
+
+/// A [`PlannedMove`] that checks a guard before every step.
+#[derive(Format, Debug)]
+pub struct GuardedMove<'a, F: FnMut() -> bool> {
+    plan: PlannedMove<'a>,
+    guard_fn: F,
+    stalled: bool,
+}
+
+impl<F: FnMut() -> bool> GuardedMove<'_, F> {
+    /// See [`PlannedMove::abort`].
+    pub fn abort(&mut self) {
+        self.plan.abort();
+    }
+
+    /// See [`PlannedMove::retarget`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`PlannedMove::retarget`].
+    pub fn retarget(&mut self, target_pos: u32) -> Result<bool, StepperError> {
+        self.plan.retarget(target_pos)
+    }
+
+    /// See [`PlannedMove::total_duration_estimate`].
+    #[must_use]
+    pub fn total_duration_estimate(&self) -> Duration {
+        self.plan.total_duration_estimate()
+    }
+
+    /// See [`PlannedMove::current_velocity`].
+    #[must_use]
+    pub fn current_velocity(&self) -> u32 {
+        self.plan.current_velocity()
+    }
+
+    /// See [`PlannedMove::state`].
+    #[must_use]
+    pub fn state(&self) -> MoveState {
+        self.plan.state()
+    }
+
+    /// How the move went, so far.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::Stalled`] if the guard stopped the move.
+    pub fn result(&self) -> Result<(), StepperError> {
+        if self.stalled {
+            Err(StepperError::Stalled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<F: FnMut() -> bool> FusedIterator for GuardedMove<'_, F> {}
+
+impl<F: FnMut() -> bool> ExactSizeIterator for GuardedMove<'_, F> {}
+
+impl<F: FnMut() -> bool> Iterator for GuardedMove<'_, F> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stalled || self.plan.len() == 0 {
+            return None;
+        }
+        if !(self.guard_fn)() {
+            // we can't know how far the motor got, so it has to be homed again.
+            self.stalled = true;
+            self.plan.stepper.curent_pos = None;
+            return None;
+        }
+        self.plan.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.stalled {
+            (0, Some(0))
+        } else {
+            self.plan.size_hint()
+        }
+    }
 }
 
 /// An iterator over the delay in between steps for a jog
-/// (continues while a condition is true).
+/// (continues while a condition is true, then slows to a stop).
 #[derive(Format, Debug)]
 pub struct ContinuousJog<'a, F: FnMut() -> bool> {
     stepper: &'a mut Stepper,
-    delay: Duration,
+    prev_delay: Duration,
+    cruise_delay: Duration,
+    rem: u64,
+    stopping: bool,
     dir: Direction,
     continue_fn: F,
 }
 
+impl<F: FnMut() -> bool> ContinuousJog<'_, F> {
+    /// See [`PlannedMove::current_velocity`].
+    #[must_use]
+    pub fn current_velocity(&self) -> u32 {
+        self.stepper.velocity
+    }
+
+    fn next_delay(&mut self) -> Option<Duration> {
+        // steps left before the soft limit, stopping dead once there are none.
+        let remaining = self.stepper.jog_limit(self.dir).map(|limit| {
+            let pos = self.stepper.curent_pos.unwrap_or(limit);
+            match self.dir {
+                Direction::AwayFromHome => limit.saturating_sub(pos),
+                Direction::ToHome => pos.saturating_sub(limit),
+            }
+        });
+        if remaining == Some(0) {
+            return None;
+        }
+        if !self.stopping && !(self.continue_fn)() {
+            self.stopping = true;
+            self.rem = 0;
+        }
+        // slows down to arrive at the soft limit at the start velocity, with the same margin as
+        // in `Stepper::planned_move`.
+        let slowing = self.stopping
+            || remaining.is_some_and(|r| r <= self.stepper.stopping_distance(self.prev_delay) + 2);
+        let p = self.prev_delay.as_ticks();
+        // back down to the start velocity, from where we can stop dead.
+        if self.stopping && p >= self.stepper.inital_delay {
+            return None;
+        }
+        let cruise = self.cruise_delay.as_ticks();
+        // as for a planned move, the ramp starts once the slack has been taken up.
+        if self.stepper.take_up_slack(self.dir) {
+            return Some(Duration::from_ticks(max(self.stepper.inital_delay, cruise)));
+        }
+        self.stepper.update_pos_one_step(self.dir);
+        let pdividend = p.saturating_pow(3) + self.rem;
+        let pdiff = pdividend / self.stepper.accel_divisor;
+        self.rem = pdividend % self.stepper.accel_divisor;
+        self.prev_delay = Duration::from_ticks(if slowing {
+            max(
+                min(p.saturating_add(pdiff), self.stepper.inital_delay),
+                cruise,
+            )
+        } else {
+            // a jog slower than the start velocity doesn't ramp at all.
+            max(
+                min(p.saturating_sub(pdiff), self.stepper.inital_delay),
+                cruise,
+            )
+        });
+        Some(self.prev_delay)
+    }
+}
+
 impl<F: FnMut() -> bool> Iterator for ContinuousJog<'_, F> {
     type Item = Duration;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if (self.continue_fn)() {
-            self.stepper.update_pos_one_step(self.dir);
-            Some(self.delay)
-        } else {
-            None
+        let delay = self.next_delay();
+        self.stepper.emit(delay)
+    }
+}
+
+impl<F: FnMut() -> bool> FusedIterator for ContinuousJog<'_, F> {}
+
+/// Homing in three legs for a more repeatable zero: towards the endstop at the start velocity,
+/// back off it by `retract` steps, then back to it at a quarter of the speed. Each leg goes one
+/// way only, so they are handed out one at a time with [`TwoPhaseHoming::next_leg`] to be run in
+/// turn.
+#[derive(Format, Debug, Clone, Copy)]
+pub struct TwoPhaseHoming {
+    max_travel: u32,
+    retract: u32,
+    next: HomingPhase,
+    result: Result<(), StepperError>,
+}
+
+#[derive(Format, Debug, Clone, Copy, PartialEq, Eq)]
+enum HomingPhase {
+    Seek,
+    Retract,
+    Approach,
+    Done,
+}
+
+impl TwoPhaseHoming {
+    /// The seek gives up after `max_travel` steps, like [`Stepper::homing_move`]. The retract is
+    /// cut to the travel limit.
+    #[must_use]
+    pub fn new(max_travel: u32, retract: u32) -> Self {
+        Self {
+            max_travel,
+            retract,
+            next: HomingPhase::Seek,
+            result: Ok(()),
         }
     }
+
+    /// The next leg to run, None once homing is over, see [`TwoPhaseHoming::result`]. Each leg
+    /// picks up from wherever the last one left the stepper, so a leg cut short fails homing.
+    pub fn next_leg<'a, F: FnMut() -> bool>(
+        &mut self,
+        stepper: &'a mut Stepper,
+        endstop_fn: F,
+    ) -> Option<HomingLeg<'a, F>> {
+        match self.next {
+            HomingPhase::Seek => {
+                self.next = HomingPhase::Retract;
+                Some(HomingLeg::Seek(
+                    stepper.homing_move(self.max_travel, endstop_fn),
+                ))
+            }
+            // the seek or the approach didn't get there.
+            HomingPhase::Retract | HomingPhase::Done if stepper.pos() != Some(0) => self.fail(),
+            HomingPhase::Retract => {
+                self.next = HomingPhase::Approach;
+                let retract = min(self.retract, stepper.travel_limit().get());
+                // can't fail, we are homed and it is within the travel limit.
+                let (plan, _) = stepper.planned_move(retract).ok()?;
+                Some(HomingLeg::Retract(plan))
+            }
+            HomingPhase::Approach => {
+                self.next = HomingPhase::Done;
+                // from wherever the retract got to, with some spare for the slack.
+                let max_travel = stepper.pos()?.saturating_mul(2);
+                let vel = stepper.start_vel / 4;
+                Some(HomingLeg::Approach(
+                    stepper.homing_move_at(vel, max_travel, endstop_fn),
+                ))
+            }
+            HomingPhase::Done => None,
+        }
+    }
+
+    fn fail<T>(&mut self) -> Option<T> {
+        self.next = HomingPhase::Done;
+        self.result = Err(StepperError::EndstopNotFound);
+        None
+    }
+
+    /// How homing went, once [`TwoPhaseHoming::next_leg`] has returned None.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`StepperError::EndstopNotFound`] if a leg didn't get to the endstop.
+    pub fn result(&self) -> Result<(), StepperError> {
+        self.result
+    }
+}
+
+/// One leg of a [`TwoPhaseHoming`].
+#[derive(Format, Debug)]
+pub enum HomingLeg<'a, F: FnMut() -> bool> {
+    /// Towards the endstop at the start velocity.
+    Seek(HomingMove<'a, F>),
+    /// Back off the endstop.
+    Retract(PlannedMove<'a>),
+    /// Back to the endstop slowly, for the zero.
+    Approach(HomingMove<'a, F>),
+}
+
+impl<F: FnMut() -> bool> HomingLeg<'_, F> {
+    /// Which way this leg goes.
+    pub fn dir(&self) -> Direction {
+        match self {
+            Self::Retract(_) => Direction::AwayFromHome,
+            Self::Seek(_) | Self::Approach(_) => Direction::ToHome,
+        }
+    }
+}
+
+impl<F: FnMut() -> bool> FusedIterator for HomingLeg<'_, F> {}
+
+impl<F: FnMut() -> bool> Iterator for HomingLeg<'_, F> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Seek(plan) | Self::Approach(plan) => plan.next(),
+            Self::Retract(plan) => plan.next(),
+        }
+    }
+}
+
+/// How many samples a [`RampRecorder`] keeps, the oldest are overwritten first.
+#[cfg(feature = "telemetry")]
+pub const RAMP_SAMPLES: usize = 128;
+
+/// The delays a planned move generated, sampled every `decimation` steps, for tuning the ramp.
+#[cfg(feature = "telemetry")]
+#[derive(Format, Debug, Clone)]
+pub struct RampRecorder {
+    samples: [(u32, Duration); RAMP_SAMPLES],
+    // where the next sample goes.
+    next: usize,
+    len: usize,
+    decimation: NonZeroU32,
+    // steps generated so far, sampled or not.
+    steps: u32,
+}
+
+#[cfg(feature = "telemetry")]
+impl RampRecorder {
+    const fn new() -> Self {
+        Self {
+            samples: [(0, Duration::from_ticks(0)); RAMP_SAMPLES],
+            next: 0,
+            len: 0,
+            decimation: NonZeroU32::MIN,
+            steps: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+        self.steps = 0;
+    }
+
+    fn record(&mut self, delay: Duration) {
+        if self.steps.is_multiple_of(self.decimation.get()) {
+            self.samples[self.next] = (self.steps, delay);
+            self.next = (self.next + 1) % RAMP_SAMPLES;
+            self.len = min(self.len + 1, RAMP_SAMPLES);
+        }
+        self.steps = self.steps.saturating_add(1);
+    }
+
+    /// The samples as (step index, delay), oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = (u32, Duration)> + '_ {
+        let start = (self.next + RAMP_SAMPLES - self.len) % RAMP_SAMPLES;
+        (0..self.len).map(move |i| self.samples[(start + i) % RAMP_SAMPLES])
+    }
+
+    /// The number of samples kept.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many steps the move generated, sampled or not.
+    #[must_use]
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    #[must_use]
+    pub fn decimation(&self) -> NonZeroU32 {
+        self.decimation
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::cast_precision_loss, clippy::cast_lossless)]
 mod test {
-    use core::num::NonZeroU32;
+    use core::{cell::Cell, num::NonZeroU32};
 
     use embassy_time::{Duration, TICK_HZ};
 
-    use crate::{Direction, Stepper, StepperError};
+    use crate::{
+        Direction, HomingLeg, MoveState, Phase, PlannedMove, Stepper, StepperError, TwoPhaseHoming,
+    };
 
     const TRAVEL_LIMIT: NonZeroU32 = NonZeroU32::new(2048).unwrap();
     const MAX_VEL: NonZeroU32 = NonZeroU32::new(255).unwrap();
     const MAX_ACCEL: NonZeroU32 = NonZeroU32::new(64).unwrap();
     const START_VEL: u32 = 50;
+    const HOMING_TRAVEL: u32 = 2 * TRAVEL_LIMIT.get();
 
     #[test]
     fn test_home() {
@@ -424,7 +1259,7 @@ mod test {
         assert_eq!(stepper.curent_pos, None);
 
         let mut endstop = [false, false, true].into_iter();
-        let steps = stepper.homing_move(|| endstop.next().unwrap());
+        let steps = stepper.homing_move(HOMING_TRAVEL, || endstop.next().unwrap());
 
         for step in steps {
             assert_eq!(step, Duration::from_hz(START_VEL as u64));
@@ -433,6 +1268,197 @@ mod test {
         assert_eq!(stepper.curent_pos, Some(0));
     }
 
+    /// A simulated endstop, modeling a carriage `distance` steps away from the switch that moves
+    /// one step towards it every time it is polled and found open.
+    struct SimEndstop {
+        distance: u32,
+        // Number of polls the switch chatters for when the carriage first reaches it.
+        bounce: u32,
+        polls: u32,
+    }
+
+    impl SimEndstop {
+        fn new(distance: u32, bounce: u32) -> Self {
+            Self {
+                distance,
+                bounce,
+                polls: 0,
+            }
+        }
+
+        fn poll(&mut self) -> bool {
+            self.polls += 1;
+            if self.distance == 0 {
+                if self.bounce > 0 {
+                    self.bounce -= 1;
+                    self.bounce.is_multiple_of(2)
+                } else {
+                    true
+                }
+            } else {
+                self.distance -= 1;
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn test_home_sim_clean_switch() {
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut endstop = SimEndstop::new(500, 0);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || endstop.poll());
+        let count = steps.by_ref().count();
+        assert_eq!(steps.steps_moved(), 500);
+        assert_eq!(count, 500);
+        assert_eq!(stepper.pos(), Some(0));
+        assert_eq!(endstop.polls, 501);
+    }
+
+    #[test]
+    fn test_home_sim_bounce() {
+        // a bouncing switch should stop homing on first contact, and not step into the endstop
+        // while it chatters.
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut endstop = SimEndstop::new(300, 5);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || endstop.poll());
+        assert_eq!(steps.by_ref().count(), 300);
+        assert_eq!(steps.next(), None);
+        assert_eq!(stepper.pos(), Some(0));
+        assert_eq!(endstop.distance, 0);
+    }
+
+    #[test]
+    fn test_home_sim_early_trigger() {
+        // already sitting on the endstop, so we should not move at all.
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut endstop = SimEndstop::new(0, 0);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || endstop.poll());
+        assert_eq!(steps.by_ref().count(), 0);
+        assert_eq!(steps.steps_moved(), 0);
+        assert_eq!(stepper.pos(), Some(0));
+    }
+
+    #[test]
+    fn test_home_sim_never_trigger() {
+        // a broken switch, the position must stay unknown for as long as we keep stepping, and
+        // stepping has to stop once the travel allowed is used up.
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut steps = stepper.homing_move(10 * TRAVEL_LIMIT.get(), || false);
+        assert_eq!(
+            steps
+                .by_ref()
+                .take(10 * TRAVEL_LIMIT.get() as usize)
+                .count(),
+            10 * TRAVEL_LIMIT.get() as usize
+        );
+        assert_eq!(steps.steps_moved(), 10 * TRAVEL_LIMIT.get());
+        assert_eq!(steps.next(), None);
+        assert_eq!(steps.result(), Err(StepperError::EndstopNotFound));
+        assert_eq!(stepper.pos(), None);
+        assert_eq!(
+            stepper.planned_move(100).unwrap_err(),
+            StepperError::NotHomed
+        );
+    }
+
+    #[test]
+    fn test_home_sim_max_travel() {
+        // the endstop on the very last step allowed still counts.
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut endstop = SimEndstop::new(HOMING_TRAVEL, 0);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || endstop.poll());
+        assert_eq!(steps.by_ref().count(), HOMING_TRAVEL as usize);
+        assert_eq!(steps.result(), Ok(()));
+        assert_eq!(stepper.pos(), Some(0));
+
+        // one further and it gives up, and a switch closing after that doesn't home it.
+        let mut endstop = SimEndstop::new(HOMING_TRAVEL + 1, 0);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || endstop.poll());
+        assert_eq!(steps.by_ref().count(), HOMING_TRAVEL as usize);
+        assert_eq!(steps.result(), Err(StepperError::EndstopNotFound));
+        assert_eq!(steps.next(), None);
+        assert_eq!(stepper.pos(), None);
+    }
+
+    /// Runs every leg of a two phase homing against a switch `distance` steps away, returning the
+    /// direction, steps and slowest delay of each.
+    fn run_two_phase(
+        stepper: &mut Stepper,
+        homing: &mut TwoPhaseHoming,
+        distance: u32,
+    ) -> Vec<(Direction, u32, Duration)> {
+        let distance = Cell::new(distance);
+        let mut legs = Vec::new();
+        while let Some(mut leg) = homing.next_leg(stepper, || distance.get() == 0) {
+            let dir = leg.dir();
+            let (mut steps, mut slowest) = (0, Duration::from_ticks(0));
+            for delay in leg.by_ref() {
+                distance.set(match dir {
+                    Direction::ToHome => distance.get() - 1,
+                    Direction::AwayFromHome => distance.get() + 1,
+                });
+                steps += 1;
+                slowest = slowest.max(delay);
+            }
+            assert!(matches!(
+                (&leg, dir),
+                (
+                    HomingLeg::Seek(_) | HomingLeg::Approach(_),
+                    Direction::ToHome
+                ) | (HomingLeg::Retract(_), Direction::AwayFromHome)
+            ));
+            legs.push((dir, steps, slowest));
+        }
+        legs
+    }
+
+    #[test]
+    fn test_two_phase_homing() {
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut homing = TwoPhaseHoming::new(HOMING_TRAVEL, 40);
+        let legs = run_two_phase(&mut stepper, &mut homing, 700);
+        assert_eq!(homing.result(), Ok(()));
+        assert_eq!(stepper.pos(), Some(0));
+        let [seek, retract, approach] = legs[..] else {
+            panic!("expected three legs, got {legs:?}");
+        };
+        assert_eq!((seek.0, seek.1), (Direction::ToHome, 700));
+        assert_eq!(seek.2, Duration::from_hz(START_VEL.into()));
+        assert_eq!((retract.0, retract.1), (Direction::AwayFromHome, 40));
+        assert_eq!((approach.0, approach.1), (Direction::ToHome, 40));
+        assert_eq!(approach.2, Duration::from_hz((START_VEL / 4).into()));
+        // and nothing more to do.
+        assert!(homing.next_leg(&mut stepper, || true).is_none());
+    }
+
+    #[test]
+    fn test_two_phase_homing_no_endstop() {
+        // a broken switch gives up after the seek, without backing off from nowhere.
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        let mut homing = TwoPhaseHoming::new(HOMING_TRAVEL, 40);
+        let legs = run_two_phase(&mut stepper, &mut homing, u32::MAX);
+        assert_eq!(legs.len(), 1);
+        assert_eq!(legs[0].1, HOMING_TRAVEL);
+        assert_eq!(homing.result(), Err(StepperError::EndstopNotFound));
+        assert_eq!(stepper.pos(), None);
+    }
+
+    #[test]
+    fn test_home_sim_position_reset() {
+        // rehoming after a move should forget the old position, then zero it at the endstop.
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        stepper.homing_move(HOMING_TRAVEL, || true).for_each(drop);
+        stepper.planned_move(1000).unwrap().0.for_each(drop);
+        assert_eq!(stepper.pos(), Some(1000));
+
+        let mut endstop = SimEndstop::new(1000, 3);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || endstop.poll());
+        steps.next();
+        assert_eq!(steps.stepper.pos(), None);
+        assert_eq!(steps.by_ref().count(), 999);
+        assert_eq!(stepper.pos(), Some(0));
+    }
+
     #[test]
     fn test_move_travel_guards() {
         let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
@@ -440,7 +1466,7 @@ mod test {
             stepper.planned_move(100).unwrap_err(),
             StepperError::NotHomed
         );
-        let mut steps = stepper.homing_move(|| true);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || true);
         steps.next();
         assert_eq!(
             stepper.planned_move(TRAVEL_LIMIT.get() + 1).unwrap_err(),
@@ -448,10 +1474,525 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_assume_pos() {
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        assert_eq!(
+            stepper.assume_pos(TRAVEL_LIMIT.get() + 1).unwrap_err(),
+            StepperError::MoveOutOfBounds
+        );
+        assert_eq!(stepper.pos(), None);
+        stepper.assume_pos(100).unwrap();
+        assert_eq!(stepper.pos(), Some(100));
+        let (steps, _) = stepper.planned_move(0).unwrap();
+        assert_eq!(steps.count(), 100);
+        assert_eq!(stepper.pos(), Some(0));
+    }
+
+    /// Checks a plan never changes speed faster than the acceleration limit allows, with the same
+    /// slack as `test_move_max_accel`.
+    fn assert_accel_limited(delays: &[Duration]) {
+        let mut prev_vel = START_VEL as f64;
+        let mut accels = [0.0; 2];
+        for (i, delay) in delays.iter().enumerate() {
+            let vel = TICK_HZ as f64 / delay.as_ticks() as f64;
+            accels[i % 2] = (vel - prev_vel) * prev_vel;
+            let avg: f64 = accels.iter().sum::<f64>() / 2.0;
+            assert!(
+                avg.abs() <= MAX_ACCEL.get() as f64 * 2.0,
+                "step {i} accelerated at {avg}"
+            );
+            prev_vel = vel;
+        }
+    }
+
+    fn homed_stepper() -> Stepper {
+        let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
+        stepper.homing_move(HOMING_TRAVEL, || true).for_each(drop);
+        stepper
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        let mut delays: Vec<Duration> = steps.by_ref().take(1000).collect();
+        steps.abort();
+        delays.extend(steps);
+        assert_accel_limited(&delays);
+        let pos = stepper.pos().unwrap();
+        assert!(pos > 1000 && pos < TRAVEL_LIMIT.get());
+        assert!(pos - 1000 <= stepper.max_stopping_distance + 2);
+    }
+
+    #[test]
+    fn test_abort_from_rest() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        steps.abort();
+        assert!(steps.count() <= 2);
+    }
+
+    #[test]
+    fn test_guarded_move() {
+        let mut reference = homed_stepper();
+        let (planned, _) = reference.planned_move(1000).unwrap();
+        let expected: Vec<Duration> = planned.collect();
+
+        let mut stepper = homed_stepper();
+        let mut guard_calls = 0;
+        let (mut steps, dir) = stepper
+            .guarded_move(1000, || {
+                guard_calls += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(dir, Direction::AwayFromHome);
+        let delays: Vec<Duration> = steps.by_ref().collect();
+        assert_eq!(steps.result(), Ok(()));
+        assert_eq!(delays, expected);
+        assert_eq!(guard_calls, 1000);
+        assert_eq!(stepper.pos(), Some(1000));
+    }
+
+    #[test]
+    fn test_guarded_move_stall() {
+        let mut stepper = homed_stepper();
+        let mut remaining = 300;
+        let (mut steps, _) = stepper
+            .guarded_move(1000, || {
+                remaining -= 1;
+                remaining >= 0
+            })
+            .unwrap();
+        assert_eq!(steps.by_ref().count(), 300);
+        assert_eq!(steps.next(), None);
+        assert_eq!(steps.len(), 0);
+        assert_eq!(steps.result(), Err(StepperError::Stalled));
+        assert_eq!(stepper.pos(), None);
+        assert_eq!(
+            stepper.planned_move(100).unwrap_err(),
+            StepperError::NotHomed
+        );
+    }
+
+    #[test]
+    fn test_planned_move_with_speed() {
+        let mut reference = homed_stepper();
+        let (planned, _) = reference.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        let full_speed = planned.total_duration_estimate();
+
+        let mut stepper = homed_stepper();
+        let slow = NonZeroU32::new(MAX_VEL.get() / 4).unwrap();
+        let (steps, _) = stepper
+            .planned_move_with_speed(TRAVEL_LIMIT.get(), slow)
+            .unwrap();
+        assert!(steps.total_duration_estimate() > full_speed * 2);
+        let delays: Vec<Duration> = steps.collect();
+        assert_accel_limited(&delays);
+        assert!(
+            delays
+                .iter()
+                .all(|d| *d >= Duration::from_hz(u64::from(slow.get())))
+        );
+        assert_eq!(stepper.pos(), Some(TRAVEL_LIMIT.get()));
+        // only that plan was slowed down.
+        assert_eq!(stepper.max_speed(), MAX_VEL);
+        let cruise_delay = stepper.cruise_delay;
+        let (steps, _) = stepper.planned_move(0).unwrap();
+        // the cruise alternates with the tick below to keep to the ideal period.
+        let fastest = steps.min().unwrap();
+        assert!(cruise_delay - fastest <= Duration::from_ticks(1));
+    }
+
+    #[test]
+    fn test_cruise_keeps_to_ideal_duration() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        let mut cruise_steps = 0;
+        let mut cruise_ticks = 0;
+        while let Some(delay) = steps.next() {
+            if steps.state().phase == Phase::Cruise {
+                cruise_steps += 1;
+                cruise_ticks += delay.as_ticks();
+            }
+        }
+        assert!(cruise_steps > 1000);
+        // within a tick of the ideal, in 1/MAX_VEL ticks.
+        let ideal = cruise_steps * TICK_HZ;
+        let actual = cruise_ticks * u64::from(MAX_VEL.get());
+        assert!(ideal.abs_diff(actual) <= u64::from(MAX_VEL.get()));
+    }
+
+    #[test]
+    fn test_planned_move_with_speed_above_max() {
+        let mut reference = homed_stepper();
+        let (planned, _) = reference.planned_move(1000).unwrap();
+        let expected: Vec<Duration> = planned.collect();
+
+        let mut stepper = homed_stepper();
+        let (steps, _) = stepper
+            .planned_move_with_speed(1000, MAX_VEL.saturating_mul(NonZeroU32::new(2).unwrap()))
+            .unwrap();
+        assert_eq!(steps.collect::<Vec<_>>(), expected);
+    }
+
+    #[cfg(feature = "telemetry")]
+    #[test]
+    fn test_ramp_telemetry() {
+        let mut stepper = homed_stepper();
+        stepper.set_ramp_decimation(NonZeroU32::new(10).unwrap());
+        let (steps, _) = stepper.planned_move(1000).unwrap();
+        let delays: Vec<Duration> = steps.collect();
+        let ramp = stepper.ramp();
+        assert_eq!(ramp.steps(), 1000);
+        assert_eq!(ramp.len(), 100);
+        for ((step, delay), expected) in ramp.samples().zip((0..).step_by(10)) {
+            assert_eq!(step, expected);
+            assert_eq!(delay, delays[step as usize]);
+        }
+    }
+
+    #[cfg(feature = "telemetry")]
+    #[test]
+    fn test_ramp_telemetry_wraps() {
+        let mut stepper = homed_stepper();
+        let (steps, _) = stepper.planned_move(1000).unwrap();
+        let delays: Vec<Duration> = steps.collect();
+        let ramp = stepper.ramp();
+        assert_eq!(ramp.len(), crate::RAMP_SAMPLES);
+        // the end of the move is what's kept.
+        let samples: Vec<(u32, Duration)> = ramp.samples().collect();
+        assert_eq!(
+            samples.first().unwrap().0,
+            1000 - u32::try_from(crate::RAMP_SAMPLES).unwrap()
+        );
+        assert_eq!(samples.last(), Some(&(999, *delays.last().unwrap())));
+
+        // each plan starts afresh.
+        let (steps, _) = stepper.planned_move(990).unwrap();
+        steps.for_each(drop);
+        assert_eq!(stepper.ramp().steps(), 10);
+        assert_eq!(stepper.ramp().len(), 10);
+    }
+
+    #[test]
+    fn test_current_velocity() {
+        let mut stepper = homed_stepper();
+        assert_eq!(stepper.current_velocity(), 0);
+        let (mut steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        assert_eq!(steps.current_velocity(), 0);
+        let mut peak = 0;
+        while let Some(delay) = steps.next() {
+            let velocity = steps.current_velocity();
+            assert_eq!(u64::from(velocity), TICK_HZ / delay.as_ticks());
+            peak = peak.max(velocity);
+        }
+        assert_eq!(steps.current_velocity(), 0);
+        // the cruise delay is rounded to whole ticks.
+        assert!(peak.abs_diff(MAX_VEL.get()) <= 1, "peaked at {peak}");
+        assert_eq!(stepper.current_velocity(), 0);
+
+        // a jog cut short leaves the velocity of its last step.
+        let mut jog = stepper.continuous_jog(|| true, Direction::ToHome).unwrap();
+        jog.next();
+        assert_eq!(stepper.current_velocity(), START_VEL);
+    }
+
+    #[test]
+    fn test_retarget_further() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(1000).unwrap();
+        let mut delays: Vec<Duration> = steps.by_ref().take(300).collect();
+        assert_eq!(steps.retarget(1800), Ok(true));
+        delays.extend(steps);
+        assert_accel_limited(&delays);
+        assert_eq!(delays.len(), 1800);
+        assert_eq!(stepper.pos(), Some(1800));
+    }
+
+    #[test]
+    fn test_retarget_closer() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(1800).unwrap();
+        let mut delays: Vec<Duration> = steps.by_ref().take(300).collect();
+        assert_eq!(steps.retarget(1000), Ok(true));
+        delays.extend(steps);
+        assert_accel_limited(&delays);
+        assert_eq!(stepper.pos(), Some(1000));
+    }
+
+    #[test]
+    fn test_retarget_too_close_or_reversed() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        steps.by_ref().take(1000).for_each(drop);
+        assert_eq!(steps.retarget(1001), Ok(false));
+        steps.for_each(drop);
+        let stopped = stepper.pos().unwrap();
+        assert!(stopped > 1001);
+
+        let (mut steps, _) = stepper.planned_move(0).unwrap();
+        steps.by_ref().take(300).for_each(drop);
+        assert_eq!(steps.retarget(stopped), Ok(false));
+        steps.for_each(drop);
+        assert!(stepper.pos().unwrap() < stopped - 300);
+    }
+
+    #[test]
+    fn test_retarget_out_of_bounds() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(1000).unwrap();
+        steps.by_ref().take(10).for_each(drop);
+        assert_eq!(
+            steps.retarget(TRAVEL_LIMIT.get() + 1),
+            Err(StepperError::MoveOutOfBounds)
+        );
+        steps.for_each(drop);
+        assert_eq!(stepper.pos(), Some(1000));
+    }
+
+    #[test]
+    fn test_move_state() {
+        let mut stepper = homed_stepper();
+        let (mut steps, _) = stepper.planned_move(1000).unwrap();
+        let state = steps.state();
+        assert_eq!(
+            state,
+            MoveState {
+                target: 1000,
+                remaining: 1000,
+                phase: Phase::Accelerate
+            }
+        );
+        steps.by_ref().take(500).for_each(drop);
+        let state = steps.state();
+        assert_eq!((state.target, state.remaining), (1000, 500));
+        assert_eq!(state.phase, Phase::Cruise);
+        assert_eq!(MoveState::from_bytes(&state.to_bytes()), Some(state));
+        assert_eq!(MoveState::from_bytes(&state.to_bytes()[..8]), None);
+        steps.for_each(drop);
+
+        let (mut steps, _) = stepper.planned_move(200).unwrap();
+        steps.by_ref().take(790).for_each(drop);
+        let state = steps.state();
+        assert_eq!((state.target, state.remaining), (200, 10));
+        assert_eq!(state.phase, Phase::Decelerate);
+    }
+
+    #[test]
+    fn test_backlash_on_reversal() {
+        let mut stepper = homed_stepper();
+        stepper.set_backlash(20);
+        // homing left the slack taken up towards home, so the first move out has to take it up.
+        let (steps, _) = stepper.planned_move(1000).unwrap();
+        let delays: Vec<Duration> = steps.collect();
+        assert_eq!(delays.len(), 1020);
+        assert!(
+            delays[..20]
+                .iter()
+                .all(|d| d.as_ticks() == stepper.inital_delay)
+        );
+        assert_accel_limited(&delays[20..]);
+        assert_eq!(stepper.pos(), Some(1000));
+
+        // carrying on the same way needs nothing extra.
+        let (steps, _) = stepper.planned_move(1500).unwrap();
+        assert_eq!(steps.count(), 500);
+        let (steps, _) = stepper.planned_move(1200).unwrap();
+        assert_eq!(steps.count(), 320);
+        assert_eq!(stepper.pos(), Some(1200));
+    }
+
+    #[test]
+    fn test_backlash_jog() {
+        let mut stepper = homed_stepper();
+        stepper.set_backlash(5);
+        let mut jog = 0;
+        let steps = stepper
+            .continuous_jog(
+                || {
+                    jog += 1;
+                    jog <= 15
+                },
+                Direction::AwayFromHome,
+            )
+            .unwrap();
+        assert_eq!(steps.count(), 15);
+        assert_eq!(stepper.pos(), Some(10));
+
+        // aborting while taking up the slack still takes all of it up, so the next move the same
+        // way doesn't take it up twice.
+        let (mut steps, _) = stepper.planned_move(0).unwrap();
+        steps.by_ref().take(2).for_each(drop);
+        steps.abort();
+        steps.for_each(drop);
+        let pos = stepper.pos().unwrap();
+        assert!(pos >= 8);
+        let (steps, _) = stepper.planned_move(0).unwrap();
+        assert_eq!(steps.count(), pos as usize);
+        assert_eq!(stepper.pos(), Some(0));
+    }
+
+    #[test]
+    fn test_ramped_jog() {
+        let mut stepper = homed_stepper();
+        let polls = Cell::new(0);
+        let jog = stepper
+            .continuous_jog_with_speed(
+                || {
+                    polls.set(polls.get() + 1);
+                    polls.get() <= 1000
+                },
+                Direction::AwayFromHome,
+                MAX_VEL,
+            )
+            .unwrap();
+        let velocities: Vec<u64> = jog.map(|d| TICK_HZ / d.as_ticks()).collect();
+        let (running, stopping) = velocities.split_at(1000);
+        assert!(running.windows(2).all(|w| w[0] <= w[1]));
+        assert!(stopping.windows(2).all(|w| w[0] >= w[1]));
+        // the cruise delay is rounded to whole ticks.
+        assert!(running[999].abs_diff(MAX_VEL.get().into()) <= 1);
+        assert!(stopping.last().unwrap().abs_diff(START_VEL.into()) <= 1);
+        // about as far as it took to get up to speed.
+        let ramp = running.iter().position(|v| *v == running[999]).unwrap();
+        assert!(
+            stopping.len().abs_diff(ramp) <= ramp / 10,
+            "{ramp} {}",
+            stopping.len()
+        );
+        assert_eq!(stepper.pos().unwrap() as usize, velocities.len());
+        assert_eq!(stepper.current_velocity(), 0);
+
+        // slower than the start velocity there is no ramp either way.
+        let slow = NonZeroU32::new(START_VEL / 2).unwrap();
+        let mut jog = 0;
+        let steps = stepper
+            .continuous_jog_with_speed(
+                || {
+                    jog += 1;
+                    jog <= 10
+                },
+                Direction::ToHome,
+                slow,
+            )
+            .unwrap();
+        for step in steps {
+            assert_eq!(step, Duration::from_hz(slow.get().into()));
+        }
+        assert_eq!(jog, 11);
+    }
+
+    #[test]
+    fn test_soft_limits() {
+        let mut stepper = homed_stepper();
+        stepper.set_soft_limit(16);
+        assert_eq!(stepper.soft_limits(), (16, TRAVEL_LIMIT.get() - 16));
+        // targets past a soft limit stop at it, but past the travel limit are still refused.
+        let (steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        steps.for_each(drop);
+        assert_eq!(stepper.pos(), Some(TRAVEL_LIMIT.get() - 16));
+        assert_eq!(
+            stepper.planned_move(TRAVEL_LIMIT.get() + 1).err(),
+            Some(StepperError::MoveOutOfBounds)
+        );
+        let (mut steps, _) = stepper.planned_move(1000).unwrap();
+        steps.by_ref().take(10).for_each(drop);
+        assert_eq!(steps.retarget(0), Ok(true));
+        steps.for_each(drop);
+        assert_eq!(stepper.pos(), Some(16));
+
+        // a soft limit wider than the travel leaves the middle.
+        stepper.set_soft_limit(u32::MAX);
+        assert_eq!(stepper.soft_limits(), (1024, 1024));
+    }
+
+    #[test]
+    fn test_jog_soft_limits() {
+        let mut stepper = homed_stepper();
+        stepper.set_soft_limit(16);
+        let jog = stepper
+            .continuous_jog_with_speed(|| true, Direction::AwayFromHome, MAX_VEL)
+            .unwrap();
+        let delays: Vec<Duration> = jog.collect();
+        assert_accel_limited(&delays);
+        assert_eq!(stepper.pos(), Some(TRAVEL_LIMIT.get() - 16));
+        let jog = stepper.continuous_jog(|| true, Direction::ToHome).unwrap();
+        jog.for_each(drop);
+        assert_eq!(stepper.pos(), Some(16));
+        // already at the soft limit.
+        let jog = stepper.continuous_jog(|| true, Direction::ToHome).unwrap();
+        assert_eq!(jog.count(), 0);
+
+        // seeking an endstop goes past them.
+        stepper.set_seek_endstop(true);
+        let (steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+        steps.for_each(drop);
+        assert_eq!(stepper.pos(), Some(TRAVEL_LIMIT.get()));
+        let mut jog = 0;
+        let steps = stepper
+            .continuous_jog(
+                || {
+                    jog += 1;
+                    jog <= 10
+                },
+                Direction::AwayFromHome,
+            )
+            .unwrap();
+        steps.for_each(drop);
+        assert_eq!(stepper.pos(), Some(TRAVEL_LIMIT.get() + 10));
+    }
+
+    #[test]
+    fn test_len() {
+        let mut stepper = homed_stepper();
+        stepper.set_backlash(10);
+        let (mut steps, _) = stepper.planned_move(1000).unwrap();
+        assert_eq!(steps.len(), 1010);
+        steps.by_ref().take(5).for_each(drop);
+        assert_eq!(steps.len(), 1005);
+        steps.by_ref().take(100).for_each(drop);
+        assert_eq!(steps.len(), 905);
+        steps.abort();
+        let left = steps.len();
+        assert_eq!(steps.by_ref().count(), left);
+        assert_eq!(steps.len(), 0);
+    }
+
+    /// Checks the estimate is within 5% of how long the rest of the plan really takes.
+    fn assert_estimate_close(steps: &mut PlannedMove<'_>) {
+        let estimate = steps.total_duration_estimate().as_ticks() as f64;
+        let actual = steps.by_ref().map(|d| d.as_ticks()).sum::<u64>() as f64;
+        assert!(
+            (estimate - actual).abs() <= actual * 0.05,
+            "estimated {estimate}, took {actual}"
+        );
+    }
+
+    #[test]
+    fn test_duration_estimate() {
+        // long enough to cruise, too short to, and from part way along.
+        for target in [TRAVEL_LIMIT.get(), 300] {
+            let mut stepper = homed_stepper();
+            let (mut steps, _) = stepper.planned_move(target).unwrap();
+            assert_estimate_close(&mut steps);
+        }
+        for taken in [100, 1000, 1900] {
+            let mut stepper = homed_stepper();
+            let (mut steps, _) = stepper.planned_move(TRAVEL_LIMIT.get()).unwrap();
+            steps.by_ref().take(taken).for_each(drop);
+            assert_estimate_close(&mut steps);
+        }
+        let mut stepper = homed_stepper();
+        let (steps, _) = stepper.planned_move(0).unwrap();
+        assert_eq!(steps.total_duration_estimate(), Duration::from_ticks(0));
+    }
+
     #[test]
     fn test_move_max_vel() {
         let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
-        let mut steps = stepper.homing_move(|| true);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || true);
         steps.next();
         dbg!(&stepper);
 
@@ -459,7 +2000,8 @@ mod test {
         print!("speed,delay");
         for step in steps {
             println!("{},{}", (TICK_HZ / step.as_ticks()), step.as_ticks());
-            assert!(step >= Duration::from_hz(MAX_VEL.get().into()));
+            // the cruise keeps to the ideal period, so it can be truncated rather than rounded.
+            assert!(step.as_ticks() >= TICK_HZ / u64::from(MAX_VEL.get()));
         }
         assert_eq!(stepper.curent_pos, Some(TRAVEL_LIMIT.get()));
     }
@@ -467,7 +2009,7 @@ mod test {
     #[test]
     fn test_move_max_accel() {
         let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
-        let mut steps = stepper.homing_move(|| true);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || true);
         steps.next();
         dbg!(&stepper);
 
@@ -525,7 +2067,7 @@ mod test {
     #[test]
     fn test_move_max_accel_short() {
         let mut stepper = Stepper::new(TRAVEL_LIMIT, MAX_VEL, MAX_ACCEL, START_VEL);
-        let mut steps = stepper.homing_move(|| true);
+        let mut steps = stepper.homing_move(HOMING_TRAVEL, || true);
         steps.next();
         dbg!(&stepper);
 